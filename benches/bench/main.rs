@@ -217,6 +217,19 @@ fn config() -> Config {
                     format!("ws://{}", SERVER_TWO_ENDPOINT),
                 ],
                 shuffle_endpoints: false,
+                max_concurrent_requests: None,
+                reserved_internal_requests: 16,
+                max_subscriptions: Default::default(),
+                header_forwarding: Vec::new(),
+                idle_timeout_ms: Default::default(),
+                queue: None,
+                subscribe_timeout_ms: None,
+                load_balancing: Default::default(),
+                circuit_breaker: None,
+                health_check: None,
+                genesis_hash: None,
+                max_header_clients: 64,
+                header_client_idle_secs: 300,
             }),
             server: Some(ServerConfig {
                 listen_address: SUBWAY_SERVER_ADDR.to_string(),
@@ -225,6 +238,25 @@ fn config() -> Config {
                 request_timeout_seconds: 120,
                 http_methods: Vec::new(),
                 cors: None,
+                unsupported_methods: None,
+                max_batch_size: None,
+                max_concurrent_calls_per_connection: None,
+                access_log_format: Default::default(),
+                bind_retry_attempts: 3,
+                bind_retry_delay_ms: 1000,
+                strict_feature_flags: false,
+                echo_method: None,
+                http_request_timeout_ms: None,
+                max_subscription_lifetime_secs: None,
+                proxy_protocol: None,
+                graceful_shutdown_timeout_secs: 30,
+                enable_http: true,
+                enable_ws: true,
+                health: None,
+                tls: None,
+                unix_socket_path: None,
+                idle_timeout_secs: None,
+                max_subscriptions_per_connection: None,
             }),
             substrate_api: Some(SubstrateApiConfig {
                 stale_timeout_seconds: 5_000,
@@ -244,6 +276,7 @@ fn config() -> Config {
                     cache: None,
                     delay_ms: None,
                     rate_limit_weight: 1,
+                    ..Default::default()
                 },
                 RpcMethod {
                     method: helpers::ASYNC_FAST_CALL.to_string(),
@@ -252,6 +285,7 @@ fn config() -> Config {
                     cache: None,
                     delay_ms: None,
                     rate_limit_weight: 1,
+                    ..Default::default()
                 },
                 RpcMethod {
                     method: helpers::SYNC_MEM_CALL.to_string(),
@@ -260,6 +294,7 @@ fn config() -> Config {
                     cache: None,
                     delay_ms: None,
                     rate_limit_weight: 1,
+                    ..Default::default()
                 },
                 RpcMethod {
                     method: helpers::ASYNC_MEM_CALL.to_string(),
@@ -268,6 +303,7 @@ fn config() -> Config {
                     cache: None,
                     delay_ms: None,
                     rate_limit_weight: 1,
+                    ..Default::default()
                 },
                 RpcMethod {
                     method: helpers::SYNC_SLOW_CALL.to_string(),
@@ -276,6 +312,7 @@ fn config() -> Config {
                     cache: None,
                     delay_ms: None,
                     rate_limit_weight: 1,
+                    ..Default::default()
                 },
                 RpcMethod {
                     method: helpers::ASYNC_SLOW_CALL.to_string(),
@@ -284,6 +321,7 @@ fn config() -> Config {
                     cache: None,
                     delay_ms: None,
                     rate_limit_weight: 1,
+                    ..Default::default()
                 },
                 RpcMethod {
                     method: helpers::ASYNC_INJECT_CALL.to_string(),
@@ -293,18 +331,27 @@ fn config() -> Config {
                             ty: "u64".to_string(),
                             optional: false,
                             inject: false,
+                            pattern: None,
+                            resolve_number: false,
+                            default: None,
+                            coerce: None,
                         },
                         MethodParam {
                             name: "bar".to_string(),
                             ty: "BlockNumber".to_string(),
                             optional: true,
                             inject: true,
+                            pattern: None,
+                            resolve_number: false,
+                            default: None,
+                            coerce: None,
                         },
                     ],
                     response: None,
                     cache: None,
                     delay_ms: None,
                     rate_limit_weight: 1,
+                    ..Default::default()
                 },
             ],
             subscriptions: vec![RpcSubscription {
@@ -312,8 +359,15 @@ fn config() -> Config {
                 unsubscribe: helpers::UNSUB_METHOD_NAME.to_string(),
                 name: helpers::SUB_METHOD_NAME.to_string(),
                 merge_strategy: Some(MergeStrategy::Replace),
+                coalesce_key: None,
+                batch: None,
+                max_lifetime_secs: None,
+                filter: None,
+                replay_last: false,
+                backpressure: None,
             }],
             aliases: vec![],
+            method_groups: vec![],
         },
     }
 }
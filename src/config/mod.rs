@@ -1,9 +1,12 @@
-use std::fs;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use serde::Deserialize;
 
-use crate::extensions::ExtensionsConfig;
+use crate::{extensions::ExtensionsConfig, middlewares::methods::transform_response};
 pub use rpc::*;
 
 mod rpc;
@@ -13,22 +16,94 @@ const ETHEREUM_CONFIG: &str = include_str!("../../rpc_configs/ethereum.yml");
 
 #[derive(Parser, Debug)]
 #[command(version, about)]
-struct Command {
+pub struct Command {
     /// The config file to use
-    #[arg(short, long, default_value = "./config.yml")]
-    config: String,
+    #[arg(short, long, default_value = "./config.yml", global = true)]
+    pub config: String,
+    /// Override the server port
+    #[arg(long, global = true)]
+    pub port: Option<u16>,
+    /// Override the upstream endpoints, can be repeated
+    #[arg(long = "endpoint", global = true)]
+    pub endpoints: Vec<String>,
+    /// Serve the config if not set
+    #[command(subcommand)]
+    pub action: Option<Action>,
+}
+
+#[derive(Subcommand, Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// Validate the config and print it with the environment overrides applied
+    Check {
+        /// The config file to use, instead of `--config`
+        file: Option<String>,
+    },
+    /// Create the extensions and methods and connect to upstream, without serving
+    DryRun {
+        /// The config file to use, instead of `--config`
+        file: Option<String>,
+    },
+}
+
+impl Command {
+    /// Reads the config file with the environment and command line overrides applied, see `read_config_file`.
+    pub fn read_config(&self) -> Result<Config, String> {
+        let mut config = load_config(self.config_file())?;
+
+        if !self.endpoints.is_empty() {
+            log::debug!("Override endpoints with --endpoint");
+            config
+                .extensions
+                .client
+                .as_mut()
+                .expect("Client extension not configured")
+                .endpoints = self.endpoints.clone();
+        }
+
+        if let Some(port) = self.port {
+            log::debug!("Override port with --port");
+            config
+                .extensions
+                .server
+                .as_mut()
+                .expect("Server extension not configured")
+                .port = port;
+        }
+
+        validate_config(&config)?;
+
+        Ok(config)
+    }
+
+    pub fn config_file(&self) -> &str {
+        match self.action {
+            Some(Action::Check { file: Some(ref file) }) | Some(Action::DryRun { file: Some(ref file) }) => file,
+            _ => &self.config,
+        }
+    }
 }
 
 #[derive(Deserialize, Debug)]
 pub struct RpcDefinitionsWithBase {
-    #[serde(default)]
-    pub base: Option<RpcOptions>,
+    /// A preset (`substrate` or `ethereum`), a file or inline definitions, or a list of them
+    /// applied in order, extended and overridden by the definitions of this file.
+    #[serde(default, alias = "extends")]
+    pub base: Option<RpcBase>,
     #[serde(default)]
     pub methods: Vec<RpcMethod>,
     #[serde(default)]
     pub subscriptions: Vec<RpcSubscription>,
-    #[serde(default)]
+    #[serde(default, deserialize_with = "deserialize_aliases")]
     pub aliases: Vec<(String, String)>,
+    #[serde(default)]
+    pub method_groups: Vec<MethodGroup>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum RpcBase {
+    One(RpcOptions),
+    Many(Vec<RpcOptions>),
 }
 
 #[derive(Deserialize, Debug)]
@@ -38,91 +113,161 @@ pub enum RpcOptions {
     Custom(Box<RpcDefinitionsWithBase>),
 }
 
-impl From<RpcDefinitionsWithBase> for RpcDefinitions {
-    fn from(defs: RpcDefinitionsWithBase) -> Self {
-        if let Some(base) = defs.base {
-            let base: RpcDefinitions = base.into();
+/// Files being included while resolving rpc definitions, to resolve relative paths and reject cycles.
+#[derive(Debug, Default)]
+struct IncludeStack {
+    // directory of the including file, relative paths are resolved against it
+    dir: PathBuf,
+    files: Vec<PathBuf>,
+}
 
-            let mut methods = base.methods;
-            methods.sort_by(|a, b| a.method.cmp(&b.method));
-            for m in defs.methods {
-                let idx = methods.binary_search_by(|probe| probe.method.cmp(&m.method));
-                match idx {
-                    Ok(i) => {
-                        methods[i] = m;
-                    }
-                    Err(i) => {
-                        methods.insert(i, m);
-                    }
+impl RpcDefinitionsWithBase {
+    fn resolve(self, includes: &mut IncludeStack) -> Result<RpcDefinitions, String> {
+        let own = RpcDefinitions {
+            methods: self.methods,
+            subscriptions: self.subscriptions,
+            aliases: self.aliases,
+            method_groups: self.method_groups,
+        };
+        let bases = match self.base {
+            None => vec![],
+            Some(RpcBase::One(base)) => vec![base],
+            Some(RpcBase::Many(bases)) => bases,
+        };
+
+        // each base overrides the previous ones, the definitions of the file override them all
+        let bases = bases
+            .into_iter()
+            .map(|base| base.resolve(includes))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut bases = bases.into_iter();
+        Ok(match bases.next() {
+            Some(first) => bases.chain(std::iter::once(own)).fold(first, RpcDefinitions::extend),
+            None => own,
+        })
+    }
+}
+
+impl TryFrom<RpcDefinitionsWithBase> for RpcDefinitions {
+    type Error = String;
+
+    fn try_from(defs: RpcDefinitionsWithBase) -> Result<Self, Self::Error> {
+        defs.resolve(&mut IncludeStack::default())
+    }
+}
+
+impl RpcDefinitions {
+    /// Replaces the methods, subscriptions, aliases and groups of the same name by the ones of `overrides`
+    /// and adds the others.
+    fn extend(self, overrides: RpcDefinitions) -> Self {
+        let mut methods = self.methods;
+        methods.sort_by(|a, b| a.method.cmp(&b.method));
+        for m in overrides.methods {
+            let idx = methods.binary_search_by(|probe| probe.method.cmp(&m.method));
+            match idx {
+                Ok(i) => {
+                    methods[i] = m;
+                }
+                Err(i) => {
+                    methods.insert(i, m);
                 }
             }
+        }
 
-            let mut subscriptions = base.subscriptions;
-            subscriptions.sort_by(|a, b| a.name.cmp(&b.name));
-            for s in defs.subscriptions {
-                let idx = subscriptions.binary_search_by(|probe| probe.name.cmp(&s.name));
-                match idx {
-                    Ok(i) => {
-                        subscriptions[i] = s;
-                    }
-                    Err(i) => {
-                        subscriptions.insert(i, s);
-                    }
+        let mut subscriptions = self.subscriptions;
+        subscriptions.sort_by(|a, b| a.name.cmp(&b.name));
+        for s in overrides.subscriptions {
+            let idx = subscriptions.binary_search_by(|probe| probe.name.cmp(&s.name));
+            match idx {
+                Ok(i) => {
+                    subscriptions[i] = s;
+                }
+                Err(i) => {
+                    subscriptions.insert(i, s);
                 }
             }
+        }
 
-            let mut aliases = base.aliases;
-            aliases.sort_by(|a, b| a.0.cmp(&b.0));
-            for a in defs.aliases {
-                let idx = aliases.binary_search_by(|probe| probe.0.cmp(&a.0));
-                match idx {
-                    Ok(i) => {
-                        aliases[i] = a;
-                    }
-                    Err(i) => {
-                        aliases.insert(i, a);
-                    }
+        // aliases are unique by alias name, a method can have many aliases
+        let mut aliases = self.aliases;
+        aliases.sort_by(|a, b| a.1.cmp(&b.1));
+        for a in overrides.aliases {
+            let idx = aliases.binary_search_by(|probe| probe.1.cmp(&a.1));
+            match idx {
+                Ok(i) => {
+                    aliases[i] = a;
+                }
+                Err(i) => {
+                    aliases.insert(i, a);
                 }
             }
+        }
 
-            return RpcDefinitions {
-                methods,
-                subscriptions,
-                aliases,
-            };
+        // groups are replaced by name
+        let mut method_groups = self.method_groups;
+        for g in overrides.method_groups {
+            match method_groups.iter_mut().find(|probe| probe.name == g.name) {
+                Some(group) => *group = g,
+                None => method_groups.push(g),
+            }
         }
+
         RpcDefinitions {
-            methods: defs.methods,
-            subscriptions: defs.subscriptions,
-            aliases: defs.aliases,
+            methods,
+            subscriptions,
+            aliases,
+            method_groups,
         }
     }
 }
 
-impl From<RpcOptions> for RpcDefinitions {
-    fn from(val: RpcOptions) -> Self {
-        match val {
+impl RpcOptions {
+    fn resolve(self, includes: &mut IncludeStack) -> Result<RpcDefinitions, String> {
+        match self {
             RpcOptions::Path(path) => match path.to_lowercase().as_str() {
-                "sub" | "substrate" => serde_yaml::from_str(SUBSTRATE_CONFIG).unwrap(),
-                "eth" | "ethereum" => serde_yaml::from_str(ETHEREUM_CONFIG).unwrap(),
+                "sub" | "substrate" => Ok(serde_yaml::from_str(SUBSTRATE_CONFIG).unwrap()),
+                "eth" | "ethereum" => Ok(serde_yaml::from_str(ETHEREUM_CONFIG).unwrap()),
                 _ => {
-                    let file = fs::File::open(path).expect("Invalid rpc config path");
-                    let defs: RpcDefinitionsWithBase = serde_yaml::from_reader(file).expect("Invalid rpc config file");
-                    defs.into()
+                    let path = includes.dir.join(path);
+                    let file = fs::canonicalize(&path)
+                        .map_err(|e| format!("Invalid rpc config path {}: {e}", path.display()))?;
+                    if includes.files.contains(&file) {
+                        return Err(format!("Rpc config {} extends itself", path.display()));
+                    }
+                    let reader = fs::File::open(&file)
+                        .map_err(|e| format!("Invalid rpc config path {}: {e}", path.display()))?;
+                    let defs: RpcDefinitionsWithBase = serde_yaml::from_reader(reader)
+                        .map_err(|e| format!("Invalid rpc config file {}: {e}", path.display()))?;
+
+                    let dir = file.parent().map(Path::to_path_buf).unwrap_or_default();
+                    let including_dir = std::mem::replace(&mut includes.dir, dir);
+                    includes.files.push(file);
+                    let result = defs.resolve(includes);
+                    includes.files.pop();
+                    includes.dir = including_dir;
+                    result
                 }
             },
-            RpcOptions::Custom(defs) => (*defs).into(),
+            RpcOptions::Custom(defs) => defs.resolve(includes),
         }
     }
 }
 
-#[derive(Deserialize, Debug)]
+impl TryFrom<RpcOptions> for RpcDefinitions {
+    type Error = String;
+
+    fn try_from(val: RpcOptions) -> Result<Self, Self::Error> {
+        val.resolve(&mut IncludeStack::default())
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
 pub struct MiddlewaresConfig {
     pub methods: Vec<String>,
     pub subscriptions: Vec<String>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct Config {
     pub extensions: ExtensionsConfig,
     pub middlewares: MiddlewaresConfig,
@@ -136,24 +281,54 @@ pub struct ParseConfig {
     pub rpcs: RpcOptions,
 }
 
-impl From<ParseConfig> for Config {
-    fn from(val: ParseConfig) -> Self {
-        Config {
-            extensions: val.extensions,
-            middlewares: val.middlewares,
-            rpcs: val.rpcs.into(),
-        }
+impl ParseConfig {
+    /// Resolves the rpc definitions, relative paths are relative to `dir`.
+    fn into_config(self, dir: &Path) -> Result<Config, String> {
+        let mut includes = IncludeStack {
+            dir: dir.to_path_buf(),
+            files: vec![],
+        };
+        Ok(Config {
+            extensions: self.extensions,
+            middlewares: self.middlewares,
+            rpcs: self.rpcs.resolve(&mut includes)?,
+        })
+    }
+}
+
+impl TryFrom<ParseConfig> for Config {
+    type Error = String;
+
+    fn try_from(val: ParseConfig) -> Result<Self, Self::Error> {
+        val.into_config(Path::new(""))
     }
 }
 
 // read config file specified in command line
 pub fn read_config() -> Result<Config, String> {
-    let cmd = Command::parse();
+    Command::parse().read_config()
+}
 
-    let config = fs::File::open(cmd.config).map_err(|e| format!("Unable to open config file: {e}"))?;
-    let config: ParseConfig =
+/// Reads the config file at `path`, applies the environment overrides and validates the result.
+pub fn read_config_file(path: &str) -> Result<Config, String> {
+    let config = load_config(path)?;
+    // TODO: shouldn't need to do this here. Creating a server should validates everything
+    validate_config(&config)?;
+    Ok(config)
+}
+
+/// Prefix of the environment variables overriding config values, see `apply_env_overrides`.
+const ENV_PREFIX: &str = "SUBWAY_";
+
+fn load_config(path: &str) -> Result<Config, String> {
+    let config = fs::File::open(path).map_err(|e| format!("Unable to open config file: {e}"))?;
+    let mut config: serde_yaml::Value =
         serde_yaml::from_reader(&config).map_err(|e| format!("Unable to parse config file: {e}"))?;
-    let mut config: Config = config.into();
+    apply_env_overrides(&mut config, std::env::vars())?;
+    let config: ParseConfig =
+        serde_yaml::from_value(config).map_err(|e| format!("Unable to parse config file: {e}"))?;
+    let dir = Path::new(path).parent().unwrap_or(Path::new(""));
+    let mut config = config.into_config(dir)?;
 
     if let Ok(endpoints) = std::env::var("ENDPOINTS") {
         log::debug!("Override endpoints with env.ENDPOINTS");
@@ -185,12 +360,46 @@ pub fn read_config() -> Result<Config, String> {
         }
     }
 
-    // TODO: shouldn't need to do this here. Creating a server should validates everything
-    validate_config(&config)?;
-
     Ok(config)
 }
 
+/// Sets the config values named by `SUBWAY_` variables, e.g. `SUBWAY_SERVER__PORT=9955`.
+/// Path segments are separated by `__` and lowercased, paths not starting with `extensions`, `middlewares`
+/// or `rpcs` are relative to `extensions`. Values are parsed as YAML, e.g. `[a, b]` for a list.
+fn apply_env_overrides(
+    config: &mut serde_yaml::Value,
+    vars: impl IntoIterator<Item = (String, String)>,
+) -> Result<(), String> {
+    for (key, value) in vars {
+        let Some(path) = key.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        let mut segments = path.split("__").map(str::to_lowercase).collect::<Vec<_>>();
+        if segments.iter().any(|s| s.is_empty()) {
+            return Err(format!("Invalid config override {key}"));
+        }
+        if !["extensions", "middlewares", "rpcs"].contains(&segments[0].as_str()) {
+            segments.insert(0, "extensions".to_string());
+        }
+        let value: serde_yaml::Value =
+            serde_yaml::from_str(&value).map_err(|e| format!("Invalid value of {key}: {e}"))?;
+
+        log::debug!("Override {} with env.{key}", segments.join("."));
+        let mut target = &mut *config;
+        for segment in segments {
+            if !target.is_mapping() {
+                *target = serde_yaml::Value::Mapping(Default::default());
+            }
+            let mapping = target.as_mapping_mut().expect("set above");
+            target = mapping.entry(segment.into()).or_insert(serde_yaml::Value::Null);
+        }
+        *target = value;
+    }
+    Ok(())
+}
+
+const SERVE_FROM_HEAD_METHODS: &[&str] = &["chain_getHeader", "chain_getBlockHash"];
+
 fn validate_config(config: &Config) -> Result<(), String> {
     // TODO: validate logic should be in each individual extensions
     // validate endpoints
@@ -200,6 +409,13 @@ fn validate_config(config: &Config) -> Result<(), String> {
         }
     }
 
+    // ensure at least one transport is served
+    if let Some(server) = &config.extensions.server {
+        if !server.enable_http && !server.enable_ws {
+            return Err("Server must enable HTTP or WebSocket".to_string());
+        }
+    }
+
     // ensure each method has only one param with inject=true
     for method in &config.rpcs.methods {
         if method.params.iter().filter(|x| x.inject).count() > 1 {
@@ -207,6 +423,43 @@ fn validate_config(config: &Config) -> Result<(), String> {
         }
     }
 
+    // ensure serve_from_head is only set on methods that can be answered from head
+    for method in &config.rpcs.methods {
+        if method.serve_from_head && !SERVE_FROM_HEAD_METHODS.contains(&method.method.as_str()) {
+            return Err(format!("Method {} can not be served from head", method.method));
+        }
+    }
+
+    // ensure coalesced notifications are queued in a bounded buffer
+    for subscription in &config.rpcs.subscriptions {
+        if subscription.coalesce_key.is_some() && subscription.backpressure.is_none() {
+            return Err(format!(
+                "Subscription {} has a coalesce_key but no backpressure",
+                subscription.subscribe
+            ));
+        }
+    }
+
+    // ensure param patterns compile
+    for method in &config.rpcs.methods {
+        for param in &method.params {
+            if let Some(pattern) = &param.pattern {
+                regex::Regex::new(pattern)
+                    .map_err(|e| format!("Method {} param {}: {e}", method.method, param.name))?;
+            }
+        }
+    }
+
+    // ensure response transforms compile
+    for method in &config.rpcs.methods {
+        for filter in &method.transform_response {
+            transform_response::compile(filter).map_err(|e| format!("Method {}: {e}", method.method))?;
+        }
+    }
+
+    // ensure aliases resolve to a method without cycles
+    config.rpcs.resolve_aliases()?;
+
     // ensure there is no required param after optional param
     for method in &config.rpcs.methods {
         let mut has_optional = false;
@@ -224,3 +477,151 @@ fn validate_config(config: &Config) -> Result<(), String> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn env_overrides_work() {
+        let mut config: serde_yaml::Value = serde_yaml::from_str(
+            r#"
+extensions:
+  server:
+    port: 9944
+middlewares:
+  methods: [upstream]
+"#,
+        )
+        .unwrap();
+
+        let vars = [
+            ("SUBWAY_SERVER__PORT", "9955"),
+            ("SUBWAY_CLIENT__ENDPOINTS", "[wss://a, wss://b]"),
+            ("SUBWAY_MIDDLEWARES__METHODS", "[cache, upstream]"),
+            ("PORT", "1"),
+        ]
+        .map(|(k, v)| (k.to_string(), v.to_string()));
+        apply_env_overrides(&mut config, vars).unwrap();
+
+        assert_eq!(config["extensions"]["server"]["port"], 9955);
+        assert_eq!(
+            config["extensions"]["client"]["endpoints"],
+            serde_yaml::from_str::<serde_yaml::Value>("[wss://a, wss://b]").unwrap()
+        );
+        assert_eq!(config["middlewares"]["methods"][0], "cache");
+
+        let vars = [("SUBWAY_SERVER____PORT".to_string(), "1".to_string())];
+        assert!(apply_env_overrides(&mut config, vars).is_err());
+    }
+
+    #[test]
+    fn extends_presets_in_order() {
+        let defs: RpcDefinitionsWithBase = serde_yaml::from_str(
+            r#"
+extends: [substrate, ethereum, { methods: [{ method: system_chain, cache: { size: 2 } }] }]
+methods:
+  - method: eth_chainId
+    cache:
+      size: 3
+"#,
+        )
+        .unwrap();
+        let defs: RpcDefinitions = defs.try_into().unwrap();
+
+        let cache_size = |name: &str| {
+            let method = defs.methods.iter().find(|m| m.method == name).unwrap();
+            method.cache.as_ref().and_then(|c| c.size)
+        };
+        assert!(defs.methods.iter().any(|m| m.method == "chain_getBlockHash"));
+        assert_eq!(cache_size("system_chain"), Some(2));
+        assert_eq!(cache_size("eth_chainId"), Some(3));
+        assert!(!defs.aliases.is_empty());
+    }
+
+    #[test]
+    fn extends_files_relative_to_including_file() {
+        let dir = std::env::temp_dir().join(format!("subway-extends-{}", std::process::id()));
+        fs::create_dir_all(dir.join("chain")).unwrap();
+        fs::write(dir.join("chain/base.yml"), "methods: [{ method: chain_base }]").unwrap();
+        fs::write(
+            dir.join("chain/rpcs.yml"),
+            "extends: ./base.yml\nmethods: [{ method: chain_own }]",
+        )
+        .unwrap();
+        fs::write(dir.join("self.yml"), "extends: ./self.yml").unwrap();
+        fs::write(dir.join("a.yml"), "extends: ./b.yml").unwrap();
+        fs::write(dir.join("b.yml"), "extends: [./chain/rpcs.yml, ./a.yml]").unwrap();
+
+        let mut includes = IncludeStack {
+            dir: dir.clone(),
+            files: vec![],
+        };
+        let defs = RpcOptions::Path("chain/rpcs.yml".to_string())
+            .resolve(&mut includes)
+            .unwrap();
+        let names = defs.methods.iter().map(|m| m.method.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["chain_base", "chain_own"]);
+
+        assert!(RpcOptions::Path("self.yml".to_string())
+            .resolve(&mut includes)
+            .unwrap_err()
+            .contains("extends itself"));
+        assert!(RpcOptions::Path("a.yml".to_string())
+            .resolve(&mut includes)
+            .unwrap_err()
+            .contains("extends itself"));
+        assert!(RpcOptions::Path("missing.yml".to_string())
+            .resolve(&mut includes)
+            .is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn coalesce_key_requires_backpressure() {
+        let config = |backpressure| Config {
+            extensions: ExtensionsConfig {
+                client: Some(Default::default()),
+                ..Default::default()
+            },
+            rpcs: RpcDefinitions {
+                subscriptions: vec![RpcSubscription {
+                    subscribe: "chain_subscribeNewHeads".to_string(),
+                    coalesce_key: Some("/number".to_string()),
+                    backpressure,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        assert!(validate_config(&config(None)).unwrap_err().contains("no backpressure"));
+        let backpressure = SubscriptionBackpressureParams {
+            buffer_size: 16,
+            disconnect_after: None,
+        };
+        assert!(validate_config(&config(Some(backpressure))).is_ok());
+    }
+
+    #[test]
+    fn serve_from_head_only_for_head_methods() {
+        let config = |method: &str| Config {
+            extensions: ExtensionsConfig {
+                client: Some(Default::default()),
+                ..Default::default()
+            },
+            rpcs: RpcDefinitions {
+                methods: vec![serde_yaml::from_str(&format!("{{ method: {method}, serve_from_head: true }}")).unwrap()],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        assert!(validate_config(&config("chain_getBlockHash")).is_ok());
+        assert!(validate_config(&config("state_getStorage"))
+            .unwrap_err()
+            .contains("can not be served from head"));
+    }
+}
@@ -1,12 +1,49 @@
+use std::collections::{BTreeMap, BTreeSet};
+
 use jsonrpsee::core::JsonValue;
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
 
-#[derive(Clone, Deserialize, Debug, Eq, PartialEq)]
+#[derive(Clone, Deserialize, Debug, Default, Eq, PartialEq)]
 pub struct CacheParams {
     #[serde(default)]
     pub size: Option<usize>,
     #[serde(default)]
     pub ttl_seconds: Option<u64>,
+    /// Include the runtime spec version in the cache key, so entries are not served across runtime upgrades.
+    #[serde(default)]
+    pub runtime_dependent: bool,
+    /// Time budget for reading a cached response from the disk tier or the shared backend, waiting for a
+    /// pending fetch of the same request is not bounded. A read exceeding it counts as a miss, and the method
+    /// timeout is extended by it so the upstream call still gets its full budget.
+    #[serde(default)]
+    pub lookup_timeout_ms: Option<u64>,
+    /// JSON pointer to the block hash or number in the response, e.g. `/hash`.
+    /// Responses are also cached with the injected block param replaced by it, so requests for
+    /// `latest` fill the cache of the resolved block. Requires the `cache_by_block` middleware.
+    #[serde(default)]
+    pub block_pointer: Option<String>,
+    /// Drop all cached responses of the method when a new block is finalized,
+    /// for head-sensitive calls that must not be served from an older block.
+    #[serde(default)]
+    pub invalidate_on_new_block: bool,
+    /// Normalize params before hashing them into the cache key, so semantically equal calls share an entry:
+    /// hex strings are lowercased, trailing `null` params dropped and object keys sorted.
+    #[serde(default)]
+    pub normalize_key: bool,
+    /// Also cache errors returned by upstream, e.g. an unknown block, overriding `cache.negative_caching.enabled`.
+    #[serde(default)]
+    pub cache_errors: Option<bool>,
+    /// Time to live of cached errors, overriding `cache.negative_caching.ttl_secs`.
+    #[serde(default)]
+    pub error_ttl_seconds: Option<u64>,
+    /// Cache responses forever only if their block param refers to a finalized block, requires
+    /// `substrate_api` or `eth_api`. `ttl_seconds` then defaults to no expiry.
+    #[serde(default)]
+    pub finalized_only: bool,
+    /// With `finalized_only`, cache responses at blocks not finalized yet for this long.
+    /// They are not cached at all if unset, as the block may still be reorged.
+    #[serde(default)]
+    pub unfinalized_ttl_seconds: Option<u64>,
 }
 
 #[derive(Clone, Deserialize, Debug, Eq, PartialEq)]
@@ -18,6 +55,30 @@ pub struct MethodParam {
     pub optional: bool,
     #[serde(default)]
     pub inject: bool,
+    /// Regex the param must match, strings as is and other values as JSON.
+    /// Requires the `validate` middleware.
+    #[serde(default)]
+    pub pattern: Option<String>,
+    /// Accept a block number for an injected `BlockHash` param and replace it with the hash of that block
+    /// from `chain_getBlockHash`. Requires the `inject_params` middleware.
+    #[serde(default)]
+    pub resolve_number: bool,
+    /// Used when the param is omitted or null. Omitted optional params before it are padded with null.
+    /// Requires the `params_transform` middleware.
+    #[serde(default)]
+    pub default: Option<JsonValue>,
+    /// Rewrites the given value, e.g. decimal block numbers to hex. Requires the `params_transform` middleware.
+    #[serde(default)]
+    pub coerce: Option<ParamCoercion>,
+}
+
+#[derive(Clone, Copy, Deserialize, Debug, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ParamCoercion {
+    /// Numbers and decimal strings to `0x` prefixed hex.
+    Hex,
+    /// `0x` prefixed strings to lowercase.
+    Lowercase,
 }
 
 #[derive(Deserialize, Debug)]
@@ -46,6 +107,122 @@ pub struct RpcMethod {
     /// Add this if you want to modify the default value of 1.
     #[serde(default = "default_rate_limit_weight")]
     pub rate_limit_weight: u32,
+
+    /// Overrides the server `request_timeout_seconds` for this method.
+    /// Useful for methods that legitimately take longer, e.g. `eth_getLogs`.
+    /// The middleware chain is cancelled when it expires, including the upstream call.
+    #[serde(default, alias = "timeout_ms")]
+    pub upstream_timeout_ms: Option<u64>,
+
+    /// Forward this method upstream under a different name.
+    #[serde(default)]
+    pub remap: Option<MethodRemap>,
+
+    /// Answer calls without params from the latest head received by the Api extension.
+    /// Only supported by `chain_getHeader` and `chain_getBlockHash`.
+    #[serde(default)]
+    pub serve_from_head: bool,
+
+    /// jq filters applied in order to the result, each one to the output of the previous,
+    /// e.g. `.logs[0]`. A filter without output yields `null`. Requires the `transform_response` middleware.
+    #[serde(default)]
+    pub transform_response: Vec<String>,
+
+    /// Returned instead of an error when upstream can not be reached, e.g. disconnected or timed out.
+    /// Errors returned by upstream itself are passed through. Requires the `fallback_response` middleware.
+    #[serde(default)]
+    pub fallback_response: Option<JsonValue>,
+
+    /// Reject every call with this message, e.g. to disable a method of the base config.
+    /// Requires the `validate` middleware.
+    #[serde(default)]
+    pub deny: Option<String>,
+
+    /// Reject calls with more params. Requires the `validate` middleware.
+    #[serde(default)]
+    pub max_params: Option<usize>,
+
+    /// Array results larger than this, in bytes of their JSON encoding, keep the leading items that fit.
+    /// Other results over it are rejected. Requires the `response_size` middleware.
+    /// Responses over `client.max_response_size` are rejected by the upstream client for every method.
+    #[serde(default)]
+    pub max_response_bytes: Option<usize>,
+
+    /// Retry the upstream call when it fails to reach upstream, e.g. disconnected or timed out.
+    /// Only set it for idempotent methods. Requires the `retry` middleware.
+    #[serde(default)]
+    pub retry: Option<RetryParams>,
+
+    /// Method middlewares of this method in order, replacing `middlewares.methods`,
+    /// e.g. `[validate, cache, upstream]`.
+    #[serde(default)]
+    pub middlewares: Option<Vec<String>>,
+}
+
+impl Default for RpcMethod {
+    fn default() -> Self {
+        Self {
+            method: Default::default(),
+            cache: None,
+            params: vec![],
+            response: None,
+            delay_ms: None,
+            rate_limit_weight: default_rate_limit_weight(),
+            upstream_timeout_ms: None,
+            remap: None,
+            serve_from_head: false,
+            transform_response: vec![],
+            fallback_response: None,
+            deny: None,
+            max_params: None,
+            max_response_bytes: None,
+            retry: None,
+            middlewares: None,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Debug, Eq, PartialEq)]
+pub struct RetryParams {
+    // including the first call
+    #[serde(default = "default_retry_max_attempts")]
+    pub max_attempts: u32,
+    // doubled after every attempt
+    #[serde(default = "default_retry_backoff_base_ms")]
+    pub backoff_base_ms: u64,
+    // up to this much random delay is added to each backoff
+    #[serde(default = "default_retry_jitter_ms")]
+    pub jitter_ms: u64,
+}
+
+fn default_retry_max_attempts() -> u32 {
+    3
+}
+
+fn default_retry_backoff_base_ms() -> u64 {
+    100
+}
+
+fn default_retry_jitter_ms() -> u64 {
+    50
+}
+
+#[derive(Clone, Deserialize, Debug, Eq, PartialEq)]
+pub struct MethodRemap {
+    /// Method name used for the upstream request
+    pub method: String,
+    /// Params for the upstream request. Original params are kept if not set.
+    #[serde(default)]
+    pub params: Option<Vec<RemapParam>>,
+}
+
+#[derive(Clone, Deserialize, Debug, Eq, PartialEq)]
+#[serde(untagged)]
+pub enum RemapParam {
+    /// Take the param at the given index of the original request, null if missing
+    From { from: usize },
+    /// Use a constant value
+    Value { value: JsonValue },
 }
 
 fn default_rate_limit_weight() -> u32 {
@@ -61,7 +238,7 @@ pub enum MergeStrategy {
     MergeStorageChanges,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Default)]
 pub struct RpcSubscription {
     pub subscribe: String,
     pub unsubscribe: String,
@@ -69,13 +246,207 @@ pub struct RpcSubscription {
 
     #[serde(default)]
     pub merge_strategy: Option<MergeStrategy>,
+
+    /// JSON pointer (e.g. `/block`) used to coalesce queued messages for slow subscribers.
+    /// Only the latest queued message per key is delivered, notifications where the pointer goes through an
+    /// array of several items are not coalesced. Requires `backpressure`.
+    #[serde(default)]
+    pub coalesce_key: Option<String>,
+
+    /// Deliver notifications in batches as a JSON array instead of one message per notification.
+    /// Clients of this subscription must expect arrays. Requires the `subscription_batch` middleware.
+    #[serde(default)]
+    pub batch: Option<SubscriptionBatchParams>,
+
+    /// Overrides `server.max_subscription_lifetime_secs` for this subscription.
+    #[serde(default)]
+    pub max_lifetime_secs: Option<u64>,
+
+    /// Drop notifications the client does not need. Requires the `subscription_filter` middleware.
+    #[serde(default)]
+    pub filter: Option<SubscriptionFilterParams>,
+
+    /// Send the last notification to new subscribers right away when the same subscription is already open.
+    /// Requires the `subscription_replay` middleware, merged subscriptions always do this.
+    #[serde(default)]
+    pub replay_last: bool,
+
+    /// Queue notifications of slow subscribers in a bounded buffer instead of waiting for them.
+    /// Requires the `subscription_backpressure` middleware.
+    #[serde(default)]
+    pub backpressure: Option<SubscriptionBackpressureParams>,
+
+    /// Subscribe again when the upstream subscription fails or its connection is lost.
+    /// Defaults to true, except for `submitAndWatch` subscriptions which would submit the transaction again.
+    #[serde(default)]
+    pub resubscribe: Option<bool>,
+}
+
+impl RpcSubscription {
+    pub fn resubscribes(&self) -> bool {
+        self.resubscribe.unwrap_or(!self.subscribe.contains("submitAndWatch"))
+    }
+}
+
+#[derive(Clone, Deserialize, Debug, Eq, PartialEq)]
+pub struct SubscriptionBackpressureParams {
+    /// Notifications queued per subscriber, the oldest is dropped when full.
+    pub buffer_size: usize,
+    /// Disconnect subscribers once they dropped more notifications than this.
+    #[serde(default)]
+    pub disconnect_after: Option<u64>,
+}
+
+#[derive(Clone, Deserialize, Debug, Eq, PartialEq)]
+pub struct SubscriptionBatchParams {
+    /// A batch is sent once it holds this many notifications.
+    pub batch_size: usize,
+    /// A partial batch is sent this long after its first notification.
+    pub batch_timeout_ms: u64,
+}
+
+#[derive(Clone, Deserialize, Debug, Default, Eq, PartialEq)]
+pub struct SubscriptionFilterParams {
+    /// Drop notifications identical to the previous one sent.
+    #[serde(default)]
+    pub dedup: bool,
+    /// Drop notifications arriving sooner than this after the previous one sent.
+    #[serde(default)]
+    pub min_interval_ms: Option<u64>,
+    /// Index of an optional param holding a client predicate, an object of json pointers to the values
+    /// notifications must have, e.g. `{"/event/section": "balances"}`. It is removed before subscribing upstream.
+    #[serde(default)]
+    pub predicate_param: Option<usize>,
 }
 
 #[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum RpcAlias {
+    // [target, alias]
+    Pair(String, String),
+    // { method: target, aliases: [alias1, alias2] }
+    Many { method: String, aliases: Vec<String> },
+}
+
+/// Accepts both `[target, alias]` pairs and `{ method, aliases }` entries and flattens them into pairs.
+pub fn deserialize_aliases<'de, D>(deserializer: D) -> Result<Vec<(String, String)>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let entries = Vec::<RpcAlias>::deserialize(deserializer)?;
+    Ok(entries
+        .into_iter()
+        .flat_map(|entry| match entry {
+            RpcAlias::Pair(target, alias) => vec![(target, alias)],
+            RpcAlias::Many { method, aliases } => aliases.into_iter().map(|alias| (method.clone(), alias)).collect(),
+        })
+        .collect())
+}
+
+#[derive(Deserialize, Debug, Default)]
 pub struct RpcDefinitions {
     pub methods: Vec<RpcMethod>,
     #[serde(default)]
     pub subscriptions: Vec<RpcSubscription>,
-    #[serde(default)]
+    /// (target, alias) pairs, an alias can point to another alias
+    #[serde(default, deserialize_with = "deserialize_aliases")]
     pub aliases: Vec<(String, String)>,
+    #[serde(default)]
+    pub method_groups: Vec<MethodGroup>,
+}
+
+/// Methods sharing execution resources, e.g. expensive methods isolated from cheap ones.
+#[derive(Clone, Deserialize, Debug, Eq, PartialEq)]
+pub struct MethodGroup {
+    pub name: String,
+    pub methods: Vec<String>,
+    /// Run the middlewares after `bulkhead` of the methods of this group on a dedicated runtime with this many
+    /// worker threads, so their CPU work cannot starve other methods. Upstream I/O stays on the main runtime.
+    /// Requires the `bulkhead` middleware.
+    #[serde(default)]
+    pub runtime_threads: Option<usize>,
+    /// At most this many calls of the group are in flight at once, further calls wait for one to
+    /// finish. Bounds the share of the upstream client the group can take. Requires the `bulkhead` middleware.
+    #[serde(default)]
+    pub max_concurrent_calls: Option<usize>,
+}
+
+impl RpcDefinitions {
+    /// Resolves alias chains so every alias points to a method which is not an alias itself.
+    /// Returns (target, alias) pairs.
+    pub fn resolve_aliases(&self) -> Result<Vec<(String, String)>, String> {
+        let mut targets = BTreeMap::<&str, &str>::new();
+        for (target, alias) in &self.aliases {
+            if let Some(existing) = targets.insert(alias, target) {
+                if existing != target {
+                    return Err(format!("Alias {alias} points to both {existing} and {target}"));
+                }
+            }
+        }
+
+        let mut resolved = Vec::with_capacity(targets.len());
+        for (alias, target) in &targets {
+            let mut visited = BTreeSet::from([*alias]);
+            let mut target = *target;
+            while let Some(next) = targets.get(target) {
+                if !visited.insert(target) {
+                    return Err(format!("Alias {alias} has a cycle through {target}"));
+                }
+                target = next;
+            }
+            if visited.contains(target) {
+                return Err(format!("Alias {alias} has a cycle through {target}"));
+            }
+            resolved.push((target.to_string(), alias.to_string()));
+        }
+
+        Ok(resolved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn definitions(aliases: &str) -> RpcDefinitions {
+        serde_yaml::from_str(&format!("methods: []\naliases: {aliases}")).unwrap()
+    }
+
+    #[test]
+    fn aliases_accept_pairs_and_lists() {
+        let defs = definitions("[[foo, bar], { method: eth_chainId, aliases: [net_chainId, legacy_chainId] }]");
+        assert_eq!(
+            defs.aliases,
+            vec![
+                ("foo".to_string(), "bar".to_string()),
+                ("eth_chainId".to_string(), "net_chainId".to_string()),
+                ("eth_chainId".to_string(), "legacy_chainId".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn resolve_alias_chains() {
+        let defs = definitions("[[b, c], [a, b], [a, d]]");
+        assert_eq!(
+            defs.resolve_aliases().unwrap(),
+            vec![
+                ("a".to_string(), "b".to_string()),
+                ("a".to_string(), "c".to_string()),
+                ("a".to_string(), "d".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn reject_alias_cycles() {
+        let defs = definitions("[[a, b], [b, c], [c, a]]");
+        assert!(defs.resolve_aliases().unwrap_err().contains("cycle"));
+
+        let defs = definitions("[[a, a]]");
+        assert!(defs.resolve_aliases().unwrap_err().contains("cycle"));
+
+        let defs = definitions("[[a, c], [b, c]]");
+        assert!(defs.resolve_aliases().unwrap_err().contains("points to both"));
+    }
 }
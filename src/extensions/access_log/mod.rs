@@ -0,0 +1,389 @@
+use std::{
+    io::{self, Write},
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        mpsc::{self, SyncSender, TrySendError},
+        Arc,
+    },
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::{future::BoxFuture, FutureExt};
+use http::HeaderMap;
+use jsonrpsee::{
+    server::{middleware::rpc::RpcServiceT, types::Request},
+    MethodResponse,
+};
+use serde::{Deserialize, Serialize};
+
+use super::{Extension, ExtensionRegistry};
+use crate::logger::RotatingFileWriter;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct AccessLogConfig {
+    pub path: String,
+    // rotate when the file grows beyond this size
+    #[serde(default)]
+    pub max_size_bytes: Option<u64>,
+    // rotate when the file is older than this
+    #[serde(default)]
+    pub rotate_interval_secs: Option<u64>,
+    // number of rotated files to keep
+    #[serde(default = "default_max_files")]
+    pub max_files: usize,
+    // params longer than this are truncated in the log
+    #[serde(default = "default_max_params_length")]
+    pub max_params_length: usize,
+}
+
+fn default_max_files() -> usize {
+    5
+}
+
+fn default_max_params_length() -> usize {
+    256
+}
+
+/// Line format of the access log.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessLogFormat {
+    /// NDJSON with all fields
+    #[default]
+    Structured,
+    /// Apache combined log format
+    Combined,
+    /// Apache common log format
+    Common,
+}
+
+pub struct AccessLog {
+    config: AccessLogConfig,
+    writer: AccessLogWriter,
+}
+
+#[async_trait]
+impl Extension for AccessLog {
+    type Config = AccessLogConfig;
+
+    async fn from_config(config: &Self::Config, _registry: &ExtensionRegistry) -> Result<Self, anyhow::Error> {
+        Self::new(config.clone())
+    }
+}
+
+impl AccessLog {
+    pub fn new(config: AccessLogConfig) -> Result<Self, anyhow::Error> {
+        let writer = RotatingFileWriter::new(
+            &config.path,
+            config.max_size_bytes,
+            config.rotate_interval_secs.map(Duration::from_secs),
+            config.max_files,
+        )?;
+
+        Ok(Self {
+            config,
+            writer: AccessLogWriter::spawn(writer)?,
+        })
+    }
+
+    pub fn layer(&self, format: AccessLogFormat, client_id: String, headers: &HeaderMap) -> AccessLogLayer {
+        let header =
+            |name: http::header::HeaderName| headers.get(name).and_then(|v| v.to_str().ok()).map(ToOwned::to_owned);
+
+        AccessLogLayer {
+            format,
+            client_id,
+            referer: header(http::header::REFERER),
+            user_agent: header(http::header::USER_AGENT),
+            max_params_length: self.config.max_params_length,
+            writer: self.writer.clone(),
+        }
+    }
+}
+
+// lines waiting for the writer thread, further lines are dropped
+const MAX_PENDING_LINES: usize = 8192;
+
+/// Sends lines to a dedicated thread writing them to the file, so serving requests never waits on disk.
+/// The thread stops once every sender is dropped.
+#[derive(Clone)]
+pub struct AccessLogWriter(SyncSender<String>);
+
+impl AccessLogWriter {
+    pub fn spawn(mut writer: RotatingFileWriter) -> io::Result<Self> {
+        let (tx, rx) = mpsc::sync_channel::<String>(MAX_PENDING_LINES);
+        std::thread::Builder::new()
+            .name("access-log".to_string())
+            .spawn(move || {
+                for line in rx {
+                    if let Err(e) = writer.write_all(line.as_bytes()) {
+                        tracing::warn!("Failed to write access log: {e}");
+                    }
+                }
+            })?;
+        Ok(Self(tx))
+    }
+
+    /// Queues the line, written with its newline at once so concurrent lines don't interleave.
+    pub fn write(&self, mut line: String) {
+        line.push('\n');
+        match self.0.try_send(line) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => tracing::warn!("Access log writer is behind, dropping line"),
+            Err(TrySendError::Disconnected(_)) => tracing::warn!("Access log writer stopped, dropping line"),
+        }
+    }
+}
+
+/// Cache status of a request, reported by the cache middleware.
+#[derive(Debug, Default)]
+pub struct CacheStatus(AtomicU8);
+
+impl CacheStatus {
+    const HIT: u8 = 1;
+    const MISS: u8 = 2;
+
+    pub fn hit(&self) {
+        self.0.store(Self::HIT, Ordering::Relaxed);
+    }
+
+    pub fn miss(&self) {
+        self.0.store(Self::MISS, Ordering::Relaxed);
+    }
+
+    pub fn as_str(&self) -> Option<&'static str> {
+        match self.0.load(Ordering::Relaxed) {
+            Self::HIT => Some("hit"),
+            Self::MISS => Some("miss"),
+            _ => None,
+        }
+    }
+}
+
+tokio::task_local! {
+    /// Set while an access-logged request is being served, so method handlers can pass it down the middlewares.
+    pub static CACHE_STATUS: Arc<CacheStatus>;
+}
+
+#[derive(Serialize, Debug)]
+pub struct AccessLogEntry<'a> {
+    #[serde(serialize_with = "serialize_rfc3339")]
+    pub timestamp: DateTime<Utc>,
+    pub client_id: &'a str,
+    pub method: &'a str,
+    pub params: String,
+    pub status: &'static str,
+    pub error_code: Option<i32>,
+    pub response_bytes: usize,
+    pub duration_ms: u128,
+    pub cache: Option<&'static str>,
+    pub referer: Option<&'a str>,
+    pub user_agent: Option<&'a str>,
+}
+
+impl AccessLogEntry<'_> {
+    /// Formats the entry as a single log line.
+    ///
+    /// Apache formats use the RPC method and params as the request line,
+    /// and 200 or the JSON-RPC error code as the status.
+    pub fn format(&self, format: AccessLogFormat) -> Result<String, serde_json::Error> {
+        let apache = || {
+            let status = self.error_code.unwrap_or(200);
+            format!(
+                "{} - - [{}] \"{} {}\" {} {}",
+                self.client_id,
+                self.timestamp.format("%d/%b/%Y:%H:%M:%S %z"),
+                self.method,
+                escape_quotes(&self.params),
+                status,
+                self.response_bytes,
+            )
+        };
+
+        Ok(match format {
+            AccessLogFormat::Structured => serde_json::to_string(self)?,
+            AccessLogFormat::Common => apache(),
+            AccessLogFormat::Combined => format!(
+                "{} \"{}\" \"{}\"",
+                apache(),
+                escape_quotes(self.referer.unwrap_or("-")),
+                escape_quotes(self.user_agent.unwrap_or("-")),
+            ),
+        })
+    }
+}
+
+fn serialize_rfc3339<S: serde::Serializer>(timestamp: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&timestamp.to_rfc3339())
+}
+
+fn escape_quotes(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[derive(Clone)]
+pub struct AccessLogLayer {
+    format: AccessLogFormat,
+    client_id: String,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    max_params_length: usize,
+    writer: AccessLogWriter,
+}
+
+impl<S> tower::Layer<S> for AccessLogLayer {
+    type Service = AccessLogService<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        AccessLogService {
+            service,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct AccessLogService<S> {
+    service: S,
+    layer: AccessLogLayer,
+}
+
+pub fn summarize_params(params: Option<&str>, max_length: usize) -> String {
+    let params = params.unwrap_or_default();
+    match params.char_indices().nth(max_length) {
+        Some((idx, _)) => format!("{}...", &params[..idx]),
+        None => params.to_string(),
+    }
+}
+
+impl<'a, S> RpcServiceT<'a> for AccessLogService<S>
+where
+    S: RpcServiceT<'a> + Send + Sync + Clone + 'static,
+{
+    type Future = BoxFuture<'a, MethodResponse>;
+
+    fn call(&self, req: Request<'a>) -> Self::Future {
+        let service = self.service.clone();
+        let layer = self.layer.clone();
+
+        async move {
+            let method = req.method_name().to_string();
+            let params = summarize_params(req.params().as_str(), layer.max_params_length);
+            let cache_status = Arc::new(CacheStatus::default());
+            let start = std::time::Instant::now();
+
+            let response = CACHE_STATUS.scope(cache_status.clone(), service.call(req)).await;
+
+            let entry = AccessLogEntry {
+                timestamp: Utc::now(),
+                client_id: &layer.client_id,
+                method: &method,
+                params,
+                status: if response.is_success() { "ok" } else { "error" },
+                error_code: response.success_or_error.as_error_code(),
+                response_bytes: response.result.len(),
+                duration_ms: start.elapsed().as_millis(),
+                cache: cache_status.as_str(),
+                referer: layer.referer.as_deref(),
+                user_agent: layer.user_agent.as_deref(),
+            };
+
+            match entry.format(layer.format) {
+                Ok(line) => layer.writer.write(line),
+                Err(e) => tracing::warn!("Failed to serialize access log entry: {e}"),
+            }
+
+            response
+        }
+        .boxed()
+    }
+}
+
+#[test]
+fn summarize_params_works() {
+    assert_eq!(summarize_params(None, 4), "");
+    assert_eq!(summarize_params(Some("[1,2]"), 10), "[1,2]");
+    assert_eq!(summarize_params(Some("[1,2,3,4]"), 4), "[1,2...");
+}
+
+#[test]
+fn format_entry_works() {
+    let entry = AccessLogEntry {
+        timestamp: DateTime::parse_from_rfc3339("2023-10-10T13:55:36Z").unwrap().into(),
+        client_id: "127.0.0.1",
+        method: "state_getStorage",
+        params: r#"["0x01"]"#.to_string(),
+        status: "error",
+        error_code: Some(-32602),
+        response_bytes: 42,
+        duration_ms: 3,
+        cache: None,
+        referer: None,
+        user_agent: Some("curl/8.0"),
+    };
+
+    assert_eq!(
+        entry.format(AccessLogFormat::Common).unwrap(),
+        r#"127.0.0.1 - - [10/Oct/2023:13:55:36 +0000] "state_getStorage [\"0x01\"]" -32602 42"#
+    );
+    assert_eq!(
+        entry.format(AccessLogFormat::Combined).unwrap(),
+        r#"127.0.0.1 - - [10/Oct/2023:13:55:36 +0000] "state_getStorage [\"0x01\"]" -32602 42 "-" "curl/8.0""#
+    );
+
+    let structured =
+        serde_json::from_str::<serde_json::Value>(&entry.format(AccessLogFormat::Structured).unwrap()).unwrap();
+    assert_eq!(structured["error_code"], -32602);
+    assert_eq!(structured["user_agent"], "curl/8.0");
+}
+
+#[test]
+fn writer_keeps_concurrent_lines_whole() {
+    let dir = std::env::temp_dir().join(format!("subway-access-log-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("access.log");
+
+    let writer = AccessLogWriter::spawn(RotatingFileWriter::new(&path, Some(256), None, 100).unwrap()).unwrap();
+    let threads = (0..4)
+        .map(|t| {
+            let writer = writer.clone();
+            std::thread::spawn(move || (0..50).for_each(|i| writer.write(format!("thread {t} line {i}"))))
+        })
+        .collect::<Vec<_>>();
+    threads.into_iter().for_each(|t| t.join().unwrap());
+    drop(writer);
+
+    // written in the background
+    let read_lines = || {
+        std::fs::read_dir(&dir)
+            .unwrap()
+            .flat_map(|file| {
+                std::fs::read_to_string(file.unwrap().path())
+                    .unwrap()
+                    .lines()
+                    .map(ToOwned::to_owned)
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>()
+    };
+    let mut lines = read_lines();
+    for _ in 0..100 {
+        if lines.len() == 200 {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+        lines = read_lines();
+    }
+
+    assert_eq!(lines.len(), 200);
+    for t in 0..4 {
+        for i in 0..50 {
+            assert!(lines.contains(&format!("thread {t} line {i}")));
+        }
+    }
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,248 @@
+use std::{
+    net::SocketAddr,
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
+
+use async_trait::async_trait;
+use jsonrpsee::{
+    core::JsonValue,
+    server::{RpcModule, ServerBuilder, ServerHandle},
+    types::ErrorObjectOwned,
+};
+use serde::Deserialize;
+use serde_json::json;
+
+use super::{
+    cache::Cache, client::Client, connection_stats::ConnectionStats, read_only::ReadOnly, rebalance::Rebalance,
+    subscription_stats::SubscriptionStats, Extension, ExtensionRegistry,
+};
+use crate::utils::errors;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct AdminConfig {
+    // keep it private, admin methods are not authenticated
+    #[serde(default = "default_listen_address")]
+    pub listen_address: String,
+    pub port: u16,
+}
+
+fn default_listen_address() -> String {
+    "127.0.0.1".to_string()
+}
+
+/// Serves methods to inspect and manage subway on a separate address:
+/// `admin_cacheStats`, `admin_flushCache`, `admin_upstreamStatus`, `admin_switchUpstream`, `admin_removeUpstream` and
+/// `admin_limits`, and the admin methods enabled in the config of other extensions, e.g. `admin_listConnections` or
+/// `admin_rebalance`.
+pub struct Admin {
+    addr: SocketAddr,
+    handle: ServerHandle,
+    // effective limits returned by `admin_limits`, set when the methods are built
+    limits: Arc<RwLock<JsonValue>>,
+}
+
+impl Drop for Admin {
+    fn drop(&mut self) {
+        let _ = self.handle.stop();
+    }
+}
+
+#[async_trait]
+impl Extension for Admin {
+    type Config = AdminConfig;
+
+    async fn from_config(config: &Self::Config, registry: &ExtensionRegistry) -> Result<Self, anyhow::Error> {
+        let targets = AdminTargets {
+            cache: registry.get::<Cache>().await,
+            client: registry.get::<Client>().await,
+            connection_stats: registry.get::<ConnectionStats>().await,
+            read_only: registry.get::<ReadOnly>().await,
+            rebalance: registry.get::<Rebalance>().await,
+            subscription_stats: registry.get::<SubscriptionStats>().await,
+        };
+        Self::serve(config, targets).await
+    }
+}
+
+/// Extensions inspected and managed by the admin methods.
+#[derive(Default)]
+pub struct AdminTargets {
+    pub cache: Option<Arc<Cache>>,
+    pub client: Option<Arc<Client>>,
+    pub connection_stats: Option<Arc<ConnectionStats>>,
+    pub read_only: Option<Arc<ReadOnly>>,
+    pub rebalance: Option<Arc<Rebalance>>,
+    pub subscription_stats: Option<Arc<SubscriptionStats>>,
+}
+
+impl Admin {
+    pub async fn serve(config: &AdminConfig, targets: AdminTargets) -> Result<Self, anyhow::Error> {
+        let AdminTargets {
+            cache,
+            client,
+            connection_stats,
+            read_only,
+            rebalance,
+            subscription_stats,
+        } = targets;
+        let mut module = RpcModule::new(());
+
+        let limits = Arc::new(RwLock::new(JsonValue::Null));
+        let served_limits = limits.clone();
+        module.register_method("admin_limits", move |_, _| {
+            Ok::<JsonValue, ErrorObjectOwned>(served_limits.read().unwrap_or_else(|e| e.into_inner()).clone())
+        })?;
+        // name it was served under before the admin methods were prefixed
+        module.register_alias("subway_limits", "admin_limits")?;
+
+        let flush_cache = cache.clone();
+        module.register_method("admin_cacheStats", move |_, _| {
+            Ok::<JsonValue, ErrorObjectOwned>(cache.as_ref().map(|c| c.stats()).unwrap_or_default())
+        })?;
+        module.register_async_method("admin_flushCache", move |params, _| {
+            let cache = flush_cache.clone();
+            async move {
+                let method = params.one::<String>()?;
+                match cache {
+                    Some(cache) if cache.flush(&method).await => Ok::<JsonValue, ErrorObjectOwned>(true.into()),
+                    _ => Err(errors::invalid_params(format!("{method} is not cached"))),
+                }
+            }
+        })?;
+        let switch_client = client.clone();
+        module.register_async_method("admin_switchUpstream", move |params, _| {
+            let client = switch_client.clone();
+            async move {
+                let url = params.one::<String>()?;
+                let client = client.ok_or_else(|| errors::failed("No upstream client"))?;
+                client.switch_endpoint(&url).await.map_err(errors::failed)?;
+                Ok::<JsonValue, ErrorObjectOwned>(client.status())
+            }
+        })?;
+        let remove_client = client.clone();
+        module.register_method("admin_removeUpstream", move |params, _| {
+            let url = params.one::<String>()?;
+            let client = remove_client
+                .as_ref()
+                .ok_or_else(|| errors::failed("No upstream client"))?;
+            client.remove_endpoint(&url).map_err(errors::failed)?;
+            Ok::<JsonValue, ErrorObjectOwned>(client.status())
+        })?;
+        module.register_method("admin_upstreamStatus", move |_, _| {
+            Ok::<JsonValue, ErrorObjectOwned>(client.as_ref().map(|c| c.status()).unwrap_or_default())
+        })?;
+
+        if let Some(stats) = connection_stats.filter(|s| s.admin_methods()) {
+            let list_stats = stats.clone();
+            module.register_method("admin_getConnectionStats", move |params, _| {
+                let id = params.one::<u64>()?;
+                stats
+                    .calls(id)
+                    .map(|calls| json!(calls))
+                    .ok_or_else(|| errors::invalid_params(format!("Unknown connection {id}")))
+            })?;
+            module.register_method("admin_listConnections", move |_, _| {
+                Ok::<JsonValue, ErrorObjectOwned>(json!(list_stats.list()))
+            })?;
+        }
+
+        if let Some(read_only) = read_only.filter(|r| r.admin_toggle()) {
+            module.register_method("admin_setReadOnly", move |params, _| {
+                let enabled = params.one::<bool>()?;
+                read_only.set_enabled(enabled);
+                Ok::<JsonValue, ErrorObjectOwned>(enabled.into())
+            })?;
+        }
+
+        if let Some(stats) = subscription_stats.filter(|s| s.admin_method()) {
+            module.register_method("admin_subscriptionStats", move |_, _| {
+                Ok::<JsonValue, ErrorObjectOwned>(json!(stats.snapshot()))
+            })?;
+        }
+
+        if let Some(rebalance) = rebalance.filter(|r| r.admin_method()) {
+            module.register_method("admin_rebalance", move |_, _| {
+                let targeted = rebalance.start();
+                Ok::<JsonValue, ErrorObjectOwned>(json!({
+                    "targeted": targeted,
+                    "hintsSent": rebalance.hints_sent(),
+                }))
+            })?;
+        }
+
+        let ip_addr = std::net::IpAddr::from_str(&config.listen_address)?;
+        let server = ServerBuilder::default()
+            .build(SocketAddr::new(ip_addr, config.port))
+            .await?;
+        let addr = server.local_addr()?;
+        let handle = server.start(module);
+        tracing::info!("Admin methods served at {addr}");
+
+        Ok(Self { addr, handle, limits })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn set_limits(&self, limits: JsonValue) {
+        *self.limits.write().unwrap_or_else(|e| e.into_inner()) = limits;
+    }
+}
+
+#[tokio::test]
+async fn admin_methods_work() {
+    use jsonrpsee::{core::client::ClientT, http_client::HttpClientBuilder, rpc_params};
+    use std::num::NonZeroUsize;
+
+    let cache = Arc::new(Cache::new(super::cache::CacheConfig {
+        default_ttl_seconds: None,
+        default_size: 10,
+        disk_spillover_path: None,
+        disk_spillover_max_entries: 100_000,
+        negative_caching: Default::default(),
+        negative_cache_size: 100,
+        redis: None,
+        warm_up: Vec::new(),
+    }));
+    let method_cache = crate::utils::Cache::new(NonZeroUsize::new(10).unwrap(), None);
+    let key = crate::utils::CacheKey::new(&"state_getStorage".to_string(), &[]);
+    method_cache.insert(key.clone(), "0x01".into()).await;
+    method_cache.sync().await;
+    cache.register("state_getStorage", method_cache.clone());
+
+    let config = AdminConfig {
+        listen_address: "127.0.0.1".to_string(),
+        port: 0,
+    };
+    let connection_stats = Arc::new(ConnectionStats::new(super::connection_stats::ConnectionStatsConfig {
+        admin_methods: true,
+    }));
+    let targets = AdminTargets {
+        cache: Some(cache),
+        connection_stats: Some(connection_stats),
+        ..Default::default()
+    };
+    let admin = Admin::serve(&config, targets).await.unwrap();
+    let client = HttpClientBuilder::default()
+        .build(format!("http://{}", admin.addr()))
+        .unwrap();
+
+    let stats: JsonValue = client.request("admin_cacheStats", rpc_params!()).await.unwrap();
+    assert_eq!(stats["state_getStorage"]["entries"], 1);
+
+    let flushed: bool = client
+        .request("admin_flushCache", rpc_params!("state_getStorage"))
+        .await
+        .unwrap();
+    assert!(flushed);
+    assert_eq!(method_cache.get(&key).await, None);
+    assert!(client
+        .request::<bool, _>("admin_flushCache", rpc_params!("system_health"))
+        .await
+        .is_err());
+
+    let connections: JsonValue = client.request("admin_listConnections", rpc_params!()).await.unwrap();
+    assert_eq!(connections, serde_json::json!([]));
+}
@@ -0,0 +1,263 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use super::{client::redact_endpoint, Extension, ExtensionRegistry};
+
+#[derive(Deserialize, Clone)]
+pub struct AlertingConfig {
+    /// Alerts are posted as JSON to this url.
+    pub webhook_url: String,
+    /// Share of failed calls of a method within the window, between 0 and 1.
+    pub error_rate_threshold: f64,
+    /// Length of the sliding window the error rate is computed over.
+    #[serde(default = "default_window_secs")]
+    pub window_secs: u64,
+    /// At most one alert per method is sent per cooldown.
+    #[serde(default = "default_cooldown_secs")]
+    pub cooldown_secs: u64,
+    /// Calls needed within the window before the error rate is considered.
+    #[serde(default = "default_min_requests")]
+    pub min_requests: u64,
+}
+
+// webhook urls usually hold a token
+impl std::fmt::Debug for AlertingConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AlertingConfig")
+            .field("webhook_url", &redact_endpoint(&self.webhook_url))
+            .field("error_rate_threshold", &self.error_rate_threshold)
+            .field("window_secs", &self.window_secs)
+            .field("cooldown_secs", &self.cooldown_secs)
+            .field("min_requests", &self.min_requests)
+            .finish()
+    }
+}
+
+fn default_window_secs() -> u64 {
+    60
+}
+
+fn default_cooldown_secs() -> u64 {
+    300
+}
+
+fn default_min_requests() -> u64 {
+    10
+}
+
+// a webhook which does not answer must not pile up pending alerts
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Payload posted to the webhook.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Alert {
+    pub method: String,
+    pub error_count: u64,
+    pub request_count: u64,
+    pub window_secs: u64,
+    pub last_error: String,
+}
+
+/// Calls and errors of a method, counted per second over the window.
+#[derive(Default)]
+struct SlidingWindow {
+    // (second, calls, errors)
+    buckets: VecDeque<(u64, u64, u64)>,
+    last_error: String,
+    // alerts for the method are not sent again until the cooldown is over
+    last_alert: Option<Instant>,
+}
+
+impl SlidingWindow {
+    fn record(&mut self, second: u64, window_secs: u64, error: Option<String>) {
+        while self.buckets.front().is_some_and(|(s, _, _)| *s + window_secs <= second) {
+            self.buckets.pop_front();
+        }
+        match self.buckets.back_mut() {
+            Some(bucket) if bucket.0 == second => bucket.1 += 1,
+            _ => self.buckets.push_back((second, 1, 0)),
+        }
+        if let Some(error) = error {
+            self.buckets.back_mut().expect("pushed above; qed").2 += 1;
+            self.last_error = error;
+        }
+    }
+
+    fn counts(&self) -> (u64, u64) {
+        self.buckets
+            .iter()
+            .fold((0, 0), |(calls, errors), (_, c, e)| (calls + c, errors + e))
+    }
+}
+
+/// Tracks the error rate of each method and posts an alert to a webhook when it exceeds the threshold.
+pub struct Alerting {
+    config: AlertingConfig,
+    http: reqwest::Client,
+    started: Instant,
+    windows: Mutex<HashMap<String, SlidingWindow>>,
+}
+
+#[async_trait]
+impl Extension for Alerting {
+    type Config = AlertingConfig;
+
+    async fn from_config(config: &Self::Config, _registry: &ExtensionRegistry) -> Result<Self, anyhow::Error> {
+        if !(0.0..=1.0).contains(&config.error_rate_threshold) {
+            anyhow::bail!(
+                "alerting error_rate_threshold must be between 0 and 1, got {}",
+                config.error_rate_threshold
+            );
+        }
+        Ok(Self::new(config.clone()))
+    }
+}
+
+impl Alerting {
+    pub fn new(config: AlertingConfig) -> Self {
+        Self {
+            config,
+            http: reqwest::Client::builder()
+                .timeout(WEBHOOK_TIMEOUT)
+                .build()
+                .unwrap_or_default(),
+            started: Instant::now(),
+            windows: Default::default(),
+        }
+    }
+
+    /// Records the outcome of a call, `error` being set for failures of upstream or subway only,
+    /// not for calls rejected because of the caller. Returns an alert if the error rate of the method exceeds the threshold
+    /// and no alert was sent for the method during the cooldown.
+    pub fn record(&self, method: &str, error: Option<String>) -> Option<Alert> {
+        let is_error = error.is_some();
+        let second = self.started.elapsed().as_secs();
+
+        let mut windows = self.windows.lock().unwrap_or_else(|e| e.into_inner());
+        let window = windows.entry(method.to_string()).or_default();
+        window.record(second, self.config.window_secs, error);
+
+        if !is_error {
+            return None;
+        }
+
+        let (request_count, error_count) = window.counts();
+        if request_count < self.config.min_requests
+            || (error_count as f64) / (request_count as f64) < self.config.error_rate_threshold
+        {
+            return None;
+        }
+
+        let cooldown = Duration::from_secs(self.config.cooldown_secs);
+        if window.last_alert.is_some_and(|at| at.elapsed() < cooldown) {
+            return None;
+        }
+        window.last_alert = Some(Instant::now());
+
+        Some(Alert {
+            method: method.to_string(),
+            error_count,
+            request_count,
+            window_secs: self.config.window_secs,
+            last_error: window.last_error.clone(),
+        })
+    }
+
+    /// Posts the alert to the webhook in the background.
+    pub fn send(&self, alert: Alert) {
+        tracing::warn!(
+            "Error rate of {} exceeded: {} errors in {} calls",
+            alert.method,
+            alert.error_count,
+            alert.request_count
+        );
+
+        let request = self.http.post(&self.config.webhook_url).json(&alert);
+        tokio::spawn(async move {
+            match request.send().await.and_then(|res| res.error_for_status()) {
+                Ok(_) => tracing::debug!("Alert for {} sent", alert.method),
+                Err(e) => tracing::error!("Failed to send alert for {}: {e}", alert.method),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_alerting(cooldown_secs: u64) -> Alerting {
+        Alerting::new(AlertingConfig {
+            webhook_url: "http://127.0.0.1:1".to_string(),
+            error_rate_threshold: 0.5,
+            window_secs: 60,
+            cooldown_secs,
+            min_requests: 4,
+        })
+    }
+
+    #[tokio::test]
+    async fn alerts_when_error_rate_exceeded() {
+        let alerting = new_alerting(300);
+
+        assert_eq!(alerting.record("foo", None), None);
+        assert_eq!(alerting.record("foo", Some("first".to_string())), None);
+        assert_eq!(alerting.record("foo", None), None);
+        // enough calls, 2 errors out of 4
+        let alert = alerting.record("foo", Some("second".to_string())).unwrap();
+        assert_eq!(
+            alert,
+            Alert {
+                method: "foo".to_string(),
+                error_count: 2,
+                request_count: 4,
+                window_secs: 60,
+                last_error: "second".to_string(),
+            }
+        );
+
+        // other methods are tracked separately
+        assert_eq!(alerting.record("bar", Some("error".to_string())), None);
+    }
+
+    #[tokio::test]
+    async fn alerts_are_rate_limited() {
+        let alerting = new_alerting(300);
+        for _ in 0..3 {
+            alerting.record("foo", Some("error".to_string()));
+        }
+        assert!(alerting.record("foo", Some("error".to_string())).is_some());
+        assert_eq!(alerting.record("foo", Some("error".to_string())), None);
+
+        // the cooldown of one method does not hold back alerts for another
+        for _ in 0..3 {
+            alerting.record("bar", Some("error".to_string()));
+        }
+        assert!(alerting.record("bar", Some("error".to_string())).is_some());
+        assert_eq!(alerting.record("bar", Some("error".to_string())), None);
+
+        let alerting = new_alerting(0);
+        for _ in 0..3 {
+            alerting.record("foo", Some("error".to_string()));
+        }
+        assert!(alerting.record("foo", Some("error".to_string())).is_some());
+        assert!(alerting.record("foo", Some("error".to_string())).is_some());
+    }
+
+    #[test]
+    fn window_drops_old_buckets() {
+        let mut window = SlidingWindow::default();
+        window.record(0, 10, Some("error".to_string()));
+        window.record(5, 10, None);
+        assert_eq!(window.counts(), (2, 1));
+        window.record(10, 10, None);
+        assert_eq!(window.counts(), (2, 0));
+    }
+}
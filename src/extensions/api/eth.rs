@@ -5,14 +5,19 @@ use jsonrpsee::core::JsonValue;
 use serde::Deserialize;
 use tokio::{sync::watch, task::JoinHandle};
 
-use crate::extensions::{
-    api::{BaseApi, ValueHandle},
-    client::Client,
-    Extension, ExtensionRegistry,
+use crate::{
+    extensions::{
+        api::{HeadTracker, ValueHandle},
+        client::Client,
+        event_bus::SubwayEvent,
+        Extension, ExtensionRegistry,
+    },
+    middlewares::CallResult,
 };
 
 pub struct EthApi {
-    inner: BaseApi,
+    client: Arc<Client>,
+    inner: HeadTracker,
     stale_timeout: Duration,
     background_tasks: Vec<JoinHandle<()>>,
 }
@@ -45,7 +50,8 @@ impl EthApi {
         let (finalized_head_tx, finalized_head_rx) = watch::channel::<Option<(JsonValue, u64)>>(None);
 
         let mut this = Self {
-            inner: BaseApi::new(head_rx, finalized_head_rx),
+            client: client.clone(),
+            inner: HeadTracker::new(head_rx, finalized_head_rx),
             stale_timeout,
             background_tasks: Vec::new(),
         };
@@ -55,6 +61,30 @@ impl EthApi {
         this
     }
 
+    /// Next nonce of the account, including pending transactions. Never cached as it changes with every transaction.
+    pub async fn get_account_nonce(&self, address: &JsonValue) -> CallResult {
+        self.client
+            .request_internal("eth_getTransactionCount", vec![address.clone(), "pending".into()])
+            .await
+    }
+
+    /// Hash of the block at `number`, null if the block is unknown.
+    pub async fn get_block_hash(&self, number: u64) -> CallResult {
+        let block = self
+            .client
+            .request_internal(
+                "eth_getBlockByNumber",
+                vec![format!("0x{number:x}").into(), false.into()],
+            )
+            .await?;
+        Ok(block["hash"].to_owned())
+    }
+
+    /// Heads followed by this api, to be shared instead of following them again.
+    pub fn head_tracker(&self) -> HeadTracker {
+        self.inner.clone()
+    }
+
     pub fn get_head(&self) -> ValueHandle<(JsonValue, u64)> {
         self.inner.get_head()
     }
@@ -63,6 +93,11 @@ impl EthApi {
         self.inner.get_finalized_head()
     }
 
+    /// Notified on every new finalized head, e.g. to invalidate cached responses.
+    pub fn finalized_head_updates(&self) -> watch::Receiver<Option<(JsonValue, u64)>> {
+        self.inner.finalized_head_updates()
+    }
+
     pub fn current_head(&self) -> Option<(JsonValue, u64)> {
         self.inner.head_rx.borrow().to_owned()
     }
@@ -90,7 +125,7 @@ impl EthApi {
                 let run = async {
                     // query current head
                     let head = client
-                        .request("eth_getBlockByNumber", vec!["latest".into(), true.into()])
+                        .request_internal("eth_getBlockByNumber", vec!["latest".into(), true.into()])
                         .await?;
                     let number = super::get_number(&head)?;
                     let hash = super::get_hash(&head)?;
@@ -162,6 +197,12 @@ impl EthApi {
                                     if let Err(e) = super::validate_new_head(&finalized_head_tx, number, &hash)
                                     {
                                         tracing::error!("Error in background task: {e}");
+                                        if let Some(event_bus) = client.event_bus() {
+                                            event_bus.publish(SubwayEvent::ReorgDetected {
+                                                number,
+                                                reason: e.to_string(),
+                                            });
+                                        }
                                         client.rotate_endpoint().await;
                                         break;
                                     }
@@ -1,6 +1,9 @@
 use jsonrpsee::core::JsonValue;
 use tokio::sync::watch;
 
+use super::ExtensionRegistry;
+use crate::utils::TypeRegistry;
+
 #[cfg(test)]
 mod tests;
 
@@ -12,12 +15,33 @@ pub use eth::{EthApi, EthApiConfig};
 pub use substrate::{SubstrateApi, SubstrateApiConfig};
 pub use value_handle::ValueHandle;
 
-pub(crate) struct BaseApi {
-    pub head_rx: watch::Receiver<Option<(JsonValue, u64)>>,
-    pub finalized_head_rx: watch::Receiver<Option<(JsonValue, u64)>>,
+/// Best and finalized heads followed by `substrate_api` or `eth_api`, as `(hash, number)`.
+/// Shared by everything depending on the heads, e.g. param injection, cache invalidation and archive routing,
+/// so upstream heads are only followed once.
+#[derive(Clone)]
+pub struct HeadTracker {
+    pub(crate) head_rx: watch::Receiver<Option<(JsonValue, u64)>>,
+    pub(crate) finalized_head_rx: watch::Receiver<Option<(JsonValue, u64)>>,
 }
 
-impl BaseApi {
+impl HeadTracker {
+    /// Heads of `substrate_api`, or `eth_api` without it.
+    pub fn from_registry(registry: &TypeRegistry) -> Option<Self> {
+        match (registry.get::<SubstrateApi>(), registry.get::<EthApi>()) {
+            (Some(api), _) => Some(api.head_tracker()),
+            (None, Some(api)) => Some(api.head_tracker()),
+            (None, None) => None,
+        }
+    }
+
+    /// Same as `from_registry`, building the api extension if it is configured but not built yet.
+    pub async fn from_extensions(registry: &ExtensionRegistry) -> Option<Self> {
+        if let Some(api) = registry.get::<SubstrateApi>().await {
+            return Some(api.head_tracker());
+        }
+        registry.get::<EthApi>().await.map(|api| api.head_tracker())
+    }
+
     pub fn new(
         head_rx: watch::Receiver<Option<(JsonValue, u64)>>,
         finalized_head_rx: watch::Receiver<Option<(JsonValue, u64)>>,
@@ -28,6 +52,15 @@ impl BaseApi {
         }
     }
 
+    /// Latest head without waiting, None until the first one is received.
+    pub fn current_head(&self) -> Option<(JsonValue, u64)> {
+        self.head_rx.borrow().to_owned()
+    }
+
+    pub fn current_finalized_head(&self) -> Option<(JsonValue, u64)> {
+        self.finalized_head_rx.borrow().to_owned()
+    }
+
     pub fn get_head(&self) -> ValueHandle<(JsonValue, u64)> {
         ValueHandle::new(self.head_rx.clone())
     }
@@ -35,6 +68,14 @@ impl BaseApi {
     pub fn get_finalized_head(&self) -> ValueHandle<(JsonValue, u64)> {
         ValueHandle::new(self.finalized_head_rx.clone())
     }
+
+    pub fn head_updates(&self) -> watch::Receiver<Option<(JsonValue, u64)>> {
+        self.head_rx.clone()
+    }
+
+    pub fn finalized_head_updates(&self) -> watch::Receiver<Option<(JsonValue, u64)>> {
+        self.finalized_head_rx.clone()
+    }
 }
 
 pub(crate) fn get_number(val: &JsonValue) -> anyhow::Result<u64> {
@@ -5,15 +5,20 @@ use jsonrpsee::core::JsonValue;
 use serde::Deserialize;
 use tokio::{sync::watch, task::JoinHandle};
 
-use crate::extensions::{
-    api::{BaseApi, ValueHandle},
-    client::Client,
-    Extension, ExtensionRegistry,
+use crate::{
+    extensions::{
+        api::{HeadTracker, ValueHandle},
+        client::Client,
+        event_bus::SubwayEvent,
+        Extension, ExtensionRegistry,
+    },
+    middlewares::CallResult,
 };
 
 pub struct SubstrateApi {
     client: Arc<Client>,
-    inner: BaseApi,
+    inner: HeadTracker,
+    head_header_rx: watch::Receiver<Option<JsonValue>>,
     stale_timeout: Duration,
     background_tasks: Vec<JoinHandle<()>>,
 }
@@ -44,19 +49,26 @@ impl SubstrateApi {
     pub fn new(client: Arc<Client>, stale_timeout: Duration) -> Self {
         let (head_tx, head_rx) = watch::channel::<Option<(JsonValue, u64)>>(None);
         let (finalized_head_tx, finalized_head_rx) = watch::channel::<Option<(JsonValue, u64)>>(None);
+        let (head_header_tx, head_header_rx) = watch::channel::<Option<JsonValue>>(None);
 
         let mut this = Self {
             client,
-            inner: BaseApi::new(head_rx, finalized_head_rx),
+            inner: HeadTracker::new(head_rx, finalized_head_rx),
+            head_header_rx,
             stale_timeout,
             background_tasks: Vec::new(),
         };
 
-        this.start_background_task(head_tx, finalized_head_tx);
+        this.start_background_task(head_tx, head_header_tx, finalized_head_tx);
 
         this
     }
 
+    /// Heads followed by this api, to be shared instead of following them again.
+    pub fn head_tracker(&self) -> HeadTracker {
+        self.inner.clone()
+    }
+
     pub fn get_head(&self) -> ValueHandle<(JsonValue, u64)> {
         self.inner.get_head()
     }
@@ -65,9 +77,34 @@ impl SubstrateApi {
         self.inner.get_finalized_head()
     }
 
+    /// Notified on every new finalized head, e.g. to invalidate cached responses.
+    pub fn finalized_head_updates(&self) -> watch::Receiver<Option<(JsonValue, u64)>> {
+        self.inner.finalized_head_updates()
+    }
+
+    /// Next nonce of the account, including transactions in the pool. Never cached as it changes with every transaction.
+    pub async fn get_account_nonce(&self, address: &JsonValue) -> CallResult {
+        self.client
+            .request_internal("system_accountNextIndex", vec![address.clone()])
+            .await
+    }
+
+    /// Hash of the block at `number` on the best chain, null if there is no such block yet.
+    pub async fn get_block_hash(&self, number: u64) -> CallResult {
+        self.client
+            .request_internal("chain_getBlockHash", vec![number.into()])
+            .await
+    }
+
+    /// The latest new head notification, exactly as received from upstream.
+    pub fn get_head_header(&self) -> ValueHandle<JsonValue> {
+        ValueHandle::new(self.head_header_rx.clone())
+    }
+
     fn start_background_task(
         &mut self,
         head_tx: watch::Sender<Option<(JsonValue, u64)>>,
+        head_header_tx: watch::Sender<Option<JsonValue>>,
         finalized_head_tx: watch::Sender<Option<(JsonValue, u64)>>,
     ) {
         let client = self.client.clone();
@@ -95,9 +132,10 @@ impl SubstrateApi {
                                     interval.reset();
 
                                     let number = super::get_number(&val)?;
+                                    head_header_tx.send_replace(Some(val));
 
                                     let hash = client
-                                        .request("chain_getBlockHash", vec![number.into()])
+                                        .request_internal("chain_getBlockHash", vec![number.into()])
                                         .await?;
 
                                     tracing::debug!("New head: {number} {hash}");
@@ -148,12 +186,18 @@ impl SubstrateApi {
                                     let number = super::get_number(&val)?;
 
                                     let hash = client
-                                        .request("chain_getBlockHash", vec![number.into()])
+                                        .request_internal("chain_getBlockHash", vec![number.into()])
                                         .await?;
 
                                     if let Err(e) = super::validate_new_head(&finalized_head_tx, number, &hash)
                                     {
                                         tracing::error!("Error in background task: {e}");
+                                        if let Some(event_bus) = client.event_bus() {
+                                            event_bus.publish(SubwayEvent::ReorgDetected {
+                                                number,
+                                                reason: e.to_string(),
+                                            });
+                                        }
                                         client.rotate_endpoint().await;
                                         break;
                                     }
@@ -0,0 +1,212 @@
+use std::{
+    collections::VecDeque,
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use blake2::Blake2b512;
+use futures::FutureExt;
+use jsonrpsee::core::JsonValue;
+use serde::Deserialize;
+use tokio::{sync::watch, task::JoinHandle};
+
+use super::{api::HeadTracker, client::Client, Extension, ExtensionRegistry};
+use crate::{
+    middlewares::{methods::archive::block_number, CallResult},
+    utils::{errors, Cache, CacheKey},
+};
+
+// block numbers of hashes looked up on the archive nodes
+const BLOCK_NUMBERS_CACHE_SIZE: usize = 4096;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ArchiveConfig {
+    /// Archive nodes serving calls at historical blocks.
+    pub endpoints: Vec<String>,
+    /// Blocks older than this many blocks behind the head are historical.
+    #[serde(default = "default_recent_blocks")]
+    pub recent_blocks: u64,
+}
+
+fn default_recent_blocks() -> u64 {
+    256
+}
+
+/// Latest head and the hashes of the recent heads, so their number is known without asking upstream.
+#[derive(Debug, Default)]
+pub struct RecentBlocks {
+    recent_blocks: u64,
+    head: Option<u64>,
+    // oldest first
+    blocks: VecDeque<(JsonValue, u64)>,
+}
+
+impl RecentBlocks {
+    pub fn new(recent_blocks: u64) -> Self {
+        Self {
+            recent_blocks,
+            ..Default::default()
+        }
+    }
+
+    pub fn insert(&mut self, hash: JsonValue, number: u64) {
+        self.head = Some(self.head.map_or(number, |head| head.max(number)));
+        self.blocks.push_back((hash, number));
+
+        let oldest = self.head.unwrap_or_default().saturating_sub(self.recent_blocks);
+        while self.blocks.front().is_some_and(|(_, number)| *number < oldest) {
+            self.blocks.pop_front();
+        }
+    }
+
+    /// Number of a recent head, None if the head with this hash was not seen or is not recent.
+    pub fn number_of(&self, hash: &JsonValue) -> Option<u64> {
+        self.blocks.iter().find(|(h, _)| h == hash).map(|(_, number)| *number)
+    }
+
+    /// Every block is historical until the head is known, an archive node can serve any block.
+    pub fn is_historical_number(&self, number: u64) -> bool {
+        self.head
+            .map_or(true, |head| head.saturating_sub(number) > self.recent_blocks)
+    }
+}
+
+/// Routes calls at historical blocks to archive nodes, other calls go to the client endpoints.
+/// Blocks given by hash are classified by their number, asked from the archive nodes unless it is a recent head.
+pub struct Archive {
+    client: Arc<Client>,
+    recent: Arc<Mutex<RecentBlocks>>,
+    // block number by hash, blocks never change their number
+    numbers: Cache<Blake2b512>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl Drop for Archive {
+    fn drop(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+    }
+}
+
+#[async_trait]
+impl Extension for Archive {
+    type Config = ArchiveConfig;
+
+    async fn from_config(config: &Self::Config, registry: &ExtensionRegistry) -> Result<Self, anyhow::Error> {
+        let client = Client::with_endpoints(&config.endpoints)?;
+        let archive = Self::new(Arc::new(client), config.recent_blocks);
+
+        match HeadTracker::from_extensions(registry).await {
+            Some(heads) => Ok(archive.with_heads(heads.head_updates())),
+            None => {
+                tracing::warn!(
+                    "Archive routing requires substrate_api or eth_api to track the head, all calls at a block go to archive nodes"
+                );
+                Ok(archive)
+            }
+        }
+    }
+}
+
+impl Archive {
+    pub fn new(client: Arc<Client>, recent_blocks: u64) -> Self {
+        Self {
+            client,
+            recent: Arc::new(Mutex::new(RecentBlocks::new(recent_blocks))),
+            numbers: Cache::new(
+                NonZeroUsize::new(BLOCK_NUMBERS_CACHE_SIZE).expect("not zero; qed"),
+                None,
+            ),
+            task: None,
+        }
+    }
+
+    /// Tracks the heads received on `heads` as recent blocks.
+    pub fn with_heads(mut self, mut heads: watch::Receiver<Option<(JsonValue, u64)>>) -> Self {
+        let recent = self.recent.clone();
+        self.task = Some(tokio::spawn(async move {
+            while heads.changed().await.is_ok() {
+                let head = heads.borrow_and_update().clone();
+                if let Some((hash, number)) = head {
+                    recent.lock().unwrap_or_else(|e| e.into_inner()).insert(hash, number);
+                }
+            }
+        }));
+        self
+    }
+
+    pub fn client(&self) -> &Arc<Client> {
+        &self.client
+    }
+
+    /// Blocks unknown to the archive nodes are historical too, they answer with the error.
+    pub async fn is_historical_hash(&self, hash: &JsonValue) -> bool {
+        match self.block_number(hash).await {
+            Some(number) => self.is_historical_number(number),
+            None => true,
+        }
+    }
+
+    async fn block_number(&self, hash: &JsonValue) -> Option<u64> {
+        let recent = self.recent.lock().unwrap_or_else(|e| e.into_inner()).number_of(hash);
+        if recent.is_some() {
+            return recent;
+        }
+
+        let key = CacheKey::new(&"block_number".to_string(), std::slice::from_ref(hash));
+        let client = self.client.clone();
+        let hash = hash.clone();
+        let number = self
+            .numbers
+            .get_or_insert_with(key, move || {
+                async move { fetch_block_number(&client, hash).await }.boxed()
+            })
+            .await
+            .ok()?;
+        number.as_u64()
+    }
+
+    pub fn is_historical_number(&self, number: u64) -> bool {
+        self.recent
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .is_historical_number(number)
+    }
+}
+
+/// Number of the block with the given hash, of a Substrate or else an Ethereum chain.
+async fn fetch_block_number(client: &Client, hash: JsonValue) -> CallResult {
+    let header = match client.request_internal("chain_getHeader", vec![hash.clone()]).await {
+        Ok(header) => header,
+        Err(_) => {
+            client
+                .request_internal("eth_getBlockByHash", vec![hash, false.into()])
+                .await?
+        }
+    };
+    header
+        .get("number")
+        .and_then(block_number)
+        .map(JsonValue::from)
+        .ok_or_else(|| errors::failed("Unknown block"))
+}
+
+#[test]
+fn recent_blocks_works() {
+    let mut recent = RecentBlocks::new(2);
+    assert_eq!(recent.number_of(&"0x01".into()), None);
+    assert!(recent.is_historical_number(0));
+
+    for number in 1..=5u64 {
+        recent.insert(format!("0x0{number}").into(), number);
+    }
+
+    assert_eq!(recent.number_of(&"0x02".into()), None);
+    assert_eq!(recent.number_of(&"0x03".into()), Some(3));
+    assert_eq!(recent.number_of(&"0x05".into()), Some(5));
+    assert!(recent.is_historical_number(2));
+    assert!(!recent.is_historical_number(3));
+    assert!(!recent.is_historical_number(6));
+}
@@ -0,0 +1,242 @@
+use std::collections::HashMap;
+
+use jsonrpsee::core::JsonValue;
+use jsonwebtoken::{jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+
+use crate::extensions::rate_limit::Rule;
+
+#[derive(Deserialize, Clone)]
+pub struct JwtConfig {
+    /// `HS256` with `secret`, or `RS256` with `public_key_file` or `jwks_url`.
+    pub algorithm: Algorithm,
+    #[serde(default)]
+    pub secret: Option<String>,
+    /// PEM encoded RSA public key.
+    #[serde(default)]
+    pub public_key_file: Option<String>,
+    /// Keys are fetched once at startup and picked by the `kid` of the token.
+    #[serde(default)]
+    pub jwks_url: Option<String>,
+    #[serde(default)]
+    pub issuer: Option<String>,
+    #[serde(default)]
+    pub audience: Option<String>,
+    /// Claim with the scopes of the token, a space separated string or a list.
+    #[serde(default = "default_scope_claim")]
+    pub scope_claim: String,
+    /// Methods allowed by each scope, `*` at the end matches a prefix, e.g. `state_*`.
+    pub scopes: HashMap<String, Vec<String>>,
+    /// Rate limit shared by all connections with a token of the same `sub` claim.
+    /// Tokens without `sub` are rate limited by ip.
+    #[serde(default)]
+    pub rate_limit: Option<Rule>,
+}
+
+impl std::fmt::Debug for JwtConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JwtConfig")
+            .field("algorithm", &self.algorithm)
+            .field("secret", &self.secret.as_ref().map(|_| "***"))
+            .field("public_key_file", &self.public_key_file)
+            .field("jwks_url", &self.jwks_url)
+            .field("issuer", &self.issuer)
+            .field("audience", &self.audience)
+            .field("scope_claim", &self.scope_claim)
+            .field("scopes", &self.scopes)
+            .field("rate_limit", &self.rate_limit)
+            .finish()
+    }
+}
+
+fn default_scope_claim() -> String {
+    "scope".to_string()
+}
+
+enum Keys {
+    Single(DecodingKey),
+    // by key id
+    Set(HashMap<String, DecodingKey>),
+}
+
+/// Claims of a valid JWT, in the context of the method and subscription middlewares of its calls.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JwtClaims {
+    /// `sub` claim, usually the user the token was issued to
+    pub sub: Option<String>,
+    /// Scopes read from the scope claim
+    pub scopes: Vec<String>,
+    /// Every claim of the token
+    pub claims: JsonValue,
+}
+
+/// Validates JWTs and maps their scopes to allowed methods.
+pub struct JwtValidator {
+    keys: Keys,
+    validation: Validation,
+    scope_claim: String,
+    scopes: HashMap<String, Vec<String>>,
+}
+
+impl JwtValidator {
+    pub async fn new(config: &JwtConfig) -> anyhow::Result<Self> {
+        let keys = match (
+            config.algorithm,
+            &config.secret,
+            &config.public_key_file,
+            &config.jwks_url,
+        ) {
+            (Algorithm::HS256, Some(secret), _, _) => Keys::Single(DecodingKey::from_secret(secret.as_bytes())),
+            (Algorithm::RS256, _, Some(path), _) => Keys::Single(DecodingKey::from_rsa_pem(&std::fs::read(path)?)?),
+            (Algorithm::RS256, _, _, Some(url)) => {
+                let jwks = reqwest::get(url).await?.error_for_status()?.json::<JwkSet>().await?;
+                Keys::Set(
+                    jwks.keys
+                        .iter()
+                        .filter_map(|jwk| Some((jwk.common.key_id.clone()?, DecodingKey::from_jwk(jwk).ok()?)))
+                        .collect(),
+                )
+            }
+            (Algorithm::HS256, ..) => anyhow::bail!("HS256 requires jwt.secret"),
+            (Algorithm::RS256, ..) => anyhow::bail!("RS256 requires jwt.public_key_file or jwt.jwks_url"),
+            (algorithm, ..) => anyhow::bail!("Unsupported JWT algorithm {algorithm:?}"),
+        };
+        Ok(Self::with_keys(config, keys))
+    }
+
+    fn with_keys(config: &JwtConfig, keys: Keys) -> Self {
+        let mut validation = Validation::new(config.algorithm);
+        if let Some(ref issuer) = config.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match config.audience {
+            Some(ref audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+
+        Self {
+            keys,
+            validation,
+            scope_claim: config.scope_claim.clone(),
+            scopes: config.scopes.clone(),
+        }
+    }
+
+    /// Claims of a valid token.
+    pub fn validate(&self, token: &str) -> anyhow::Result<JwtClaims> {
+        let key = match self.keys {
+            Keys::Single(ref key) => key,
+            Keys::Set(ref keys) => {
+                let kid = jsonwebtoken::decode_header(token)?
+                    .kid
+                    .ok_or_else(|| anyhow::anyhow!("missing kid"))?;
+                keys.get(&kid).ok_or_else(|| anyhow::anyhow!("unknown kid {kid}"))?
+            }
+        };
+        let claims = jsonwebtoken::decode::<JsonValue>(token, key, &self.validation)?.claims;
+
+        let scopes = match &claims[&self.scope_claim] {
+            JsonValue::String(s) => s.split_whitespace().map(ToString::to_string).collect(),
+            JsonValue::Array(list) => list
+                .iter()
+                .filter_map(|s| s.as_str().map(ToString::to_string))
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        Ok(JwtClaims {
+            sub: claims["sub"].as_str().map(ToString::to_string),
+            scopes,
+            claims,
+        })
+    }
+
+    /// Methods allowed by the scopes of the claims.
+    pub fn methods(&self, claims: &JwtClaims) -> Vec<String> {
+        claims
+            .scopes
+            .iter()
+            .filter_map(|scope| self.scopes.get(scope))
+            .flatten()
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
+
+    fn validator() -> JwtValidator {
+        let config = serde_yaml::from_str::<JwtConfig>(
+            r#"
+            algorithm: HS256
+            secret: secret
+            issuer: subway
+            scopes:
+              read: [state_*, chain_*]
+              submit: [author_submitExtrinsic]
+            "#,
+        )
+        .unwrap();
+        JwtValidator::with_keys(&config, Keys::Single(DecodingKey::from_secret(b"secret")))
+    }
+
+    fn token(claims: JsonValue, secret: &[u8]) -> String {
+        encode(&Header::default(), &claims, &EncodingKey::from_secret(secret)).unwrap()
+    }
+
+    #[test]
+    fn maps_scopes_to_methods() {
+        let validator = validator();
+        let exp = jsonwebtoken::get_current_timestamp() + 60;
+
+        let claims = validator
+            .validate(&token(
+                json!({ "iss": "subway", "exp": exp, "scope": "read" }),
+                b"secret",
+            ))
+            .unwrap();
+        assert_eq!(validator.methods(&claims), ["state_*", "chain_*"]);
+        assert_eq!(claims.sub, None);
+
+        let claims = validator
+            .validate(&token(
+                json!({ "iss": "subway", "exp": exp, "sub": "alice", "scope": ["submit", "unknown"] }),
+                b"secret",
+            ))
+            .unwrap();
+        assert_eq!(validator.methods(&claims), ["author_submitExtrinsic"]);
+        assert_eq!(claims.sub.as_deref(), Some("alice"));
+        assert_eq!(claims.claims["iss"], "subway");
+    }
+
+    #[test]
+    fn rejects_invalid_tokens() {
+        let validator = validator();
+        let exp = jsonwebtoken::get_current_timestamp() + 60;
+        let expired = jsonwebtoken::get_current_timestamp() - 120;
+
+        assert!(validator
+            .validate(&token(
+                json!({ "iss": "subway", "exp": exp, "scope": "read" }),
+                b"wrong"
+            ))
+            .is_err());
+        assert!(validator
+            .validate(&token(
+                json!({ "iss": "other", "exp": exp, "scope": "read" }),
+                b"secret"
+            ))
+            .is_err());
+        assert!(validator
+            .validate(&token(
+                json!({ "iss": "subway", "exp": expired, "scope": "read" }),
+                b"secret"
+            ))
+            .is_err());
+    }
+}
@@ -0,0 +1,583 @@
+use std::{collections::HashMap, num::NonZeroU32, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use futures::{future::BoxFuture, FutureExt};
+use governor::{DefaultKeyedRateLimiter, Jitter, RateLimiter};
+use jsonrpsee::{
+    server::{middleware::rpc::RpcServiceT, types::Request},
+    MethodResponse,
+};
+use serde::Deserialize;
+
+use super::{
+    cache::RedisCacheConfig,
+    rate_limit::{build_quota, IpRateLimitLayer, MethodWeights, Rule},
+    Extension, ExtensionRegistry,
+};
+use crate::utils::errors;
+
+mod jwt;
+mod quota;
+pub use jwt::{JwtClaims, JwtConfig, JwtValidator};
+pub use quota::{Quota, QuotaCounter, QuotaExceeded, QuotaPeriod};
+
+tokio::task_local! {
+    /// Claims of the JWT presented by the HTTP request or WebSocket connection being served.
+    pub static JWT_CLAIMS: JwtClaims;
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct AuthConfig {
+    #[serde(default)]
+    pub keys: Vec<ApiKey>,
+    /// YAML file with a list of more keys, in the same format as `keys`.
+    #[serde(default)]
+    pub keys_file: Option<String>,
+    /// Request header carrying the key.
+    #[serde(default = "default_header")]
+    pub header: String,
+    /// Query parameter carrying the key, for clients which can not set headers, e.g. WebSocket in browsers.
+    #[serde(default = "default_query_param")]
+    pub query_param: String,
+    /// Policy of requests without a key, denies every method by default.
+    #[serde(default = "default_unauthenticated_policy")]
+    pub default_policy: Policy,
+    /// Also accept JWTs in the `Authorization: Bearer` header, allowed methods are given by their scopes.
+    #[serde(default)]
+    pub jwt: Option<JwtConfig>,
+    /// Keep the quota counts of the keys in Redis, so they survive restarts and are shared by all
+    /// instances using it. Counted in memory otherwise.
+    #[serde(default)]
+    pub redis: Option<RedisCacheConfig>,
+}
+
+fn default_header() -> String {
+    "X-Api-Key".to_string()
+}
+
+fn default_query_param() -> String {
+    "api_key".to_string()
+}
+
+fn default_unauthenticated_policy() -> Policy {
+    Policy {
+        methods: Some(Vec::new()),
+        rate_limit: None,
+        quota: None,
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct ApiKey {
+    pub key: String,
+    #[serde(flatten)]
+    pub policy: Policy,
+}
+
+impl std::fmt::Debug for ApiKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ApiKey")
+            .field("key", &"***")
+            .field("policy", &self.policy)
+            .finish()
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Policy {
+    /// Allowed methods, `*` at the end matches a prefix, e.g. `state_*`. None allows every method.
+    #[serde(default)]
+    pub methods: Option<Vec<String>>,
+    /// Rate limit shared by all connections using the key. For requests without a key, applies per ip.
+    #[serde(default)]
+    pub rate_limit: Option<Rule>,
+    /// Calls allowed to the key per UTC day and month. Ignored for requests without a key.
+    #[serde(default)]
+    pub quota: Option<Quota>,
+}
+
+impl Policy {
+    pub fn allows(&self, method: &str) -> bool {
+        self.methods
+            .as_ref()
+            .map_or(true, |methods| matches_method(methods, method))
+    }
+}
+
+/// Whether `method` is one of `patterns`, `*` at the end of a pattern matches a prefix.
+pub fn matches_method(patterns: &[String], method: &str) -> bool {
+    patterns.iter().any(|m| match m.strip_suffix('*') {
+        Some(prefix) => method.starts_with(prefix),
+        None => m == method,
+    })
+}
+
+struct RateLimit {
+    limiter: Arc<DefaultKeyedRateLimiter<String>>,
+    jitter: Jitter,
+    reject: bool,
+}
+
+impl RateLimit {
+    fn new(rule: &Rule) -> anyhow::Result<Self> {
+        let burst = NonZeroU32::new(rule.burst).ok_or_else(|| anyhow::anyhow!("burst must be greater than 0"))?;
+        anyhow::ensure!(rule.period_secs > 0, "period_secs must be greater than 0");
+        let quota = build_quota(burst, Duration::from_secs(rule.period_secs));
+        Ok(Self {
+            limiter: Arc::new(RateLimiter::keyed(quota)),
+            jitter: Jitter::up_to(Duration::from_millis(rule.jitter_up_to_millis)),
+            reject: rule.reject,
+        })
+    }
+}
+
+struct ResolvedPolicy {
+    policy: Policy,
+    rate_limit: Option<RateLimit>,
+}
+
+impl ResolvedPolicy {
+    fn new(policy: Policy) -> anyhow::Result<Arc<Self>> {
+        let rate_limit = policy.rate_limit.as_ref().map(RateLimit::new).transpose()?;
+        Ok(Arc::new(Self { policy, rate_limit }))
+    }
+}
+
+/// Authenticates requests with an API key from a header or query parameter,
+/// and applies the method permissions and rate limit of the key.
+pub struct Auth {
+    header: String,
+    query_param: String,
+    keys: HashMap<String, Arc<ResolvedPolicy>>,
+    default_policy: Arc<ResolvedPolicy>,
+    jwt: Option<JwtValidator>,
+    // keyed by the `sub` claim of the token
+    jwt_rate_limit: Option<RateLimit>,
+    quotas: Arc<QuotaCounter>,
+}
+
+#[async_trait]
+impl Extension for Auth {
+    type Config = AuthConfig;
+
+    async fn from_config(config: &Self::Config, _registry: &ExtensionRegistry) -> Result<Self, anyhow::Error> {
+        let mut auth = Self::new(config)?;
+        if let Some(ref redis) = config.redis {
+            auth.quotas = Arc::new(QuotaCounter::connect(redis).await?);
+        }
+        match config.jwt {
+            Some(ref jwt) => auth.with_jwt(JwtValidator::new(jwt).await?, jwt.rate_limit.as_ref()),
+            None => Ok(auth),
+        }
+    }
+}
+
+impl Auth {
+    pub fn new(config: &AuthConfig) -> anyhow::Result<Self> {
+        let mut keys = config.keys.clone();
+        if let Some(ref path) = config.keys_file {
+            let content = std::fs::read_to_string(path)?;
+            keys.extend(serde_yaml::from_str::<Vec<ApiKey>>(&content)?);
+        }
+
+        let keys = keys
+            .into_iter()
+            .map(|key| Ok((key.key, ResolvedPolicy::new(key.policy)?)))
+            .collect::<anyhow::Result<HashMap<_, _>>>()?;
+
+        Ok(Self {
+            header: config.header.clone(),
+            query_param: config.query_param.clone(),
+            keys,
+            default_policy: ResolvedPolicy::new(config.default_policy.clone())?,
+            jwt: None,
+            jwt_rate_limit: None,
+            quotas: Default::default(),
+        })
+    }
+
+    /// Accepts JWTs validated by `jwt`, rate limited by their `sub` claim with `rate_limit`.
+    pub fn with_jwt(mut self, jwt: JwtValidator, rate_limit: Option<&Rule>) -> anyhow::Result<Self> {
+        self.jwt = Some(jwt);
+        self.jwt_rate_limit = rate_limit.map(RateLimit::new).transpose()?;
+        Ok(self)
+    }
+
+    fn key<'a>(&self, headers: &'a http::HeaderMap, uri: &'a http::Uri) -> Option<&'a str> {
+        headers.get(&self.header).and_then(|v| v.to_str().ok()).or_else(|| {
+            uri.query()?.split('&').find_map(|pair| match pair.split_once('=') {
+                Some((name, value)) if name == self.query_param => Some(value),
+                _ => None,
+            })
+        })
+    }
+
+    /// Layers enforcing the policy of the key presented by the request, the scopes of its JWT,
+    /// or the default policy without either. Requests without a key are rate limited by `remote_ip`,
+    /// the ones with a JWT by its `sub` claim if it has one.
+    pub fn layers(
+        &self,
+        headers: &http::HeaderMap,
+        uri: &http::Uri,
+        remote_ip: String,
+        method_weights: MethodWeights,
+    ) -> (AuthLayer, Option<IpRateLimitLayer>) {
+        let (policy, limit_key, authenticated) = match self.key(headers, uri) {
+            Some(key) => match self.keys.get(key) {
+                Some(policy) => (Ok(policy.clone()), key.to_string(), true),
+                None => return (AuthLayer::new(Err("Invalid API key".to_string())), None),
+            },
+            None => match (&self.jwt, bearer_token(headers)) {
+                (Some(jwt), Some(token)) => {
+                    let claims = match jwt.validate(token) {
+                        Ok(claims) => claims,
+                        Err(e) => return (AuthLayer::new(Err(format!("Invalid token: {e}"))), None),
+                    };
+                    let policy = ResolvedPolicy::new(Policy {
+                        methods: Some(jwt.methods(&claims)),
+                        rate_limit: None,
+                        quota: None,
+                    })
+                    .map_err(|e| format!("Invalid token: {e}"));
+                    let limit_key = match claims.sub {
+                        Some(ref sub) => format!("jwt:{sub}"),
+                        None => remote_ip,
+                    };
+                    let rate_limit = self
+                        .jwt_rate_limit
+                        .as_ref()
+                        .map(|r| rate_limit_layer(r, limit_key, method_weights));
+                    return (AuthLayer::new(policy).with_claims(claims), rate_limit);
+                }
+                _ => (Ok(self.default_policy.clone()), remote_ip, false),
+            },
+        };
+
+        let rate_limit = policy
+            .as_ref()
+            .ok()
+            .and_then(|p| p.rate_limit.as_ref())
+            .map(|r| rate_limit_layer(r, limit_key.clone(), method_weights));
+
+        let quota = match policy {
+            Ok(ref policy) if authenticated => policy.policy.quota.clone(),
+            _ => None,
+        };
+        let layer = AuthLayer::new(policy);
+        let layer = match quota {
+            Some(quota) => layer.with_quota(self.quotas.clone(), limit_key, quota),
+            None if authenticated => layer.authenticated(),
+            None => layer,
+        };
+        (layer, rate_limit)
+    }
+}
+
+fn rate_limit_layer(rate_limit: &RateLimit, key: String, method_weights: MethodWeights) -> IpRateLimitLayer {
+    let layer = IpRateLimitLayer::new(key, rate_limit.limiter.clone(), rate_limit.jitter, method_weights);
+    if rate_limit.reject {
+        layer.rejecting()
+    } else {
+        layer
+    }
+}
+
+fn bearer_token(headers: &http::HeaderMap) -> Option<&str> {
+    headers
+        .get(http::header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
+/// Rejects calls to methods not allowed by the policy, or every call if the key is invalid.
+/// Makes the claims of a JWT available to method and subscription handlers.
+#[derive(Clone)]
+pub struct AuthLayer {
+    policy: Result<Arc<ResolvedPolicy>, String>,
+    claims: Option<JwtClaims>,
+    // whether the request has a valid API key or JWT, rather than the default policy
+    authenticated: bool,
+    // methods rejected without authentication, whatever the default policy allows
+    require_auth: Arc<Vec<String>>,
+    // counter, API key and quota of the key
+    quota: Option<(Arc<QuotaCounter>, String, Quota)>,
+}
+
+impl AuthLayer {
+    fn new(policy: Result<Arc<ResolvedPolicy>, String>) -> Self {
+        Self {
+            policy,
+            claims: None,
+            authenticated: false,
+            require_auth: Default::default(),
+            quota: None,
+        }
+    }
+
+    fn with_claims(mut self, claims: JwtClaims) -> Self {
+        self.claims = Some(claims);
+        self.authenticated()
+    }
+
+    fn authenticated(mut self) -> Self {
+        self.authenticated = true;
+        self
+    }
+
+    fn with_quota(mut self, counter: Arc<QuotaCounter>, key: String, quota: Quota) -> Self {
+        self.quota = Some((counter, key, quota));
+        self.authenticated()
+    }
+
+    /// Rejects calls to `methods` without a valid API key or JWT.
+    pub fn with_methods_require_auth(mut self, methods: Arc<Vec<String>>) -> Self {
+        self.require_auth = methods;
+        self
+    }
+
+    /// Reason to reject a call to `method`, if any.
+    fn rejection(&self, method: &str) -> Option<String> {
+        match self.policy {
+            Ok(_) if !self.authenticated && matches_method(&self.require_auth, method) => {
+                Some(format!("Method {method} requires authentication"))
+            }
+            Ok(ref policy) if policy.policy.allows(method) => None,
+            Ok(_) => Some(format!("Method {method} is not allowed")),
+            Err(ref e) => Some(e.clone()),
+        }
+    }
+}
+
+impl<S> tower::Layer<S> for AuthLayer {
+    type Service = AuthService<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        AuthService {
+            service,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct AuthService<S> {
+    service: S,
+    layer: AuthLayer,
+}
+
+impl<'a, S> RpcServiceT<'a> for AuthService<S>
+where
+    S: RpcServiceT<'a> + Send + Sync + Clone + 'static,
+{
+    type Future = BoxFuture<'a, MethodResponse>;
+
+    fn call(&self, req: Request<'a>) -> Self::Future {
+        if let Some(message) = self.layer.rejection(req.method_name()) {
+            return async move { MethodResponse::error(req.id, errors::unauthorized(message)) }.boxed();
+        }
+
+        if let Some((counter, key, quota)) = self.layer.quota.clone() {
+            let service = self.service.clone();
+            return async move {
+                match counter.check(&key, &quota, chrono::Utc::now()).await {
+                    Ok(()) => service.call(req).await,
+                    Err(e) => MethodResponse::error(
+                        req.id,
+                        errors::quota_exceeded(e.period, e.limit, e.reset_at.to_rfc3339()),
+                    ),
+                }
+            }
+            .boxed();
+        }
+
+        match self.layer.claims {
+            Some(ref claims) => {
+                // subscription handlers run when the call is made rather than when it is polled
+                let fut = JWT_CLAIMS.sync_scope(claims.clone(), || self.service.call(req));
+                JWT_CLAIMS.scope(claims.clone(), fut).boxed()
+            }
+            None => self.service.call(req).boxed(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth() -> Auth {
+        Auth::new(
+            &serde_yaml::from_str(
+                r#"
+                keys:
+                  - key: secret
+                    methods: [state_*, chain_getBlockHash]
+                  - key: admin
+                default_policy:
+                  methods: [system_health]
+                "#,
+            )
+            .unwrap(),
+        )
+        .unwrap()
+    }
+
+    fn allows(auth: &Auth, headers: &[(&str, &str)], uri: &str, method: &str) -> Result<bool, String> {
+        let mut header_map = http::HeaderMap::new();
+        for (name, value) in headers {
+            header_map.insert(
+                http::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                value.parse().unwrap(),
+            );
+        }
+        let (layer, _) = auth.layers(
+            &header_map,
+            &uri.parse().unwrap(),
+            "127.0.0.1".to_string(),
+            Default::default(),
+        );
+        layer.policy.map(|p| p.policy.allows(method))
+    }
+
+    #[test]
+    fn applies_key_policies() {
+        let auth = auth();
+        let secret = [("x-api-key", "secret")];
+        assert_eq!(allows(&auth, &secret, "/", "state_getStorage"), Ok(true));
+        assert_eq!(allows(&auth, &secret, "/", "chain_getBlockHash"), Ok(true));
+        assert_eq!(allows(&auth, &secret, "/", "author_submitExtrinsic"), Ok(false));
+        assert_eq!(
+            allows(&auth, &[], "/?api_key=admin", "author_submitExtrinsic"),
+            Ok(true)
+        );
+        assert_eq!(allows(&auth, &[], "/", "system_health"), Ok(true));
+        assert_eq!(allows(&auth, &[], "/", "state_getStorage"), Ok(false));
+        assert!(allows(&auth, &[("x-api-key", "wrong")], "/", "system_health").is_err());
+    }
+
+    #[test]
+    fn requires_auth_for_listed_methods() {
+        let auth = Auth::new(&serde_yaml::from_str("keys: [{ key: secret }]\ndefault_policy: {}").unwrap()).unwrap();
+        let require_auth = Arc::new(vec!["author_*".to_string()]);
+        let rejection = |headers: &http::HeaderMap, method: &str| {
+            let (layer, _) = auth.layers(
+                headers,
+                &"/".parse().unwrap(),
+                "127.0.0.1".to_string(),
+                Default::default(),
+            );
+            layer.with_methods_require_auth(require_auth.clone()).rejection(method)
+        };
+
+        let mut headers = http::HeaderMap::new();
+        assert_eq!(
+            rejection(&headers, "author_submitExtrinsic"),
+            Some("Method author_submitExtrinsic requires authentication".to_string())
+        );
+        assert_eq!(rejection(&headers, "system_health"), None);
+        headers.insert("x-api-key", "secret".parse().unwrap());
+        assert_eq!(rejection(&headers, "author_submitExtrinsic"), None);
+    }
+
+    #[test]
+    fn attaches_quota_of_key() {
+        let auth = Auth::new(
+            &serde_yaml::from_str("keys: [{ key: secret, quota: { daily_limit: 10 } }]\ndefault_policy: {}").unwrap(),
+        )
+        .unwrap();
+        let quota = |headers: &[(&str, &str)]| {
+            let mut header_map = http::HeaderMap::new();
+            for (name, value) in headers {
+                header_map.insert(
+                    http::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                    value.parse().unwrap(),
+                );
+            }
+            let (layer, _) = auth.layers(
+                &header_map,
+                &"/".parse().unwrap(),
+                "127.0.0.1".to_string(),
+                Default::default(),
+            );
+            layer.quota.map(|(_, key, quota)| (key, quota))
+        };
+
+        assert_eq!(
+            quota(&[("x-api-key", "secret")]),
+            Some((
+                "secret".to_string(),
+                Quota {
+                    daily_limit: Some(10),
+                    monthly_limit: None
+                }
+            ))
+        );
+        assert_eq!(quota(&[]), None);
+    }
+
+    #[test]
+    fn denies_unauthenticated_calls_by_default() {
+        let auth = Auth::new(&serde_yaml::from_str("keys: []").unwrap()).unwrap();
+        assert_eq!(allows(&auth, &[], "/", "system_health"), Ok(false));
+    }
+
+    #[tokio::test]
+    async fn passes_jwt_claims_on() {
+        let config: AuthConfig = serde_yaml::from_str(
+            r#"
+            jwt:
+              algorithm: HS256
+              secret: secret
+              scopes:
+                read: [state_*]
+              rate_limit:
+                burst: 10
+            "#,
+        )
+        .unwrap();
+        let jwt = config.jwt.as_ref().unwrap();
+        let auth = Auth::new(&config)
+            .unwrap()
+            .with_jwt(JwtValidator::new(jwt).await.unwrap(), jwt.rate_limit.as_ref())
+            .unwrap();
+
+        let exp = jsonwebtoken::get_current_timestamp() + 60;
+        let token = jsonwebtoken::encode(
+            &Default::default(),
+            &serde_json::json!({ "exp": exp, "sub": "alice", "scope": "read" }),
+            &jsonwebtoken::EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap();
+        let mut headers = http::HeaderMap::new();
+        headers.insert(http::header::AUTHORIZATION, format!("Bearer {token}").parse().unwrap());
+
+        let (layer, rate_limit) = auth.layers(
+            &headers,
+            &"/".parse().unwrap(),
+            "127.0.0.1".to_string(),
+            Default::default(),
+        );
+        assert!(layer.policy.unwrap().policy.allows("state_getStorage"));
+        assert_eq!(layer.claims.unwrap().sub.as_deref(), Some("alice"));
+        assert!(rate_limit.is_some());
+    }
+
+    #[test]
+    fn debug_output_hides_secrets() {
+        let config: AuthConfig = serde_yaml::from_str(
+            r#"
+            keys:
+              - key: key-secret
+            jwt:
+              algorithm: HS256
+              secret: jwt-secret
+              scopes: {}
+            "#,
+        )
+        .unwrap();
+        let debug = format!("{config:?}");
+        assert!(!debug.contains("key-secret"));
+        assert!(!debug.contains("jwt-secret"));
+    }
+}
@@ -0,0 +1,224 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use blake2::{Blake2b512, Digest};
+use chrono::{DateTime, Datelike, NaiveDate, TimeZone, Utc};
+use redis::aio::ConnectionManager;
+use serde::Deserialize;
+
+use crate::extensions::cache::RedisCacheConfig;
+
+/// Calls allowed to an API key per UTC day and per UTC month.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Quota {
+    #[serde(default)]
+    pub daily_limit: Option<u64>,
+    #[serde(default)]
+    pub monthly_limit: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum QuotaPeriod {
+    Day,
+    Month,
+}
+
+impl QuotaPeriod {
+    fn id(&self, now: DateTime<Utc>) -> String {
+        match self {
+            Self::Day => now.format("%Y-%m-%d").to_string(),
+            Self::Month => now.format("%Y-%m").to_string(),
+        }
+    }
+
+    /// Start of the next period, when counters reset.
+    pub fn reset_at(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let date = now.date_naive();
+        let next = match self {
+            Self::Day => date.succ_opt(),
+            Self::Month if date.month() == 12 => NaiveDate::from_ymd_opt(date.year() + 1, 1, 1),
+            Self::Month => NaiveDate::from_ymd_opt(date.year(), date.month() + 1, 1),
+        };
+        next.and_then(|d| d.and_hms_opt(0, 0, 0))
+            .map(|d| Utc.from_utc_datetime(&d))
+            .expect("valid date; qed")
+    }
+}
+
+impl std::fmt::Display for QuotaPeriod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Day => write!(f, "daily"),
+            Self::Month => write!(f, "monthly"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuotaExceeded {
+    pub period: QuotaPeriod,
+    pub limit: u64,
+    pub reset_at: DateTime<Utc>,
+}
+
+enum Store {
+    // (key, period) -> (period id, calls), replaced once the period is over
+    Memory(Mutex<HashMap<(String, QuotaPeriod), (String, u64)>>),
+    Redis { conn: ConnectionManager, prefix: String },
+}
+
+/// Counts the calls made with each API key, in memory or in Redis so counts survive restarts and are
+/// shared by all subway instances using it.
+pub struct QuotaCounter {
+    store: Store,
+}
+
+impl Default for QuotaCounter {
+    fn default() -> Self {
+        Self {
+            store: Store::Memory(Default::default()),
+        }
+    }
+}
+
+impl QuotaCounter {
+    pub async fn connect(config: &RedisCacheConfig) -> anyhow::Result<Self> {
+        let client = redis::Client::open(config.url.as_str())?;
+        let conn = client.get_tokio_connection_manager().await?;
+        Ok(Self {
+            store: Store::Redis {
+                conn,
+                prefix: format!("{}:quota:", config.key_prefix),
+            },
+        })
+    }
+
+    /// Counts a call made with `key` at `now`, unless it is over one of the limits of `quota`.
+    pub async fn check(&self, key: &str, quota: &Quota, now: DateTime<Utc>) -> Result<(), QuotaExceeded> {
+        let limits = [
+            (QuotaPeriod::Day, quota.daily_limit),
+            (QuotaPeriod::Month, quota.monthly_limit),
+        ];
+        let mut counted = Vec::new();
+        for (period, limit) in limits {
+            let Some(limit) = limit else {
+                continue;
+            };
+            let calls = self.increment(key, period, now, 1).await;
+            counted.push(period);
+            if calls.is_some_and(|calls| calls > limit) {
+                // rejected calls do not use the quota
+                for period in counted {
+                    self.increment(key, period, now, -1).await;
+                }
+                return Err(QuotaExceeded {
+                    period,
+                    limit,
+                    reset_at: period.reset_at(now),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Calls of the period after adding `delta`, None if Redis failed.
+    async fn increment(&self, key: &str, period: QuotaPeriod, now: DateTime<Utc>, delta: i64) -> Option<u64> {
+        let period_id = period.id(now);
+        match self.store {
+            Store::Memory(ref counts) => {
+                let mut counts = counts.lock().unwrap_or_else(|e| e.into_inner());
+                let entry = counts
+                    .entry((key.to_string(), period))
+                    .or_insert_with(|| (period_id.clone(), 0));
+                if entry.0 != period_id {
+                    *entry = (period_id, 0);
+                }
+                entry.1 = entry.1.saturating_add_signed(delta);
+                Some(entry.1)
+            }
+            Store::Redis { ref conn, ref prefix } => {
+                // keys are kept out of Redis, only a digest of them
+                let digest = Blake2b512::digest(key.as_bytes());
+                let hex: String = digest[..16].iter().map(|b| format!("{b:02x}")).collect();
+                let counter = format!("{prefix}{hex}:{period_id}");
+                // expires a day after the period, clocks of instances may differ a little
+                let expire_at = period.reset_at(now).timestamp() + 24 * 60 * 60;
+                let res = redis::pipe()
+                    .atomic()
+                    .cmd("INCRBY")
+                    .arg(&counter)
+                    .arg(delta)
+                    .cmd("EXPIREAT")
+                    .arg(&counter)
+                    .arg(expire_at)
+                    .ignore()
+                    .query_async::<_, (i64,)>(&mut conn.clone())
+                    .await;
+                match res {
+                    Ok((calls,)) => Some(calls.max(0) as u64),
+                    Err(e) => {
+                        // calls are allowed rather than failed while Redis is unavailable
+                        tracing::warn!("Quota: Redis INCRBY failed: {e}");
+                        None
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn periods_reset_at_utc_midnight_and_month_start() {
+        let now = at("2024-12-31T18:30:00Z");
+        assert_eq!(QuotaPeriod::Day.reset_at(now), at("2025-01-01T00:00:00Z"));
+        assert_eq!(QuotaPeriod::Month.reset_at(now), at("2025-01-01T00:00:00Z"));
+        let now = at("2024-02-10T00:00:00Z");
+        assert_eq!(QuotaPeriod::Day.reset_at(now), at("2024-02-11T00:00:00Z"));
+        assert_eq!(QuotaPeriod::Month.reset_at(now), at("2024-03-01T00:00:00Z"));
+    }
+
+    #[tokio::test]
+    async fn enforces_daily_and_monthly_limits() {
+        let counter = QuotaCounter::default();
+        let quota = Quota {
+            daily_limit: Some(2),
+            monthly_limit: Some(3),
+        };
+
+        let day1 = at("2024-05-01T10:00:00Z");
+        assert_eq!(counter.check("key", &quota, day1).await, Ok(()));
+        assert_eq!(counter.check("key", &quota, day1).await, Ok(()));
+        assert_eq!(
+            counter.check("key", &quota, day1).await,
+            Err(QuotaExceeded {
+                period: QuotaPeriod::Day,
+                limit: 2,
+                reset_at: at("2024-05-02T00:00:00Z"),
+            })
+        );
+        // other keys have their own counts
+        assert_eq!(counter.check("other", &quota, day1).await, Ok(()));
+
+        // the daily count resets, the monthly one does not
+        let day2 = at("2024-05-02T10:00:00Z");
+        assert_eq!(counter.check("key", &quota, day2).await, Ok(()));
+        assert_eq!(
+            counter.check("key", &quota, day2).await,
+            Err(QuotaExceeded {
+                period: QuotaPeriod::Month,
+                limit: 3,
+                reset_at: at("2024-06-01T00:00:00Z"),
+            })
+        );
+
+        let next_month = at("2024-06-01T00:00:01Z");
+        assert_eq!(counter.check("key", &quota, next_month).await, Ok(()));
+    }
+}
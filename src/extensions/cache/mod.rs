@@ -1,10 +1,24 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
 use async_trait::async_trait;
+use blake2::Blake2b512;
+use jsonrpsee::core::JsonValue;
 use serde::Deserialize;
 
 use super::{Extension, ExtensionRegistry};
+use crate::utils::{Cache as ResponseCache, SharedCacheBackend};
+
+mod redis;
+pub use self::redis::{RedisBackend, RedisCacheConfig};
 
 pub struct Cache {
     pub config: CacheConfig,
+    // response cache of each method, registered by the cache middleware
+    method_caches: Mutex<BTreeMap<String, ResponseCache<Blake2b512>>>,
+    redis: Option<RedisBackend>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -13,6 +27,60 @@ pub struct CacheConfig {
     #[serde(default)]
     pub default_ttl_seconds: Option<u64>,
     pub default_size: usize,
+    // entries evicted from memory are written here instead of being discarded, one directory per method
+    #[serde(default)]
+    pub disk_spillover_path: Option<String>,
+    // files kept on disk per method, the oldest are removed beyond it
+    #[serde(default = "default_disk_spillover_max_entries")]
+    pub disk_spillover_max_entries: usize,
+    // cache upstream errors, e.g. for an unknown block hash, so repeats are answered locally
+    #[serde(default)]
+    pub negative_caching: NegativeCachingConfig,
+    // capacity of the error cache of each method, separate from the response cache
+    #[serde(default = "default_negative_cache_size")]
+    pub negative_cache_size: usize,
+    // responses are also stored in redis, shared by all subway instances using it
+    #[serde(default)]
+    pub redis: Option<RedisCacheConfig>,
+    // called once on startup, before serving, so the first clients are answered from the cache
+    #[serde(default)]
+    pub warm_up: Vec<WarmUpCall>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct WarmUpCall {
+    pub method: String,
+    #[serde(default)]
+    pub params: Vec<JsonValue>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct NegativeCachingConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_negative_ttl_secs")]
+    pub ttl_secs: u64,
+}
+
+impl Default for NegativeCachingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            ttl_secs: default_negative_ttl_secs(),
+        }
+    }
+}
+
+fn default_negative_ttl_secs() -> u64 {
+    10
+}
+
+fn default_disk_spillover_max_entries() -> usize {
+    100_000
+}
+
+fn default_negative_cache_size() -> usize {
+    100
 }
 
 #[async_trait]
@@ -20,12 +88,75 @@ impl Extension for Cache {
     type Config = CacheConfig;
 
     async fn from_config(config: &Self::Config, _registry: &ExtensionRegistry) -> Result<Self, anyhow::Error> {
-        Ok(Self::new(config.clone()))
+        let mut cache = Self::new(config.clone());
+        if let Some(ref redis) = config.redis {
+            cache.redis = Some(RedisBackend::connect(redis).await?);
+        }
+        Ok(cache)
     }
 }
 
 impl Cache {
     pub fn new(config: CacheConfig) -> Self {
-        Self { config }
+        Self {
+            config,
+            method_caches: Default::default(),
+            redis: None,
+        }
+    }
+
+    /// Shared backend of the response cache of the method, if configured.
+    pub fn shared_backend(&self, method: &str) -> Option<Arc<dyn SharedCacheBackend>> {
+        self.redis
+            .as_ref()
+            .map(|redis| Arc::new(redis.for_method(method)) as Arc<dyn SharedCacheBackend>)
+    }
+
+    pub fn register(&self, method: &str, cache: ResponseCache<Blake2b512>) {
+        let mut caches = self.method_caches.lock().unwrap_or_else(|e| e.into_inner());
+        caches.insert(method.to_string(), cache);
+    }
+
+    /// Entries and evictions of the response cache of each method.
+    pub fn stats(&self) -> JsonValue {
+        let caches = self.method_caches.lock().unwrap_or_else(|e| e.into_inner());
+        caches
+            .iter()
+            .map(|(method, cache)| {
+                let stats = serde_json::json!({
+                    "entries": cache.entry_count(),
+                    "evictions": cache.evictions(),
+                });
+                (method.clone(), stats)
+            })
+            .collect::<serde_json::Map<_, _>>()
+            .into()
+    }
+
+    /// Removes all cached responses of the method, false if it has no cache.
+    pub async fn flush(&self, method: &str) -> bool {
+        let cache = {
+            let caches = self.method_caches.lock().unwrap_or_else(|e| e.into_inner());
+            caches.get(method).cloned()
+        };
+        match cache {
+            Some(cache) => {
+                cache.clear().await;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Default cache budget, methods may override it.
+    pub fn limits(&self) -> JsonValue {
+        serde_json::json!({
+            "default_size": self.config.default_size,
+            "default_ttl_seconds": self.config.default_ttl_seconds,
+            "disk_spillover_path": self.config.disk_spillover_path,
+            "disk_spillover_max_entries": self.config.disk_spillover_max_entries,
+            "negative_caching": self.config.negative_caching.enabled,
+            "redis": self.redis.is_some(),
+        })
     }
 }
@@ -0,0 +1,131 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use jsonrpsee::core::JsonValue;
+use redis::aio::ConnectionManager;
+use serde::Deserialize;
+
+use crate::{extensions::client::redact_endpoint, utils::SharedCacheBackend};
+
+#[derive(Deserialize, Clone)]
+pub struct RedisCacheConfig {
+    /// e.g. `redis://127.0.0.1:6379/0`
+    pub url: String,
+    /// Prepended to every key, so several deployments can share one Redis.
+    #[serde(default = "default_key_prefix")]
+    pub key_prefix: String,
+}
+
+// the url may hold the password
+impl std::fmt::Debug for RedisCacheConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedisCacheConfig")
+            .field("url", &redact_endpoint(&self.url))
+            .field("key_prefix", &self.key_prefix)
+            .finish()
+    }
+}
+
+fn default_key_prefix() -> String {
+    "subway".to_string()
+}
+
+/// Stores responses as JSON strings under `<key_prefix>:<method>:<hex of the cache key>`.
+#[derive(Clone)]
+pub struct RedisBackend {
+    conn: ConnectionManager,
+    prefix: String,
+}
+
+impl RedisBackend {
+    pub async fn connect(config: &RedisCacheConfig) -> anyhow::Result<Self> {
+        let client = redis::Client::open(config.url.as_str())?;
+        let conn = client.get_tokio_connection_manager().await?;
+        Ok(Self {
+            conn,
+            prefix: format!("{}:", config.key_prefix),
+        })
+    }
+
+    /// Backend of the cache of `method`, sharing the connection.
+    pub fn for_method(&self, method: &str) -> Self {
+        Self {
+            conn: self.conn.clone(),
+            prefix: format!("{}{method}:", self.prefix),
+        }
+    }
+
+    fn key(&self, key: &[u8]) -> String {
+        let hex: String = key.iter().map(|b| format!("{b:02x}")).collect();
+        format!("{}{hex}", self.prefix)
+    }
+}
+
+#[async_trait]
+impl SharedCacheBackend for RedisBackend {
+    async fn get(&self, key: &[u8]) -> Option<JsonValue> {
+        let res = redis::cmd("GET")
+            .arg(self.key(key))
+            .query_async::<_, Option<String>>(&mut self.conn.clone())
+            .await;
+        match res {
+            Ok(value) => serde_json::from_str(&value?).ok(),
+            Err(e) => {
+                tracing::warn!("Cache: Redis GET failed: {e}");
+                None
+            }
+        }
+    }
+
+    async fn set(&self, key: &[u8], value: &JsonValue, ttl: Option<Duration>) {
+        let mut cmd = redis::cmd("SET");
+        cmd.arg(self.key(key)).arg(value.to_string());
+        if let Some(ttl) = ttl {
+            cmd.arg("PX").arg(ttl.as_millis() as u64);
+        }
+        if let Err(e) = cmd.query_async::<_, ()>(&mut self.conn.clone()).await {
+            tracing::warn!("Cache: Redis SET failed: {e}");
+        }
+    }
+
+    async fn remove(&self, key: &[u8]) {
+        let res = redis::cmd("DEL")
+            .arg(self.key(key))
+            .query_async::<_, ()>(&mut self.conn.clone())
+            .await;
+        if let Err(e) = res {
+            tracing::warn!("Cache: Redis DEL failed: {e}");
+        }
+    }
+
+    async fn clear(&self) {
+        let mut conn = self.conn.clone();
+        let mut cursor = 0u64;
+        loop {
+            let res = redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(format!("{}*", self.prefix))
+                .arg("COUNT")
+                .arg(1000)
+                .query_async::<_, (u64, Vec<String>)>(&mut conn)
+                .await;
+            let (next, keys) = match res {
+                Ok(page) => page,
+                Err(e) => {
+                    tracing::warn!("Cache: Redis SCAN failed: {e}");
+                    return;
+                }
+            };
+            if !keys.is_empty() {
+                if let Err(e) = redis::cmd("DEL").arg(keys).query_async::<_, ()>(&mut conn).await {
+                    tracing::warn!("Cache: Redis DEL failed: {e}");
+                }
+            }
+            if next == 0 {
+                return;
+            }
+            cursor = next;
+        }
+    }
+}
@@ -69,6 +69,22 @@ impl TestServerBuilder {
         rx
     }
 
+    /// Subscription which is never acknowledged.
+    pub fn register_unresponsive_subscription(
+        &mut self,
+        sub_name: &'static str,
+        method_name: &'static str,
+        unsub_name: &'static str,
+    ) {
+        self.module
+            .register_subscription(sub_name, method_name, unsub_name, move |_, sink, _| async move {
+                let _sink = sink;
+                futures::future::pending::<()>().await;
+                Ok(())
+            })
+            .unwrap();
+    }
+
     pub fn register_error_subscription(
         &mut self,
         sub_name: &'static str,
@@ -0,0 +1,140 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use jsonrpsee::core::JsonValue;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use super::Client;
+use crate::{extensions::subscription_stats::ExponentialMovingAverage, middlewares::CallResult};
+
+/// How requests are spread over the upstream endpoints.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LoadBalancing {
+    // one endpoint at a time, the next one is used when it fails
+    #[default]
+    Failover,
+    RoundRobin,
+    Random,
+    // endpoint with the lowest moving average of response times
+    LeastLatency,
+}
+
+// weight of the latest response time
+const LATENCY_SMOOTHING: f64 = 0.2;
+
+/// Connections to every endpoint, requests are sent to the one picked by the strategy.
+/// Endpoints without a connection are skipped while any other is connected.
+pub struct EndpointPool {
+    strategy: LoadBalancing,
+    clients: Vec<Arc<Client>>,
+    next: AtomicUsize,
+    latencies: Mutex<Vec<ExponentialMovingAverage>>,
+}
+
+impl EndpointPool {
+    pub fn new(strategy: LoadBalancing, clients: Vec<Arc<Client>>) -> Self {
+        let latencies = vec![ExponentialMovingAverage::new(LATENCY_SMOOTHING); clients.len()];
+        Self {
+            strategy,
+            clients,
+            next: AtomicUsize::new(0),
+            latencies: Mutex::new(latencies),
+        }
+    }
+
+    pub fn strategy(&self) -> LoadBalancing {
+        self.strategy
+    }
+
+    pub fn clients(&self) -> &[Arc<Client>] {
+        &self.clients
+    }
+
+    /// Index of the endpoint the next request goes to.
+    pub fn pick(&self) -> usize {
+        let connected = (0..self.clients.len())
+            .filter(|i| self.clients[*i].is_healthy())
+            .collect::<Vec<_>>();
+        let candidates = if connected.is_empty() {
+            (0..self.clients.len()).collect()
+        } else {
+            connected
+        };
+        self.pick_from(&candidates)
+    }
+
+    fn pick_from(&self, candidates: &[usize]) -> usize {
+        match self.strategy {
+            LoadBalancing::Failover => candidates[0],
+            LoadBalancing::RoundRobin => candidates[self.next.fetch_add(1, Ordering::Relaxed) % candidates.len()],
+            LoadBalancing::Random => candidates[rand::thread_rng().gen_range(0..candidates.len())],
+            LoadBalancing::LeastLatency => {
+                let latencies = self.latencies.lock().unwrap_or_else(|e| e.into_inner());
+                // endpoints without samples average zero, so each one is tried first
+                *candidates
+                    .iter()
+                    .min_by(|a, b| latencies[**a].value().total_cmp(&latencies[**b].value()))
+                    .expect("at least one endpoint; qed")
+            }
+        }
+    }
+
+    /// Records the response time of a request sent to the endpoint.
+    pub fn record(&self, index: usize, elapsed: Duration) {
+        let mut latencies = self.latencies.lock().unwrap_or_else(|e| e.into_inner());
+        latencies[index].update(elapsed.as_secs_f64());
+    }
+
+    pub async fn request(&self, method: &str, params: Vec<JsonValue>, retries: Option<u32>) -> CallResult {
+        let index = self.pick();
+        let start = tokio::time::Instant::now();
+        let result = self.clients[index].send_request(method, params, retries).await;
+        self.record(index, start.elapsed());
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn pool(strategy: LoadBalancing) -> EndpointPool {
+        let clients = (1..=3)
+            .map(|port| Arc::new(Client::with_endpoints([format!("ws://127.0.0.1:{port}")]).unwrap()))
+            .collect();
+        EndpointPool::new(strategy, clients)
+    }
+
+    #[tokio::test]
+    async fn round_robin_cycles_endpoints() {
+        let pool = pool(LoadBalancing::RoundRobin).await;
+        let picked = (0..6).map(|_| pool.pick()).collect::<Vec<_>>();
+        assert_eq!(picked, [0, 1, 2, 0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn least_latency_prefers_fastest_endpoint() {
+        let pool = pool(LoadBalancing::LeastLatency).await;
+        pool.record(0, Duration::from_millis(50));
+        pool.record(1, Duration::from_millis(10));
+        pool.record(2, Duration::from_millis(30));
+        assert_eq!(pool.pick(), 1);
+
+        pool.record(1, Duration::from_millis(200));
+        pool.record(1, Duration::from_millis(200));
+        assert_eq!(pool.pick(), 2);
+    }
+
+    #[tokio::test]
+    async fn random_picks_known_endpoints() {
+        let pool = pool(LoadBalancing::Random).await;
+        assert!((0..20).all(|_| pool.pick() < 3));
+    }
+}
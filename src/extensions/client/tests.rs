@@ -205,8 +205,9 @@ async fn retry_requests_out_of_retries() {
     });
 
     let h3 = tokio::spawn(async move {
-        let res = client.request("mock_rpc", vec![]).await;
-        assert_eq!(res.unwrap_err().data().unwrap().to_string(), "\"Request timeout\"");
+        let err = client.request("mock_rpc", vec![]).await.unwrap_err();
+        assert_eq!(err.data().unwrap().to_string(), "\"Request timeout\"");
+        assert!(errors::is_upstream_unreachable(&err));
     });
 
     h3.await.unwrap();
@@ -216,3 +217,430 @@ async fn retry_requests_out_of_retries() {
     handle1.stop().unwrap();
     handle2.stop().unwrap();
 }
+
+#[tokio::test]
+async fn internal_requests_bypass_client_limit() {
+    let mut builder = TestServerBuilder::new();
+    let mut slow_rx = builder.register_method("mock_rpc");
+    let mut health_rx = builder.register_method("system_health");
+    let (addr, handle) = builder.build().await;
+
+    let client = Arc::new(
+        Client::with_endpoints([format!("ws://{addr}")])
+            .unwrap()
+            .with_request_limits(Some(2), 1),
+    );
+
+    // hold slow requests so the client limiter is saturated
+    let slow = tokio::spawn(async move {
+        let mut pending = vec![];
+        while let Some(req) = slow_rx.recv().await {
+            pending.push(req);
+        }
+    });
+
+    let health = tokio::spawn(async move {
+        let req = health_rx.recv().await.unwrap();
+        req.respond(json!({ "peers": 1 }));
+    });
+
+    let mut calls = vec![];
+    for _ in 0..4 {
+        let client = client.clone();
+        calls.push(tokio::spawn(async move { client.request("mock_rpc", vec![]).await }));
+    }
+
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let res = tokio::time::timeout(
+        Duration::from_millis(500),
+        client.request_internal("system_health", vec![]),
+    )
+    .await
+    .expect("internal request should not wait for client slots");
+    assert_eq!(res.unwrap(), json!({ "peers": 1 }));
+
+    health.await.unwrap();
+    calls.into_iter().for_each(|c| c.abort());
+    handle.stop().unwrap();
+    slow.abort();
+}
+
+#[tokio::test]
+async fn header_clients_are_bounded() {
+    let (addr, handle, _rx, _) = dummy_server().await;
+
+    let client = Arc::new(
+        Client::with_endpoints([format!("ws://{addr}")])
+            .unwrap()
+            .with_header_client_limits(2, Duration::from_millis(50)),
+    );
+    let headers = |token: &str| ForwardedHeaders(vec![("authorization".to_string(), token.to_string())]);
+
+    let a = client.headers_client(&headers("a")).unwrap();
+    let b = client.headers_client(&headers("b")).unwrap();
+    // a is used more recently than b
+    assert!(Arc::ptr_eq(&a, &client.headers_client(&headers("a")).unwrap()));
+
+    // b is dropped to make room
+    client.headers_client(&headers("c")).unwrap();
+    assert!(Arc::ptr_eq(&a, &client.headers_client(&headers("a")).unwrap()));
+    assert!(!Arc::ptr_eq(&b, &client.headers_client(&headers("b")).unwrap()));
+
+    // idle clients are dropped
+    tokio::time::sleep(Duration::from_millis(60)).await;
+    assert!(!Arc::ptr_eq(&a, &client.headers_client(&headers("a")).unwrap()));
+
+    handle.stop().unwrap();
+}
+
+#[tokio::test]
+async fn header_clients_share_subscription_limits() {
+    use crate::middlewares::subscriptions::upstream::EndpointSubscriptions;
+
+    let (addr, handle, _rx, _) = dummy_server().await;
+    let endpoint = format!("ws://{addr}");
+
+    let client = Arc::new(
+        Client::with_endpoints([&endpoint])
+            .unwrap()
+            .with_subscription_limits(HashMap::from([(endpoint.clone(), 1)])),
+    );
+    let header_client = client
+        .headers_client(&ForwardedHeaders(vec![("authorization".to_string(), "a".to_string())]))
+        .unwrap();
+    assert_eq!(header_client.subscription_limit(&endpoint), Some(1));
+
+    let subscriptions = Arc::new(EndpointSubscriptions::default());
+    let slot = subscriptions.acquire(&client);
+    assert!(slot.is_some());
+    // the endpoint is full for the clients of all headers
+    assert!(subscriptions.acquire(&header_client).is_none());
+    drop(slot);
+    assert!(subscriptions.acquire(&header_client).is_some());
+
+    handle.stop().unwrap();
+}
+
+#[tokio::test]
+async fn endpoint_clients_stay_on_their_endpoint() {
+    let (addr, handle, _rx, _) = dummy_server().await;
+    let endpoint = format!("ws://{addr}");
+
+    let client = Arc::new(Client::with_endpoints([&endpoint]).unwrap());
+
+    // the current endpoint gets its own client too, which does not rotate with this one
+    let endpoint_client = client.endpoint_client(&endpoint).unwrap();
+    assert!(!Arc::ptr_eq(&endpoint_client, &client));
+    assert_eq!(endpoint_client.endpoints(), vec![endpoint.clone()]);
+    assert!(Arc::ptr_eq(
+        &endpoint_client,
+        &client.endpoint_client(&endpoint).unwrap()
+    ));
+
+    handle.stop().unwrap();
+}
+
+#[tokio::test]
+async fn forwarded_headers_client() {
+    let (addr, handle, mut rx, _) = dummy_server().await;
+
+    let client = Arc::new(
+        Client::with_endpoints([format!("ws://{addr}")])
+            .unwrap()
+            .with_header_forwarding(vec!["Authorization".to_string(), "X-Route".to_string()]),
+    );
+
+    let mut request_headers = http::HeaderMap::new();
+    request_headers.insert("authorization", "Bearer abc".parse().unwrap());
+    request_headers.insert("x-other", "1".parse().unwrap());
+
+    let headers = ForwardedHeaders::from_request(client.header_forwarding(), &request_headers);
+    assert_eq!(
+        headers,
+        ForwardedHeaders(vec![("authorization".to_string(), "Bearer abc".to_string())])
+    );
+
+    // no forwarded header uses the shared client
+    let shared = client.headers_client(&ForwardedHeaders::default()).unwrap();
+    assert!(Arc::ptr_eq(&shared, &client));
+
+    // same headers reuse the same upstream connection
+    let header_client = client.headers_client(&headers).unwrap();
+    assert!(!Arc::ptr_eq(&header_client, &client));
+    assert!(Arc::ptr_eq(&header_client, &client.headers_client(&headers).unwrap()));
+
+    let task = tokio::spawn(async move {
+        let req = rx.recv().await.unwrap();
+        req.respond(json!(1));
+    });
+
+    let result = header_client.request("mock_rpc", vec![]).await.unwrap();
+    assert_eq!(result, json!(1));
+
+    handle.stop().unwrap();
+    task.await.unwrap();
+}
+
+#[tokio::test]
+async fn idle_connection_refreshed_before_request() {
+    let (addr, handle, mut rx, _) = dummy_server().await;
+    let endpoint = format!("ws://{addr}");
+
+    let client = Client::with_endpoints([endpoint.clone()]).unwrap();
+    client.set_idle_timeouts([(endpoint.clone(), Duration::from_millis(100))].into());
+
+    let task = tokio::spawn(async move {
+        while let Some(req) = rx.recv().await {
+            req.respond(json!(1));
+        }
+    });
+
+    let result = client.request("mock_rpc", vec![]).await.unwrap();
+    assert_eq!(result, json!(1));
+
+    // idle past the threshold, the connection is re-established to the same endpoint
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let result = client.request("mock_rpc", vec![]).await.unwrap();
+    assert_eq!(result, json!(1));
+    assert_eq!(client.current_endpoint(), endpoint);
+
+    handle.stop().unwrap();
+    task.abort();
+}
+
+#[tokio::test]
+async fn recorded_activity_keeps_idle_connection() {
+    let (addr, handle, mut rx, _) = dummy_server().await;
+    let endpoint = format!("ws://{addr}");
+
+    let client = Client::with_endpoints([endpoint.clone()]).unwrap();
+    client.set_idle_timeouts([(endpoint.clone(), Duration::from_millis(100))].into());
+
+    let task = tokio::spawn(async move {
+        while let Some(req) = rx.recv().await {
+            req.respond(json!(1));
+        }
+    });
+
+    assert_eq!(client.request("mock_rpc", vec![]).await.unwrap(), json!(1));
+    let connection = client.connection_changes();
+
+    // notifications keep arriving while no request is sent
+    for _ in 0..4 {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        client.record_activity();
+    }
+
+    assert_eq!(client.request("mock_rpc", vec![]).await.unwrap(), json!(1));
+    assert!(!connection.has_changed().unwrap());
+
+    handle.stop().unwrap();
+    task.abort();
+}
+
+#[tokio::test]
+async fn subscribe_with_timeout() {
+    let mut builder = TestServerBuilder::new();
+    let mut sub_rx = builder.register_subscription("mock_sub", "mock", "mock_unsub");
+    builder.register_unresponsive_subscription("slow_sub", "slow", "slow_unsub");
+    let (addr, _handle) = builder.build().await;
+
+    let client = Client::with_endpoints([format!("ws://{addr}")]).unwrap();
+    let timeout = Duration::from_millis(100);
+
+    let res = client
+        .subscribe_with_timeout("slow_sub", vec![], "slow_unsub", timeout)
+        .await;
+    assert!(matches!(res, Err(SubscribeError::Timeout(t)) if t == timeout));
+
+    let mut sub = client
+        .subscribe_with_timeout("mock_sub", vec![], "mock_unsub", timeout)
+        .await
+        .unwrap();
+    let upstream = sub_rx.recv().await.unwrap();
+    upstream.send(json!("ok")).await;
+    assert_eq!(sub.next().await.unwrap().unwrap(), json!("ok"));
+}
+
+#[tokio::test]
+async fn health_follows_connection() {
+    let (addr, handle, mut rx, _) = dummy_server().await;
+
+    let client = Client::with_endpoints([format!("ws://{addr}")]).unwrap();
+
+    let task = tokio::spawn(async move {
+        let req = rx.recv().await.unwrap();
+        req.respond(json!(1));
+    });
+
+    client.request("mock_rpc", vec![]).await.unwrap();
+    assert!(client.is_healthy());
+
+    // nothing listens on port 1
+    let unreachable = Client::with_endpoints(["ws://127.0.0.1:1"]).unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(!unreachable.is_healthy());
+
+    handle.stop().unwrap();
+    task.await.unwrap();
+}
+
+#[tokio::test]
+async fn health_check_rotates_stalled_endpoint() {
+    let (addr1, handle1, mut rx1, _) = dummy_server().await;
+    let (addr2, handle2, mut rx2, _) = dummy_server().await;
+
+    let client = Client::with_endpoints([format!("ws://{addr1}"), format!("ws://{addr2}")])
+        .unwrap()
+        .with_health_check(Some(HealthCheckConfig {
+            method: "mock_rpc".to_string(),
+            interval_ms: 50,
+            timeout_ms: 50,
+        }));
+
+    // the first endpoint accepts requests but never answers
+    let stalled = tokio::spawn(async move {
+        let mut pending = Vec::new();
+        while let Some(req) = rx1.recv().await {
+            pending.push(req);
+        }
+    });
+    let healthy = tokio::spawn(async move {
+        while let Some(req) = rx2.recv().await {
+            req.respond(json!(2));
+        }
+    });
+
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert_eq!(client.current_endpoint(), format!("ws://{addr2}"));
+    assert_eq!(client.request("mock_rpc", vec![]).await.unwrap(), json!(2));
+
+    handle1.stop().unwrap();
+    handle2.stop().unwrap();
+    stalled.abort();
+    healthy.abort();
+}
+
+#[tokio::test]
+async fn switch_endpoint_verifies_genesis_hash() {
+    async fn chain_server(
+        genesis: &'static str,
+        id: u32,
+    ) -> (
+        std::net::SocketAddr,
+        jsonrpsee::server::ServerHandle,
+        tokio::task::JoinHandle<()>,
+    ) {
+        let mut builder = TestServerBuilder::new();
+        let mut genesis_rx = builder.register_method("chain_getBlockHash");
+        let mut rpc_rx = builder.register_method("mock_rpc");
+        let (addr, handle) = builder.build().await;
+        let task = tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    Some(req) = genesis_rx.recv() => req.respond(json!(genesis)),
+                    Some(req) = rpc_rx.recv() => req.respond(json!(id)),
+                    else => break,
+                }
+            }
+        });
+        (addr, handle, task)
+    }
+
+    let (addr1, handle1, task1) = chain_server("0xaa", 1).await;
+    let (addr2, handle2, task2) = chain_server("0xaa", 2).await;
+    let (addr3, handle3, task3) = chain_server("0xbb", 3).await;
+
+    let client = Client::with_endpoints([format!("ws://{addr1}")]).unwrap();
+    assert_eq!(client.request("mock_rpc", vec![]).await.unwrap(), json!(1));
+
+    // another chain is refused
+    assert!(client.switch_endpoint(&format!("ws://{addr3}")).await.is_err());
+    assert_eq!(client.current_endpoint(), format!("ws://{addr1}"));
+
+    client.switch_endpoint(&format!("ws://{addr2}")).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(client.current_endpoint(), format!("ws://{addr2}"));
+    assert_eq!(
+        client.endpoints(),
+        vec![format!("ws://{addr1}"), format!("ws://{addr2}")]
+    );
+    assert_eq!(client.request("mock_rpc", vec![]).await.unwrap(), json!(2));
+
+    // the added endpoint can be removed once no longer in use, configured ones are kept
+    assert!(client.remove_endpoint(&format!("ws://{addr2}")).is_err());
+    assert!(client.remove_endpoint(&format!("ws://{addr1}")).is_err());
+    client.switch_endpoint(&format!("ws://{addr1}")).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    client.remove_endpoint(&format!("ws://{addr2}")).unwrap();
+    assert_eq!(client.endpoints(), vec![format!("ws://{addr1}")]);
+    assert_eq!(client.current_endpoint(), format!("ws://{addr1}"));
+    assert_eq!(client.request("mock_rpc", vec![]).await.unwrap(), json!(1));
+
+    // the configured genesis hash is used, the current endpoint is not asked
+    client.set_genesis_hash("0xbb".to_string());
+    assert!(client.switch_endpoint(&format!("ws://{addr2}")).await.is_err());
+    client.switch_endpoint(&format!("ws://{addr3}")).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(client.request("mock_rpc", vec![]).await.unwrap(), json!(3));
+
+    for handle in [handle1, handle2, handle3] {
+        handle.stop().unwrap();
+    }
+    for task in [task1, task2, task3] {
+        task.abort();
+    }
+}
+
+#[tokio::test]
+async fn skips_endpoints_of_other_chains() {
+    let mut builder = TestServerBuilder::new();
+    let mut other_rx = builder.register_method("chain_getBlockHash");
+    let (other_addr, other_handle) = builder.build().await;
+
+    let mut builder = TestServerBuilder::new();
+    let mut genesis_rx = builder.register_method("chain_getBlockHash");
+    let mut rpc_rx = builder.register_method("mock_rpc");
+    let (addr, handle) = builder.build().await;
+
+    let task = tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                Some(req) = other_rx.recv() => req.respond(json!("0xbb")),
+                Some(req) = genesis_rx.recv() => req.respond(json!("0xaa")),
+                Some(req) = rpc_rx.recv() => req.respond(json!(1)),
+                else => break,
+            }
+        }
+    });
+
+    let endpoints = [format!("ws://{other_addr}"), format!("ws://{addr}")];
+    let err = check_genesis_hash(&endpoints, "0xAA").await.unwrap_err();
+    assert!(err.to_string().contains(&endpoints[0]));
+    check_genesis_hash(&endpoints[1..], "0xAA").await.unwrap();
+
+    let client = Client::with_endpoints(endpoints.clone()).unwrap();
+    client.set_genesis_hash("0xAA".to_string());
+    assert_eq!(client.request("mock_rpc", vec![]).await.unwrap(), json!(1));
+    assert_eq!(client.current_endpoint(), endpoints[1]);
+
+    other_handle.stop().unwrap();
+    handle.stop().unwrap();
+    task.abort();
+}
+
+#[tokio::test]
+async fn status_redacts_endpoints() {
+    let (addr, handle, _rx, _) = dummy_server().await;
+
+    let endpoint = format!("ws://{addr}/secret-key");
+    let client = Client::with_endpoints([endpoint]).unwrap();
+
+    let status = client.status();
+    assert_eq!(status["current_endpoint"], json!(format!("ws://{addr}/***")));
+    assert_eq!(status["endpoints"], json!([format!("ws://{addr}/***")]));
+
+    handle.stop().unwrap();
+}
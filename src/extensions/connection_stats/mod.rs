@@ -0,0 +1,200 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use async_trait::async_trait;
+use futures::{future::BoxFuture, FutureExt};
+use jsonrpsee::{
+    core::JsonValue,
+    server::{middleware::rpc::RpcServiceT, types::Request},
+    MethodResponse,
+};
+use serde::Deserialize;
+
+use super::{Extension, ExtensionRegistry};
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ConnectionStatsConfig {
+    // register `admin_getConnectionStats` and `admin_listConnections` on the admin endpoint
+    #[serde(default)]
+    pub admin_methods: bool,
+}
+
+/// Calls of a connection, by method.
+#[derive(Debug)]
+struct Connection {
+    addr: SocketAddr,
+    calls: Mutex<HashMap<String, u64>>,
+}
+
+/// Counts the calls of each method per client connection, while the connection is open.
+#[derive(Debug)]
+pub struct ConnectionStats {
+    config: ConnectionStatsConfig,
+    next_id: AtomicU64,
+    connections: Mutex<BTreeMap<u64, Arc<Connection>>>,
+}
+
+/// Registration of an open connection, removed when the last clone is dropped.
+pub struct ConnectionHandle {
+    id: u64,
+    connection: Arc<Connection>,
+    stats: Arc<ConnectionStats>,
+}
+
+impl Drop for ConnectionHandle {
+    fn drop(&mut self) {
+        let mut connections = self.stats.connections.lock().unwrap_or_else(|e| e.into_inner());
+        connections.remove(&self.id);
+    }
+}
+
+impl ConnectionHandle {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn record(&self, method: &str) {
+        let mut calls = self.connection.calls.lock().unwrap_or_else(|e| e.into_inner());
+        *calls.entry(method.to_string()).or_default() += 1;
+    }
+}
+
+#[async_trait]
+impl Extension for ConnectionStats {
+    type Config = ConnectionStatsConfig;
+
+    async fn from_config(config: &Self::Config, _registry: &ExtensionRegistry) -> Result<Self, anyhow::Error> {
+        Ok(Self::new(config.clone()))
+    }
+}
+
+impl ConnectionStats {
+    pub fn new(config: ConnectionStatsConfig) -> Self {
+        Self {
+            config,
+            next_id: AtomicU64::new(1),
+            connections: Default::default(),
+        }
+    }
+
+    pub fn admin_methods(&self) -> bool {
+        self.config.admin_methods
+    }
+
+    /// Tracks a new connection until the returned handle is dropped.
+    pub fn register(self: &Arc<Self>, addr: SocketAddr) -> Arc<ConnectionHandle> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let connection = Arc::new(Connection {
+            addr,
+            calls: Default::default(),
+        });
+        let mut connections = self.connections.lock().unwrap_or_else(|e| e.into_inner());
+        connections.insert(id, connection.clone());
+        Arc::new(ConnectionHandle {
+            id,
+            connection,
+            stats: self.clone(),
+        })
+    }
+
+    /// Calls of each method made by the given connection, None if it is closed.
+    pub fn calls(&self, id: u64) -> Option<BTreeMap<String, u64>> {
+        let connections = self.connections.lock().unwrap_or_else(|e| e.into_inner());
+        let connection = connections.get(&id)?;
+        let calls = connection.calls.lock().unwrap_or_else(|e| e.into_inner());
+        Some(calls.iter().map(|(method, count)| (method.clone(), *count)).collect())
+    }
+
+    /// Open connections with their address and total number of calls.
+    pub fn list(&self) -> Vec<JsonValue> {
+        let connections = self.connections.lock().unwrap_or_else(|e| e.into_inner());
+        connections
+            .iter()
+            .map(|(id, connection)| {
+                let calls = connection.calls.lock().unwrap_or_else(|e| e.into_inner());
+                serde_json::json!({
+                    "id": id,
+                    "address": connection.addr.to_string(),
+                    "calls": calls.values().sum::<u64>(),
+                })
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone)]
+pub struct ConnectionStatsLayer {
+    handle: Arc<ConnectionHandle>,
+}
+
+impl ConnectionStatsLayer {
+    pub fn new(handle: Arc<ConnectionHandle>) -> Self {
+        Self { handle }
+    }
+}
+
+impl<S> tower::Layer<S> for ConnectionStatsLayer {
+    type Service = ConnectionStatsService<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        ConnectionStatsService {
+            service,
+            handle: self.handle.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ConnectionStatsService<S> {
+    service: S,
+    // kept alive by WebSocket connections after the HTTP upgrade
+    handle: Arc<ConnectionHandle>,
+}
+
+impl<'a, S> RpcServiceT<'a> for ConnectionStatsService<S>
+where
+    S: RpcServiceT<'a> + Send + Sync + Clone + 'static,
+{
+    type Future = BoxFuture<'a, MethodResponse>;
+
+    fn call(&self, req: Request<'a>) -> Self::Future {
+        self.handle.record(req.method_name());
+        self.service.call(req).boxed()
+    }
+}
+
+#[test]
+fn calls_are_counted_per_connection() {
+    let stats = Arc::new(ConnectionStats::new(ConnectionStatsConfig { admin_methods: true }));
+
+    let first = stats.register("127.0.0.1:1000".parse().unwrap());
+    let second = stats.register("127.0.0.1:2000".parse().unwrap());
+    first.record("chain_getBlockHash");
+    first.record("chain_getBlockHash");
+    first.record("system_health");
+    second.record("system_health");
+
+    assert_eq!(
+        stats.calls(first.id()).unwrap(),
+        BTreeMap::from([("chain_getBlockHash".to_string(), 2), ("system_health".to_string(), 1)])
+    );
+    assert_eq!(
+        stats.list(),
+        vec![
+            serde_json::json!({ "id": first.id(), "address": "127.0.0.1:1000", "calls": 3 }),
+            serde_json::json!({ "id": second.id(), "address": "127.0.0.1:2000", "calls": 1 }),
+        ]
+    );
+
+    // cleaned up when the connection closes
+    let id = first.id();
+    drop(first);
+    assert_eq!(stats.calls(id), None);
+    assert_eq!(stats.list().len(), 1);
+}
@@ -1,18 +1,135 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
 use async_trait::async_trait;
-use serde::Deserialize;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
 
 use super::{Extension, ExtensionRegistry};
 
-pub struct EventBus;
+/// Lifecycle events published by subway subsystems.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SubwayEvent {
+    EndpointUnhealthy { endpoint: String, reason: String },
+    Failover { from: String, to: String },
+    ReorgDetected { number: u64, reason: String },
+    ConfigReloaded,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct TimestampedEvent {
+    pub timestamp: DateTime<Utc>,
+    #[serde(flatten)]
+    pub event: SubwayEvent,
+}
+
+pub struct EventBus {
+    sender: broadcast::Sender<TimestampedEvent>,
+    dropped: Arc<AtomicU64>,
+}
 
 #[derive(Deserialize, Debug, Clone)]
-pub struct EventBusConfig;
+pub struct EventBusConfig {
+    // number of events buffered per receiver before the oldest are dropped
+    #[serde(default = "default_capacity")]
+    pub capacity: usize,
+}
+
+fn default_capacity() -> usize {
+    256
+}
+
+impl Default for EventBusConfig {
+    fn default() -> Self {
+        Self {
+            capacity: default_capacity(),
+        }
+    }
+}
 
 #[async_trait]
 impl Extension for EventBus {
     type Config = EventBusConfig;
 
-    async fn from_config(_config: &Self::Config, _registry: &ExtensionRegistry) -> Result<Self, anyhow::Error> {
-        Ok(EventBus)
+    async fn from_config(config: &Self::Config, _registry: &ExtensionRegistry) -> Result<Self, anyhow::Error> {
+        Ok(Self::new(config.capacity))
+    }
+}
+
+impl EventBus {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity.max(1));
+        Self {
+            sender,
+            dropped: Default::default(),
+        }
     }
+
+    /// Publishes an event to all receivers. Never blocks, slow receivers lose the oldest events.
+    pub fn publish(&self, event: SubwayEvent) {
+        tracing::info!("Event: {:?}", event);
+        let _ = self.sender.send(TimestampedEvent {
+            timestamp: Utc::now(),
+            event,
+        });
+    }
+
+    pub fn subscribe(&self) -> EventReceiver {
+        EventReceiver {
+            receiver: self.sender.subscribe(),
+            dropped: self.dropped.clone(),
+        }
+    }
+
+    /// Total number of events dropped because receivers were lagging.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+pub struct EventReceiver {
+    receiver: broadcast::Receiver<TimestampedEvent>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl EventReceiver {
+    /// Returns the next event, or None if the bus is gone.
+    pub async fn recv(&mut self) -> Option<TimestampedEvent> {
+        loop {
+            match self.receiver.recv().await {
+                Ok(event) => return Some(event),
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    self.dropped.fetch_add(n, Ordering::Relaxed);
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn lagging_receiver_counts_dropped_events() {
+    let bus = EventBus::new(2);
+    let mut rx = bus.subscribe();
+
+    bus.publish(SubwayEvent::ConfigReloaded);
+    bus.publish(SubwayEvent::Failover {
+        from: "a".into(),
+        to: "b".into(),
+    });
+    bus.publish(SubwayEvent::ConfigReloaded);
+
+    assert_eq!(
+        rx.recv().await.unwrap().event,
+        SubwayEvent::Failover {
+            from: "a".into(),
+            to: "b".into()
+        }
+    );
+    assert_eq!(rx.recv().await.unwrap().event, SubwayEvent::ConfigReloaded);
+    assert_eq!(bus.dropped(), 1);
 }
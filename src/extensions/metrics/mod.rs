@@ -0,0 +1,184 @@
+use std::{convert::Infallible, net::SocketAddr, str::FromStr, sync::OnceLock};
+
+use async_trait::async_trait;
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, StatusCode,
+};
+use prometheus::{Encoder, HistogramVec, IntCounterVec, TextEncoder};
+use serde::Deserialize;
+use tokio::task::JoinHandle;
+
+use super::{Extension, ExtensionRegistry};
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct MetricsConfig {
+    #[serde(default = "default_listen_address")]
+    pub listen_address: String,
+    pub port: u16,
+    #[serde(default = "default_path")]
+    pub path: String,
+}
+
+fn default_listen_address() -> String {
+    "0.0.0.0".to_string()
+}
+
+fn default_path() -> String {
+    "/metrics".to_string()
+}
+
+/// Calls served, labeled by method and `ok` or `error`.
+pub fn rpc_calls() -> &'static IntCounterVec {
+    static COUNTER: OnceLock<IntCounterVec> = OnceLock::new();
+    COUNTER.get_or_init(|| {
+        prometheus::register_int_counter_vec!("subway_rpc_calls_total", "RPC calls served", &["method", "status"])
+            .expect("Failed to register subway_rpc_calls_total")
+    })
+}
+
+/// Time to answer a call, labeled by method.
+pub fn rpc_call_duration() -> &'static HistogramVec {
+    static HISTOGRAM: OnceLock<HistogramVec> = OnceLock::new();
+    HISTOGRAM.get_or_init(|| {
+        prometheus::register_histogram_vec!(
+            "subway_rpc_call_duration_seconds",
+            "Time to answer an RPC call, including middlewares and upstream",
+            &["method"]
+        )
+        .expect("Failed to register subway_rpc_call_duration_seconds")
+    })
+}
+
+/// Cache lookups, labeled by method and `hit` or `miss`.
+pub fn cache_lookups() -> &'static IntCounterVec {
+    static COUNTER: OnceLock<IntCounterVec> = OnceLock::new();
+    COUNTER.get_or_init(|| {
+        prometheus::register_int_counter_vec!(
+            "subway_cache_lookups_total",
+            "Response cache lookups",
+            &["method", "result"]
+        )
+        .expect("Failed to register subway_cache_lookups_total")
+    })
+}
+
+/// Failed upstream calls, labeled by method and JSON-RPC error code.
+pub fn upstream_errors() -> &'static IntCounterVec {
+    static COUNTER: OnceLock<IntCounterVec> = OnceLock::new();
+    COUNTER.get_or_init(|| {
+        prometheus::register_int_counter_vec!(
+            "subway_upstream_errors_total",
+            "Upstream calls that returned an error or failed",
+            &["method", "code"]
+        )
+        .expect("Failed to register subway_upstream_errors_total")
+    })
+}
+
+/// Failures while forwarding subscription notifications, labeled by subscription and `sink` (the client
+/// went away) or `upstream` (the upstream subscription failed or ended).
+pub fn subscription_errors() -> &'static IntCounterVec {
+    static COUNTER: OnceLock<IntCounterVec> = OnceLock::new();
+    COUNTER.get_or_init(|| {
+        prometheus::register_int_counter_vec!(
+            "subway_subscription_errors_total",
+            "Subscription forwarding failures by failing side",
+            &["subscription", "side"]
+        )
+        .expect("Failed to register subway_subscription_errors_total")
+    })
+}
+
+/// Serves all registered metrics in the Prometheus text format.
+pub struct Metrics {
+    addr: SocketAddr,
+    server_task: JoinHandle<()>,
+}
+
+impl Drop for Metrics {
+    fn drop(&mut self) {
+        self.server_task.abort();
+    }
+}
+
+#[async_trait]
+impl Extension for Metrics {
+    type Config = MetricsConfig;
+
+    async fn from_config(config: &Self::Config, _registry: &ExtensionRegistry) -> Result<Self, anyhow::Error> {
+        Self::serve(config.clone())
+    }
+}
+
+impl Metrics {
+    pub fn serve(config: MetricsConfig) -> Result<Self, anyhow::Error> {
+        let ip_addr = std::net::IpAddr::from_str(&config.listen_address)?;
+        let listener = std::net::TcpListener::bind(SocketAddr::new(ip_addr, config.port))?;
+        let addr = listener.local_addr()?;
+
+        let path = config.path;
+        let make_service = make_service_fn(move |_| {
+            let path = path.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |req| metrics_response(req, path.clone()))) }
+        });
+        let server = hyper::Server::from_tcp(listener)?.serve(make_service);
+
+        tracing::info!("Metrics served at http://{addr}");
+        let server_task = tokio::spawn(async move {
+            if let Err(e) = server.await {
+                tracing::error!("Metrics server failed: {e}");
+            }
+        });
+
+        Ok(Self { addr, server_task })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+async fn metrics_response(req: Request<Body>, path: String) -> Result<Response<Body>, Infallible> {
+    if req.method() != Method::GET || req.uri().path() != path {
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = StatusCode::NOT_FOUND;
+        return Ok(response);
+    }
+
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        tracing::error!("Failed to encode metrics: {e}");
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+        return Ok(response);
+    }
+
+    Ok(Response::builder()
+        .header(hyper::header::CONTENT_TYPE, encoder.format_type())
+        .body(Body::from(buffer))
+        .expect("valid response"))
+}
+
+#[tokio::test]
+async fn serves_metrics() {
+    let metrics = Metrics::serve(MetricsConfig {
+        listen_address: "127.0.0.1".to_string(),
+        port: 0,
+        path: "/metrics".to_string(),
+    })
+    .unwrap();
+    rpc_calls().with_label_values(&["system_health", "ok"]).inc();
+
+    let body = reqwest::get(format!("http://{}/metrics", metrics.addr()))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(body.contains("subway_rpc_calls_total{method=\"system_health\",status=\"ok\"}"));
+
+    let res = reqwest::get(format!("http://{}/other", metrics.addr())).await.unwrap();
+    assert_eq!(res.status(), 404);
+}
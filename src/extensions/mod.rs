@@ -8,13 +8,23 @@ use tokio::sync::RwLock;
 
 use crate::utils::{TypeRegistry, TypeRegistryRef};
 
+pub mod access_log;
+pub mod admin;
+pub mod alerting;
 pub mod api;
+pub mod archive;
+pub mod auth;
 pub mod cache;
 pub mod client;
+pub mod connection_stats;
 pub mod event_bus;
 pub mod merge_subscription;
+pub mod metrics;
 pub mod rate_limit;
+pub mod read_only;
+pub mod rebalance;
 pub mod server;
+pub mod subscription_stats;
 pub mod telemetry;
 
 #[async_trait]
@@ -114,7 +124,12 @@ macro_rules! define_all_extensions {
 
         impl ExtensionsConfig {
             pub async fn create_registry(self) -> Result<TypeRegistryRef, anyhow::Error> {
-                let reg = Arc::new(RwLock::new(TypeRegistry::new()));
+                self.create_registry_with(TypeRegistry::new()).await
+            }
+
+            /// Creates the configured extensions missing from `registry`, the given ones are used instead.
+            pub async fn create_registry_with(self, registry: TypeRegistry) -> Result<TypeRegistryRef, anyhow::Error> {
+                let reg = Arc::new(RwLock::new(registry));
                 let ext_reg = ExtensionRegistry::new(reg.clone(), Arc::new(self));
 
                 // ensure all the extensions are created
@@ -138,4 +153,14 @@ define_all_extensions! {
     server: server::SubwayServerBuilder,
     event_bus: event_bus::EventBus,
     rate_limit: rate_limit::RateLimitBuilder,
+    access_log: access_log::AccessLog,
+    read_only: read_only::ReadOnly,
+    rebalance: rebalance::Rebalance,
+    subscription_stats: subscription_stats::SubscriptionStats,
+    alerting: alerting::Alerting,
+    connection_stats: connection_stats::ConnectionStats,
+    metrics: metrics::Metrics,
+    admin: admin::Admin,
+    archive: archive::Archive,
+    auth: auth::Auth,
 }
@@ -1,6 +1,9 @@
 use crate::{extensions::rate_limit::MethodWeights, utils::errors};
 use futures::{future::BoxFuture, FutureExt};
-use governor::{DefaultDirectRateLimiter, Jitter, RateLimiter};
+use governor::{
+    clock::{Clock, DefaultClock},
+    DefaultDirectRateLimiter, Jitter, RateLimiter,
+};
 use jsonrpsee::{
     server::{middleware::rpc::RpcServiceT, types::Request},
     MethodResponse,
@@ -13,6 +16,7 @@ pub struct ConnectionRateLimitLayer {
     period: Duration,
     jitter: Jitter,
     method_weights: MethodWeights,
+    reject: bool,
 }
 
 impl ConnectionRateLimitLayer {
@@ -22,21 +26,33 @@ impl ConnectionRateLimitLayer {
             period,
             jitter,
             method_weights,
+            reject: false,
         }
     }
+
+    /// Rejects calls over the limit with a retry hint instead of delaying them.
+    pub fn rejecting(mut self) -> Self {
+        self.reject = true;
+        self
+    }
 }
 
 impl<S> tower::Layer<S> for ConnectionRateLimitLayer {
     type Service = ConnectionRateLimit<S>;
 
     fn layer(&self, service: S) -> Self::Service {
-        ConnectionRateLimit::new(
+        let service = ConnectionRateLimit::new(
             service,
             self.burst,
             self.period,
             self.jitter,
             self.method_weights.clone(),
-        )
+        );
+        if self.reject {
+            service.rejecting()
+        } else {
+            service
+        }
     }
 }
 
@@ -46,6 +62,7 @@ pub struct ConnectionRateLimit<S> {
     limiter: Arc<DefaultDirectRateLimiter>,
     jitter: Jitter,
     method_weights: MethodWeights,
+    reject: bool,
 }
 
 impl<S> ConnectionRateLimit<S> {
@@ -57,8 +74,14 @@ impl<S> ConnectionRateLimit<S> {
             limiter,
             jitter,
             method_weights,
+            reject: false,
         }
     }
+
+    pub fn rejecting(mut self) -> Self {
+        self.reject = true;
+        self
+    }
 }
 
 impl<'a, S> RpcServiceT<'a> for ConnectionRateLimit<S>
@@ -72,10 +95,20 @@ where
         let service = self.service.clone();
         let limiter = self.limiter.clone();
         let weight = self.method_weights.get(req.method_name());
+        let reject = self.reject;
 
         async move {
             if let Some(n) = NonZeroU32::new(weight) {
-                if limiter.until_n_ready_with_jitter(n, jitter).await.is_err() {
+                if reject {
+                    match limiter.check_n(n) {
+                        Ok(Ok(())) => {}
+                        Ok(Err(not_until)) => {
+                            let retry_after = not_until.wait_time_from(DefaultClock::default().now());
+                            return MethodResponse::error(req.id, errors::rate_limited(retry_after));
+                        }
+                        Err(_) => return MethodResponse::error(req.id, errors::failed("rate limit exceeded")),
+                    }
+                } else if limiter.until_n_ready_with_jitter(n, jitter).await.is_err() {
                     return MethodResponse::error(req.id, errors::failed("rate limit exceeded"));
                 }
             }
@@ -132,4 +165,30 @@ mod tests {
         // should take between 800..900 millis. each 100ms period handles 10 calls
         assert!(duration > 800 && duration < 900);
     }
+
+    #[tokio::test]
+    async fn rejecting_returns_retry_hint() {
+        let service = ConnectionRateLimit::new(
+            MockService,
+            NonZeroU32::new(2).unwrap(),
+            Duration::from_millis(100),
+            Jitter::up_to(Duration::from_millis(10)),
+            Default::default(),
+        )
+        .rejecting();
+
+        let call = |id| service.call(Request::new("test".into(), None, Id::Number(id)));
+        assert!(call(1).await.is_success());
+        assert!(call(2).await.is_success());
+
+        let res = call(3).await;
+        assert!(!res.is_success());
+        let res: serde_json::Value = serde_json::from_str(&res.result).unwrap();
+        assert_eq!(res["error"]["message"], "rate limit exceeded");
+        let retry_after_ms = res["error"]["data"]["retry_after_ms"].as_u64().unwrap();
+        assert!(retry_after_ms <= 50);
+
+        tokio::time::sleep(Duration::from_millis(retry_after_ms + 1)).await;
+        assert!(call(4).await.is_success());
+    }
 }
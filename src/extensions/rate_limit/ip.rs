@@ -1,6 +1,9 @@
 use crate::{extensions::rate_limit::MethodWeights, utils::errors};
 use futures::{future::BoxFuture, FutureExt};
-use governor::{DefaultKeyedRateLimiter, Jitter};
+use governor::{
+    clock::{Clock, DefaultClock},
+    DefaultKeyedRateLimiter, Jitter,
+};
 use jsonrpsee::{
     server::{middleware::rpc::RpcServiceT, types::Request},
     MethodResponse,
@@ -13,6 +16,7 @@ pub struct IpRateLimitLayer {
     limiter: Arc<DefaultKeyedRateLimiter<String>>,
     jitter: Jitter,
     method_weights: MethodWeights,
+    reject: bool,
 }
 
 impl IpRateLimitLayer {
@@ -27,21 +31,33 @@ impl IpRateLimitLayer {
             limiter,
             jitter,
             method_weights,
+            reject: false,
         }
     }
+
+    /// Rejects calls over the limit with a retry hint instead of delaying them.
+    pub fn rejecting(mut self) -> Self {
+        self.reject = true;
+        self
+    }
 }
 
 impl<S> tower::Layer<S> for IpRateLimitLayer {
     type Service = IpRateLimit<S>;
 
     fn layer(&self, service: S) -> Self::Service {
-        IpRateLimit::new(
+        let service = IpRateLimit::new(
             service,
             self.ip_addr.clone(),
             self.limiter.clone(),
             self.jitter,
             self.method_weights.clone(),
-        )
+        );
+        if self.reject {
+            service.rejecting()
+        } else {
+            service
+        }
     }
 }
 
@@ -52,6 +68,7 @@ pub struct IpRateLimit<S> {
     limiter: Arc<DefaultKeyedRateLimiter<String>>,
     jitter: Jitter,
     method_weights: MethodWeights,
+    reject: bool,
 }
 
 impl<S> IpRateLimit<S> {
@@ -68,8 +85,14 @@ impl<S> IpRateLimit<S> {
             limiter,
             jitter,
             method_weights,
+            reject: false,
         }
     }
+
+    pub fn rejecting(mut self) -> Self {
+        self.reject = true;
+        self
+    }
 }
 
 impl<'a, S> RpcServiceT<'a> for IpRateLimit<S>
@@ -84,9 +107,19 @@ where
         let service = self.service.clone();
         let limiter = self.limiter.clone();
         let weight = self.method_weights.get(req.method_name());
+        let reject = self.reject;
         async move {
             if let Some(n) = NonZeroU32::new(weight) {
-                if limiter
+                if reject {
+                    match limiter.check_key_n(&ip_addr, n) {
+                        Ok(Ok(())) => {}
+                        Ok(Err(not_until)) => {
+                            let retry_after = not_until.wait_time_from(DefaultClock::default().now());
+                            return MethodResponse::error(req.id, errors::rate_limited(retry_after));
+                        }
+                        Err(_) => return MethodResponse::error(req.id, errors::failed("rate limit exceeded")),
+                    }
+                } else if limiter
                     .until_key_n_ready_with_jitter(&ip_addr, n, jitter)
                     .await
                     .is_err()
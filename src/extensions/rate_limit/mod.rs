@@ -1,5 +1,6 @@
 use governor::{DefaultKeyedRateLimiter, Jitter, Quota, RateLimiter};
-use serde::Deserialize;
+use jsonrpsee::core::JsonValue;
+use serde::{Deserialize, Serialize};
 use std::num::NonZeroU32;
 use std::{sync::Arc, time::Duration};
 
@@ -15,7 +16,7 @@ pub use ip::{IpRateLimit, IpRateLimitLayer};
 pub use weight::MethodWeights;
 pub use xff::XFF;
 
-#[derive(Deserialize, Debug, Clone, Default)]
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
 pub struct RateLimitConfig {
     pub ip: Option<Rule>,
     pub connection: Option<Rule>,
@@ -23,7 +24,7 @@ pub struct RateLimitConfig {
     pub use_xff: bool,
 }
 
-#[derive(Deserialize, Debug, Clone, Default)]
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
 pub struct Rule {
     // burst is the maximum number of requests that can be made in a period
     pub burst: u32,
@@ -35,6 +36,9 @@ pub struct Rule {
     // e.g. if jitter_up_to_millis is 1000, then additional delay of random(0, 1000) milliseconds will be added
     #[serde(default = "default_jitter_up_to_millis")]
     pub jitter_up_to_millis: u64,
+    // reject calls over the limit right away with a `retry_after_ms` hint instead of delaying them
+    #[serde(default)]
+    pub reject: bool,
 }
 
 fn default_period_secs() -> u64 {
@@ -95,19 +99,26 @@ impl RateLimitBuilder {
             let burst = NonZeroU32::new(rule.burst).unwrap();
             let period = Duration::from_secs(rule.period_secs);
             let jitter = Jitter::up_to(Duration::from_millis(rule.jitter_up_to_millis));
-            Some(ConnectionRateLimitLayer::new(burst, period, jitter, method_weights))
+            let layer = ConnectionRateLimitLayer::new(burst, period, jitter, method_weights);
+            Some(if rule.reject { layer.rejecting() } else { layer })
         } else {
             None
         }
     }
     pub fn ip_limit(&self, remote_ip: String, method_weights: MethodWeights) -> Option<IpRateLimitLayer> {
+        let reject = self.config.ip.as_ref().map(|rule| rule.reject).unwrap_or_default();
         self.ip_limiter.as_ref().map(|ip_limiter| {
-            IpRateLimitLayer::new(
+            let layer = IpRateLimitLayer::new(
                 remote_ip,
                 ip_limiter.clone(),
                 self.ip_jitter.unwrap_or_default(),
                 method_weights,
-            )
+            );
+            if reject {
+                layer.rejecting()
+            } else {
+                layer
+            }
         })
     }
 
@@ -115,6 +126,19 @@ impl RateLimitBuilder {
     pub fn use_xff(&self) -> bool {
         self.config.use_xff
     }
+
+    /// Effective per-ip and per-connection rules.
+    pub fn config(&self) -> &RateLimitConfig {
+        &self.config
+    }
+
+    pub fn limits(&self) -> JsonValue {
+        serde_json::json!({
+            "ip": self.config.ip,
+            "connection": self.config.connection,
+            "use_xff": self.config.use_xff,
+        })
+    }
 }
 
 pub fn build_quota(burst: NonZeroU32, period: Duration) -> Quota {
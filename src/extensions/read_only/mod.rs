@@ -0,0 +1,89 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use async_trait::async_trait;
+use serde::Deserialize;
+
+use super::{Extension, ExtensionRegistry};
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ReadOnlyConfig {
+    // start in read-only mode
+    #[serde(default)]
+    pub enabled: bool,
+    // state-mutating methods, a trailing `*` matches any suffix e.g. `author_*`
+    #[serde(default = "default_methods")]
+    pub methods: Vec<String>,
+    // register `admin_setReadOnly` on the admin endpoint to toggle the mode at runtime
+    #[serde(default)]
+    pub admin_toggle: bool,
+}
+
+fn default_methods() -> Vec<String> {
+    vec!["author_*".to_string()]
+}
+
+pub struct ReadOnly {
+    config: ReadOnlyConfig,
+    enabled: AtomicBool,
+}
+
+#[async_trait]
+impl Extension for ReadOnly {
+    type Config = ReadOnlyConfig;
+
+    async fn from_config(config: &Self::Config, _registry: &ExtensionRegistry) -> Result<Self, anyhow::Error> {
+        Ok(Self::new(config.clone()))
+    }
+}
+
+impl ReadOnly {
+    pub fn new(config: ReadOnlyConfig) -> Self {
+        Self {
+            enabled: AtomicBool::new(config.enabled),
+            config,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        if self.enabled.swap(enabled, Ordering::Relaxed) != enabled {
+            tracing::info!("Read-only mode {}", if enabled { "enabled" } else { "disabled" });
+        }
+    }
+
+    pub fn admin_toggle(&self) -> bool {
+        self.config.admin_toggle
+    }
+
+    /// Whether the method mutates state and is rejected in read-only mode.
+    pub fn is_write_method(&self, method: &str) -> bool {
+        self.config
+            .methods
+            .iter()
+            .any(|pattern| match pattern.strip_suffix('*') {
+                Some(prefix) => method.starts_with(prefix),
+                None => method == pattern,
+            })
+    }
+}
+
+#[test]
+fn is_write_method_works() {
+    let read_only = ReadOnly::new(ReadOnlyConfig {
+        enabled: false,
+        methods: vec!["author_*".to_string(), "eth_sendRawTransaction".to_string()],
+        admin_toggle: false,
+    });
+
+    assert!(read_only.is_write_method("author_submitExtrinsic"));
+    assert!(read_only.is_write_method("eth_sendRawTransaction"));
+    assert!(!read_only.is_write_method("eth_sendRawTransactions"));
+    assert!(!read_only.is_write_method("chain_getBlock"));
+
+    assert!(!read_only.is_enabled());
+    read_only.set_enabled(true);
+    assert!(read_only.is_enabled());
+}
@@ -0,0 +1,176 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use rand::seq::IteratorRandom;
+use serde::Deserialize;
+use tokio::sync::oneshot;
+
+use super::{Extension, ExtensionRegistry};
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct RebalanceConfig {
+    // share of the active subscriptions asked to reconnect by each rebalance
+    #[serde(default = "default_fraction")]
+    pub fraction: f64,
+    // hints are spread evenly over this window to avoid a reconnect storm
+    #[serde(default = "default_window_seconds")]
+    pub window_seconds: u64,
+    // register `admin_rebalance` on the admin endpoint to start a rebalance at runtime, e.g. from an autoscaler hook
+    #[serde(default)]
+    pub admin_method: bool,
+}
+
+fn default_fraction() -> f64 {
+    0.1
+}
+
+fn default_window_seconds() -> u64 {
+    60
+}
+
+/// Error a subscription is closed with by a rebalance, so clients know to subscribe again.
+pub const RECONNECT_HINT: &str = "subway: reconnect";
+
+/// Asks a share of the long-lived subscriptions to reconnect, so a load balancer can move them to new instances.
+pub struct Rebalance {
+    config: RebalanceConfig,
+    next_id: AtomicU64,
+    subscriptions: Mutex<HashMap<u64, oneshot::Sender<()>>>,
+    hints_sent: AtomicU64,
+}
+
+/// Registration of an active subscription, removed on drop.
+pub struct RebalanceHandle {
+    id: u64,
+    rebalance: Arc<Rebalance>,
+    pub hint: oneshot::Receiver<()>,
+}
+
+impl Drop for RebalanceHandle {
+    fn drop(&mut self) {
+        let mut subscriptions = self.rebalance.subscriptions.lock().unwrap_or_else(|e| e.into_inner());
+        subscriptions.remove(&self.id);
+    }
+}
+
+#[async_trait]
+impl Extension for Rebalance {
+    type Config = RebalanceConfig;
+
+    async fn from_config(config: &Self::Config, _registry: &ExtensionRegistry) -> Result<Self, anyhow::Error> {
+        if !(0.0..=1.0).contains(&config.fraction) {
+            anyhow::bail!("rebalance fraction must be between 0 and 1, got {}", config.fraction);
+        }
+        Ok(Self::new(config.clone()))
+    }
+}
+
+impl Rebalance {
+    pub fn new(config: RebalanceConfig) -> Self {
+        Self {
+            config,
+            next_id: AtomicU64::new(0),
+            subscriptions: Default::default(),
+            hints_sent: AtomicU64::new(0),
+        }
+    }
+
+    pub fn register(self: &Arc<Self>) -> RebalanceHandle {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, hint) = oneshot::channel();
+        let mut subscriptions = self.subscriptions.lock().unwrap_or_else(|e| e.into_inner());
+        subscriptions.insert(id, tx);
+        RebalanceHandle {
+            id,
+            rebalance: self.clone(),
+            hint,
+        }
+    }
+
+    pub fn admin_method(&self) -> bool {
+        self.config.admin_method
+    }
+
+    pub fn active(&self) -> usize {
+        let subscriptions = self.subscriptions.lock().unwrap_or_else(|e| e.into_inner());
+        subscriptions.len()
+    }
+
+    /// Total number of reconnect hints delivered.
+    pub fn hints_sent(&self) -> u64 {
+        self.hints_sent.load(Ordering::Relaxed)
+    }
+
+    /// Picks the configured share of active subscriptions at random and hints them over the window.
+    /// Returns the number of subscriptions targeted.
+    pub fn start(self: &Arc<Self>) -> usize {
+        let targets = {
+            let subscriptions = self.subscriptions.lock().unwrap_or_else(|e| e.into_inner());
+            let count = (subscriptions.len() as f64 * self.config.fraction).ceil() as usize;
+            subscriptions
+                .keys()
+                .copied()
+                .choose_multiple(&mut rand::thread_rng(), count)
+        };
+
+        let count = targets.len();
+        if count == 0 {
+            return 0;
+        }
+
+        tracing::info!("Rebalancing {count} subscriptions over {}s", self.config.window_seconds);
+
+        let interval = Duration::from_secs(self.config.window_seconds) / count as u32;
+        let this = self.clone();
+        tokio::spawn(async move {
+            for (i, id) in targets.into_iter().enumerate() {
+                if i > 0 {
+                    tokio::time::sleep(interval).await;
+                }
+                let tx = {
+                    let mut subscriptions = this.subscriptions.lock().unwrap_or_else(|e| e.into_inner());
+                    subscriptions.remove(&id)
+                };
+                // subscription may have ended in the meantime
+                if let Some(tx) = tx {
+                    if tx.send(()).is_ok() {
+                        this.hints_sent.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            }
+        });
+
+        count
+    }
+}
+
+#[tokio::test]
+async fn rebalance_hints_fraction_of_subscriptions() {
+    let rebalance = Arc::new(Rebalance::new(RebalanceConfig {
+        fraction: 0.5,
+        window_seconds: 0,
+        admin_method: false,
+    }));
+
+    let mut handles = (0..4).map(|_| rebalance.register()).collect::<Vec<_>>();
+    assert_eq!(rebalance.active(), 4);
+
+    assert_eq!(rebalance.start(), 2);
+    tokio::time::sleep(Duration::from_millis(10)).await;
+
+    let hinted = handles.iter_mut().filter(|h| h.hint.try_recv().is_ok()).count();
+    assert_eq!(hinted, 2);
+    assert_eq!(rebalance.hints_sent(), 2);
+    assert_eq!(rebalance.active(), 2);
+
+    drop(handles);
+    assert_eq!(rebalance.active(), 0);
+    assert_eq!(rebalance.start(), 0);
+}
@@ -0,0 +1,66 @@
+use futures::{future::BoxFuture, FutureExt};
+use jsonrpsee::{
+    server::{middleware::rpc::RpcServiceT, types::Request},
+    MethodResponse,
+};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+use crate::utils::errors;
+
+/// Limits the number of concurrent calls served by one rpc service instance.
+/// A service instance is created per HTTP request (i.e. per batch) or per WebSocket connection.
+#[derive(Clone)]
+pub struct ConcurrencyLimitLayer {
+    limit: usize,
+}
+
+impl ConcurrencyLimitLayer {
+    pub fn new(limit: usize) -> Self {
+        Self { limit }
+    }
+}
+
+impl<S> tower::Layer<S> for ConcurrencyLimitLayer {
+    type Service = ConcurrencyLimit<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        ConcurrencyLimit::new(service, self.limit)
+    }
+}
+
+#[derive(Clone)]
+pub struct ConcurrencyLimit<S> {
+    service: S,
+    semaphore: Arc<Semaphore>,
+}
+
+impl<S> ConcurrencyLimit<S> {
+    pub fn new(service: S, limit: usize) -> Self {
+        Self {
+            service,
+            semaphore: Arc::new(Semaphore::new(limit.max(1))),
+        }
+    }
+}
+
+impl<'a, S> RpcServiceT<'a> for ConcurrencyLimit<S>
+where
+    S: RpcServiceT<'a> + Send + Sync + Clone + 'static,
+{
+    type Future = BoxFuture<'a, MethodResponse>;
+
+    fn call(&self, req: Request<'a>) -> Self::Future {
+        let service = self.service.clone();
+        let semaphore = self.semaphore.clone();
+
+        async move {
+            let _permit = match semaphore.acquire().await {
+                Ok(permit) => permit,
+                Err(e) => return MethodResponse::error(req.id, errors::internal_error(e)),
+            };
+            service.call(req).await
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,222 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock, Weak,
+    },
+    time::Duration,
+};
+
+use futures::{future::BoxFuture, FutureExt};
+use jsonrpsee::{
+    core::server::MethodCallback,
+    server::{middleware::rpc::RpcServiceT, stop_channel, types::Request, StopHandle},
+    MethodResponse, Methods,
+};
+use prometheus::IntCounterVec;
+use tokio::time::Instant;
+
+tokio::task_local! {
+    /// Activity of the connection being served, when connection limits are enabled.
+    pub static CONNECTION_ACTIVITY: Arc<ConnectionActivity>;
+}
+
+/// Connection limit events, e.g. `idle_disconnect` or `subscription_rejected`.
+pub fn limit_counter() -> &'static IntCounterVec {
+    static COUNTER: OnceLock<IntCounterVec> = OnceLock::new();
+    COUNTER.get_or_init(|| {
+        prometheus::register_int_counter_vec!(
+            "subway_connection_limit_events_total",
+            "Calls rejected and connections closed by the connection limits",
+            &["event"]
+        )
+        .expect("Failed to register subway_connection_limit_events_total")
+    })
+}
+
+/// Calls and open subscriptions of a client connection.
+#[derive(Debug)]
+pub struct ConnectionActivity {
+    last_call: Mutex<Instant>,
+    subscriptions: AtomicUsize,
+}
+
+impl Default for ConnectionActivity {
+    fn default() -> Self {
+        Self {
+            last_call: Mutex::new(Instant::now()),
+            subscriptions: Default::default(),
+        }
+    }
+}
+
+impl ConnectionActivity {
+    fn touch(&self) {
+        *self.last_call.lock().unwrap_or_else(|e| e.into_inner()) = Instant::now();
+    }
+
+    pub fn subscriptions(&self) -> usize {
+        self.subscriptions.load(Ordering::Relaxed)
+    }
+
+    /// How long the connection went without calls, zero while it has subscriptions.
+    pub fn idle_for(&self) -> Duration {
+        if self.subscriptions() > 0 {
+            return Duration::ZERO;
+        }
+        self.last_call.lock().unwrap_or_else(|e| e.into_inner()).elapsed()
+    }
+}
+
+/// Counts a subscription of the connection as open until dropped.
+///
+/// Kept by the task forwarding notifications to the subscription sink, so subscriptions closed by
+/// the server (max lifetime, slow subscriber, upstream gone) are released as well as unsubscribed ones.
+#[derive(Debug)]
+pub struct OpenSubscription(Arc<ConnectionActivity>);
+
+impl OpenSubscription {
+    pub fn new(activity: Arc<ConnectionActivity>) -> Self {
+        activity.subscriptions.fetch_add(1, Ordering::Relaxed);
+        Self(activity)
+    }
+}
+
+impl Drop for OpenSubscription {
+    fn drop(&mut self) {
+        self.0.subscriptions.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Returns a stop handle for the connection of `activity`, stopped with the server or once the
+/// connection is idle for `idle_timeout`. Gives up once the connection is closed.
+pub fn idle_stop_handle(server: StopHandle, activity: &Arc<ConnectionActivity>, idle_timeout: Duration) -> StopHandle {
+    let (stop_handle, connection_handle) = stop_channel();
+    let activity = Arc::downgrade(activity);
+
+    tokio::spawn(async move {
+        let server_stopped = server.shutdown();
+        tokio::pin!(server_stopped);
+        loop {
+            let Some(idle_for) = Weak::upgrade(&activity).map(|a| a.idle_for()) else {
+                break;
+            };
+            if idle_for >= idle_timeout {
+                tracing::debug!("Closing connection idle for {idle_for:?}");
+                limit_counter().with_label_values(&["idle_disconnect"]).inc();
+                let _ = connection_handle.stop();
+                break;
+            }
+            tokio::select! {
+                _ = &mut server_stopped => {
+                    let _ = connection_handle.stop();
+                    break;
+                }
+                _ = tokio::time::sleep(idle_timeout - idle_for) => {}
+            }
+        }
+    });
+
+    stop_handle
+}
+
+/// Tracks the activity of a connection and counts subscriptions rejected over `max_subscriptions`.
+#[derive(Clone)]
+pub struct ConnectionLimitsLayer {
+    methods: Methods,
+    activity: Arc<ConnectionActivity>,
+    max_subscriptions: Option<u32>,
+}
+
+impl ConnectionLimitsLayer {
+    pub fn new(methods: Methods, activity: Arc<ConnectionActivity>, max_subscriptions: Option<u32>) -> Self {
+        Self {
+            methods,
+            activity,
+            max_subscriptions,
+        }
+    }
+}
+
+impl<S> tower::Layer<S> for ConnectionLimitsLayer {
+    type Service = ConnectionLimits<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        ConnectionLimits {
+            service,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ConnectionLimits<S> {
+    service: S,
+    layer: ConnectionLimitsLayer,
+}
+
+impl<'a, S> RpcServiceT<'a> for ConnectionLimits<S>
+where
+    S: RpcServiceT<'a> + Send + Sync + Clone + 'static,
+{
+    type Future = BoxFuture<'a, MethodResponse>;
+
+    fn call(&self, req: Request<'a>) -> Self::Future {
+        let layer = self.layer.clone();
+        layer.activity.touch();
+
+        let callback = layer.methods.method(req.method_name());
+        let is_subscribe = matches!(callback, Some(MethodCallback::Subscription(_)));
+        let open = layer.activity.subscriptions();
+
+        // subscription handlers run when the call is made rather than when it is polled
+        let fut = CONNECTION_ACTIVITY.sync_scope(layer.activity.clone(), || self.service.call(req));
+        let fut = CONNECTION_ACTIVITY.scope(layer.activity.clone(), fut);
+
+        async move {
+            let response = fut.await;
+            if is_subscribe && !response.is_success() && layer.max_subscriptions.is_some_and(|max| open >= max as usize)
+            {
+                limit_counter().with_label_values(&["subscription_rejected"]).inc();
+            }
+            response
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn stops_idle_connections() {
+        let (server_stop, _server_handle) = stop_channel();
+        let activity = Arc::new(ConnectionActivity::default());
+        let stop = idle_stop_handle(server_stop, &activity, Duration::from_millis(50));
+
+        // busy with a subscription
+        let subscription = OpenSubscription::new(activity.clone());
+        tokio::time::sleep(Duration::from_millis(80)).await;
+        assert_eq!(activity.idle_for(), Duration::ZERO);
+
+        // closed by the server, e.g. for exceeding its lifetime
+        drop(subscription);
+        assert_eq!(activity.subscriptions(), 0);
+        activity.touch();
+        tokio::time::timeout(Duration::from_millis(200), stop.shutdown())
+            .await
+            .expect("idle connection should be stopped");
+    }
+
+    #[tokio::test]
+    async fn stops_with_server() {
+        let (server_stop, server_handle) = stop_channel();
+        let activity = Arc::new(ConnectionActivity::default());
+        let stop = idle_stop_handle(server_stop, &activity, Duration::from_secs(60));
+
+        server_handle.stop().unwrap();
+        tokio::time::timeout(Duration::from_millis(200), stop.shutdown())
+            .await
+            .expect("connection should be stopped with the server");
+    }
+}
@@ -0,0 +1,62 @@
+use futures::{future::BoxFuture, FutureExt};
+use jsonrpsee::{
+    server::{middleware::rpc::RpcServiceT, types::Request},
+    MethodResponse,
+};
+
+use crate::{middlewares::FeatureFlags, utils::errors};
+
+tokio::task_local! {
+    /// Feature flags of the HTTP request or WebSocket connection being served.
+    pub static FEATURE_FLAGS: FeatureFlags;
+}
+
+/// Makes the feature flags parsed from the request headers available to method handlers,
+/// or rejects every call if the header is invalid.
+#[derive(Clone)]
+pub struct FeatureFlagsLayer {
+    flags: Result<FeatureFlags, String>,
+}
+
+impl FeatureFlagsLayer {
+    pub fn new(flags: Result<FeatureFlags, String>) -> Self {
+        Self { flags }
+    }
+}
+
+impl<S> tower::Layer<S> for FeatureFlagsLayer {
+    type Service = FeatureFlagsService<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        FeatureFlagsService {
+            service,
+            flags: self.flags.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct FeatureFlagsService<S> {
+    service: S,
+    flags: Result<FeatureFlags, String>,
+}
+
+impl<'a, S> RpcServiceT<'a> for FeatureFlagsService<S>
+where
+    S: RpcServiceT<'a> + Send + Sync + Clone + 'static,
+{
+    type Future = BoxFuture<'a, MethodResponse>;
+
+    fn call(&self, req: Request<'a>) -> Self::Future {
+        let service = self.service.clone();
+        let flags = self.flags.clone();
+
+        async move {
+            match flags {
+                Ok(flags) => FEATURE_FLAGS.scope(flags, service.call(req)).await,
+                Err(e) => MethodResponse::error(req.id, errors::invalid_params(e)),
+            }
+        }
+        .boxed()
+    }
+}
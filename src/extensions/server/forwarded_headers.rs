@@ -0,0 +1,54 @@
+use futures::{future::BoxFuture, FutureExt};
+use jsonrpsee::{
+    server::{middleware::rpc::RpcServiceT, types::Request},
+    MethodResponse,
+};
+
+use crate::extensions::client::ForwardedHeaders;
+
+tokio::task_local! {
+    /// Forwarded headers of the HTTP request or WebSocket connection being served.
+    pub static FORWARDED_HEADERS: ForwardedHeaders;
+}
+
+/// Makes the headers to forward upstream available to method and subscription handlers.
+#[derive(Clone)]
+pub struct ForwardedHeadersLayer {
+    headers: ForwardedHeaders,
+}
+
+impl ForwardedHeadersLayer {
+    pub fn new(headers: ForwardedHeaders) -> Self {
+        Self { headers }
+    }
+}
+
+impl<S> tower::Layer<S> for ForwardedHeadersLayer {
+    type Service = ForwardedHeadersService<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        ForwardedHeadersService {
+            service,
+            headers: self.headers.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ForwardedHeadersService<S> {
+    service: S,
+    headers: ForwardedHeaders,
+}
+
+impl<'a, S> RpcServiceT<'a> for ForwardedHeadersService<S>
+where
+    S: RpcServiceT<'a> + Send + Sync + Clone + 'static,
+{
+    type Future = BoxFuture<'a, MethodResponse>;
+
+    fn call(&self, req: Request<'a>) -> Self::Future {
+        // subscription handlers run when the call is made rather than when it is polled
+        let fut = FORWARDED_HEADERS.sync_scope(self.headers.clone(), || self.service.call(req));
+        FORWARDED_HEADERS.scope(self.headers.clone(), fut).boxed()
+    }
+}
@@ -0,0 +1,141 @@
+//! Middleware answering liveness and readiness probes, e.g. of Kubernetes, without going through
+//! the JSON-RPC server.
+
+use std::{
+    error::Error,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use hyper::{header::CONTENT_TYPE, Body, Method, Request, Response, StatusCode};
+use jsonrpsee::core::JsonValue;
+use serde::Deserialize;
+use tower::{Layer, Service};
+
+use crate::extensions::client::Client;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct HealthConfig {
+    /// Answers 200 while the server is running.
+    #[serde(default = "default_liveness_path")]
+    pub liveness_path: String,
+    /// Answers 200 when upstream is connected and not syncing, 503 otherwise.
+    #[serde(default = "default_readiness_path")]
+    pub readiness_path: String,
+    /// How long to wait for upstream `system_health`.
+    #[serde(default = "default_readiness_timeout_ms")]
+    pub readiness_timeout_ms: u64,
+}
+
+fn default_liveness_path() -> String {
+    "/health".to_string()
+}
+
+fn default_readiness_path() -> String {
+    "/ready".to_string()
+}
+
+fn default_readiness_timeout_ms() -> u64 {
+    5000
+}
+
+#[derive(Clone)]
+pub struct HealthLayer {
+    config: HealthConfig,
+    client: Option<Arc<Client>>,
+}
+
+impl HealthLayer {
+    pub fn new(config: HealthConfig, client: Option<Arc<Client>>) -> Self {
+        Self { config, client }
+    }
+}
+
+impl<S> Layer<S> for HealthLayer {
+    type Service = Health<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Health {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Health<S> {
+    inner: S,
+    layer: HealthLayer,
+}
+
+/// Why upstream is not ready, None if it is.
+async fn not_ready_reason(client: Option<&Client>, timeout: Duration) -> Option<String> {
+    let Some(client) = client else {
+        return Some("no upstream client".to_string());
+    };
+    if !client.is_healthy() {
+        return Some("upstream not connected".to_string());
+    }
+
+    match tokio::time::timeout(timeout, client.request_internal("system_health", vec![])).await {
+        Ok(Ok(health)) if health["isSyncing"] == JsonValue::Bool(true) => Some("upstream is syncing".to_string()),
+        Ok(Ok(_)) => None,
+        Ok(Err(e)) => Some(format!("system_health failed: {}", e.message())),
+        Err(_) => Some("system_health timed out".to_string()),
+    }
+}
+
+fn json_response(status: StatusCode, body: JsonValue) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .expect("valid response")
+}
+
+impl<S> Service<Request<Body>> for Health<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>>,
+    S::Response: 'static,
+    S::Error: Into<Box<dyn Error + Send + Sync>> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = Box<dyn Error + Send + Sync + 'static>;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send + 'static>>;
+
+    #[inline]
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let path = req.uri().path();
+        let is_get = req.method() == Method::GET;
+
+        if is_get && path == self.layer.config.liveness_path {
+            return Box::pin(async { Ok(json_response(StatusCode::OK, serde_json::json!({ "status": "ok" }))) });
+        }
+
+        if is_get && path == self.layer.config.readiness_path {
+            let client = self.layer.client.clone();
+            let timeout = Duration::from_millis(self.layer.config.readiness_timeout_ms);
+            return Box::pin(async move {
+                let response = match not_ready_reason(client.as_deref(), timeout).await {
+                    None => json_response(StatusCode::OK, serde_json::json!({ "ready": true })),
+                    Some(reason) => json_response(
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        serde_json::json!({ "ready": false, "reason": reason }),
+                    ),
+                };
+                Ok(response)
+            });
+        }
+
+        let fut = self.inner.call(req);
+        Box::pin(async move { fut.await.map_err(Into::into) })
+    }
+}
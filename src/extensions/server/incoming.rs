@@ -0,0 +1,127 @@
+use std::{
+    future::Future,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use hyper::server::accept::Accept;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+
+/// Listener `accept` takes connections from.
+pub trait Listener: Send + 'static {
+    type Stream: Send + 'static;
+
+    fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<io::Result<(Self::Stream, SocketAddr)>>;
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<io::Result<(Self::Stream, SocketAddr)>> {
+        TcpListener::poll_accept(self, cx)
+    }
+}
+
+/// Connections with their handshake done, served by hyper instead of the plain listener.
+pub struct Incoming<C>(mpsc::Receiver<C>);
+
+impl<C> Accept for Incoming<C> {
+    type Conn = C;
+    type Error = io::Error;
+
+    fn poll_accept(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<io::Result<Self::Conn>>> {
+        self.0.poll_recv(cx).map(|conn| conn.map(Ok))
+    }
+}
+
+/// Accepts connections and runs `handshake` on each, e.g. reading its PROXY header, in its own task.
+/// Connections failing `handshake` or not done within `timeout` are dropped, `name` describes it in logs.
+/// Stops once the returned incoming connections are dropped.
+pub fn accept<L, C, H, F>(listener: L, name: &'static str, timeout: Duration, handshake: H) -> Incoming<C>
+where
+    L: Listener,
+    C: Send + 'static,
+    H: Fn(L::Stream, SocketAddr) -> F + Send + 'static,
+    F: Future<Output = io::Result<C>> + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel(128);
+
+    tokio::spawn(async move {
+        loop {
+            let (stream, addr) = tokio::select! {
+                res = std::future::poll_fn(|cx| listener.poll_accept(cx)) => match res {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        // e.g. too many open files, back off instead of spinning
+                        tracing::warn!("Failed to accept connection: {e}");
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        continue;
+                    }
+                },
+                _ = tx.closed() => break,
+            };
+
+            let tx = tx.clone();
+            let handshake = handshake(stream, addr);
+            tokio::spawn(async move {
+                match tokio::time::timeout(timeout, handshake).await {
+                    Ok(Ok(conn)) => {
+                        let _ = tx.send(conn).await;
+                    }
+                    Ok(Err(e)) => tracing::debug!("{name} with {addr} failed: {e}"),
+                    Err(_) => tracing::debug!("Timeout in {name} with {addr}"),
+                }
+            });
+        }
+    });
+
+    Incoming(rx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::StreamExt;
+
+    #[tokio::test]
+    async fn drops_failed_handshakes() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut incoming = futures::stream::poll_fn({
+            let mut incoming = accept(
+                listener,
+                "test handshake",
+                Duration::from_secs(1),
+                |stream, _| async move {
+                    let port = stream.peer_addr()?.port();
+                    if port % 2 == 0 {
+                        Ok(port)
+                    } else {
+                        Err(io::Error::new(io::ErrorKind::InvalidData, "odd port"))
+                    }
+                },
+            );
+            move |cx| Pin::new(&mut incoming).poll_accept(cx)
+        });
+
+        // only connections from even ports come through
+        let mut even = None;
+        let mut clients = Vec::new();
+        while even.is_none() {
+            let client = TcpStream::connect(addr).await.unwrap();
+            let port = client.local_addr().unwrap().port();
+            clients.push(client);
+            if port % 2 == 0 {
+                even = Some(port);
+            }
+        }
+        assert_eq!(incoming.next().await.unwrap().unwrap(), even.unwrap());
+    }
+}
@@ -4,27 +4,90 @@ use hyper::server::conn::AddrStream;
 use hyper::service::Service;
 use hyper::service::{make_service_fn, service_fn};
 use jsonrpsee::server::{
-    middleware::rpc::RpcServiceBuilder, stop_channel, RandomStringIdProvider, RpcModule, ServerBuilder, ServerHandle,
+    middleware::rpc::RpcServiceBuilder, stop_channel, BatchRequestConfig, RandomStringIdProvider, RpcModule,
+    ServerBuilder, ServerHandle,
 };
-use jsonrpsee::Methods;
+use jsonrpsee::{core::JsonValue, Methods};
 use serde::ser::StdError;
 use serde::Deserialize;
 use std::str::FromStr;
-use std::sync::Arc;
-use std::{future::Future, net::SocketAddr};
+use std::sync::{Arc, RwLock};
+use std::{future::Future, net::SocketAddr, time::Duration};
 use tower::ServiceBuilder;
 use tower_http::cors::{AllowOrigin, CorsLayer};
 
 use super::{Extension, ExtensionRegistry};
-use crate::extensions::rate_limit::{MethodWeights, RateLimitBuilder, XFF};
+use crate::{
+    extensions::{
+        access_log::{AccessLog, AccessLogFormat},
+        auth::Auth,
+        client::{Client, ForwardedHeaders},
+        connection_stats::{ConnectionStats, ConnectionStatsLayer},
+        rate_limit::{MethodWeights, RateLimitBuilder, XFF},
+    },
+    middlewares::FeatureFlags,
+    utils::errors,
+};
 
+mod concurrency_limit;
+mod connection_limits;
+mod feature_flags;
+mod forwarded_headers;
+mod health;
+mod incoming;
 mod proxy_get_request;
+mod proxy_protocol;
+mod tls;
+#[cfg(unix)]
+mod unix_socket;
+use concurrency_limit::ConcurrencyLimitLayer;
+use connection_limits::{ConnectionActivity, ConnectionLimitsLayer};
+pub use connection_limits::{OpenSubscription, CONNECTION_ACTIVITY};
+use feature_flags::FeatureFlagsLayer;
+pub use feature_flags::FEATURE_FLAGS;
+use forwarded_headers::ForwardedHeadersLayer;
+pub use forwarded_headers::FORWARDED_HEADERS;
+pub use health::HealthConfig;
+use health::HealthLayer;
 use proxy_get_request::{ProxyGetRequestLayer, ProxyGetRequestMethod};
+use proxy_protocol::ProxiedStream;
+pub use proxy_protocol::ProxyProtocol;
+pub use tls::TlsConfig;
+use tls::TlsConnection;
 
 pub struct SubwayServerBuilder {
     pub config: ServerConfig,
+    // downstream headers passed on to upstream, from the client config
+    header_forwarding: Vec<String>,
+    connection_stats: Option<Arc<ConnectionStats>>,
+    // checked by the readiness probe
+    client: Option<Arc<Client>>,
+    auth: Option<Arc<Auth>>,
+}
+
+/// Methods and their rate limit weights served to new requests, replaced when the config is reloaded.
+/// WebSocket connections keep the methods they were opened with.
+#[derive(Clone)]
+pub struct ServedMethods(Arc<RwLock<(Methods, MethodWeights)>>);
+
+impl ServedMethods {
+    pub fn new(module: RpcModule<()>, weights: MethodWeights) -> Self {
+        Self(Arc::new(RwLock::new((module.into(), weights))))
+    }
+
+    pub fn current(&self) -> (Methods, MethodWeights) {
+        self.0.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    pub fn replace(&self, module: RpcModule<()>, weights: MethodWeights) {
+        *self.0.write().unwrap_or_else(|e| e.into_inner()) = (module.into(), weights);
+    }
 }
 
+/// Method answered on HTTP GET `path`. Calls of the method, whether over GET or JSON-RPC, are sent to
+/// the current endpoint through the client's reserved internal slots, so probes keep working when
+/// upstream is saturated: they bypass `max_concurrent_requests`, the upstream queue, the circuit breaker
+/// and load balancing.
 #[derive(Deserialize, Debug, Clone)]
 pub struct HttpMethodsConfig {
     pub path: String,
@@ -47,6 +110,18 @@ impl<T> ItemOrList<T> {
     }
 }
 
+/// What to do with a configured method that the upstream does not expose in `rpc_methods`.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UnsupportedMethodPolicy {
+    // refuse to start
+    Fail,
+    // do not register the method
+    Skip,
+    // register anyway, upstream will return method not found
+    Register,
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct ServerConfig {
     pub port: u16,
@@ -58,21 +133,173 @@ pub struct ServerConfig {
     pub request_timeout_seconds: u64,
     #[serde(default)]
     pub cors: Option<ItemOrList<String>>,
+    /// When set, configured methods are checked against upstream `rpc_methods` at startup.
+    #[serde(default)]
+    pub unsupported_methods: Option<UnsupportedMethodPolicy>,
+    /// Batches with more calls than this are rejected before any call is executed. None means unlimited.
+    #[serde(default)]
+    pub max_batch_size: Option<u32>,
+    /// Maximum number of calls executed concurrently per WebSocket connection, and per HTTP request,
+    /// i.e. the calls of a batch. Calls of a WebSocket connection share the limit whether batched or not.
+    #[serde(default, alias = "max_batch_concurrency")]
+    pub max_concurrent_calls_per_connection: Option<usize>,
+    /// Line format of the access log, if the access_log extension is enabled.
+    #[serde(default)]
+    pub access_log_format: AccessLogFormat,
+    /// Number of retries when the port can not be bound yet, e.g. still held by a previous process.
+    #[serde(default = "default_bind_retry_attempts")]
+    pub bind_retry_attempts: u32,
+    #[serde(default = "default_bind_retry_delay_ms")]
+    pub bind_retry_delay_ms: u64,
+    /// Reject requests with unknown or not allowed flags in the `X-Subway-Features` header instead of ignoring them.
+    #[serde(default)]
+    pub strict_feature_flags: bool,
+    /// Flags of the `X-Subway-Features` header clients may set, others are ignored.
+    /// `no-cache` is not allowed by default since it lets any client send every call to upstream.
+    #[serde(default = "default_allowed_feature_flags")]
+    pub allowed_feature_flags: Vec<String>,
+    /// Name of a built-in method returning its params and the server time, bypassing middlewares and upstream.
+    /// Useful to check connectivity and round-trip latency, e.g. `debug_echo`.
+    #[serde(default)]
+    pub echo_method: Option<String>,
+    /// Timeout of a whole HTTP request, WebSocket calls only use `request_timeout_seconds`.
+    /// An HTTP request exceeding it gets a 504 response with a JSON-RPC error body.
+    #[serde(default)]
+    pub http_request_timeout_ms: Option<u64>,
+    /// Subscriptions are closed after this long, with a `subscription_expired` notification.
+    /// Can be overridden by `max_lifetime_secs` of a subscription.
+    #[serde(default)]
+    pub max_subscription_lifetime_secs: Option<u64>,
+    /// Read a PROXY protocol header (`v1` or `v2`) at the start of each connection and use the client
+    /// address it carries for rate limiting and access logs. Connections without a valid header are dropped.
+    #[serde(default)]
+    pub proxy_protocol: Option<ProxyProtocol>,
+    /// On SIGTERM or Ctrl-C, how long in-flight requests may take to complete before subway exits.
+    /// A second signal exits immediately.
+    #[serde(default = "default_graceful_shutdown_timeout_secs")]
+    pub graceful_shutdown_timeout_secs: u64,
+    /// Serve JSON-RPC over HTTP POST. Both transports share the port.
+    #[serde(default = "default_enabled")]
+    pub enable_http: bool,
+    /// Serve JSON-RPC over WebSocket, the only transport for subscriptions.
+    #[serde(default = "default_enabled")]
+    pub enable_ws: bool,
+    /// Answer liveness and readiness probes over HTTP GET, taking precedence over `http_methods` on the same path.
+    #[serde(default)]
+    pub health: Option<HealthConfig>,
+    /// Serve HTTPS and WSS with this certificate, instead of plain HTTP and WebSocket.
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    /// Close WebSocket connections without calls for this long. Connections with subscriptions are not idle.
+    #[serde(default)]
+    pub idle_timeout_secs: Option<u64>,
+    /// Reject subscriptions of a connection over this many open ones. jsonrpsee allows 1024 by default.
+    #[serde(default)]
+    pub max_subscriptions_per_connection: Option<u32>,
+    /// Also serve on a Unix socket at this path, e.g. for a sidecar on the same host, without TLS or PROXY
+    /// protocol. Its clients count as `127.0.0.1` for rate limits and access logs. Only on Unix.
+    #[serde(default)]
+    pub unix_socket_path: Option<String>,
+    /// Methods only served to requests with a valid API key or JWT, whatever `auth.default_policy` allows.
+    /// `*` at the end matches a prefix, e.g. `author_*`. Requires the `auth` extension.
+    #[serde(default)]
+    pub methods_require_auth: Vec<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            port: 9944,
+            listen_address: "0.0.0.0".to_string(),
+            max_connections: 2000,
+            http_methods: Vec::new(),
+            request_timeout_seconds: default_request_timeout_seconds(),
+            cors: None,
+            unsupported_methods: None,
+            max_batch_size: None,
+            max_concurrent_calls_per_connection: None,
+            access_log_format: Default::default(),
+            bind_retry_attempts: default_bind_retry_attempts(),
+            bind_retry_delay_ms: default_bind_retry_delay_ms(),
+            strict_feature_flags: false,
+            allowed_feature_flags: default_allowed_feature_flags(),
+            echo_method: None,
+            http_request_timeout_ms: None,
+            max_subscription_lifetime_secs: None,
+            proxy_protocol: None,
+            graceful_shutdown_timeout_secs: default_graceful_shutdown_timeout_secs(),
+            enable_http: default_enabled(),
+            enable_ws: default_enabled(),
+            health: None,
+            tls: None,
+            idle_timeout_secs: None,
+            max_subscriptions_per_connection: None,
+            unix_socket_path: None,
+            methods_require_auth: Vec::new(),
+        }
+    }
 }
 
 fn default_request_timeout_seconds() -> u64 {
     120
 }
 
+fn default_graceful_shutdown_timeout_secs() -> u64 {
+    30
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_bind_retry_attempts() -> u32 {
+    3
+}
+
+fn default_bind_retry_delay_ms() -> u64 {
+    1000
+}
+
+fn default_allowed_feature_flags() -> Vec<String> {
+    vec!["finalized".to_string()]
+}
+
 #[async_trait]
 impl Extension for SubwayServerBuilder {
     type Config = ServerConfig;
 
-    async fn from_config(config: &Self::Config, _registry: &ExtensionRegistry) -> Result<Self, anyhow::Error> {
-        Ok(Self::new(config.clone()))
+    async fn from_config(config: &Self::Config, registry: &ExtensionRegistry) -> Result<Self, anyhow::Error> {
+        let mut builder = Self::new(config.clone());
+        if let Some(client) = registry.get::<Client>().await {
+            builder.header_forwarding = client.header_forwarding().to_vec();
+            builder.client = Some(client);
+        }
+        builder.connection_stats = registry.get::<ConnectionStats>().await;
+        builder.auth = registry.get::<Auth>().await;
+        Ok(builder)
     }
 }
 
+fn is_websocket_request<B>(req: &hyper::Request<B>) -> bool {
+    req.headers()
+        .get(http::header::UPGRADE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.eq_ignore_ascii_case("websocket"))
+}
+
+fn http_timeout_response() -> hyper::Response<hyper::Body> {
+    let body = serde_json::json!({
+        "jsonrpc": "2.0",
+        "error": errors::failed("Request timeout"),
+        "id": null,
+    });
+    hyper::Response::builder()
+        .status(http::StatusCode::GATEWAY_TIMEOUT)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(hyper::Body::from(body.to_string()))
+        .expect("valid response")
+}
+
 fn cors_layer(cors: Option<ItemOrList<String>>) -> anyhow::Result<CorsLayer> {
     let origins = cors.map(|c| c.into_list()).unwrap_or_default();
 
@@ -91,27 +318,62 @@ fn cors_layer(cors: Option<ItemOrList<String>>) -> anyhow::Result<CorsLayer> {
 
 impl SubwayServerBuilder {
     pub fn new(config: ServerConfig) -> Self {
-        Self { config }
+        Self {
+            config,
+            header_forwarding: Vec::new(),
+            connection_stats: None,
+            client: None,
+            auth: None,
+        }
+    }
+
+    /// Effective connection, timeout, batch and subscription limits.
+    pub fn limits(&self) -> JsonValue {
+        serde_json::json!({
+            "max_connections": self.config.max_connections,
+            "request_timeout_seconds": self.config.request_timeout_seconds,
+            "http_request_timeout_ms": self.config.http_request_timeout_ms,
+            "idle_timeout_secs": self.config.idle_timeout_secs,
+            "max_batch_size": self.config.max_batch_size,
+            "max_concurrent_calls_per_connection": self.config.max_concurrent_calls_per_connection,
+            "max_subscriptions_per_connection": self.config.max_subscriptions_per_connection,
+            "max_subscription_lifetime_secs": self.config.max_subscription_lifetime_secs,
+        })
     }
 
     pub async fn build<Fut: Future<Output = anyhow::Result<RpcModule<()>>>>(
         &self,
         rate_limit_builder: Option<Arc<RateLimitBuilder>>,
         rpc_method_weights: MethodWeights,
+        access_log: Option<Arc<AccessLog>>,
         rpc_module_builder: impl FnOnce() -> Fut,
-    ) -> anyhow::Result<(SocketAddr, ServerHandle)> {
+    ) -> anyhow::Result<(SocketAddr, ServerHandle, ServedMethods)> {
         let config = self.config.clone();
+        let header_forwarding = self.header_forwarding.clone();
+        let connection_stats = self.connection_stats.clone();
+        let auth = self.auth.clone();
+        if !self.config.methods_require_auth.is_empty() && auth.is_none() {
+            anyhow::bail!("server.methods_require_auth requires the auth extension");
+        }
+        let methods_require_auth = Arc::new(self.config.methods_require_auth.clone());
+        let health_layer = self
+            .config
+            .health
+            .clone()
+            .map(|health| HealthLayer::new(health, self.client.clone()));
 
         let (stop_handle, server_handle) = stop_channel();
         let handle = stop_handle.clone();
-        let rpc_module = rpc_module_builder().await?;
+        let served_methods = ServedMethods::new(rpc_module_builder().await?, rpc_method_weights);
+        let served = served_methods.clone();
 
-        // make_service handle each connection
-        let make_service = make_service_fn(move |socket: &AddrStream| {
-            let socket_ip = socket.remote_addr().ip().to_string();
+        // connection_service handle each connection
+        let connection_service = move |remote_addr: SocketAddr| {
+            let socket_ip = remote_addr.ip().to_string();
 
             let http_middleware: ServiceBuilder<_> = tower::ServiceBuilder::new()
                 .layer(cors_layer(config.cors.clone()).expect("Invalid CORS config"))
+                .option_layer(health_layer.clone())
                 .layer(
                     ProxyGetRequestLayer::new(
                         config
@@ -126,24 +388,80 @@ impl SubwayServerBuilder {
                     .expect("Invalid health config"),
                 );
 
-            let rpc_module = rpc_module.clone();
-            let stop_handle = stop_handle.clone();
+            let served_methods = served_methods.clone();
+            // tracked for the connection limits
+            let activity = (config.idle_timeout_secs.is_some() || config.max_subscriptions_per_connection.is_some())
+                .then(|| Arc::new(ConnectionActivity::default()));
+            let stop_handle = match (config.idle_timeout_secs, &activity) {
+                (Some(secs), Some(activity)) => {
+                    connection_limits::idle_stop_handle(stop_handle.clone(), activity, Duration::from_secs(secs))
+                }
+                _ => stop_handle.clone(),
+            };
             let rate_limit_builder = rate_limit_builder.clone();
-            let rpc_method_weights = rpc_method_weights.clone();
+            let access_log = access_log.clone();
+            let header_forwarding = header_forwarding.clone();
+            let auth = auth.clone();
+            let methods_require_auth = methods_require_auth.clone();
+            // dropped with the last request of the connection, or when its WebSocket closes
+            let connection = connection_stats.as_ref().map(|stats| stats.register(remote_addr));
 
             async move {
                 // service_fn handle each request
                 Ok::<_, Box<dyn StdError + Send + Sync>>(service_fn(move |req| {
                     let mut socket_ip = socket_ip.clone();
-                    let methods: Methods = rpc_module.clone().into();
+                    let (methods, rpc_method_weights) = served_methods.current();
                     let stop_handle = stop_handle.clone();
                     let http_middleware = http_middleware.clone();
+                    let connection = connection.clone();
+                    let connection_limits = activity.clone().map(|activity| {
+                        ConnectionLimitsLayer::new(methods.clone(), activity, config.max_subscriptions_per_connection)
+                    });
 
                     if let Some(true) = rate_limit_builder.as_ref().map(|r| r.use_xff()) {
                         socket_ip = req.xxf_ip().unwrap_or(socket_ip);
                     }
 
+                    let feature_flags = match req.headers().get(FeatureFlags::HEADER) {
+                        Some(value) => value.to_str().map_err(|e| e.to_string()).and_then(|v| {
+                            FeatureFlags::parse(v, &config.allowed_feature_flags, config.strict_feature_flags)
+                        }),
+                        None => Ok(FeatureFlags::default()),
+                    };
+
+                    let forwarded_headers = ForwardedHeaders::from_request(&header_forwarding, req.headers());
+
+                    let (auth_layer, key_rate_limit) = match auth {
+                        Some(ref auth) => {
+                            let (layer, rate_limit) =
+                                auth.layers(req.headers(), req.uri(), socket_ip.clone(), rpc_method_weights.clone());
+                            (
+                                Some(layer.with_methods_require_auth(methods_require_auth.clone())),
+                                rate_limit,
+                            )
+                        }
+                        None => (None, None),
+                    };
+
                     let rpc_middleware = RpcServiceBuilder::new()
+                        .layer(FeatureFlagsLayer::new(feature_flags))
+                        .option_layer(connection.map(ConnectionStatsLayer::new))
+                        .option_layer(connection_limits)
+                        .option_layer(
+                            (!forwarded_headers.is_empty()).then(|| ForwardedHeadersLayer::new(forwarded_headers)),
+                        )
+                        .option_layer(
+                            access_log
+                                .as_ref()
+                                .map(|a| a.layer(config.access_log_format, socket_ip.clone(), req.headers())),
+                        )
+                        .option_layer(
+                            config
+                                .max_concurrent_calls_per_connection
+                                .map(ConcurrencyLimitLayer::new),
+                        )
+                        .option_layer(auth_layer)
+                        .option_layer(key_rate_limit)
                         .option_layer(
                             rate_limit_builder
                                 .as_ref()
@@ -155,30 +473,122 @@ impl SubwayServerBuilder {
                                 .and_then(|r| r.connection_limit(rpc_method_weights.clone())),
                         );
 
-                    let service_builder = ServerBuilder::default()
+                    let batch_config = match config.max_batch_size {
+                        Some(limit) => BatchRequestConfig::Limit(limit),
+                        None => BatchRequestConfig::Unlimited,
+                    };
+
+                    let server_builder = match (config.enable_http, config.enable_ws) {
+                        (true, false) => ServerBuilder::default().http_only(),
+                        (false, true) => ServerBuilder::default().ws_only(),
+                        _ => ServerBuilder::default(),
+                    };
+                    let server_builder = match config.max_subscriptions_per_connection {
+                        Some(max) => server_builder.max_subscriptions_per_connection(max),
+                        None => server_builder,
+                    };
+
+                    let service_builder = server_builder
                         .set_rpc_middleware(rpc_middleware)
+                        .set_batch_request_config(batch_config)
                         .set_http_middleware(http_middleware)
                         .max_connections(config.max_connections)
                         .set_id_provider(RandomStringIdProvider::new(16))
                         .to_service_builder();
 
+                    let http_timeout = config
+                        .http_request_timeout_ms
+                        .filter(|_| !is_websocket_request(&req))
+                        .map(Duration::from_millis);
+
                     let mut service = service_builder.build(methods, stop_handle);
-                    service.call(req)
+                    let response = service.call(req);
+
+                    async move {
+                        match http_timeout {
+                            Some(timeout) => match tokio::time::timeout(timeout, response).await {
+                                Ok(response) => response,
+                                Err(_) => Ok(http_timeout_response()),
+                            },
+                            None => response.await,
+                        }
+                    }
                 }))
             }
-        });
+        };
 
         let ip_addr = std::net::IpAddr::from_str(&self.config.listen_address)?;
         let addr = SocketAddr::new(ip_addr, self.config.port);
 
-        let server = hyper::Server::bind(&addr).serve(make_service);
-        let addr = server.local_addr();
+        let tls_acceptor = self.config.tls.as_ref().map(tls::acceptor).transpose()?;
 
-        tokio::spawn(async move {
-            let graceful = server.with_graceful_shutdown(async move { handle.shutdown().await });
-            graceful.await.unwrap()
-        });
+        let mut attempts = 0;
+        let listener = loop {
+            match std::net::TcpListener::bind(addr) {
+                Ok(listener) => break listener,
+                // only a port still in use can become free, other errors are final
+                Err(e) if e.kind() == std::io::ErrorKind::AddrInUse && attempts < self.config.bind_retry_attempts => {
+                    attempts += 1;
+                    tracing::warn!(
+                        "Failed to bind {addr}, retrying in {}ms ({attempts}/{}): {e}",
+                        self.config.bind_retry_delay_ms,
+                        self.config.bind_retry_attempts
+                    );
+                    tokio::time::sleep(Duration::from_millis(self.config.bind_retry_delay_ms)).await;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        };
+
+        let addr = listener.local_addr()?;
+
+        #[cfg(unix)]
+        if let Some(ref path) = self.config.unix_socket_path {
+            let incoming = unix_socket::bind(path)?;
+            let connection_service = connection_service.clone();
+            let handle = handle.clone();
+            let make_service =
+                make_service_fn(move |_: &tokio::net::UnixStream| connection_service(unix_socket::PEER_ADDR));
+            let server = hyper::Server::builder(incoming).serve(make_service);
+            tokio::spawn(async move {
+                let graceful = server.with_graceful_shutdown(async move { handle.shutdown().await });
+                graceful.await.unwrap()
+            });
+        }
+
+        match (tls_acceptor, self.config.proxy_protocol) {
+            (Some(acceptor), proxy_protocol) => {
+                listener.set_nonblocking(true)?;
+                let incoming = tls::accept(tokio::net::TcpListener::from_std(listener)?, acceptor, proxy_protocol);
+                let make_service =
+                    make_service_fn(move |socket: &TlsConnection| connection_service(socket.remote_addr()));
+                let server = hyper::Server::builder(incoming).serve(make_service);
+                tokio::spawn(async move {
+                    let graceful = server.with_graceful_shutdown(async move { handle.shutdown().await });
+                    graceful.await.unwrap()
+                });
+            }
+            (None, None) => {
+                let make_service = make_service_fn(move |socket: &AddrStream| connection_service(socket.remote_addr()));
+                let server = hyper::Server::from_tcp(listener)?.serve(make_service);
+                tokio::spawn(async move {
+                    let graceful = server.with_graceful_shutdown(async move { handle.shutdown().await });
+                    graceful.await.unwrap()
+                });
+            }
+            (None, Some(version)) => {
+                listener.set_nonblocking(true)?;
+                let incoming = proxy_protocol::accept(tokio::net::TcpListener::from_std(listener)?, version);
+                let make_service =
+                    make_service_fn(move |socket: &ProxiedStream| connection_service(socket.remote_addr()));
+                let server = hyper::Server::builder(incoming).serve(make_service);
+                tokio::spawn(async move {
+                    let graceful = server.with_graceful_shutdown(async move { handle.shutdown().await });
+                    graceful.await.unwrap()
+                });
+            }
+        }
 
-        Ok((addr, server_handle))
+        Ok((addr, server_handle, served))
     }
 }
@@ -0,0 +1,205 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
+};
+
+use super::incoming::{self, Incoming};
+
+/// Version of the PROXY protocol header sent by the load balancer in front of subway.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyProtocol {
+    V1,
+    V2,
+}
+
+const V1_MAX_LENGTH: usize = 107;
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Reads the PROXY protocol header from the start of the stream, leaving the stream at the first byte after it.
+/// Returns the client address, or `None` when the proxy does not relay one, e.g. for its own health checks.
+pub async fn read_header<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    version: ProxyProtocol,
+) -> io::Result<Option<SocketAddr>> {
+    match version {
+        ProxyProtocol::V1 => read_v1(stream).await,
+        ProxyProtocol::V2 => read_v2(stream).await,
+    }
+}
+
+async fn read_v1<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    // read byte by byte, anything after the header belongs to the connection
+    let mut line = Vec::with_capacity(V1_MAX_LENGTH);
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            return Err(invalid("PROXY v1 header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2]).map_err(|_| invalid("PROXY v1 header is not ASCII"))?;
+    let parts = line.split(' ').collect::<Vec<_>>();
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", src, _dst, src_port, _dst_port] => {
+            let ip = src
+                .parse::<IpAddr>()
+                .map_err(|_| invalid("invalid PROXY v1 source address"))?;
+            let port = src_port
+                .parse::<u16>()
+                .map_err(|_| invalid("invalid PROXY v1 source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("invalid PROXY v1 header")),
+    }
+}
+
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    let mut header = [0u8; 16];
+    stream.read_exact(&mut header).await?;
+    if header[..12] != V2_SIGNATURE {
+        return Err(invalid("invalid PROXY v2 signature"));
+    }
+    if header[12] >> 4 != 2 {
+        return Err(invalid("unsupported PROXY v2 version"));
+    }
+
+    let mut addresses = vec![0u8; u16::from_be_bytes([header[14], header[15]]) as usize];
+    stream.read_exact(&mut addresses).await?;
+
+    // LOCAL connections are made by the proxy itself
+    if header[12] & 0x0f == 0 {
+        return Ok(None);
+    }
+
+    match header[13] >> 4 {
+        // AF_INET
+        1 if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&addresses[..4]).expect("length checked; qed"));
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        // AF_INET6
+        2 if addresses.len() >= 36 => {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&addresses[..16]).expect("length checked; qed"));
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        // AF_UNSPEC or AF_UNIX, no usable client address
+        0 | 3 => Ok(None),
+        _ => Err(invalid("invalid PROXY v2 address block")),
+    }
+}
+
+/// Connection whose PROXY protocol header has been read, with the client address it relayed.
+pub struct ProxiedStream {
+    stream: TcpStream,
+    remote_addr: SocketAddr,
+}
+
+impl ProxiedStream {
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+}
+
+impl AsyncRead for ProxiedStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ProxiedStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// Accepts connections and reads their PROXY header, connections with an invalid header are dropped.
+/// Stops once the returned incoming connections are dropped.
+pub fn accept(listener: TcpListener, version: ProxyProtocol) -> Incoming<ProxiedStream> {
+    incoming::accept(
+        listener,
+        "PROXY header",
+        HEADER_TIMEOUT,
+        move |mut stream, addr| async move {
+            let remote_addr = read_header(&mut stream, version).await?.unwrap_or(addr);
+            Ok(ProxiedStream { stream, remote_addr })
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn parse(version: ProxyProtocol, header: &[u8]) -> (io::Result<Option<SocketAddr>>, Vec<u8>) {
+        let input = [header, b"GET /"].concat();
+        let mut reader = input.as_slice();
+        let res = read_header(&mut reader, version).await;
+        (res, reader.to_vec())
+    }
+
+    #[tokio::test]
+    async fn reads_v1_header() {
+        let (res, rest) = parse(ProxyProtocol::V1, b"PROXY TCP4 192.168.0.1 10.0.0.1 56324 9944\r\n").await;
+        assert_eq!(res.unwrap(), Some("192.168.0.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"GET /");
+
+        let (res, _) = parse(ProxyProtocol::V1, b"PROXY TCP6 ::1 ::1 56324 9944\r\n").await;
+        assert_eq!(res.unwrap(), Some("[::1]:56324".parse().unwrap()));
+
+        let (res, _) = parse(ProxyProtocol::V1, b"PROXY UNKNOWN\r\n").await;
+        assert_eq!(res.unwrap(), None);
+
+        let (res, _) = parse(ProxyProtocol::V1, b"GET / HTTP/1.1\r\n").await;
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_v2_header() {
+        let mut header = V2_SIGNATURE.to_vec();
+        // PROXY command, TCP over IPv4, 12 bytes of addresses
+        header.extend_from_slice(&[0x21, 0x11, 0x00, 0x0c]);
+        header.extend_from_slice(&[192, 168, 0, 1, 10, 0, 0, 1]);
+        header.extend_from_slice(&56324u16.to_be_bytes());
+        header.extend_from_slice(&9944u16.to_be_bytes());
+
+        let (res, rest) = parse(ProxyProtocol::V2, &header).await;
+        assert_eq!(res.unwrap(), Some("192.168.0.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"GET /");
+
+        // LOCAL command, e.g. health checks of the proxy
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+        let (res, rest) = parse(ProxyProtocol::V2, &local).await;
+        assert_eq!(res.unwrap(), None);
+        assert_eq!(rest, b"GET /");
+
+        let (res, _) = parse(ProxyProtocol::V2, b"PROXY TCP4 192.168.0.1 10.0.0.1 56324 9944\r\n").await;
+        assert!(res.is_err());
+    }
+}
@@ -0,0 +1,181 @@
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
+};
+use tokio_rustls::{rustls, server::TlsStream, TlsAcceptor};
+
+use super::{
+    incoming::{self, Incoming},
+    proxy_protocol::{read_header, ProxyProtocol},
+};
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TlsConfig {
+    /// PEM encoded certificate chain, leaf certificate first.
+    pub cert_path: String,
+    /// PEM encoded private key, PKCS#8, PKCS#1 (RSA) or SEC1 (EC).
+    pub key_path: String,
+}
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Loads the certificate and key of the config, failing at startup instead of on the first connection.
+pub fn acceptor(config: &TlsConfig) -> anyhow::Result<TlsAcceptor> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(&config.cert_path)?))?
+        .into_iter()
+        .map(rustls::Certificate)
+        .collect::<Vec<_>>();
+    anyhow::ensure!(!certs.is_empty(), "No certificate found in {}", config.cert_path);
+
+    let mut reader = BufReader::new(File::open(&config.key_path)?);
+    let key = loop {
+        match rustls_pemfile::read_one(&mut reader)? {
+            Some(
+                rustls_pemfile::Item::PKCS8Key(key)
+                | rustls_pemfile::Item::RSAKey(key)
+                | rustls_pemfile::Item::ECKey(key),
+            ) => break rustls::PrivateKey(key),
+            Some(_) => continue,
+            None => anyhow::bail!("No private key found in {}", config.key_path),
+        }
+    };
+
+    let mut server_config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    // WebSocket upgrades need HTTP/1.1
+    server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+pub struct TlsConnection {
+    stream: TlsStream<TcpStream>,
+    remote_addr: SocketAddr,
+}
+
+impl TlsConnection {
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+}
+
+impl AsyncRead for TlsConnection {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TlsConnection {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// Accepts connections, reads their PROXY header if `proxy_protocol` is set, and completes the TLS handshake.
+/// Connections failing either are dropped. Stops once the returned incoming connections are dropped.
+pub fn accept(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    proxy_protocol: Option<ProxyProtocol>,
+) -> Incoming<TlsConnection> {
+    incoming::accept(listener, "TLS handshake", HANDSHAKE_TIMEOUT, move |mut stream, addr| {
+        let acceptor = acceptor.clone();
+        async move {
+            let remote_addr = match proxy_protocol {
+                Some(version) => read_header(&mut stream, version).await?.unwrap_or(addr),
+                None => addr,
+            };
+            let stream = acceptor.accept(stream).await?;
+            Ok(TlsConnection { stream, remote_addr })
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::StreamExt;
+    use hyper::server::accept::Accept;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::TlsConnector;
+
+    #[tokio::test]
+    async fn serves_tls_connections() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let dir = std::env::temp_dir().join(format!("subway-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = TlsConfig {
+            cert_path: dir.join("cert.pem").to_string_lossy().to_string(),
+            key_path: dir.join("key.pem").to_string_lossy().to_string(),
+        };
+        std::fs::write(&config.cert_path, cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(&config.key_path, cert.serialize_private_key_pem()).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut incoming = futures::stream::poll_fn({
+            let mut incoming = accept(listener, acceptor(&config).unwrap(), None);
+            move |cx| Pin::new(&mut incoming).poll_accept(cx)
+        });
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(&rustls::Certificate(cert.serialize_der().unwrap())).unwrap();
+        let connector = TlsConnector::from(Arc::new(
+            rustls::ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        ));
+
+        let client = async {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let mut stream = connector
+                .connect("localhost".try_into().unwrap(), stream)
+                .await
+                .unwrap();
+            stream.write_all(b"ping").await.unwrap();
+            stream.flush().await.unwrap();
+            stream
+        };
+        let (_client, conn) = tokio::join!(client, incoming.next());
+
+        let mut conn = conn.unwrap().unwrap();
+        assert_eq!(conn.remote_addr().ip(), addr.ip());
+        let mut buf = [0u8; 4];
+        conn.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_missing_files() {
+        let config = TlsConfig {
+            cert_path: "/nonexistent/cert.pem".to_string(),
+            key_path: "/nonexistent/key.pem".to_string(),
+        };
+        assert!(acceptor(&config).is_err());
+    }
+}
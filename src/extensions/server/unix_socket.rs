@@ -0,0 +1,77 @@
+use std::{
+    io,
+    net::SocketAddr,
+    os::unix::fs::FileTypeExt,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::net::{UnixListener, UnixStream};
+
+use super::incoming::{self, Incoming, Listener};
+
+/// Address used for rate limiting and access logs of clients connected over the Unix socket.
+pub const PEER_ADDR: SocketAddr = SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST), 0);
+
+impl Listener for UnixListener {
+    type Stream = UnixStream;
+
+    fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<io::Result<(Self::Stream, SocketAddr)>> {
+        UnixListener::poll_accept(self, cx).map(|res| res.map(|(stream, _)| (stream, PEER_ADDR)))
+    }
+}
+
+/// Binds a Unix socket at `path` and accepts its connections, backing off on accept errors.
+/// A socket left over by a previous process is replaced, one still accepting connections or other files
+/// at `path` are kept and fail the bind.
+pub fn bind(path: &str) -> io::Result<Incoming<UnixStream>> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => match std::os::unix::net::UnixStream::connect(path) {
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("{path} is in use by another process"),
+                ))
+            }
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => std::fs::remove_file(path)?,
+            Err(_) => {}
+        },
+        _ => {}
+    }
+    let listener = UnixListener::bind(path)?;
+    // there is no handshake, the timeout never applies
+    Ok(incoming::accept(
+        listener,
+        "Unix socket",
+        Duration::from_secs(1),
+        |stream, _| async move { Ok(stream) },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn replaces_stale_socket() {
+        let path = std::env::temp_dir().join(format!("subway-{}.sock", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        // a socket in use is kept
+        let first = bind(path).unwrap();
+        assert_eq!(bind(path).err().unwrap().kind(), io::ErrorKind::AddrInUse);
+        drop(first);
+        // give the accept task time to drop the listener
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // the socket file is still there
+        let second = bind(path).unwrap();
+        assert!(UnixStream::connect(path).await.is_ok());
+        drop(second);
+
+        std::fs::remove_file(path).unwrap();
+        std::fs::write(path, "not a socket").unwrap();
+        assert!(bind(path).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
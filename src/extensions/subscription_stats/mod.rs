@@ -0,0 +1,213 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use prometheus::GaugeVec;
+use serde::Deserialize;
+use tokio::task::JoinHandle;
+
+use super::{Extension, ExtensionRegistry};
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SubscriptionStatsConfig {
+    // log a warning when a subscription delivers more notifications per second than this
+    #[serde(default)]
+    pub max_notifications_per_second: Option<f64>,
+    // register `admin_subscriptionStats` on the admin endpoint, returning the rate of each subscription
+    #[serde(default)]
+    pub admin_method: bool,
+}
+
+// weight of the latest one second sample
+const SMOOTHING: f64 = 0.2;
+
+/// Rolling notifications per second, labeled by subscription name.
+pub fn rate_gauge() -> &'static GaugeVec {
+    static GAUGE: OnceLock<GaugeVec> = OnceLock::new();
+    GAUGE.get_or_init(|| {
+        prometheus::register_gauge_vec!(
+            "subway_subscription_notifications_per_second",
+            "Rolling notifications per second delivered by each subscription",
+            &["subscription"]
+        )
+        .expect("Failed to register subway_subscription_notifications_per_second")
+    })
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ExponentialMovingAverage {
+    alpha: f64,
+    value: Option<f64>,
+}
+
+impl ExponentialMovingAverage {
+    pub fn new(alpha: f64) -> Self {
+        Self { alpha, value: None }
+    }
+
+    /// Adds a sample and returns the new average, the first sample is taken as is.
+    pub fn update(&mut self, sample: f64) -> f64 {
+        let value = match self.value {
+            Some(value) => self.alpha * sample + (1.0 - self.alpha) * value,
+            None => sample,
+        };
+        self.value = Some(value);
+        value
+    }
+
+    pub fn value(&self) -> f64 {
+        self.value.unwrap_or_default()
+    }
+}
+
+/// Notification rate of a subscription, shared by all its subscribers.
+#[derive(Debug)]
+pub struct SubscriptionRate {
+    // notifications since the last tick
+    count: AtomicU64,
+    average: Mutex<ExponentialMovingAverage>,
+}
+
+impl SubscriptionRate {
+    fn new() -> Self {
+        Self {
+            count: AtomicU64::new(0),
+            average: Mutex::new(ExponentialMovingAverage::new(SMOOTHING)),
+        }
+    }
+
+    pub fn record(&self) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn per_second(&self) -> f64 {
+        self.average.lock().unwrap_or_else(|e| e.into_inner()).value()
+    }
+
+    fn tick(&self, elapsed: Duration) -> f64 {
+        let count = self.count.swap(0, Ordering::Relaxed);
+        let sample = count as f64 / elapsed.as_secs_f64();
+        self.average.lock().unwrap_or_else(|e| e.into_inner()).update(sample)
+    }
+}
+
+type Rates = Arc<Mutex<BTreeMap<String, Arc<SubscriptionRate>>>>;
+
+pub struct SubscriptionStats {
+    config: SubscriptionStatsConfig,
+    rates: Rates,
+    background_task: Option<JoinHandle<()>>,
+}
+
+impl Drop for SubscriptionStats {
+    fn drop(&mut self) {
+        if let Some(handle) = self.background_task.take() {
+            handle.abort();
+        }
+    }
+}
+
+#[async_trait]
+impl Extension for SubscriptionStats {
+    type Config = SubscriptionStatsConfig;
+
+    async fn from_config(config: &Self::Config, _registry: &ExtensionRegistry) -> Result<Self, anyhow::Error> {
+        let mut this = Self::new(config.clone());
+        this.start_background_task();
+        Ok(this)
+    }
+}
+
+impl SubscriptionStats {
+    pub fn new(config: SubscriptionStatsConfig) -> Self {
+        Self {
+            config,
+            rates: Default::default(),
+            background_task: None,
+        }
+    }
+
+    fn start_background_task(&mut self) {
+        let rates = self.rates.clone();
+        let max = self.config.max_notifications_per_second;
+        self.background_task = Some(tokio::spawn(async move {
+            let interval = Duration::from_secs(1);
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                update_rates(&rates, interval, max);
+            }
+        }));
+    }
+
+    pub fn admin_method(&self) -> bool {
+        self.config.admin_method
+    }
+
+    /// Rate of the given subscription, created on first use.
+    pub fn rate(&self, subscription: &str) -> Arc<SubscriptionRate> {
+        let mut rates = self.rates.lock().unwrap_or_else(|e| e.into_inner());
+        rates
+            .entry(subscription.to_string())
+            .or_insert_with(|| Arc::new(SubscriptionRate::new()))
+            .clone()
+    }
+
+    /// Notifications per second of each subscription.
+    pub fn snapshot(&self) -> BTreeMap<String, f64> {
+        let rates = self.rates.lock().unwrap_or_else(|e| e.into_inner());
+        rates
+            .iter()
+            .map(|(name, rate)| (name.clone(), rate.per_second()))
+            .collect()
+    }
+}
+
+fn update_rates(rates: &Rates, elapsed: Duration, max: Option<f64>) {
+    let rates = rates.lock().unwrap_or_else(|e| e.into_inner());
+    for (name, rate) in rates.iter() {
+        let per_second = rate.tick(elapsed);
+        rate_gauge().with_label_values(&[name]).set(per_second);
+        if max.is_some_and(|max| per_second > max) {
+            tracing::warn!("Subscription {name} delivers {per_second:.1} notifications per second");
+        }
+    }
+}
+
+#[test]
+fn moving_average_works() {
+    let mut average = ExponentialMovingAverage::new(0.5);
+    assert_eq!(average.value(), 0.0);
+    assert_eq!(average.update(10.0), 10.0);
+    assert_eq!(average.update(20.0), 15.0);
+    assert_eq!(average.update(0.0), 7.5);
+}
+
+#[test]
+fn rates_are_tracked_per_subscription() {
+    let stats = SubscriptionStats::new(SubscriptionStatsConfig {
+        max_notifications_per_second: None,
+        admin_method: false,
+    });
+
+    let heads = stats.rate("chain_newHead");
+    for _ in 0..4 {
+        heads.record();
+    }
+    stats.rate("state_storage").record();
+    assert!(Arc::ptr_eq(&heads, &stats.rate("chain_newHead")));
+
+    update_rates(&stats.rates, Duration::from_secs(2), None);
+
+    let snapshot = stats.snapshot();
+    assert_eq!(snapshot["chain_newHead"], 2.0);
+    assert_eq!(snapshot["state_storage"], 0.5);
+    assert_eq!(rate_gauge().with_label_values(&["chain_newHead"]).get(), 2.0);
+}
@@ -1,8 +1,9 @@
 use std::env;
 
 use async_trait::async_trait;
-use opentelemetry::{global, trace::TraceError};
-use opentelemetry_sdk::trace::Tracer;
+use opentelemetry::{global, trace::TraceError, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{trace::Tracer, Resource};
 use serde::Deserialize;
 
 use super::{Extension, ExtensionRegistry};
@@ -13,6 +14,8 @@ pub enum TelemetryProvider {
     None,
     Datadog,
     Jaeger,
+    // OTLP over gRPC, `agent_endpoint` defaults to http://localhost:4317
+    Otlp,
 }
 
 #[derive(Deserialize, Debug)]
@@ -85,6 +88,24 @@ pub fn setup_telemetry(options: &TelemetryConfig) -> Result<Option<Tracer>, Trac
 
             Some(tracer)
         }
+        TelemetryProvider::Otlp => {
+            let mut exporter = opentelemetry_otlp::new_exporter().tonic();
+
+            if let Some(ref agent_endpoint) = options.agent_endpoint {
+                exporter = exporter.with_endpoint(agent_endpoint.clone());
+            }
+
+            let tracer = opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(exporter)
+                .with_trace_config(
+                    opentelemetry_sdk::trace::config()
+                        .with_resource(Resource::new(vec![KeyValue::new("service.name", service_name)])),
+                )
+                .install_batch(opentelemetry_sdk::runtime::Tokio)?;
+
+            Some(tracer)
+        }
         TelemetryProvider::None => None,
     };
 
@@ -2,7 +2,10 @@ use chrono::Utc;
 use opentelemetry::trace::TraceContextExt;
 use opentelemetry::Context;
 use serde::ser::{SerializeMap, Serializer as _};
-use std::io;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write as _};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 use tracing::{Event, Subscriber};
 use tracing_serde::fields::AsMap;
 use tracing_serde::AsSerde;
@@ -109,3 +112,145 @@ pub fn enable_logger() {
         _ => log_layer.with(fmt_layer.with_filter(filter)).try_init(),
     };
 }
+
+/// A file writer that rotates by size and/or age, keeping at most `max_files` rotated files.
+/// Rotated files are named `<path>.1`, `<path>.2`, ... with `.1` being the most recent.
+/// Each `write` goes whole to a single file, so a line written at once is never split by a rotation.
+pub struct RotatingFileWriter {
+    path: PathBuf,
+    max_size_bytes: Option<u64>,
+    rotate_interval: Option<Duration>,
+    max_files: usize,
+    file: File,
+    size: u64,
+    opened_at: Instant,
+}
+
+impl RotatingFileWriter {
+    pub fn new(
+        path: impl Into<PathBuf>,
+        max_size_bytes: Option<u64>,
+        rotate_interval: Option<Duration>,
+        max_files: usize,
+    ) -> io::Result<Self> {
+        let path = path.into();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Self {
+            path,
+            max_size_bytes,
+            rotate_interval,
+            max_files,
+            file,
+            size,
+            opened_at: Instant::now(),
+        })
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{index}"));
+        path.into()
+    }
+
+    fn should_rotate(&self, incoming: usize) -> bool {
+        let too_big = self
+            .max_size_bytes
+            .map(|max| self.size > 0 && self.size + incoming as u64 > max)
+            .unwrap_or(false);
+        let too_old = self
+            .rotate_interval
+            .map(|interval| self.opened_at.elapsed() >= interval)
+            .unwrap_or(false);
+        too_big || too_old
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        if self.max_files == 0 {
+            // no retention, just truncate
+            self.file = OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(&self.path)?;
+        } else {
+            // drop the oldest file and shift the rest
+            let _ = fs::remove_file(self.rotated_path(self.max_files));
+            for index in (1..self.max_files).rev() {
+                let from = self.rotated_path(index);
+                if from.exists() {
+                    fs::rename(&from, self.rotated_path(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+            self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        }
+
+        self.size = 0;
+        self.opened_at = Instant::now();
+        Ok(())
+    }
+}
+
+impl io::Write for RotatingFileWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.should_rotate(buf.len()) {
+            self.rotate()?;
+        }
+        // a short write would let the rest of the buffer go to the next file
+        self.file.write_all(buf)?;
+        self.size += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[test]
+fn rotating_file_writer_works() {
+    let dir = std::env::temp_dir().join(format!("subway-rotate-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("access.log");
+
+    let mut writer = RotatingFileWriter::new(&path, Some(10), None, 2).unwrap();
+    for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+        writer.write_all(line.as_bytes()).unwrap();
+    }
+    writer.flush().unwrap();
+
+    assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+    assert_eq!(fs::read_to_string(dir.join("access.log.1")).unwrap(), "third\n");
+    assert_eq!(fs::read_to_string(dir.join("access.log.2")).unwrap(), "second\n");
+    // retention is 2 files so the oldest is gone
+    assert!(!dir.join("access.log.3").exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn rotating_file_writer_keeps_writes_whole() {
+    let dir = std::env::temp_dir().join(format!("subway-rotate-whole-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("access.log");
+
+    let mut writer = RotatingFileWriter::new(&path, Some(10), None, 2).unwrap();
+    for line in ["short\n", "longer than max size\n", "end\n"] {
+        writer.write_all(line.as_bytes()).unwrap();
+    }
+
+    assert_eq!(fs::read_to_string(&path).unwrap(), "end\n");
+    assert_eq!(
+        fs::read_to_string(dir.join("access.log.1")).unwrap(),
+        "longer than max size\n"
+    );
+    assert_eq!(fs::read_to_string(dir.join("access.log.2")).unwrap(), "short\n");
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -1,22 +1,96 @@
+use clap::Parser;
+use subway::config::Action;
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let cmd = subway::config::Command::parse();
+
     // read config from file
-    let config = match subway::config::read_config() {
+    let config = match cmd.read_config() {
         Ok(config) => config,
         Err(e) => {
             return Err(anyhow::anyhow!(e));
         }
     };
 
+    match cmd.action {
+        Some(Action::Check { .. }) => {
+            println!("{:#?}", config);
+            return Ok(());
+        }
+        Some(Action::DryRun { .. }) => {
+            subway::logger::enable_logger();
+            let dry_run = subway::server::dry_run(config).await?;
+            if let Some(genesis_hash) = dry_run.genesis_hash {
+                println!("Connected to upstream, genesis hash {genesis_hash}");
+            }
+            println!("{} methods: {}", dry_run.methods.len(), dry_run.methods.join(", "));
+            return Ok(());
+        }
+        None => {}
+    }
+
     subway::logger::enable_logger();
     tracing::trace!("{:#?}", config);
 
     let subway_server = subway::server::build(config).await?;
     tracing::info!("Server running at {}", subway_server.addr);
 
-    subway_server.handle.stopped().await;
+    tokio::select! {
+        _ = subway_server.handle.stopped() => {}
+        _ = reload_on_hangup(&subway_server) => {}
+        _ = shutdown_signal() => {
+            tracing::info!("Shutting down, waiting for in-flight requests");
+            tokio::select! {
+                drained = subway_server.shutdown() => {
+                    if !drained {
+                        tracing::warn!("Graceful shutdown timed out, in-flight requests are dropped");
+                    }
+                }
+                _ = shutdown_signal() => tracing::warn!("Received another signal, shutting down immediately"),
+            }
+        }
+    }
 
     opentelemetry::global::shutdown_tracer_provider();
 
     Ok(())
 }
+
+/// Resolves on SIGTERM or Ctrl-C.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM");
+        tokio::select! {
+            _ = terminate.recv() => {}
+            _ = tokio::signal::ctrl_c() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+/// Reloads the config file on SIGHUP, never resolves.
+async fn reload_on_hangup(subway_server: &subway::server::SubwayServerHandle) {
+    #[cfg(unix)]
+    {
+        let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+            .expect("Failed to listen for SIGHUP");
+        while hangup.recv().await.is_some() {
+            tracing::info!("Reloading config");
+            let result = match subway::config::read_config() {
+                Ok(config) => subway_server.reload(config).await,
+                Err(e) => Err(anyhow::anyhow!(e)),
+            };
+            match result {
+                Ok(()) => tracing::info!("Config reloaded"),
+                Err(e) => tracing::error!("Failed to reload config, keeping the current one: {e}"),
+            }
+        }
+    }
+    std::future::pending::<()>().await
+}
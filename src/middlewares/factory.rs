@@ -1,3 +1,7 @@
+use std::collections::HashMap;
+
+use futures::future::BoxFuture;
+
 use super::{CallRequest, CallResult, SubscriptionRequest, SubscriptionResult};
 use crate::{
     config::{RpcMethod, RpcSubscription},
@@ -5,6 +9,159 @@ use crate::{
     utils::TypeRegistryRef,
 };
 
+/// Builds a method middleware, see `MiddlewareBuilder::build`.
+pub type MethodMiddlewareFactory = for<'a> fn(
+    &'a RpcMethod,
+    &'a TypeRegistryRef,
+) -> BoxFuture<'a, Option<Box<dyn Middleware<CallRequest, CallResult>>>>;
+
+/// Builds a subscription middleware, see `MiddlewareBuilder::build`.
+pub type SubscriptionMiddlewareFactory =
+    for<'a> fn(
+        &'a RpcSubscription,
+        &'a TypeRegistryRef,
+    ) -> BoxFuture<'a, Option<Box<dyn Middleware<SubscriptionRequest, SubscriptionResult>>>>;
+
+fn build_method<'a, M: MiddlewareBuilder<RpcMethod, CallRequest, CallResult>>(
+    method: &'a RpcMethod,
+    extensions: &'a TypeRegistryRef,
+) -> BoxFuture<'a, Option<Box<dyn Middleware<CallRequest, CallResult>>>> {
+    M::build(method, extensions)
+}
+
+fn build_subscription<'a, M: MiddlewareBuilder<RpcSubscription, SubscriptionRequest, SubscriptionResult>>(
+    method: &'a RpcSubscription,
+    extensions: &'a TypeRegistryRef,
+) -> BoxFuture<'a, Option<Box<dyn Middleware<SubscriptionRequest, SubscriptionResult>>>> {
+    M::build(method, extensions)
+}
+
+/// Middlewares registered by crates embedding subway, usable by name in the config like the built-in ones.
+/// Built-in names take precedence.
+#[derive(Default, Clone)]
+pub struct CustomMiddlewares {
+    methods: HashMap<String, MethodMiddlewareFactory>,
+    subscriptions: HashMap<String, SubscriptionMiddlewareFactory>,
+}
+
+impl CustomMiddlewares {
+    /// Registers a method middleware built by `M` under `name`.
+    pub fn with_method_middleware<M: MiddlewareBuilder<RpcMethod, CallRequest, CallResult>>(
+        mut self,
+        name: impl ToString,
+    ) -> Self {
+        self.methods.insert(name.to_string(), build_method::<M>);
+        self
+    }
+
+    /// Registers a subscription middleware built by `M` under `name`.
+    pub fn with_subscription_middleware<
+        M: MiddlewareBuilder<RpcSubscription, SubscriptionRequest, SubscriptionResult>,
+    >(
+        mut self,
+        name: impl ToString,
+    ) -> Self {
+        self.subscriptions.insert(name.to_string(), build_subscription::<M>);
+        self
+    }
+
+    pub fn method_middleware(&self, name: &str) -> Option<MethodMiddlewareFactory> {
+        self.methods.get(name).copied()
+    }
+
+    pub fn subscription_middleware(&self, name: &str) -> Option<SubscriptionMiddlewareFactory> {
+        self.subscriptions.get(name).copied()
+    }
+
+    pub fn has_method_middleware(&self, name: &str) -> bool {
+        builtin(METHOD_MIDDLEWARES, name).is_some() || self.methods.contains_key(name)
+    }
+
+    pub fn has_subscription_middleware(&self, name: &str) -> bool {
+        builtin(SUBSCRIPTION_MIDDLEWARES, name).is_some() || self.subscriptions.contains_key(name)
+    }
+}
+
+fn builtin<F: Copy>(middlewares: &[(&str, F)], name: &str) -> Option<F> {
+    middlewares.iter().find(|(n, _)| *n == name).map(|(_, build)| *build)
+}
+
+/// Built-in method middlewares by name, accepted by `create_method_middleware`.
+pub const METHOD_MIDDLEWARES: &[(&str, MethodMiddlewareFactory)] = {
+    use super::methods::*;
+    &[
+        ("response", build_method::<response::ResponseMiddleware>),
+        (
+            "fallback_response",
+            build_method::<fallback_response::FallbackResponseMiddleware>,
+        ),
+        ("validate", build_method::<validate::ValidateMiddleware>),
+        ("response_size", build_method::<response_size::ResponseSizeMiddleware>),
+        (
+            "transform_response",
+            build_method::<transform_response::ResponseTransformMiddleware>,
+        ),
+        ("read_only", build_method::<read_only::ReadOnlyMiddleware>),
+        ("upstream", build_method::<upstream::UpstreamMiddleware>),
+        ("archive", build_method::<archive::ArchiveMiddleware>),
+        ("cache", build_method::<cache::CacheMiddleware>),
+        (
+            "cache_by_block",
+            build_method::<cache_by_block::ResponseCachingByBlockMiddleware>,
+        ),
+        ("block_tag", build_method::<block_tag::BlockTagMiddleware>),
+        ("inject_params", build_method::<inject_params::InjectParamsMiddleware>),
+        (
+            "params_transform",
+            build_method::<params_transform::ParamsTransformMiddleware>,
+        ),
+        ("delay", build_method::<delay::DelayMiddleware>),
+        (
+            "serve_from_head",
+            build_method::<serve_from_head::ServeFromHeadMiddleware>,
+        ),
+        ("method_remap", build_method::<method_remap::MethodRemapMiddleware>),
+        ("retry", build_method::<retry::RetryMiddleware>),
+        ("bulkhead", build_method::<bulkhead::BulkheadMiddleware>),
+        ("preflight", build_method::<preflight::PreflightCheckMiddleware>),
+        ("alerting", build_method::<alerting::AlertingMiddleware>),
+        ("logging", build_method::<logging::LoggingMiddleware>),
+    ]
+};
+
+/// Built-in subscription middlewares by name, accepted by `create_subscription_middleware`.
+pub const SUBSCRIPTION_MIDDLEWARES: &[(&str, SubscriptionMiddlewareFactory)] = {
+    use super::subscriptions::*;
+    &[
+        ("upstream", build_subscription::<upstream::UpstreamMiddleware>),
+        ("read_only", build_subscription::<read_only::ReadOnlyMiddleware>),
+        (
+            "merge_subscription",
+            build_subscription::<merge_subscription::MergeSubscriptionMiddleware>,
+        ),
+        (
+            "subscription_backpressure",
+            build_subscription::<backpressure::SubscriptionBackpressureMiddleware>,
+        ),
+        (
+            "subscription_batch",
+            build_subscription::<batch::SubscriptionBatchMiddleware>,
+        ),
+        (
+            "subscription_filter",
+            build_subscription::<filter::SubscriptionFilterMiddleware>,
+        ),
+        (
+            "subscription_replay",
+            build_subscription::<replay::SubscriptionReplayMiddleware>,
+        ),
+        (
+            "subscription_stats",
+            build_subscription::<stats::SubscriptionStatsMiddleware>,
+        ),
+    ]
+};
+
 /// Creates a middleware for the given RPC method.
 ///
 /// # Arguments
@@ -21,19 +178,16 @@ pub async fn create_method_middleware(
     method: &RpcMethod,
     extensions: &TypeRegistryRef,
 ) -> Option<Box<dyn Middleware<CallRequest, CallResult>>> {
-    use super::methods::*;
-
-    match name {
-        "response" => response::ResponseMiddleware::build(method, extensions).await,
-        "upstream" => upstream::UpstreamMiddleware::build(method, extensions).await,
-        "cache" => cache::CacheMiddleware::build(method, extensions).await,
-        "block_tag" => block_tag::BlockTagMiddleware::build(method, extensions).await,
-        "inject_params" => inject_params::InjectParamsMiddleware::build(method, extensions).await,
-        "delay" => delay::DelayMiddleware::build(method, extensions).await,
-        #[cfg(test)]
-        "crazy" => testing::CrazyMiddleware::build(method, extensions).await,
-        _ => panic!("Unknown method middleware: {}", name),
-    }
+    let build = match builtin(METHOD_MIDDLEWARES, name) {
+        Some(build) => build,
+        None => {
+            let custom = extensions.read().await.get::<CustomMiddlewares>();
+            custom
+                .and_then(|custom| custom.method_middleware(name))
+                .unwrap_or_else(|| panic!("Unknown method middleware: {}", name))
+        }
+    };
+    build(method, extensions).await
 }
 
 /// Creates a middleware for a given RPC subscription.
@@ -52,11 +206,14 @@ pub async fn create_subscription_middleware(
     method: &RpcSubscription,
     extensions: &TypeRegistryRef,
 ) -> Option<Box<dyn Middleware<SubscriptionRequest, SubscriptionResult>>> {
-    use super::subscriptions::*;
-
-    match name {
-        "upstream" => upstream::UpstreamMiddleware::build(method, extensions).await,
-        "merge_subscription" => merge_subscription::MergeSubscriptionMiddleware::build(method, extensions).await,
-        _ => panic!("Unknown subscription middleware: {}", name),
-    }
+    let build = match builtin(SUBSCRIPTION_MIDDLEWARES, name) {
+        Some(build) => build,
+        None => {
+            let custom = extensions.read().await.get::<CustomMiddlewares>();
+            custom
+                .and_then(|custom| custom.subscription_middleware(name))
+                .unwrap_or_else(|| panic!("Unknown subscription middleware: {}", name))
+        }
+    };
+    build(method, extensions).await
 }
@@ -0,0 +1,111 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use jsonrpsee::types::{error::INTERNAL_ERROR_CODE, ErrorObjectOwned};
+use opentelemetry::trace::FutureExt;
+
+use crate::{
+    extensions::alerting::Alerting,
+    middlewares::{
+        methods::fallback_response::is_upstream_unavailable, CallRequest, CallResult, Middleware, MiddlewareBuilder,
+        NextFn, RpcMethod, TRACER,
+    },
+    utils::{TypeRegistry, TypeRegistryRef},
+};
+
+/// Errors counted towards the alert threshold: upstream could not be reached or failed internally,
+/// or subway did. Calls rejected because of the caller, e.g. invalid params or rate limits, are not.
+fn is_failure(error: &ErrorObjectOwned) -> bool {
+    error.code() == INTERNAL_ERROR_CODE || is_upstream_unavailable(error)
+}
+
+pub struct AlertingMiddleware {
+    alerting: Arc<Alerting>,
+}
+
+impl AlertingMiddleware {
+    pub fn new(alerting: Arc<Alerting>) -> Self {
+        Self { alerting }
+    }
+}
+
+#[async_trait]
+impl MiddlewareBuilder<RpcMethod, CallRequest, CallResult> for AlertingMiddleware {
+    async fn build(
+        _method: &RpcMethod,
+        extensions: &TypeRegistryRef,
+    ) -> Option<Box<dyn Middleware<CallRequest, CallResult>>> {
+        let alerting = extensions.read().await.get::<Alerting>()?;
+        Some(Box::new(AlertingMiddleware::new(alerting)))
+    }
+}
+
+#[async_trait]
+impl Middleware<CallRequest, CallResult> for AlertingMiddleware {
+    async fn call(
+        &self,
+        request: CallRequest,
+        context: TypeRegistry,
+        next: NextFn<CallRequest, CallResult>,
+    ) -> CallResult {
+        async move {
+            let method = request.method.clone();
+            let result = next(request, context).await;
+
+            let error = result
+                .as_ref()
+                .err()
+                .filter(|e| is_failure(e))
+                .map(|e| e.message().to_string());
+            if let Some(alert) = self.alerting.record(&method, error) {
+                self.alerting.send(alert);
+            }
+
+            result
+        }
+        .with_context(TRACER.context("alerting"))
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{extensions::alerting::AlertingConfig, utils::errors};
+    use futures::FutureExt;
+
+    #[tokio::test]
+    async fn only_failures_count_towards_alerts() {
+        let alerting = Arc::new(Alerting::new(AlertingConfig {
+            webhook_url: "http://127.0.0.1:1".to_string(),
+            error_rate_threshold: 0.5,
+            window_secs: 60,
+            cooldown_secs: 300,
+            min_requests: 4,
+        }));
+        let middleware = AlertingMiddleware::new(alerting.clone());
+        let call = |method: &'static str, error: ErrorObjectOwned| {
+            middleware.call(
+                CallRequest::new(method, vec![]),
+                Default::default(),
+                Box::new(move |_, _| async move { Err(error) }.boxed()),
+            )
+        };
+
+        // errors of the caller are not counted
+        for error in [
+            errors::invalid_params("bad"),
+            errors::failed("rate limit exceeded"),
+            errors::invalid_params("bad"),
+        ] {
+            let _ = call("foo", error).await;
+        }
+        assert_eq!(alerting.record("foo", Some("connection closed".to_string())), None);
+
+        // internal errors of upstream or subway are
+        for _ in 0..3 {
+            let _ = call("bar", errors::internal_error("runtime panicked")).await;
+        }
+        assert!(alerting.record("bar", Some("connection closed".to_string())).is_some());
+    }
+}
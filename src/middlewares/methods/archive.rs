@@ -0,0 +1,158 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use jsonrpsee::core::JsonValue;
+use opentelemetry::trace::FutureExt;
+
+use crate::{
+    extensions::archive::Archive,
+    middlewares::{CallRequest, CallResult, Middleware, MiddlewareBuilder, NextFn, RpcMethod, TRACER},
+    utils::{TypeRegistry, TypeRegistryRef},
+};
+
+pub enum BlockParam {
+    HashAt(usize),
+    NumberAt(usize),
+}
+
+pub struct ArchiveMiddleware {
+    archive: Arc<Archive>,
+    block: BlockParam,
+}
+
+impl ArchiveMiddleware {
+    pub fn new(archive: Arc<Archive>, block: BlockParam) -> Self {
+        Self { archive, block }
+    }
+
+    async fn is_historical(&self, params: &[JsonValue]) -> bool {
+        match self.block {
+            BlockParam::HashAt(index) => match params.get(index) {
+                Some(hash) if !hash.is_null() => self.archive.is_historical_hash(hash).await,
+                // latest block
+                _ => false,
+            },
+            BlockParam::NumberAt(index) => params
+                .get(index)
+                .and_then(block_number)
+                .is_some_and(|number| self.archive.is_historical_number(number)),
+        }
+    }
+}
+
+pub fn block_number(value: &JsonValue) -> Option<u64> {
+    match value {
+        JsonValue::Number(n) => n.as_u64(),
+        JsonValue::String(s) => u64::from_str_radix(s.strip_prefix("0x")?, 16).ok(),
+        _ => None,
+    }
+}
+
+#[async_trait]
+impl MiddlewareBuilder<RpcMethod, CallRequest, CallResult> for ArchiveMiddleware {
+    async fn build(
+        method: &RpcMethod,
+        extensions: &TypeRegistryRef,
+    ) -> Option<Box<dyn Middleware<CallRequest, CallResult>>> {
+        let block = method
+            .params
+            .iter()
+            .enumerate()
+            .find_map(|(index, p)| match p.ty.as_str() {
+                "BlockHash" => Some(BlockParam::HashAt(index)),
+                "BlockNumber" => Some(BlockParam::NumberAt(index)),
+                _ => None,
+            })?;
+
+        let archive = extensions.read().await.get::<Archive>()?;
+
+        Some(Box::new(Self::new(archive, block)))
+    }
+}
+
+#[async_trait]
+impl Middleware<CallRequest, CallResult> for ArchiveMiddleware {
+    async fn call(
+        &self,
+        request: CallRequest,
+        context: TypeRegistry,
+        next: NextFn<CallRequest, CallResult>,
+    ) -> CallResult {
+        if !self.is_historical(&request.params).await {
+            return next(request, context).await;
+        }
+
+        async move {
+            tracing::trace!("Routing {} to archive nodes", request.method);
+            self.archive.client().request(&request.method, request.params).await
+        }
+        .with_context(TRACER.context("archive"))
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::FutureExt;
+    use serde_json::json;
+    use tokio::sync::watch;
+
+    use crate::extensions::client::{mock::TestServerBuilder, Client};
+
+    #[tokio::test]
+    async fn routes_historical_calls_to_archive() {
+        let mut builder = TestServerBuilder::new();
+        let mut storage_rx = builder.register_method("state_getStorage");
+        let mut header_rx = builder.register_method("chain_getHeader");
+        let (addr, _server) = builder.build().await;
+        let client = Client::with_endpoints([format!("ws://{addr}")]).unwrap();
+
+        // 0x04 is old, 0x5f is a recent block which was not seen as a head
+        let headers = tokio::spawn(async move {
+            while let Some(req) = header_rx.recv().await {
+                let number = match req.params[0].as_str() {
+                    Some("0x04") => json!("0x04"),
+                    Some("0x5f") => json!("0x5f"),
+                    _ => JsonValue::Null,
+                };
+                req.respond(json!({ "number": number }));
+            }
+        });
+
+        let (head_tx, head_rx) = watch::channel(None);
+        let archive = Arc::new(Archive::new(Arc::new(client), 10).with_heads(head_rx));
+        head_tx.send_replace(Some((json!("0x05"), 100)));
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+
+        let middleware = ArchiveMiddleware::new(archive, BlockParam::HashAt(1));
+        let call = |params: Vec<JsonValue>| {
+            middleware.call(
+                CallRequest::new("state_getStorage", params),
+                Default::default(),
+                Box::new(move |_, _| async move { Ok(json!("full node")) }.boxed()),
+            )
+        };
+
+        assert_eq!(call(vec![json!("0x01")]).await, Ok(json!("full node")));
+        assert_eq!(call(vec![json!("0x01"), json!("0x05")]).await, Ok(json!("full node")));
+        assert_eq!(call(vec![json!("0x01"), json!("0x5f")]).await, Ok(json!("full node")));
+
+        let (res, _) = tokio::join!(call(vec![json!("0x01"), json!("0x04")]), async {
+            let req = storage_rx.recv().await.unwrap();
+            assert_eq!(req.params, json!(["0x01", "0x04"]));
+            req.respond(json!("archive node"));
+        });
+        assert_eq!(res, Ok(json!("archive node")));
+
+        headers.abort();
+    }
+
+    #[test]
+    fn parses_block_numbers() {
+        assert_eq!(block_number(&json!(16)), Some(16));
+        assert_eq!(block_number(&json!("0x10")), Some(16));
+        assert_eq!(block_number(&json!("16")), None);
+    }
+}
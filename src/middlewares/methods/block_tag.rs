@@ -43,12 +43,22 @@ impl BlockTagMiddleware {
 
     async fn replace(&self, mut request: CallRequest, mut context: TypeRegistry) -> (CallRequest, TypeRegistry) {
         let maybe_value = {
-            if let Some(param) = request.params.get(self.index).cloned() {
+            let param = match request.params.get(self.index) {
+                Some(param) => Some(param.clone()),
+                // an omitted tag means latest, inject the number so the call can be cached
+                None if request.params.len() == self.index => Some("latest".into()),
+                None => None,
+            };
+            if let Some(param) = param {
                 if !param.is_string() {
                     // nothing to do here
                     return (request, context);
                 }
-                match param.as_str().unwrap_or_default() {
+                let tag = match param.as_str().unwrap_or_default() {
+                    "latest" if request.features.finalized => "finalized",
+                    tag => tag,
+                };
+                match tag {
                     "finalized" => {
                         let finalized_head = self.api.current_finalized_head();
                         if let Some((_, finalized_number)) = finalized_head {
@@ -81,7 +91,9 @@ impl BlockTagMiddleware {
                 request.params,
                 (self.index, &value),
             );
-            request.params.remove(self.index);
+            if self.index < request.params.len() {
+                request.params.remove(self.index);
+            }
             request.params.insert(self.index, value);
         }
 
@@ -176,12 +188,20 @@ mod tests {
                 ty: "StorageKey".to_string(),
                 optional: false,
                 inject: false,
+                pattern: None,
+                resolve_number: false,
+                default: None,
+                coerce: None,
             },
             MethodParam {
                 name: "at".to_string(),
                 ty: "BlockTag".to_string(),
                 optional: false,
                 inject: true,
+                pattern: None,
+                resolve_number: false,
+                default: None,
+                coerce: None,
             },
         ])
         .await;
@@ -209,6 +229,55 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn injects_omitted_tag() {
+        let (middleware, mut context) = create_block_tag_middleware(vec![
+            MethodParam {
+                name: "address".to_string(),
+                ty: "Bytes".to_string(),
+                optional: false,
+                inject: false,
+                pattern: None,
+                resolve_number: false,
+                default: None,
+                coerce: None,
+            },
+            MethodParam {
+                name: "block".to_string(),
+                ty: "BlockTag".to_string(),
+                optional: true,
+                inject: true,
+                pattern: None,
+                resolve_number: false,
+                default: None,
+                coerce: None,
+            },
+        ])
+        .await;
+
+        context
+            .send_current_block(json!({ "number": "0x4321", "hash": "0x00" }))
+            .await;
+
+        assert_eq!(
+            middleware
+                .call(
+                    CallRequest::new("eth_getBalance", vec![json!("0x1234")]),
+                    Default::default(),
+                    Box::new(move |req: CallRequest, _| {
+                        async move {
+                            assert_eq!(req.params, vec![json!("0x1234"), json!("0x4321")]);
+                            Ok(json!("0x1111"))
+                        }
+                        .boxed()
+                    }),
+                )
+                .await
+                .unwrap(),
+            json!("0x1111")
+        );
+    }
+
     #[tokio::test]
     async fn works() {
         let (middleware, mut context) = create_block_tag_middleware(vec![
@@ -217,12 +286,20 @@ mod tests {
                 ty: "StorageKey".to_string(),
                 optional: false,
                 inject: false,
+                pattern: None,
+                resolve_number: false,
+                default: None,
+                coerce: None,
             },
             MethodParam {
                 name: "at".to_string(),
                 ty: "BlockTag".to_string(),
                 optional: false,
                 inject: true,
+                pattern: None,
+                resolve_number: false,
+                default: None,
+                coerce: None,
             },
         ])
         .await;
@@ -0,0 +1,228 @@
+use std::{collections::HashMap, sync::Arc};
+
+use async_trait::async_trait;
+use opentelemetry::trace::FutureExt;
+use tokio::{
+    runtime::{Builder, Handle, Runtime},
+    sync::Semaphore,
+    task::AbortHandle,
+};
+
+use crate::{
+    config::MethodGroup,
+    middlewares::{CallRequest, CallResult, Middleware, MiddlewareBuilder, NextFn, RpcMethod, TRACER},
+    utils::{errors, TypeRegistry, TypeRegistryRef},
+};
+
+/// Dedicated runtimes of the method groups with `runtime_threads` and concurrency budgets of the ones with
+/// `max_concurrent_calls`, shared by the middlewares of their methods.
+///
+/// A dedicated runtime only polls the middlewares after the bulkhead, e.g. caching and encoding of responses.
+/// Upstream I/O is still driven by the tasks of the shared client on the main runtime, so only
+/// `max_concurrent_calls` bounds the share of upstream a group takes.
+pub struct MethodGroupRuntimes {
+    runtimes: Vec<Runtime>,
+    // method -> runtime of its group
+    handles: HashMap<String, Handle>,
+    // method -> in-flight calls of its group
+    limiters: HashMap<String, Arc<Semaphore>>,
+}
+
+impl MethodGroupRuntimes {
+    pub fn new(groups: &[MethodGroup]) -> std::io::Result<Self> {
+        let mut runtimes = Vec::new();
+        let mut handles = HashMap::new();
+        let mut limiters = HashMap::new();
+
+        for group in groups {
+            if let Some(max) = group.max_concurrent_calls {
+                let limiter = Arc::new(Semaphore::new(max.max(1)));
+                for method in &group.methods {
+                    limiters.entry(method.clone()).or_insert_with(|| limiter.clone());
+                }
+            }
+            let Some(threads) = group.runtime_threads else {
+                continue;
+            };
+            let runtime = Builder::new_multi_thread()
+                .worker_threads(threads.max(1))
+                .thread_name(format!("subway-{}", group.name))
+                .enable_all()
+                .build()?;
+            for method in &group.methods {
+                handles
+                    .entry(method.clone())
+                    .or_insert_with(|| runtime.handle().clone());
+            }
+            runtimes.push(runtime);
+        }
+
+        Ok(Self {
+            runtimes,
+            handles,
+            limiters,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.runtimes.is_empty() && self.limiters.is_empty()
+    }
+
+    pub fn handle(&self, method: &str) -> Option<Handle> {
+        self.handles.get(method).cloned()
+    }
+
+    pub fn limiter(&self, method: &str) -> Option<Arc<Semaphore>> {
+        self.limiters.get(method).cloned()
+    }
+}
+
+impl Drop for MethodGroupRuntimes {
+    fn drop(&mut self) {
+        // dropping a runtime blocks, which is not allowed from within the main runtime
+        for runtime in self.runtimes.drain(..) {
+            runtime.shutdown_background();
+        }
+    }
+}
+
+struct AbortOnDrop(AbortHandle);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+pub struct BulkheadMiddleware {
+    handle: Option<Handle>,
+    limiter: Option<Arc<Semaphore>>,
+}
+
+impl BulkheadMiddleware {
+    pub fn new(handle: Option<Handle>, limiter: Option<Arc<Semaphore>>) -> Self {
+        Self { handle, limiter }
+    }
+}
+
+#[async_trait]
+impl MiddlewareBuilder<RpcMethod, CallRequest, CallResult> for BulkheadMiddleware {
+    async fn build(
+        method: &RpcMethod,
+        extensions: &TypeRegistryRef,
+    ) -> Option<Box<dyn Middleware<CallRequest, CallResult>>> {
+        let runtimes = extensions.read().await.get::<MethodGroupRuntimes>()?;
+        let handle = runtimes.handle(&method.method);
+        let limiter = runtimes.limiter(&method.method);
+        if handle.is_none() && limiter.is_none() {
+            return None;
+        }
+        Some(Box::new(BulkheadMiddleware::new(handle, limiter)))
+    }
+}
+
+#[async_trait]
+impl Middleware<CallRequest, CallResult> for BulkheadMiddleware {
+    async fn call(
+        &self,
+        request: CallRequest,
+        context: TypeRegistry,
+        next: NextFn<CallRequest, CallResult>,
+    ) -> CallResult {
+        async move {
+            // waits for a call of the group to finish, until the request times out
+            let _permit = match self.limiter {
+                Some(ref limiter) => Some(limiter.acquire().await.map_err(errors::internal_error)?),
+                None => None,
+            };
+            let Some(ref handle) = self.handle else {
+                return next(request, context).await;
+            };
+            // task locals of the request are already in the context, the trace context has to be carried over
+            let task = handle.spawn(next(request, context).with_current_context());
+            // stop the task when the call is dropped, e.g. on timeout
+            let _abort = AbortOnDrop(task.abort_handle());
+            task.await.unwrap_or_else(|e| Err(errors::internal_error(e)))
+        }
+        .with_context(TRACER.context("bulkhead"))
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+    use opentelemetry::trace::TraceContextExt;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn runs_group_methods_on_dedicated_runtime() {
+        let runtimes = MethodGroupRuntimes::new(&[
+            MethodGroup {
+                name: "heavy".to_string(),
+                methods: vec!["eth_getLogs".to_string()],
+                runtime_threads: Some(1),
+                max_concurrent_calls: None,
+            },
+            MethodGroup {
+                name: "light".to_string(),
+                methods: vec!["eth_blockNumber".to_string()],
+                runtime_threads: None,
+                max_concurrent_calls: None,
+            },
+        ])
+        .unwrap();
+        assert!(runtimes.handle("eth_blockNumber").is_none());
+
+        let middleware = BulkheadMiddleware::new(runtimes.handle("eth_getLogs"), None);
+        let result = middleware
+            .call(
+                CallRequest::new("eth_getLogs", vec![]),
+                Default::default(),
+                Box::new(|_, _| {
+                    async move {
+                        let thread = std::thread::current();
+                        // still within the span of the bulkhead
+                        let traced = opentelemetry::Context::current().has_active_span();
+                        Ok(json!([thread.name(), traced]))
+                    }
+                    .boxed()
+                }),
+            )
+            .await;
+        assert_eq!(result, Ok(json!(["subway-heavy", true])));
+    }
+
+    #[tokio::test]
+    async fn limits_concurrent_calls_of_group() {
+        let runtimes = MethodGroupRuntimes::new(&[MethodGroup {
+            name: "heavy".to_string(),
+            methods: vec!["eth_getLogs".to_string(), "trace_block".to_string()],
+            runtime_threads: None,
+            max_concurrent_calls: Some(1),
+        }])
+        .unwrap();
+        assert!(!runtimes.is_empty());
+        assert!(runtimes.handle("eth_getLogs").is_none());
+
+        let limiter = runtimes.limiter("eth_getLogs").unwrap();
+        assert!(Arc::ptr_eq(&limiter, &runtimes.limiter("trace_block").unwrap()));
+
+        // the only permit of the group is taken
+        let permit = limiter.clone().acquire_owned().await.unwrap();
+        let middleware = BulkheadMiddleware::new(None, Some(limiter));
+        let call = middleware.call(
+            CallRequest::new("trace_block", vec![]),
+            Default::default(),
+            Box::new(|_, _| async move { Ok(json!("ok")) }.boxed()),
+        );
+        tokio::pin!(call);
+        assert!(tokio::time::timeout(std::time::Duration::from_millis(50), &mut call)
+            .await
+            .is_err());
+
+        drop(permit);
+        assert_eq!(call.await, Ok(json!("ok")));
+    }
+}
@@ -1,26 +1,301 @@
-use std::num::NonZeroUsize;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use async_trait::async_trait;
 use blake2::Blake2b512;
 use futures::FutureExt as _;
+use jsonrpsee::{core::JsonValue, types::ErrorObjectOwned};
 use opentelemetry::trace::FutureExt;
+use tokio::{sync::watch, task::JoinHandle};
 
 use crate::{
     config::CacheParams,
-    extensions::cache::Cache as CacheExtension,
-    middlewares::{CallRequest, CallResult, Middleware, MiddlewareBuilder, NextFn, RpcMethod, TRACER},
+    extensions::{
+        access_log::CacheStatus,
+        api::HeadTracker,
+        cache::Cache as CacheExtension,
+        client::{Client, ForwardedHeaders},
+        metrics,
+    },
+    middlewares::{
+        methods::{
+            archive::{block_number, BlockParam},
+            upstream::UpstreamError,
+        },
+        CallRequest, CallResult, Middleware, MiddlewareBuilder, NextFn, RpcMethod, TRACER,
+    },
     utils::{Cache, CacheKey, TypeRegistry, TypeRegistryRef},
 };
 
 pub struct BypassCache(pub bool);
 
+/// Derives the cache key of a call, shared with the `cache_by_block` middleware so both store entries
+/// under the same keys.
+#[derive(Clone, Default)]
+pub struct CacheKeys {
+    normalize_key: bool,
+    // set for runtime dependent methods
+    spec_version_client: Option<Arc<Client>>,
+}
+
+impl CacheKeys {
+    /// None while the runtime version of a runtime dependent method is unknown, as a cached response may be stale.
+    pub fn key(
+        &self,
+        method: &String,
+        params: &[JsonValue],
+        headers: Option<&ForwardedHeaders>,
+    ) -> Option<CacheKey<Blake2b512>> {
+        let normalized;
+        let params = if self.normalize_key {
+            normalized = normalize_params(params);
+            &normalized
+        } else {
+            params
+        };
+        let key = match self.spec_version_client {
+            Some(ref client) => CacheKey::with_spec_version(method, params, client.spec_version()?),
+            None => CacheKey::new(method, params),
+        };
+        // upstream may answer differently depending on the forwarded credentials
+        Some(match headers {
+            Some(headers) if !headers.is_empty() => key.scoped(&headers.cache_scope()),
+            _ => key,
+        })
+    }
+}
+
+/// Cache of a method with a `block_pointer`, shared with its `cache_by_block` middleware.
+#[derive(Clone)]
+pub struct MethodCache {
+    pub cache: Cache<Blake2b512>,
+    pub keys: CacheKeys,
+    // set for methods caching only responses at finalized blocks
+    pub finality: Option<Arc<Finality>>,
+}
+
+/// Caches of the methods with a `block_pointer`.
+#[derive(Default)]
+pub struct MethodCaches(Mutex<HashMap<String, MethodCache>>);
+
+impl MethodCaches {
+    pub fn get(&self, method: &str) -> Option<MethodCache> {
+        let caches = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        caches.get(method).cloned()
+    }
+
+    fn insert(&self, method: &str, cache: MethodCache) {
+        let mut caches = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        caches.insert(method.to_string(), cache);
+    }
+}
+
+/// Number of finalized head hashes remembered to tell finalized blocks by hash.
+const FINALIZED_HASHES: usize = 4096;
+
+/// Latest finalized head hashes, the oldest one is forgotten first.
+#[derive(Default)]
+struct FinalizedHashes {
+    hashes: HashSet<String>,
+    order: VecDeque<String>,
+}
+
+impl FinalizedHashes {
+    fn insert(&mut self, hash: &str) {
+        let hash = hash.to_ascii_lowercase();
+        if !self.hashes.insert(hash.clone()) {
+            return;
+        }
+        self.order.push_back(hash);
+        if self.order.len() > FINALIZED_HASHES {
+            if let Some(oldest) = self.order.pop_front() {
+                self.hashes.remove(&oldest);
+            }
+        }
+    }
+
+    fn contains(&self, hash: &str) -> bool {
+        self.hashes.contains(&hash.to_ascii_lowercase())
+    }
+}
+
+/// Tells whether the block param of a call refers to a finalized block.
+pub struct Finality {
+    block: BlockParam,
+    finalized: watch::Receiver<Option<(JsonValue, u64)>>,
+    finalized_hashes: Arc<Mutex<FinalizedHashes>>,
+    task: JoinHandle<()>,
+}
+
+impl Drop for Finality {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl Finality {
+    pub fn new(block: BlockParam, finalized: watch::Receiver<Option<(JsonValue, u64)>>) -> Self {
+        let finalized_hashes = Arc::new(Mutex::new(FinalizedHashes::default()));
+        let task = {
+            let finalized_hashes = finalized_hashes.clone();
+            let mut finalized = finalized.clone();
+            tokio::spawn(async move {
+                loop {
+                    let head = finalized.borrow_and_update().clone();
+                    if let Some((JsonValue::String(hash), _)) = head {
+                        let mut finalized_hashes = finalized_hashes.lock().unwrap_or_else(|e| e.into_inner());
+                        finalized_hashes.insert(&hash);
+                    }
+                    if finalized.changed().await.is_err() {
+                        break;
+                    }
+                }
+            })
+        };
+
+        Self {
+            block,
+            finalized,
+            finalized_hashes,
+            task,
+        }
+    }
+
+    /// Hashes are finalized only if they were seen as the finalized head, others may be on a fork or unknown.
+    /// Missing or `null` block params stand for the latest block and are never finalized.
+    pub fn is_finalized(&self, params: &[JsonValue]) -> bool {
+        let Some(finalized_number) = self.finalized.borrow().as_ref().map(|(_, number)| *number) else {
+            return false;
+        };
+        match self.block {
+            BlockParam::HashAt(index) => match params.get(index) {
+                Some(JsonValue::String(hash)) => {
+                    let finalized_hashes = self.finalized_hashes.lock().unwrap_or_else(|e| e.into_inner());
+                    finalized_hashes.contains(hash)
+                }
+                _ => false,
+            },
+            BlockParam::NumberAt(index) => params
+                .get(index)
+                .and_then(block_number)
+                .is_some_and(|number| number <= finalized_number),
+        }
+    }
+}
+
 pub struct CacheMiddleware {
     cache: Cache<Blake2b512>,
+    keys: CacheKeys,
+    // a slower lookup counts as a miss
+    lookup_timeout: Option<Duration>,
+    // upstream errors, kept apart so they can not evict responses
+    negative_cache: Option<Cache<Blake2b512>>,
+    // clears the caches on every new finalized head
+    invalidation_task: Option<JoinHandle<()>>,
+    // set for methods caching only responses at finalized blocks
+    finality: Option<Arc<Finality>>,
+    // responses at blocks not finalized yet, with a short ttl
+    unfinalized_cache: Option<Cache<Blake2b512>>,
+}
+
+impl Drop for CacheMiddleware {
+    fn drop(&mut self) {
+        if let Some(task) = self.invalidation_task.take() {
+            task.abort();
+        }
+    }
 }
 
 impl CacheMiddleware {
     pub fn new(cache: Cache<Blake2b512>) -> Self {
-        Self { cache }
+        Self {
+            cache,
+            keys: Default::default(),
+            lookup_timeout: None,
+            negative_cache: None,
+            invalidation_task: None,
+            finality: None,
+            unfinalized_cache: None,
+        }
+    }
+
+    /// Caches only responses at finalized blocks, the others go to `unfinalized_cache` if any.
+    pub fn with_finality(mut self, finality: Finality, unfinalized_cache: Option<Cache<Blake2b512>>) -> Self {
+        self.finality = Some(Arc::new(finality));
+        self.unfinalized_cache = unfinalized_cache;
+        self
+    }
+
+    /// Keys cached responses by normalized params, see `normalize_params`.
+    pub fn with_key_normalization(mut self) -> Self {
+        self.keys.normalize_key = true;
+        self
+    }
+
+    /// Drops all cached responses and errors whenever a new finalized head is received.
+    /// Shared backend entries are keyed by the finalized block number instead of being removed,
+    /// so instances following the same chain keep sharing them.
+    pub fn with_invalidation(mut self, mut finalized_head: watch::Receiver<Option<(JsonValue, u64)>>) -> Self {
+        let cache = self.cache.clone();
+        let negative_cache = self.negative_cache.clone();
+        self.invalidation_task = Some(tokio::spawn(async move {
+            while finalized_head.changed().await.is_ok() {
+                let Some(number) = finalized_head.borrow_and_update().as_ref().map(|(_, number)| *number) else {
+                    continue;
+                };
+                cache.start_generation(number).await;
+                if let Some(ref negative_cache) = negative_cache {
+                    negative_cache.start_generation(number).await;
+                }
+            }
+        }));
+        self
+    }
+
+    /// Caches upstream errors in the given cache, repeated requests get the error without calling upstream.
+    pub fn with_negative_cache(mut self, cache: Cache<Blake2b512>) -> Self {
+        self.negative_cache = Some(cache);
+        self
+    }
+
+    /// Gives up on reading the disk tier or shared backend after `timeout` and fetches the value instead.
+    pub fn with_lookup_timeout(mut self, timeout: Duration) -> Self {
+        self.lookup_timeout = Some(timeout);
+        self
+    }
+
+    /// Keys cached responses by the runtime spec version tracked by the client.
+    pub fn with_spec_version(mut self, client: Arc<Client>) -> Self {
+        client.track_spec_version();
+        self.keys.spec_version_client = Some(client);
+        self
+    }
+}
+
+/// Params of semantically equal calls, e.g. `["0xABC", null]` and `["0xabc"]`, normalize to the same value:
+/// hex strings are lowercased and trailing `null` params, which stand for omitted optional params, are dropped.
+/// Object keys are already sorted when serialized, so nested objects hash the same regardless of their order.
+pub fn normalize_params(params: &[JsonValue]) -> Vec<JsonValue> {
+    let len = params.len() - params.iter().rev().take_while(|p| p.is_null()).count();
+    params[..len].iter().map(normalize_value).collect()
+}
+
+fn normalize_value(value: &JsonValue) -> JsonValue {
+    match value {
+        JsonValue::String(s) if s.starts_with("0x") || s.starts_with("0X") => {
+            if s[2..].chars().all(|c| c.is_ascii_hexdigit()) {
+                JsonValue::String(s.to_ascii_lowercase())
+            } else {
+                value.clone()
+            }
+        }
+        JsonValue::Array(values) => JsonValue::Array(values.iter().map(normalize_value).collect()),
+        JsonValue::Object(map) => JsonValue::Object(map.iter().map(|(k, v)| (k.clone(), normalize_value(v))).collect()),
+        _ => value.clone(),
     }
 }
 
@@ -48,16 +323,144 @@ impl MiddlewareBuilder<RpcMethod, CallRequest, CallResult> for CacheMiddleware {
             Some(CacheParams {
                 ttl_seconds: Some(0), ..
             }) => None,
+            // finalized blocks do not change
+            Some(CacheParams {
+                ttl_seconds: None,
+                finalized_only: true,
+                ..
+            }) => None,
             Some(CacheParams { ttl_seconds, .. }) => ttl_seconds.or(cache_ext.config.default_ttl_seconds),
             None => cache_ext.config.default_ttl_seconds,
         };
 
-        let cache = Cache::new(
-            NonZeroUsize::new(size)?,
-            ttl_seconds.map(std::time::Duration::from_secs),
-        );
+        let size = NonZeroUsize::new(size)?;
+        let ttl = ttl_seconds.map(std::time::Duration::from_secs);
+        let cache = match cache_ext.config.disk_spillover_path {
+            Some(ref path) => {
+                let dir = std::path::Path::new(path).join(&method.method);
+                Cache::with_disk_spillover(size, ttl, dir, cache_ext.config.disk_spillover_max_entries)
+                    .expect("Failed to create cache spillover directory")
+            }
+            None => Cache::new(size, ttl),
+        };
+        let cache = match cache_ext.shared_backend(&method.method) {
+            Some(backend) => cache.with_shared_backend(backend),
+            None => cache,
+        };
+
+        cache_ext.register(&method.method, cache.clone());
+        let mut middleware = Self::new(cache);
 
-        Some(Box::new(Self::new(cache)))
+        if let Some(CacheParams {
+            normalize_key: true, ..
+        }) = method.cache
+        {
+            middleware = middleware.with_key_normalization();
+        }
+
+        if let Some(CacheParams {
+            lookup_timeout_ms: Some(timeout),
+            ..
+        }) = method.cache
+        {
+            middleware = middleware.with_lookup_timeout(Duration::from_millis(timeout));
+        }
+
+        let negative_caching = &cache_ext.config.negative_caching;
+        let cache_errors = method.cache.as_ref().and_then(|c| c.cache_errors);
+        if cache_errors.unwrap_or(negative_caching.enabled) {
+            if let Some(size) = NonZeroUsize::new(cache_ext.config.negative_cache_size) {
+                let ttl_secs = method.cache.as_ref().and_then(|c| c.error_ttl_seconds);
+                let ttl = Duration::from_secs(ttl_secs.unwrap_or(negative_caching.ttl_secs));
+                middleware = middleware.with_negative_cache(Cache::new(size, Some(ttl)));
+            }
+        }
+
+        if let Some(CacheParams {
+            invalidate_on_new_block: true,
+            ..
+        }) = method.cache
+        {
+            let head_tracker = HeadTracker::from_registry(&*extensions.read().await);
+            match head_tracker.map(|heads| heads.finalized_head_updates()) {
+                Some(finalized_head) => middleware = middleware.with_invalidation(finalized_head),
+                None => tracing::warn!(
+                    "{} has invalidate_on_new_block but no substrate_api or eth_api to follow finalized heads",
+                    method.method
+                ),
+            }
+        }
+
+        if let Some(CacheParams {
+            finalized_only: true,
+            unfinalized_ttl_seconds,
+            ..
+        }) = method.cache
+        {
+            let block = method
+                .params
+                .iter()
+                .enumerate()
+                .find_map(|(index, p)| match p.ty.as_str() {
+                    "BlockHash" => Some(BlockParam::HashAt(index)),
+                    "BlockNumber" => Some(BlockParam::NumberAt(index)),
+                    _ => None,
+                });
+            let Some(block) = block else {
+                tracing::warn!(
+                    "{} has finalized_only but no BlockHash or BlockNumber param, not caching",
+                    method.method
+                );
+                return None;
+            };
+            let Some(heads) = HeadTracker::from_registry(&*extensions.read().await) else {
+                tracing::warn!(
+                    "{} has finalized_only but no substrate_api or eth_api to follow finalized heads, not caching",
+                    method.method
+                );
+                return None;
+            };
+            let finality = Finality::new(block, heads.finalized_head_updates());
+            let unfinalized_cache = unfinalized_ttl_seconds.map(|ttl| Cache::new(size, Some(Duration::from_secs(ttl))));
+            middleware = middleware.with_finality(finality, unfinalized_cache);
+        }
+
+        // responses keyed by spec version can not be found by block
+        if let Some(CacheParams {
+            block_pointer: Some(_),
+            runtime_dependent: false,
+            ..
+        }) = method.cache
+        {
+            let mut extensions = extensions.write().await;
+            if !extensions.has::<MethodCaches>() {
+                extensions.insert(MethodCaches::default());
+            }
+            let caches = extensions.get::<MethodCaches>().expect("inserted above");
+            caches.insert(
+                &method.method,
+                MethodCache {
+                    cache: middleware.cache.clone(),
+                    keys: middleware.keys.clone(),
+                    finality: middleware.finality.clone(),
+                },
+            );
+        }
+
+        if let Some(CacheParams {
+            runtime_dependent: true,
+            ..
+        }) = method.cache
+        {
+            let client = extensions
+                .read()
+                .await
+                .get::<Client>()
+                .expect("Client extension not found");
+            return Some(Box::new(middleware.with_spec_version(client)));
+        }
+
+        Some(Box::new(middleware))
     }
 }
 
@@ -66,27 +469,73 @@ impl Middleware<CallRequest, CallResult> for CacheMiddleware {
     async fn call(
         &self,
         request: CallRequest,
-        context: TypeRegistry,
+        mut context: TypeRegistry,
         next: NextFn<CallRequest, CallResult>,
     ) -> CallResult {
         async move {
-            let bypass_cache = context.get::<BypassCache>().map(|v| v.0).unwrap_or(false);
+            let bypass_cache = request.features.no_cache || context.get::<BypassCache>().map(|v| v.0).unwrap_or(false);
             if bypass_cache {
                 return next(request, context).await;
             }
 
-            let key = CacheKey::<Blake2b512>::new(&request.method, &request.params);
+            // upstream still gets the params as sent
+            let headers = context.get::<ForwardedHeaders>();
+            let Some(key) = self.keys.key(&request.method, &request.params, headers.as_deref()) else {
+                return next(request, context).await;
+            };
+
+            let cache = match self.finality {
+                Some(ref finality) if !finality.is_finalized(&request.params) => match self.unfinalized_cache {
+                    Some(ref cache) => cache,
+                    None => return next(request, context).await,
+                },
+                _ => &self.cache,
+            };
+
+            // shared with the access log if the request is logged
+            let cache_status = context.get::<CacheStatus>().unwrap_or_default();
+            cache_status.hit();
+            let method = request.method.clone();
+
+            if let Some(ref negative_cache) = self.negative_cache {
+                if let Some(error) = negative_cache.get(&key).await.and_then(error_from_json) {
+                    metrics::cache_lookups().with_label_values(&[&method, "hit"]).inc();
+                    return Err(error);
+                }
+            }
+
+            // errors raised by subway, e.g. on timeouts, are transient and not cached
+            let upstream_error = Arc::new(UpstreamError::default());
+            context.insert_raw(upstream_error.clone());
+
+            let fetch = {
+                let cache_status = cache_status.clone();
+                move || {
+                    cache_status.miss();
+                    next(request, context).boxed()
+                }
+            };
 
-            let result = self
-                .cache
-                .get_or_insert_with(key.clone(), || next(request, context).boxed())
-                .await;
+            let result = match cache.lookup_with_timeout(&key, self.lookup_timeout).await {
+                Some(value) => value,
+                None => cache.insert_with(key.clone(), fetch).await,
+            };
 
             if let Ok(ref value) = result {
                 // avoid caching null value because it usually means data not available
                 // but it could be available in the future
                 if value.is_null() {
-                    self.cache.remove(&key).await;
+                    cache.discard(&key).await;
+                }
+            }
+
+            if let Some(lookup) = cache_status.as_str() {
+                metrics::cache_lookups().with_label_values(&[&method, lookup]).inc();
+            }
+
+            if let (Err(ref error), Some(ref negative_cache)) = (&result, &self.negative_cache) {
+                if upstream_error.is_set() {
+                    negative_cache.insert(key, error_to_json(error)).await;
                 }
             }
 
@@ -97,6 +546,21 @@ impl Middleware<CallRequest, CallResult> for CacheMiddleware {
     }
 }
 
+fn error_to_json(error: &ErrorObjectOwned) -> JsonValue {
+    serde_json::json!({
+        "code": error.code(),
+        "message": error.message(),
+        "data": error.data(),
+    })
+}
+
+fn error_from_json(value: JsonValue) -> Option<ErrorObjectOwned> {
+    let code = value.get("code")?.as_i64()?;
+    let message = value.get("message")?.as_str()?;
+    let data = value.get("data").filter(|d| !d.is_null()).cloned();
+    Some(ErrorObjectOwned::owned(code as i32, message, data))
+}
+
 #[cfg(test)]
 mod tests {
     use futures::FutureExt;
@@ -106,6 +570,126 @@ mod tests {
     use std::time::Duration;
 
     use super::*;
+    use crate::utils::errors;
+
+    #[tokio::test]
+    async fn normalized_params_share_cache_entry() {
+        let cache = Cache::new(NonZeroUsize::try_from(10).unwrap(), None);
+        let middleware = CacheMiddleware::new(cache).with_key_normalization();
+
+        let res = middleware
+            .call(
+                CallRequest::new("state_getStorage", vec![json!("0xABC"), JsonValue::Null]),
+                Default::default(),
+                Box::new(move |request, _| {
+                    // upstream gets the params as sent
+                    assert_eq!(request.params, vec![json!("0xABC"), JsonValue::Null]);
+                    async move { Ok(json!(1)) }.boxed()
+                }),
+            )
+            .await;
+        assert_eq!(res.unwrap(), json!(1));
+
+        let res = middleware
+            .call(
+                CallRequest::new("state_getStorage", vec![json!("0xabc")]),
+                Default::default(),
+                Box::new(move |_, _| async move { panic!() }.boxed()),
+            )
+            .await;
+        assert_eq!(res.unwrap(), json!(1));
+    }
+
+    #[test]
+    fn normalize_params_works() {
+        assert_eq!(
+            normalize_params(&[json!("0xABcd"), json!({ "b": "0XFF", "a": [1, "Alice"] }), json!(null)]),
+            vec![json!("0xabcd"), json!({ "a": [1, "Alice"], "b": "0xff" })]
+        );
+        assert_eq!(normalize_params(&[json!(null), json!(null)]), Vec::<JsonValue>::new());
+        assert_eq!(
+            normalize_params(&[json!("0xZZ"), json!(null), json!(1)]),
+            vec![json!("0xZZ"), json!(null), json!(1)]
+        );
+    }
+
+    #[tokio::test]
+    async fn invalidates_on_new_finalized_head() {
+        let (finalized_head_tx, finalized_head_rx) = watch::channel::<Option<(JsonValue, u64)>>(None);
+        let cache = Cache::new(NonZeroUsize::try_from(10).unwrap(), None);
+        let middleware = CacheMiddleware::new(cache.clone()).with_invalidation(finalized_head_rx);
+
+        let res = middleware
+            .call(
+                CallRequest::new("state_getStorage", vec![json!("0x01")]),
+                Default::default(),
+                Box::new(move |_, _| async move { Ok(json!(1)) }.boxed()),
+            )
+            .await;
+        assert_eq!(res.unwrap(), json!(1));
+
+        // cached until a new block is finalized
+        let res = middleware
+            .call(
+                CallRequest::new("state_getStorage", vec![json!("0x01")]),
+                Default::default(),
+                Box::new(move |_, _| async move { panic!() }.boxed()),
+            )
+            .await;
+        assert_eq!(res.unwrap(), json!(1));
+
+        finalized_head_tx.send_replace(Some((json!("0xabcd"), 0x10)));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let res = middleware
+            .call(
+                CallRequest::new("state_getStorage", vec![json!("0x01")]),
+                Default::default(),
+                Box::new(move |_, _| async move { Ok(json!(2)) }.boxed()),
+            )
+            .await;
+        assert_eq!(res.unwrap(), json!(2));
+    }
+
+    #[tokio::test]
+    async fn forwarded_headers_have_own_entries() {
+        let middleware = CacheMiddleware::new(Cache::new(NonZeroUsize::new(3).unwrap(), None));
+        let context = |token: &str| {
+            let mut headers = http::HeaderMap::new();
+            headers.insert("authorization", token.parse().unwrap());
+            let mut context = TypeRegistry::new();
+            context.insert(ForwardedHeaders::from_request(&["authorization".to_string()], &headers));
+            context
+        };
+
+        let res = middleware
+            .call(
+                CallRequest::new("test", vec![]),
+                context("Bearer a"),
+                Box::new(move |_, _| async move { Ok(json!("a")) }.boxed()),
+            )
+            .await;
+        assert_eq!(res.unwrap(), json!("a"));
+
+        // not served the response fetched with other credentials
+        let res = middleware
+            .call(
+                CallRequest::new("test", vec![]),
+                context("Bearer b"),
+                Box::new(move |_, _| async move { Ok(json!("b")) }.boxed()),
+            )
+            .await;
+        assert_eq!(res.unwrap(), json!("b"));
+
+        let res = middleware
+            .call(
+                CallRequest::new("test", vec![]),
+                context("Bearer a"),
+                Box::new(move |_, _| async move { panic!() }.boxed()),
+            )
+            .await;
+        assert_eq!(res.unwrap(), json!("a"));
+    }
 
     #[tokio::test]
     async fn handle_ok_resp() {
@@ -187,7 +771,8 @@ mod tests {
 
     #[tokio::test]
     async fn should_not_cache_null() {
-        let middleware = CacheMiddleware::new(Cache::new(NonZeroUsize::try_from(3).unwrap(), None));
+        let cache = Cache::new(NonZeroUsize::try_from(3).unwrap(), None);
+        let middleware = CacheMiddleware::new(cache.clone());
 
         let res = middleware
             .call(
@@ -210,6 +795,65 @@ mod tests {
             )
             .await;
         assert_eq!(res.unwrap(), json!(2));
+        // dropping the null value is not an eviction
+        assert_eq!(cache.evictions(), 0);
+    }
+
+    #[tokio::test]
+    async fn negative_cache_works() {
+        let negative_cache = Cache::new(NonZeroUsize::new(3).unwrap(), Some(Duration::from_millis(10)));
+        let middleware = CacheMiddleware::new(Cache::new(NonZeroUsize::new(3).unwrap(), None))
+            .with_negative_cache(negative_cache.clone());
+        let unknown_block = || ErrorObjectOwned::owned(4003, "Client error: UnknownBlock", Some("0xdead"));
+
+        let res = middleware
+            .call(
+                CallRequest::new("test", vec![json!("0xdead")]),
+                Default::default(),
+                Box::new(move |_, context: TypeRegistry| {
+                    async move {
+                        context.get::<UpstreamError>().unwrap().set();
+                        Err(unknown_block())
+                    }
+                    .boxed()
+                }),
+            )
+            .await;
+        assert_eq!(res, Err(unknown_block()));
+
+        // error served from the negative cache
+        let res = middleware
+            .call(
+                CallRequest::new("test", vec![json!("0xdead")]),
+                Default::default(),
+                Box::new(move |_, _| async move { panic!() }.boxed()),
+            )
+            .await;
+        assert_eq!(res, Err(unknown_block()));
+
+        // wait for the error to expire
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let res = middleware
+            .call(
+                CallRequest::new("test", vec![json!("0xdead")]),
+                Default::default(),
+                Box::new(move |_, _| async move { Ok(json!(1)) }.boxed()),
+            )
+            .await;
+        assert_eq!(res.unwrap(), json!(1));
+
+        // errors raised by subway are not marked by the upstream middleware and not cached
+        let res = middleware
+            .call(
+                CallRequest::new("test", vec![json!(22)]),
+                Default::default(),
+                Box::new(move |_, _| async move { Err(errors::internal_error("connection closed")) }.boxed()),
+            )
+            .await;
+        assert!(res.is_err());
+        let key = CacheKey::new(&"test".to_string(), &[json!(22)]);
+        assert_eq!(negative_cache.get(&key).await, None);
     }
 
     #[tokio::test]
@@ -298,6 +942,36 @@ mod tests {
         assert_eq!(res.unwrap(), json!(1));
     }
 
+    #[tokio::test]
+    async fn no_cache_feature_flag() {
+        let middleware = CacheMiddleware::new(Cache::new(NonZeroUsize::try_from(3).unwrap(), None));
+
+        let res = middleware
+            .call(
+                CallRequest::new("test", vec![json!(11)]),
+                Default::default(),
+                Box::new(move |_, _| async move { Ok(json!(1)) }.boxed()),
+            )
+            .await;
+        assert_eq!(res.unwrap(), json!(1));
+
+        // wait for cache write
+        tokio::time::sleep(tokio::time::Duration::from_millis(1)).await;
+
+        let features = crate::middlewares::FeatureFlags {
+            no_cache: true,
+            ..Default::default()
+        };
+        let res = middleware
+            .call(
+                CallRequest::new("test", vec![json!(11)]).with_features(features),
+                Default::default(),
+                Box::new(move |_, _| async move { Ok(json!(2)) }.boxed()),
+            )
+            .await;
+        assert_eq!(res.unwrap(), json!(2));
+    }
+
     #[tokio::test]
     async fn avoid_repeated_requests() {
         let middleware = CacheMiddleware::new(Cache::new(NonZeroUsize::try_from(3).unwrap(), None));
@@ -324,12 +998,219 @@ mod tests {
         assert_eq!(res2.unwrap(), json!(1));
     }
 
+    /// Shared backend answering after a delay, e.g. a remote store under load.
+    struct SlowBackend(Duration);
+
+    #[async_trait]
+    impl crate::utils::SharedCacheBackend for SlowBackend {
+        async fn get(&self, _key: &[u8]) -> Option<JsonValue> {
+            tokio::time::sleep(self.0).await;
+            Some(json!(1))
+        }
+
+        async fn set(&self, _key: &[u8], _value: &JsonValue, _ttl: Option<Duration>) {}
+
+        async fn remove(&self, _key: &[u8]) {}
+
+        async fn clear(&self) {}
+    }
+
+    #[tokio::test]
+    async fn slow_lookup_leaves_upstream_budget() {
+        let lookup_timeout = Duration::from_millis(20);
+        let upstream_timeout = Duration::from_millis(100);
+        let cache = Cache::new(NonZeroUsize::try_from(3).unwrap(), None)
+            .with_shared_backend(Arc::new(SlowBackend(Duration::from_secs(10))));
+        let middleware = CacheMiddleware::new(cache).with_lookup_timeout(lookup_timeout);
+
+        // without a lookup timeout the call would wait on the shared backend until the overall timeout
+        let res = tokio::time::timeout(
+            lookup_timeout + upstream_timeout,
+            middleware.call(
+                CallRequest::new("test", vec![json!(11)]),
+                Default::default(),
+                Box::new(move |_, _| {
+                    async move {
+                        tokio::time::sleep(Duration::from_millis(80)).await;
+                        Ok(json!(2))
+                    }
+                    .boxed()
+                }),
+            ),
+        )
+        .await
+        .expect("upstream phase should get its budget");
+        assert_eq!(res.unwrap(), json!(2));
+    }
+
+    #[tokio::test]
+    async fn lookup_timeout_waits_for_pending_fetch() {
+        let middleware = Arc::new(
+            CacheMiddleware::new(Cache::new(NonZeroUsize::try_from(3).unwrap(), None))
+                .with_lookup_timeout(Duration::from_millis(10)),
+        );
+
+        let first = tokio::spawn({
+            let middleware = middleware.clone();
+            async move {
+                middleware
+                    .call(
+                        CallRequest::new("test", vec![json!(11)]),
+                        Default::default(),
+                        Box::new(move |_, _| {
+                            async move {
+                                tokio::time::sleep(Duration::from_millis(50)).await;
+                                Ok(json!(1))
+                            }
+                            .boxed()
+                        }),
+                    )
+                    .await
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(1)).await;
+
+        // the fetch in flight outlasts the lookup timeout, it is awaited rather than made again
+        let res = middleware
+            .call(
+                CallRequest::new("test", vec![json!(11)]),
+                Default::default(),
+                Box::new(move |_, _| async move { panic!("should not be called") }.boxed()),
+            )
+            .await;
+        assert_eq!(res.unwrap(), json!(1));
+        assert_eq!(first.await.unwrap().unwrap(), json!(1));
+    }
+
+    #[tokio::test]
+    async fn runtime_upgrade_causes_cache_miss() {
+        let mut builder = crate::extensions::client::mock::TestServerBuilder::new();
+        let mut version_rx = builder.register_subscription(
+            "state_subscribeRuntimeVersion",
+            "state_runtimeVersion",
+            "state_unsubscribeRuntimeVersion",
+        );
+        let (addr, _server) = builder.build().await;
+
+        let client = Arc::new(Client::with_endpoints([format!("ws://{addr}")]).unwrap());
+        let middleware = CacheMiddleware::new(Cache::new(NonZeroUsize::try_from(3).unwrap(), None))
+            .with_spec_version(client.clone());
+
+        let version_sub = version_rx.recv().await.unwrap();
+        version_sub.send(json!({ "specVersion": 1 })).await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(client.spec_version(), Some(1));
+
+        let res = middleware
+            .call(
+                CallRequest::new("state_getMetadata", vec![]),
+                Default::default(),
+                Box::new(move |_, _| async move { Ok(json!("0x01")) }.boxed()),
+            )
+            .await;
+        assert_eq!(res.unwrap(), json!("0x01"));
+
+        // wait for cache write
+        tokio::time::sleep(Duration::from_millis(1)).await;
+
+        // cache hit
+        let res = middleware
+            .call(
+                CallRequest::new("state_getMetadata", vec![]),
+                Default::default(),
+                Box::new(move |_, _| async move { panic!() }.boxed()),
+            )
+            .await;
+        assert_eq!(res.unwrap(), json!("0x01"));
+
+        // runtime upgrade
+        version_sub.send(json!({ "specVersion": 2 })).await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        // cache miss
+        let res = middleware
+            .call(
+                CallRequest::new("state_getMetadata", vec![]),
+                Default::default(),
+                Box::new(move |_, _| async move { Ok(json!("0x02")) }.boxed()),
+            )
+            .await;
+        assert_eq!(res.unwrap(), json!("0x02"));
+    }
+
+    #[tokio::test]
+    async fn caches_finalized_blocks_only() {
+        let (finalized_tx, finalized_rx) = watch::channel(None);
+        let finality = Finality::new(BlockParam::HashAt(1), finalized_rx);
+        finalized_tx.send_replace(Some((json!("0x01"), 1)));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        assert!(finality.is_finalized(&[json!("0xaa"), json!("0x01")]));
+        // a new head, or a block on a fork
+        assert!(!finality.is_finalized(&[json!("0xaa"), json!("0x02")]));
+        // latest block
+        assert!(!finality.is_finalized(&[json!("0xaa")]));
+        assert!(!finality.is_finalized(&[json!("0xaa"), JsonValue::Null]));
+
+        let middleware =
+            CacheMiddleware::new(Cache::new(NonZeroUsize::try_from(3).unwrap(), None)).with_finality(finality, None);
+        let call = |params: Vec<JsonValue>, value: JsonValue| {
+            middleware.call(
+                CallRequest::new("state_getStorage", params),
+                Default::default(),
+                Box::new(move |_, _| async move { Ok(value) }.boxed()),
+            )
+        };
+
+        assert_eq!(
+            call(vec![json!("0xaa"), json!("0x02")], json!(1)).await.unwrap(),
+            json!(1)
+        );
+        assert_eq!(
+            call(vec![json!("0xaa"), json!("0x02")], json!(2)).await.unwrap(),
+            json!(2)
+        );
+
+        // 0x02 gets finalized
+        finalized_tx.send_replace(Some((json!("0x02"), 2)));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(
+            call(vec![json!("0xaa"), json!("0x02")], json!(3)).await.unwrap(),
+            json!(3)
+        );
+        assert_eq!(
+            call(vec![json!("0xaa"), json!("0x02")], json!(4)).await.unwrap(),
+            json!(3)
+        );
+    }
+
+    #[tokio::test]
+    async fn finalized_block_numbers() {
+        let (finalized_tx, finalized_rx) = watch::channel(None);
+        let finality = Finality::new(BlockParam::NumberAt(0), finalized_rx);
+
+        // finalized head unknown
+        assert!(!finality.is_finalized(&[json!(1)]));
+
+        finalized_tx.send_replace(Some((json!("0x0a"), 10)));
+        assert!(finality.is_finalized(&[json!(10)]));
+        assert!(finality.is_finalized(&[json!("0x9")]));
+        assert!(!finality.is_finalized(&[json!("0xb")]));
+        assert!(!finality.is_finalized(&[json!("latest")]));
+    }
+
     #[tokio::test]
     async fn cache_builder_works() {
         let ext = crate::extensions::ExtensionsConfig {
             cache: Some(crate::extensions::cache::CacheConfig {
                 default_size: 100,
                 default_ttl_seconds: Some(10),
+                disk_spillover_path: None,
+                disk_spillover_max_entries: 100_000,
+                negative_caching: Default::default(),
+                negative_cache_size: 100,
+                redis: None,
+                warm_up: Vec::new(),
             }),
             ..Default::default()
         }
@@ -344,11 +1225,13 @@ mod tests {
                 cache: Some(CacheParams {
                     size: Some(0),
                     ttl_seconds: None,
+                    ..Default::default()
                 }),
                 params: vec![],
                 response: None,
                 delay_ms: None,
                 rate_limit_weight: 1,
+                ..Default::default()
             },
             &ext,
         )
@@ -362,11 +1245,13 @@ mod tests {
                 cache: Some(CacheParams {
                     size: None,
                     ttl_seconds: None,
+                    ..Default::default()
                 }),
                 params: vec![],
                 response: None,
                 delay_ms: None,
                 rate_limit_weight: 1,
+                ..Default::default()
             },
             &ext,
         )
@@ -380,11 +1265,13 @@ mod tests {
                 cache: Some(CacheParams {
                     size: Some(1),
                     ttl_seconds: None,
+                    ..Default::default()
                 }),
                 params: vec![],
                 response: None,
                 delay_ms: None,
                 rate_limit_weight: 1,
+                ..Default::default()
             },
             &ext,
         )
@@ -400,6 +1287,7 @@ mod tests {
                 response: None,
                 delay_ms: None,
                 rate_limit_weight: 1,
+                ..Default::default()
             },
             &ext,
         )
@@ -0,0 +1,178 @@
+use async_trait::async_trait;
+use opentelemetry::trace::FutureExt;
+
+use crate::{
+    config::CacheParams,
+    extensions::client::ForwardedHeaders,
+    middlewares::{
+        methods::cache::{MethodCache, MethodCaches},
+        CallRequest, CallResult, Middleware, MiddlewareBuilder, NextFn, RpcMethod, TRACER,
+    },
+    utils::{TypeRegistry, TypeRegistryRef},
+};
+
+/// Caches responses under the block they resolved to, e.g. a request for `latest` also fills the
+/// cache entry of the block number it returned. The cache middleware keeps the original params entry.
+/// Entries are keyed like the ones of the cache middleware, and skipped at unfinalized blocks for `finalized_only`.
+pub struct ResponseCachingByBlockMiddleware {
+    cache: MethodCache,
+    // JSON pointer to the block in the response
+    pointer: String,
+    // index of the block param
+    index: usize,
+}
+
+impl ResponseCachingByBlockMiddleware {
+    pub fn new(cache: MethodCache, pointer: String, index: usize) -> Self {
+        Self { cache, pointer, index }
+    }
+}
+
+#[async_trait]
+impl MiddlewareBuilder<RpcMethod, CallRequest, CallResult> for ResponseCachingByBlockMiddleware {
+    async fn build(
+        method: &RpcMethod,
+        extensions: &TypeRegistryRef,
+    ) -> Option<Box<dyn Middleware<CallRequest, CallResult>>> {
+        let Some(CacheParams {
+            block_pointer: Some(ref pointer),
+            ..
+        }) = method.cache
+        else {
+            return None;
+        };
+
+        let Some(index) = method.params.iter().position(|p| p.inject) else {
+            tracing::warn!("{} has a block_pointer but no injected block param", method.method);
+            return None;
+        };
+
+        // registered by the cache middleware, which must come first
+        let Some(cache) = extensions
+            .read()
+            .await
+            .get::<MethodCaches>()
+            .and_then(|caches| caches.get(&method.method))
+        else {
+            tracing::warn!("{} has a block_pointer but no cache to share, it must follow the cache middleware and not be runtime dependent", method.method);
+            return None;
+        };
+
+        Some(Box::new(ResponseCachingByBlockMiddleware::new(
+            cache,
+            pointer.clone(),
+            index,
+        )))
+    }
+}
+
+#[async_trait]
+impl Middleware<CallRequest, CallResult> for ResponseCachingByBlockMiddleware {
+    async fn call(
+        &self,
+        request: CallRequest,
+        context: TypeRegistry,
+        next: NextFn<CallRequest, CallResult>,
+    ) -> CallResult {
+        async move {
+            let method = request.method.clone();
+            let mut params = request.params.clone();
+            let headers = context.get::<ForwardedHeaders>();
+
+            let result = next(request, context).await;
+
+            if let Ok(ref value) = result {
+                if let Some(block) = value.pointer(&self.pointer).filter(|b| !b.is_null()) {
+                    if params.len() <= self.index {
+                        params.resize(self.index + 1, Default::default());
+                    }
+                    if params[self.index] != *block {
+                        params[self.index] = block.clone();
+                        let finalized = match self.cache.finality {
+                            Some(ref finality) => finality.is_finalized(&params),
+                            None => true,
+                        };
+                        if finalized {
+                            if let Some(key) = self.cache.keys.key(&method, &params, headers.as_deref()) {
+                                self.cache.cache.insert(key, value.clone()).await;
+                            }
+                        }
+                    }
+                }
+            }
+
+            result
+        }
+        .with_context(TRACER.context("cache_by_block"))
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{Cache, CacheKey};
+    use futures::FutureExt;
+    use serde_json::json;
+    use std::num::NonZeroUsize;
+
+    fn method_cache(cache: &Cache<blake2::Blake2b512>) -> MethodCache {
+        MethodCache {
+            cache: cache.clone(),
+            keys: Default::default(),
+            finality: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn caches_response_under_resolved_block() {
+        let cache = Cache::new(NonZeroUsize::new(10).unwrap(), None);
+        let middleware = ResponseCachingByBlockMiddleware::new(method_cache(&cache), "/number".to_string(), 0);
+
+        let block = json!({ "number": "0x10", "hash": "0xabcd" });
+        let response = block.clone();
+        let result = middleware
+            .call(
+                CallRequest::new("eth_getBlockByNumber", vec![json!("latest"), json!(false)]),
+                Default::default(),
+                Box::new(move |_, _| async move { Ok(response) }.boxed()),
+            )
+            .await;
+        assert_eq!(result, Ok(block.clone()));
+
+        let method = "eth_getBlockByNumber".to_string();
+        let resolved = CacheKey::new(&method, &[json!("0x10"), json!(false)]);
+        assert_eq!(cache.get(&resolved).await, Some(block));
+        // the original params are cached by the cache middleware
+        let original = CacheKey::new(&method, &[json!("latest"), json!(false)]);
+        assert_eq!(cache.get(&original).await, None);
+    }
+
+    #[tokio::test]
+    async fn resolved_entry_is_scoped_by_forwarded_headers() {
+        let cache = Cache::new(NonZeroUsize::new(10).unwrap(), None);
+        let middleware = ResponseCachingByBlockMiddleware::new(method_cache(&cache), "/number".to_string(), 0);
+
+        let mut headers = http::HeaderMap::new();
+        headers.insert("authorization", "Bearer a".parse().unwrap());
+        let forwarded = ForwardedHeaders::from_request(&["authorization".to_string()], &headers);
+        let mut context = TypeRegistry::new();
+        context.insert(forwarded.clone());
+
+        let block = json!({ "number": "0x10" });
+        let response = block.clone();
+        middleware
+            .call(
+                CallRequest::new("eth_getBlockByNumber", vec![json!("latest")]),
+                context,
+                Box::new(move |_, _| async move { Ok(response) }.boxed()),
+            )
+            .await
+            .unwrap();
+
+        let method = "eth_getBlockByNumber".to_string();
+        let resolved = CacheKey::new(&method, &[json!("0x10")]);
+        assert_eq!(cache.get(&resolved).await, None);
+        assert_eq!(cache.get(&resolved.scoped(&forwarded.cache_scope())).await, Some(block));
+    }
+}
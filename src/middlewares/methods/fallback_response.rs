@@ -0,0 +1,122 @@
+use async_trait::async_trait;
+use jsonrpsee::{
+    core::JsonValue,
+    types::{error::CALL_EXECUTION_FAILED_CODE, ErrorObjectOwned},
+};
+use opentelemetry::trace::FutureExt;
+
+use crate::{
+    middlewares::{
+        methods::{
+            preflight::UPSTREAM_UNAVAILABLE_ERROR,
+            upstream::{CIRCUIT_OPEN_ERROR, QUEUE_FULL_ERROR, QUEUE_TIMEOUT_ERROR},
+        },
+        CallRequest, CallResult, Middleware, MiddlewareBuilder, NextFn, RpcMethod, TRACER,
+    },
+    utils::{errors, TypeRegistry, TypeRegistryRef},
+};
+
+pub struct FallbackResponseMiddleware {
+    response: JsonValue,
+}
+
+impl FallbackResponseMiddleware {
+    pub fn new(response: JsonValue) -> Self {
+        Self { response }
+    }
+}
+
+/// Connection failures and timeouts are reported by the client as `upstream_unreachable`, the preflight middleware
+/// fails calls without a connection and the upstream middleware while its circuit is open or its queue is full.
+/// Internal errors returned by upstream itself are not.
+pub fn is_upstream_unavailable(error: &ErrorObjectOwned) -> bool {
+    if errors::is_upstream_unreachable(error) {
+        return true;
+    }
+    match error.code() {
+        CALL_EXECUTION_FAILED_CODE => error.data().is_some_and(|d| {
+            [
+                UPSTREAM_UNAVAILABLE_ERROR,
+                CIRCUIT_OPEN_ERROR,
+                QUEUE_FULL_ERROR,
+                QUEUE_TIMEOUT_ERROR,
+            ]
+            .iter()
+            .any(|msg| d.get().contains(msg))
+        }),
+        _ => false,
+    }
+}
+
+#[async_trait]
+impl MiddlewareBuilder<RpcMethod, CallRequest, CallResult> for FallbackResponseMiddleware {
+    async fn build(
+        method: &RpcMethod,
+        _extensions: &TypeRegistryRef,
+    ) -> Option<Box<dyn Middleware<CallRequest, CallResult>>> {
+        method.fallback_response.as_ref().map(|resp| {
+            Box::new(FallbackResponseMiddleware::new(resp.clone())) as Box<dyn Middleware<CallRequest, CallResult>>
+        })
+    }
+}
+
+#[async_trait]
+impl Middleware<CallRequest, CallResult> for FallbackResponseMiddleware {
+    async fn call(
+        &self,
+        request: CallRequest,
+        context: TypeRegistry,
+        next: NextFn<CallRequest, CallResult>,
+    ) -> CallResult {
+        async move {
+            let method = request.method.clone();
+            match next(request, context).await {
+                Err(error) if is_upstream_unavailable(&error) => {
+                    tracing::debug!("Upstream unavailable for {method}, serving fallback response: {error}");
+                    Ok(self.response.clone())
+                }
+                result => result,
+            }
+        }
+        .with_context(TRACER.context("fallback_response"))
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+    use serde_json::json;
+
+    async fn call(error: ErrorObjectOwned) -> CallResult {
+        FallbackResponseMiddleware::new(json!({ "name": "fallback" }))
+            .call(
+                CallRequest::new("system_properties", vec![]),
+                Default::default(),
+                Box::new(move |_, _| async move { Err(error) }.boxed()),
+            )
+            .await
+    }
+
+    #[tokio::test]
+    async fn serves_fallback_when_upstream_unavailable() {
+        let res = call(errors::upstream_unreachable("connection closed")).await;
+        assert_eq!(res, Ok(json!({ "name": "fallback" })));
+
+        let res = call(errors::failed(UPSTREAM_UNAVAILABLE_ERROR)).await;
+        assert_eq!(res, Ok(json!({ "name": "fallback" })));
+    }
+
+    #[tokio::test]
+    async fn passes_through_upstream_errors() {
+        let res = call(errors::invalid_params("bad block hash")).await;
+        assert_eq!(res, Err(errors::invalid_params("bad block hash")));
+
+        let res = call(errors::failed("rate limit exceeded")).await;
+        assert_eq!(res, Err(errors::failed("rate limit exceeded")));
+
+        let res = call(errors::internal_error("runtime panicked")).await;
+        assert_eq!(res, Err(errors::internal_error("runtime panicked")));
+    }
+}
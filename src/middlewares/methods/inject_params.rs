@@ -3,9 +3,11 @@ use jsonrpsee::core::JsonValue;
 use opentelemetry::trace::FutureExt;
 use std::sync::Arc;
 
+use super::cache::BypassCache;
+
 use crate::{
     config::MethodParam,
-    extensions::api::{SubstrateApi, ValueHandle},
+    extensions::api::{EthApi, HeadTracker, SubstrateApi, ValueHandle},
     middlewares::{CallRequest, CallResult, Middleware, MiddlewareBuilder, NextFn, RpcMethod, TRACER},
     utils::errors,
     utils::{TypeRegistry, TypeRegistryRef},
@@ -14,15 +16,69 @@ use crate::{
 pub enum InjectType {
     BlockHashAt(usize),
     BlockNumberAt(usize),
+    /// Next nonce of the account given at `address`, injected at `nonce`.
+    AccountNonce {
+        address: usize,
+        nonce: usize,
+    },
+}
+
+/// The configured chain api, which params are injected with.
+#[derive(Clone)]
+pub enum ChainApi {
+    Substrate(Arc<SubstrateApi>),
+    Eth(Arc<EthApi>),
+}
+
+impl ChainApi {
+    /// The substrate api if configured, else the eth api, like `HeadTracker::from_registry`.
+    pub fn from_registry(registry: &TypeRegistry) -> Option<Self> {
+        match (registry.get::<SubstrateApi>(), registry.get::<EthApi>()) {
+            (Some(api), _) => Some(Self::Substrate(api)),
+            (None, Some(api)) => Some(Self::Eth(api)),
+            (None, None) => None,
+        }
+    }
+
+    fn head_tracker(&self) -> HeadTracker {
+        match self {
+            Self::Substrate(api) => api.head_tracker(),
+            Self::Eth(api) => api.head_tracker(),
+        }
+    }
+
+    async fn get_account_nonce(&self, address: &JsonValue) -> CallResult {
+        match self {
+            Self::Substrate(api) => api.get_account_nonce(address).await,
+            Self::Eth(api) => api.get_account_nonce(address).await,
+        }
+    }
+
+    async fn get_block_hash(&self, number: u64) -> CallResult {
+        match self {
+            Self::Substrate(api) => api.get_block_hash(number).await,
+            Self::Eth(api) => api.get_block_hash(number).await,
+        }
+    }
 }
 
 pub struct InjectParamsMiddleware {
+    api: ChainApi,
     head: ValueHandle<(JsonValue, u64)>,
+    finalized_head: ValueHandle<(JsonValue, u64)>,
     inject: InjectType,
     params: Vec<MethodParam>,
+    // a block number given for the injected hash is resolved to the hash
+    resolve_number: bool,
 }
 
 fn inject_type(params: &[MethodParam]) -> Option<InjectType> {
+    let maybe_nonce = params.iter().position(|p| p.inject && p.ty == "Nonce");
+    if let Some(nonce) = maybe_nonce {
+        let address = params.iter().position(|p| p.ty == "AccountId")?;
+        return Some(InjectType::AccountNonce { address, nonce });
+    }
+
     let maybe_block_num = params.iter().position(|p| p.inject && p.ty == "BlockNumber");
     if let Some(block_num) = maybe_block_num {
         return Some(InjectType::BlockNumberAt(block_num));
@@ -46,20 +102,25 @@ impl MiddlewareBuilder<RpcMethod, CallRequest, CallResult> for InjectParamsMiddl
             return None;
         };
 
-        let api = extensions
-            .read()
-            .await
-            .get::<SubstrateApi>()
-            .expect("SubstrateApi extension not found");
+        let api =
+            ChainApi::from_registry(&*extensions.read().await).expect("SubstrateApi or EthApi extension not found");
 
         Some(Box::new(Self::new(api, inject_type, method.params.clone())))
     }
 }
 
 impl InjectParamsMiddleware {
-    pub fn new(api: Arc<SubstrateApi>, inject: InjectType, params: Vec<MethodParam>) -> Self {
+    pub fn new(api: ChainApi, inject: InjectType, params: Vec<MethodParam>) -> Self {
+        let heads = api.head_tracker();
+        let resolve_number = match inject {
+            InjectType::BlockHashAt(index) => params.get(index).is_some_and(|p| p.resolve_number),
+            _ => false,
+        };
         Self {
-            head: api.get_head(),
+            resolve_number,
+            head: heads.get_head(),
+            finalized_head: heads.get_finalized_head(),
+            api,
             inject,
             params,
         }
@@ -69,14 +130,43 @@ impl InjectParamsMiddleware {
         match self.inject {
             InjectType::BlockHashAt(index) => index,
             InjectType::BlockNumberAt(index) => index,
+            InjectType::AccountNonce { nonce, .. } => nonce,
         }
     }
 
-    async fn get_parameter(&self) -> JsonValue {
-        let res = self.head.read().await;
+    async fn get_parameter(&self, request: &CallRequest) -> CallResult {
+        let head = if request.features.finalized {
+            &self.finalized_head
+        } else {
+            &self.head
+        };
         match self.inject {
-            InjectType::BlockHashAt(_) => res.0,
-            InjectType::BlockNumberAt(_) => res.1.into(),
+            InjectType::BlockHashAt(_) => Ok(head.read().await.0),
+            InjectType::BlockNumberAt(_) => Ok(head.read().await.1.into()),
+            InjectType::AccountNonce { address, .. } => {
+                let address = request
+                    .params
+                    .get(address)
+                    .filter(|a| !a.is_null())
+                    .ok_or_else(|| errors::invalid_params("Missing account to inject the nonce for"))?;
+                self.api.get_account_nonce(address).await
+            }
+        }
+    }
+
+    /// Block number given in place of the injected block hash, if numbers are resolved.
+    fn block_number(&self, param: &JsonValue) -> Option<u64> {
+        if !self.resolve_number {
+            return None;
+        }
+        match param {
+            JsonValue::Number(n) => n.as_u64(),
+            // anything longer is a hash
+            JsonValue::String(s) => s
+                .strip_prefix("0x")
+                .filter(|hex| hex.len() <= 16)
+                .and_then(|hex| u64::from_str_radix(hex, 16).ok()),
+            _ => None,
         }
     }
 
@@ -99,19 +189,37 @@ impl Middleware<CallRequest, CallResult> for InjectParamsMiddleware {
     async fn call(
         &self,
         mut request: CallRequest,
-        context: TypeRegistry,
+        mut context: TypeRegistry,
         next: NextFn<CallRequest, CallResult>,
     ) -> CallResult {
         let idx = self.get_index();
         match request.params.len() {
             len if len == idx + 1 => {
-                // full params with current block
-                return next(request, context).await;
+                let Some(number) = self.block_number(&request.params[idx]) else {
+                    // full params with current block
+                    return next(request, context).await;
+                };
+                async move {
+                    let hash = self.api.get_block_hash(number).await?;
+                    if hash.is_null() {
+                        return Err(errors::invalid_params(format!("Unknown block number {number}")));
+                    }
+                    tracing::trace!("Resolved block {number} to {hash} for method {}", request.method);
+                    request.params[idx] = hash;
+
+                    next(request, context).await
+                }
+                .with_context(TRACER.context("inject_params"))
+                .await
             }
             len if len <= idx => {
                 async move {
                     // without current block
-                    let to_inject = self.get_parameter().await;
+                    let to_inject = self.get_parameter(&request).await?;
+                    if let InjectType::AccountNonce { .. } = self.inject {
+                        // the response depends on the account state at the time of the call
+                        context.insert(BypassCache(true));
+                    }
                     tracing::trace!("Injected param {} to method {}", &to_inject, request.method);
                     let params_passed = request.params.len();
                     while request.params.len() < idx {
@@ -147,7 +255,7 @@ impl Middleware<CallRequest, CallResult> for InjectParamsMiddleware {
 mod tests {
     use super::*;
 
-    use crate::extensions::api::SubstrateApi;
+    use crate::extensions::api::{EthApi, SubstrateApi};
     use crate::extensions::client::mock::{MockRequest, MockSubscription};
     use crate::extensions::client::{mock::TestServerBuilder, Client};
     use futures::FutureExt;
@@ -162,6 +270,7 @@ mod tests {
         head_rx: mpsc::Receiver<MockSubscription>,
         _finalized_head_rx: mpsc::Receiver<MockSubscription>,
         block_hash_rx: mpsc::Receiver<MockRequest>,
+        nonce_rx: mpsc::Receiver<MockRequest>,
         head_sink: Option<SubscriptionSink>,
     }
 
@@ -178,6 +287,7 @@ mod tests {
         );
 
         let block_hash_rx = builder.register_method("chain_getBlockHash");
+        let nonce_rx = builder.register_method("system_accountNextIndex");
 
         let (addr, _server) = builder.build().await;
 
@@ -190,6 +300,7 @@ mod tests {
             head_rx,
             _finalized_head_rx,
             block_hash_rx,
+            nonce_rx,
             head_sink: None,
         }
     }
@@ -211,7 +322,7 @@ mod tests {
         context.head_sink = Some(head_sub.sink);
 
         (
-            InjectParamsMiddleware::new(context.api.clone(), inject_type, params),
+            InjectParamsMiddleware::new(ChainApi::Substrate(context.api.clone()), inject_type, params),
             context,
         )
     }
@@ -227,12 +338,20 @@ mod tests {
                     ty: "StorageKey".to_string(),
                     optional: false,
                     inject: false,
+                    pattern: None,
+                    resolve_number: false,
+                    default: None,
+                    coerce: None,
                 },
                 MethodParam {
                     name: "at".to_string(),
                     ty: "BlockHash".to_string(),
                     optional: true,
                     inject: true,
+                    pattern: None,
+                    resolve_number: false,
+                    default: None,
+                    coerce: None,
                 },
             ],
         )
@@ -254,6 +373,61 @@ mod tests {
         assert_eq!(result, json!("0x1111"));
     }
 
+    #[tokio::test]
+    async fn resolve_block_number_to_hash() {
+        let (middleware, mut context) = create_inject_middleware(
+            InjectType::BlockHashAt(1),
+            vec![
+                MethodParam {
+                    name: "key".to_string(),
+                    ty: "StorageKey".to_string(),
+                    optional: false,
+                    inject: false,
+                    pattern: None,
+                    resolve_number: false,
+                    default: None,
+                    coerce: None,
+                },
+                MethodParam {
+                    name: "at".to_string(),
+                    ty: "BlockHash".to_string(),
+                    optional: true,
+                    inject: true,
+                    pattern: None,
+                    resolve_number: true,
+                    default: None,
+                    coerce: None,
+                },
+            ],
+        )
+        .await;
+
+        let call = |at: JsonValue| {
+            middleware.call(
+                CallRequest::new("state_getStorage", vec![json!("0x1234"), at]),
+                Default::default(),
+                Box::new(move |req: CallRequest, _| async move { Ok(req.params[1].clone()) }.boxed()),
+            )
+        };
+
+        let (result, _) = tokio::join!(call(json!(16)), async {
+            let req = context.block_hash_rx.recv().await.unwrap();
+            assert_eq!(req.params, json!([16]));
+            req.respond(json!("0xbeef"));
+        });
+        assert_eq!(result, Ok(json!("0xbeef")));
+
+        let (result, _) = tokio::join!(call(json!("0x10")), async {
+            let req = context.block_hash_rx.recv().await.unwrap();
+            req.respond(JsonValue::Null);
+        });
+        assert!(result.is_err());
+
+        // hashes are passed on as is
+        let hash = format!("0x{}", "ab".repeat(32));
+        assert_eq!(call(json!(hash)).await, Ok(json!(hash)));
+    }
+
     #[tokio::test]
     async fn inject_if_without_current_block_hash() {
         let (middleware, _context) = create_inject_middleware(
@@ -264,12 +438,20 @@ mod tests {
                     ty: "StorageKey".to_string(),
                     optional: false,
                     inject: false,
+                    pattern: None,
+                    resolve_number: false,
+                    default: None,
+                    coerce: None,
                 },
                 MethodParam {
                     name: "at".to_string(),
                     ty: "BlockHash".to_string(),
                     optional: true,
                     inject: true,
+                    pattern: None,
+                    resolve_number: false,
+                    default: None,
+                    coerce: None,
                 },
             ],
         )
@@ -301,18 +483,30 @@ mod tests {
                     ty: "StorageKey".to_string(),
                     optional: false,
                     inject: false,
+                    pattern: None,
+                    resolve_number: false,
+                    default: None,
+                    coerce: None,
                 },
                 MethodParam {
                     name: "pho".to_string(),
                     ty: "u32".to_string(),
                     optional: true,
                     inject: false,
+                    pattern: None,
+                    resolve_number: false,
+                    default: None,
+                    coerce: None,
                 },
                 MethodParam {
                     name: "at".to_string(),
                     ty: "BlockHash".to_string(),
                     optional: true,
                     inject: true,
+                    pattern: None,
+                    resolve_number: false,
+                    default: None,
+                    coerce: None,
                 },
             ],
         )
@@ -344,18 +538,30 @@ mod tests {
                     ty: "StorageKey".to_string(),
                     optional: false,
                     inject: false,
+                    pattern: None,
+                    resolve_number: false,
+                    default: None,
+                    coerce: None,
                 },
                 MethodParam {
                     name: "foo".to_string(),
                     ty: "u32".to_string(),
                     optional: false,
                     inject: false,
+                    pattern: None,
+                    resolve_number: false,
+                    default: None,
+                    coerce: None,
                 },
                 MethodParam {
                     name: "at".to_string(),
                     ty: "BlockHash".to_string(),
                     optional: true,
                     inject: true,
+                    pattern: None,
+                    resolve_number: false,
+                    default: None,
+                    coerce: None,
                 },
             ],
         )
@@ -391,12 +597,20 @@ mod tests {
                     ty: "StorageKey".to_string(),
                     optional: false,
                     inject: false,
+                    pattern: None,
+                    resolve_number: false,
+                    default: None,
+                    coerce: None,
                 },
                 MethodParam {
                     name: "at".to_string(),
                     ty: "BlockNumber".to_string(),
                     optional: true,
                     inject: true,
+                    pattern: None,
+                    resolve_number: false,
+                    default: None,
+                    coerce: None,
                 },
             ],
         )
@@ -446,4 +660,216 @@ mod tests {
             .unwrap();
         assert_eq!(result2, json!("0x1111"));
     }
+
+    #[tokio::test]
+    async fn inject_finalized_with_feature_flag() {
+        let (middleware, mut context) = create_inject_middleware(
+            InjectType::BlockHashAt(1),
+            vec![
+                MethodParam {
+                    name: "key".to_string(),
+                    ty: "StorageKey".to_string(),
+                    optional: false,
+                    inject: false,
+                    pattern: None,
+                    resolve_number: false,
+                    default: None,
+                    coerce: None,
+                },
+                MethodParam {
+                    name: "at".to_string(),
+                    ty: "BlockHash".to_string(),
+                    optional: true,
+                    inject: true,
+                    pattern: None,
+                    resolve_number: false,
+                    default: None,
+                    coerce: None,
+                },
+            ],
+        )
+        .await;
+
+        let finalized_sub = context._finalized_head_rx.recv().await.unwrap();
+        finalized_sub.send(json!({ "number": "0x4000" })).await;
+        {
+            let req = context.block_hash_rx.recv().await.unwrap();
+            req.respond(json!("0xf1f1"));
+        }
+
+        let features = crate::middlewares::FeatureFlags {
+            finalized: true,
+            ..Default::default()
+        };
+        let result = middleware
+            .call(
+                CallRequest::new("state_getStorage", vec![json!("0x1234")]).with_features(features),
+                Default::default(),
+                Box::new(move |req: CallRequest, _| {
+                    async move {
+                        assert_eq!(req.params, vec![json!("0x1234"), json!("0xf1f1")]);
+                        Ok(json!("0x1111"))
+                    }
+                    .boxed()
+                }),
+            )
+            .await
+            .unwrap();
+        assert_eq!(result, json!("0x1111"));
+    }
+
+    #[tokio::test]
+    async fn inject_account_nonce() {
+        let (middleware, mut context) = create_inject_middleware(
+            InjectType::AccountNonce { address: 0, nonce: 1 },
+            vec![
+                MethodParam {
+                    name: "account".to_string(),
+                    ty: "AccountId".to_string(),
+                    optional: false,
+                    inject: false,
+                    pattern: None,
+                    resolve_number: false,
+                    default: None,
+                    coerce: None,
+                },
+                MethodParam {
+                    name: "nonce".to_string(),
+                    ty: "Nonce".to_string(),
+                    optional: true,
+                    inject: true,
+                    pattern: None,
+                    resolve_number: false,
+                    default: None,
+                    coerce: None,
+                },
+            ],
+        )
+        .await;
+
+        tokio::spawn(async move {
+            let req = context.nonce_rx.recv().await.unwrap();
+            assert_eq!(req.params, json!(["5Alice"]));
+            req.respond(json!(7));
+        });
+
+        let result = middleware
+            .call(
+                CallRequest::new("author_submitWithNonce", vec![json!("5Alice")]),
+                Default::default(),
+                Box::new(move |req: CallRequest, context: TypeRegistry| {
+                    async move {
+                        assert_eq!(req.params, vec![json!("5Alice"), json!(7)]);
+                        assert_eq!(context.get::<BypassCache>().map(|v| v.0), Some(true));
+                        Ok(json!("0x1111"))
+                    }
+                    .boxed()
+                }),
+            )
+            .await
+            .unwrap();
+        assert_eq!(result, json!("0x1111"));
+    }
+
+    #[tokio::test]
+    async fn err_if_missing_account_for_nonce() {
+        let (middleware, _context) = create_inject_middleware(
+            InjectType::AccountNonce { address: 0, nonce: 1 },
+            vec![
+                MethodParam {
+                    name: "account".to_string(),
+                    ty: "AccountId".to_string(),
+                    optional: true,
+                    inject: false,
+                    pattern: None,
+                    resolve_number: false,
+                    default: None,
+                    coerce: None,
+                },
+                MethodParam {
+                    name: "nonce".to_string(),
+                    ty: "Nonce".to_string(),
+                    optional: true,
+                    inject: true,
+                    pattern: None,
+                    resolve_number: false,
+                    default: None,
+                    coerce: None,
+                },
+            ],
+        )
+        .await;
+
+        let result = middleware
+            .call(
+                CallRequest::new("author_submitWithNonce", vec![]),
+                Default::default(),
+                Box::new(move |_, _| async move { panic!("should not be called") }.boxed()),
+            )
+            .await;
+        assert_eq!(
+            result,
+            Err(errors::invalid_params("Missing account to inject the nonce for"))
+        );
+    }
+
+    #[tokio::test]
+    async fn inject_eth_account_nonce() {
+        let mut builder = TestServerBuilder::new();
+        let _subscribe_rx = builder.register_subscription("eth_subscribe", "eth_subscription", "eth_unsubscribe");
+        let _get_block_rx = builder.register_method("eth_getBlockByNumber");
+        let mut nonce_rx = builder.register_method("eth_getTransactionCount");
+        let (addr, _server) = builder.build().await;
+
+        let client = Client::with_endpoints([format!("ws://{addr}")]).unwrap();
+        let api = EthApi::new(Arc::new(client), Duration::from_secs(100));
+        let middleware = InjectParamsMiddleware::new(
+            ChainApi::Eth(Arc::new(api)),
+            InjectType::AccountNonce { address: 0, nonce: 1 },
+            vec![
+                MethodParam {
+                    name: "address".to_string(),
+                    ty: "AccountId".to_string(),
+                    optional: false,
+                    inject: false,
+                    pattern: None,
+                    resolve_number: false,
+                    default: None,
+                    coerce: None,
+                },
+                MethodParam {
+                    name: "nonce".to_string(),
+                    ty: "Nonce".to_string(),
+                    optional: true,
+                    inject: true,
+                    pattern: None,
+                    resolve_number: false,
+                    default: None,
+                    coerce: None,
+                },
+            ],
+        );
+
+        tokio::spawn(async move {
+            let req = nonce_rx.recv().await.unwrap();
+            assert_eq!(req.params, json!(["0xabcd", "pending"]));
+            req.respond(json!("0x7"));
+        });
+
+        let result = middleware
+            .call(
+                CallRequest::new("eth_submitWithNonce", vec![json!("0xabcd")]),
+                Default::default(),
+                Box::new(move |req: CallRequest, _| {
+                    async move {
+                        assert_eq!(req.params, vec![json!("0xabcd"), json!("0x7")]);
+                        Ok(json!("0x1111"))
+                    }
+                    .boxed()
+                }),
+            )
+            .await
+            .unwrap();
+        assert_eq!(result, json!("0x1111"));
+    }
 }
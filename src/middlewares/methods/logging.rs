@@ -0,0 +1,183 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use jsonrpsee::types::ErrorObjectOwned;
+use opentelemetry::trace::FutureExt;
+use tracing::Instrument;
+
+use crate::{
+    extensions::access_log::{summarize_params, CacheStatus},
+    middlewares::{CallRequest, CallResult, Middleware, MiddlewareBuilder, NextFn, RpcMethod, TRACER},
+    utils::{TypeRegistry, TypeRegistryRef},
+};
+
+// params longer than this are truncated in the log
+const MAX_PARAMS_LENGTH: usize = 256;
+
+/// Time spent waiting for upstream, reported by the upstream middleware.
+#[derive(Debug, Default)]
+pub struct UpstreamLatency(AtomicU64);
+
+impl UpstreamLatency {
+    pub fn record(&self, elapsed: Duration) {
+        self.0.store(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn as_millis(&self) -> Option<f64> {
+        match self.0.load(Ordering::Relaxed) {
+            0 => None,
+            micros => Some(micros as f64 / 1000.0),
+        }
+    }
+}
+
+/// Logs every call with a correlation id, which is also appended to the message of errors
+/// returned to the client so a reported error can be found in the logs.
+pub struct LoggingMiddleware;
+
+fn correlation_id() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
+
+fn with_correlation_id(error: ErrorObjectOwned, id: &str) -> ErrorObjectOwned {
+    ErrorObjectOwned::owned(
+        error.code(),
+        format!("{} (request id: {id})", error.message()),
+        error.data(),
+    )
+}
+
+#[async_trait]
+impl MiddlewareBuilder<RpcMethod, CallRequest, CallResult> for LoggingMiddleware {
+    async fn build(
+        _method: &RpcMethod,
+        _extensions: &TypeRegistryRef,
+    ) -> Option<Box<dyn Middleware<CallRequest, CallResult>>> {
+        Some(Box::new(LoggingMiddleware))
+    }
+}
+
+#[async_trait]
+impl Middleware<CallRequest, CallResult> for LoggingMiddleware {
+    async fn call(
+        &self,
+        request: CallRequest,
+        mut context: TypeRegistry,
+        next: NextFn<CallRequest, CallResult>,
+    ) -> CallResult {
+        async move {
+            let id = correlation_id();
+            let method = request.method.clone();
+            let params = summarize_params(
+                serde_json::to_string(&request.params).ok().as_deref(),
+                MAX_PARAMS_LENGTH,
+            );
+
+            // shared with the access log if the request is logged
+            let cache_status = context.get::<CacheStatus>().unwrap_or_else(|| {
+                let cache_status = Arc::new(CacheStatus::default());
+                context.insert_raw(cache_status.clone());
+                cache_status
+            });
+            let upstream_latency = Arc::new(UpstreamLatency::default());
+            context.insert_raw(upstream_latency.clone());
+
+            let start = Instant::now();
+            let result = next(request, context)
+                .instrument(tracing::info_span!("call", correlation_id = %id))
+                .await;
+            let duration_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+            match result {
+                Ok(value) => {
+                    tracing::info!(
+                        correlation_id = %id,
+                        method = %method,
+                        params = %params,
+                        cache = ?cache_status.as_str(),
+                        upstream_ms = ?upstream_latency.as_millis(),
+                        duration_ms,
+                        outcome = "ok",
+                    );
+                    Ok(value)
+                }
+                Err(error) => {
+                    tracing::info!(
+                        correlation_id = %id,
+                        method = %method,
+                        params = %params,
+                        cache = ?cache_status.as_str(),
+                        upstream_ms = ?upstream_latency.as_millis(),
+                        duration_ms,
+                        outcome = "error",
+                        error_code = error.code(),
+                        error = error.message(),
+                    );
+                    Err(with_correlation_id(error, &id))
+                }
+            }
+        }
+        .with_context(TRACER.context("logging"))
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::FutureExt;
+    use jsonrpsee::core::JsonValue;
+    use serde_json::json;
+
+    use crate::utils::errors;
+
+    async fn call(result: CallResult) -> (CallResult, Option<&'static str>, Option<f64>) {
+        let mut context = TypeRegistry::new();
+        let cache_status = Arc::new(CacheStatus::default());
+        context.insert_raw(cache_status.clone());
+
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let result = LoggingMiddleware
+            .call(
+                CallRequest::new("state_getStorage", vec![json!("0x01")]),
+                context,
+                Box::new(move |_, context: TypeRegistry| {
+                    async move {
+                        context.get::<CacheStatus>().unwrap().miss();
+                        let latency = context.get::<UpstreamLatency>().unwrap();
+                        latency.record(Duration::from_millis(3));
+                        tx.send(latency).unwrap();
+                        result
+                    }
+                    .boxed()
+                }),
+            )
+            .await;
+        let latency = rx.await.unwrap();
+        (result, cache_status.as_str(), latency.as_millis())
+    }
+
+    #[tokio::test]
+    async fn reports_cache_status_and_upstream_latency() {
+        let (result, cache, upstream_ms) = call(Ok(JsonValue::from("0x02"))).await;
+        assert_eq!(result, Ok(json!("0x02")));
+        assert_eq!(cache, Some("miss"));
+        assert_eq!(upstream_ms, Some(3.0));
+    }
+
+    #[tokio::test]
+    async fn appends_correlation_id_to_errors() {
+        let (result, _, _) = call(Err(errors::failed("boom"))).await;
+        let error = result.unwrap_err();
+        assert_eq!(error.code(), errors::failed("boom").code());
+        assert!(error.message().starts_with("Call Execution Failed (request id: "));
+        assert_eq!(error.data().unwrap().get(), r#""boom""#);
+    }
+}
@@ -0,0 +1,137 @@
+use async_trait::async_trait;
+use jsonrpsee::core::JsonValue;
+use opentelemetry::trace::FutureExt;
+
+use crate::{
+    config::{MethodRemap, RemapParam},
+    middlewares::{CallRequest, CallResult, Middleware, MiddlewareBuilder, NextFn, RpcMethod, TRACER},
+    utils::{TypeRegistry, TypeRegistryRef},
+};
+
+/// The method name requested by the client before it was remapped.
+pub struct OriginalMethod(pub String);
+
+pub struct MethodRemapMiddleware {
+    remap: MethodRemap,
+}
+
+impl MethodRemapMiddleware {
+    pub fn new(remap: MethodRemap) -> Self {
+        Self { remap }
+    }
+
+    fn remap_params(&self, params: Vec<JsonValue>) -> Vec<JsonValue> {
+        let Some(ref mapping) = self.remap.params else {
+            return params;
+        };
+
+        mapping
+            .iter()
+            .map(|param| match param {
+                RemapParam::From { from } => params.get(*from).cloned().unwrap_or(JsonValue::Null),
+                RemapParam::Value { value } => value.clone(),
+            })
+            .collect()
+    }
+}
+
+#[async_trait]
+impl MiddlewareBuilder<RpcMethod, CallRequest, CallResult> for MethodRemapMiddleware {
+    async fn build(
+        method: &RpcMethod,
+        _extensions: &TypeRegistryRef,
+    ) -> Option<Box<dyn Middleware<CallRequest, CallResult>>> {
+        method.remap.as_ref().map(|remap| {
+            Box::new(MethodRemapMiddleware::new(remap.clone())) as Box<dyn Middleware<CallRequest, CallResult>>
+        })
+    }
+}
+
+#[async_trait]
+impl Middleware<CallRequest, CallResult> for MethodRemapMiddleware {
+    async fn call(
+        &self,
+        request: CallRequest,
+        mut context: TypeRegistry,
+        next: NextFn<CallRequest, CallResult>,
+    ) -> CallResult {
+        async move {
+            let CallRequest {
+                method,
+                params,
+                features,
+            } = request;
+            tracing::trace!("Remap method {} to {}", method, self.remap.method);
+
+            let request = CallRequest::new(&self.remap.method, self.remap_params(params)).with_features(features);
+            context.insert(OriginalMethod(method));
+
+            next(request, context).await
+        }
+        .with_context(TRACER.context("method_remap"))
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::FutureExt;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn remap_method_and_params() {
+        let middleware = MethodRemapMiddleware::new(MethodRemap {
+            method: "debug_call".to_string(),
+            params: Some(vec![
+                RemapParam::From { from: 1 },
+                RemapParam::Value { value: json!("latest") },
+                RemapParam::From { from: 5 },
+            ]),
+        });
+
+        let res = middleware
+            .call(
+                CallRequest::new("eth_call", vec![json!("0x01"), json!("0x02")]),
+                Default::default(),
+                Box::new(move |req: CallRequest, context: TypeRegistry| {
+                    async move {
+                        assert_eq!(req.method, "debug_call");
+                        assert_eq!(req.params, vec![json!("0x02"), json!("latest"), JsonValue::Null]);
+                        assert_eq!(context.get::<OriginalMethod>().unwrap().0, "eth_call");
+                        Ok(json!("0x1111"))
+                    }
+                    .boxed()
+                }),
+            )
+            .await;
+
+        assert_eq!(res.unwrap(), json!("0x1111"));
+    }
+
+    #[tokio::test]
+    async fn keep_params_if_not_configured() {
+        let middleware = MethodRemapMiddleware::new(MethodRemap {
+            method: "debug_call".to_string(),
+            params: None,
+        });
+
+        let res = middleware
+            .call(
+                CallRequest::new("eth_call", vec![json!(1)]),
+                Default::default(),
+                Box::new(move |req: CallRequest, _| {
+                    async move {
+                        assert_eq!(req.method, "debug_call");
+                        assert_eq!(req.params, vec![json!(1)]);
+                        Ok(json!(2))
+                    }
+                    .boxed()
+                }),
+            )
+            .await;
+
+        assert_eq!(res.unwrap(), json!(2));
+    }
+}
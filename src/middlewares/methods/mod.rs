@@ -1,9 +1,24 @@
+pub mod alerting;
+pub mod archive;
 pub mod block_tag;
+pub mod bulkhead;
 pub mod cache;
+pub mod cache_by_block;
 pub mod delay;
+pub mod fallback_response;
 pub mod inject_params;
+pub mod logging;
+pub mod method_remap;
+pub mod params_transform;
+pub mod preflight;
+pub mod read_only;
 pub mod response;
+pub mod response_size;
+pub mod retry;
+pub mod serve_from_head;
+pub mod transform_response;
 pub mod upstream;
+pub mod validate;
 
 #[cfg(test)]
 pub mod testing;
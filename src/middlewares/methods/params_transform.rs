@@ -0,0 +1,152 @@
+use async_trait::async_trait;
+use jsonrpsee::core::JsonValue;
+use opentelemetry::trace::FutureExt;
+
+use crate::{
+    config::{MethodParam, ParamCoercion},
+    middlewares::{CallRequest, CallResult, Middleware, MiddlewareBuilder, NextFn, RpcMethod, TRACER},
+    utils::{TypeRegistry, TypeRegistryRef},
+};
+
+/// Applies the `default` and `coerce` of the method params, so equivalent calls share cache entries.
+pub struct ParamsTransformMiddleware {
+    params: Vec<MethodParam>,
+}
+
+impl ParamsTransformMiddleware {
+    pub fn new(params: Vec<MethodParam>) -> Self {
+        Self { params }
+    }
+
+    fn transform(&self, params: &mut Vec<JsonValue>) {
+        for (value, param) in params.iter_mut().zip(&self.params) {
+            match (&param.default, param.coerce) {
+                (Some(default), _) if value.is_null() => *value = default.clone(),
+                (_, Some(coercion)) => *value = coerce(value, coercion),
+                _ => {}
+            }
+        }
+
+        let Some(last) = self.params.iter().rposition(|p| p.default.is_some()) else {
+            return;
+        };
+        while params.len() <= last {
+            let param = &self.params[params.len()];
+            match param.default {
+                Some(ref default) => params.push(default.clone()),
+                None if param.optional => params.push(JsonValue::Null),
+                // leave the missing required param to upstream to reject
+                None => return,
+            }
+        }
+    }
+}
+
+fn coerce(value: &JsonValue, coercion: ParamCoercion) -> JsonValue {
+    match (coercion, value) {
+        (ParamCoercion::Hex, JsonValue::Number(n)) => match n.as_u64() {
+            Some(n) => format!("0x{n:x}").into(),
+            None => value.clone(),
+        },
+        (ParamCoercion::Hex, JsonValue::String(s)) if !s.is_empty() && s.chars().all(|c| c.is_ascii_digit()) => {
+            match s.parse::<u64>() {
+                Ok(n) => format!("0x{n:x}").into(),
+                Err(_) => value.clone(),
+            }
+        }
+        (ParamCoercion::Lowercase, JsonValue::String(s)) if s.starts_with("0x") || s.starts_with("0X") => {
+            s.to_ascii_lowercase().into()
+        }
+        _ => value.clone(),
+    }
+}
+
+#[async_trait]
+impl MiddlewareBuilder<RpcMethod, CallRequest, CallResult> for ParamsTransformMiddleware {
+    async fn build(
+        method: &RpcMethod,
+        _extensions: &TypeRegistryRef,
+    ) -> Option<Box<dyn Middleware<CallRequest, CallResult>>> {
+        if !method.params.iter().any(|p| p.default.is_some() || p.coerce.is_some()) {
+            return None;
+        }
+        Some(Box::new(Self::new(method.params.clone())))
+    }
+}
+
+#[async_trait]
+impl Middleware<CallRequest, CallResult> for ParamsTransformMiddleware {
+    async fn call(
+        &self,
+        mut request: CallRequest,
+        context: TypeRegistry,
+        next: NextFn<CallRequest, CallResult>,
+    ) -> CallResult {
+        async move {
+            self.transform(&mut request.params);
+            next(request, context).await
+        }
+        .with_context(TRACER.context("params_transform"))
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    fn middleware() -> ParamsTransformMiddleware {
+        ParamsTransformMiddleware::new(
+            serde_yaml::from_str(
+                r#"
+                - name: number
+                  ty: BlockNumber
+                  coerce: hex
+                - name: key
+                  optional: true
+                  coerce: lowercase
+                - name: full
+                  ty: Boolean
+                  optional: true
+                  default: false
+                "#,
+            )
+            .unwrap(),
+        )
+    }
+
+    fn transform(params: Vec<JsonValue>) -> Vec<JsonValue> {
+        let mut params = params;
+        middleware().transform(&mut params);
+        params
+    }
+
+    #[test]
+    fn coerces_params() {
+        assert_eq!(
+            transform(vec![json!(16), json!("0xABCD"), json!(true)]),
+            vec![json!("0x10"), json!("0xabcd"), json!(true)]
+        );
+        assert_eq!(
+            transform(vec![json!("16"), json!("Alice"), json!(true)]),
+            vec![json!("0x10"), json!("Alice"), json!(true)]
+        );
+        assert_eq!(transform(vec![json!("0x10")])[0], json!("0x10"));
+    }
+
+    #[test]
+    fn injects_defaults() {
+        assert_eq!(
+            transform(vec![json!(16)]),
+            vec![json!("0x10"), json!(null), json!(false)]
+        );
+        assert_eq!(
+            transform(vec![json!(16), json!(null), json!(null)]),
+            vec![json!("0x10"), json!(null), json!(false)]
+        );
+        // required params are not made up
+        assert_eq!(transform(vec![]), Vec::<JsonValue>::new());
+    }
+}
@@ -0,0 +1,80 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use opentelemetry::trace::FutureExt;
+
+use crate::{
+    extensions::client::Client,
+    middlewares::{CallRequest, CallResult, Middleware, MiddlewareBuilder, NextFn, RpcMethod, TRACER},
+    utils::{errors, TypeRegistry, TypeRegistryRef},
+};
+
+pub const UPSTREAM_UNAVAILABLE_ERROR: &str = "Service unavailable: no upstream connection";
+
+/// Fails calls immediately while the client has no upstream connection, instead of waiting for the
+/// connection to come back or the request to time out.
+pub struct PreflightCheckMiddleware {
+    client: Arc<Client>,
+}
+
+impl PreflightCheckMiddleware {
+    pub fn new(client: Arc<Client>) -> Self {
+        Self { client }
+    }
+}
+
+#[async_trait]
+impl MiddlewareBuilder<RpcMethod, CallRequest, CallResult> for PreflightCheckMiddleware {
+    async fn build(
+        _method: &RpcMethod,
+        extensions: &TypeRegistryRef,
+    ) -> Option<Box<dyn Middleware<CallRequest, CallResult>>> {
+        let client = extensions
+            .read()
+            .await
+            .get::<Client>()
+            .expect("Client extension not found");
+        Some(Box::new(PreflightCheckMiddleware::new(client)))
+    }
+}
+
+#[async_trait]
+impl Middleware<CallRequest, CallResult> for PreflightCheckMiddleware {
+    async fn call(
+        &self,
+        request: CallRequest,
+        context: TypeRegistry,
+        next: NextFn<CallRequest, CallResult>,
+    ) -> CallResult {
+        async move {
+            if !self.client.is_healthy() {
+                return Err(errors::failed(UPSTREAM_UNAVAILABLE_ERROR));
+            }
+            next(request, context).await
+        }
+        .with_context(TRACER.context("preflight"))
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    #[tokio::test]
+    async fn fails_fast_without_upstream_connection() {
+        // nothing listens on port 1
+        let client = Arc::new(Client::with_endpoints(["ws://127.0.0.1:1"]).unwrap());
+        let middleware = PreflightCheckMiddleware::new(client);
+
+        let res = middleware
+            .call(
+                CallRequest::new("system_name", vec![]),
+                Default::default(),
+                Box::new(move |_, _| async move { panic!("must not be forwarded") }.boxed()),
+            )
+            .await;
+        assert_eq!(res, Err(errors::failed(UPSTREAM_UNAVAILABLE_ERROR)));
+    }
+}
@@ -0,0 +1,131 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use opentelemetry::trace::FutureExt;
+
+use crate::{
+    extensions::read_only::ReadOnly,
+    middlewares::{CallRequest, CallResult, Middleware, MiddlewareBuilder, NextFn, RpcMethod, TRACER},
+    utils::{errors, TypeRegistry, TypeRegistryRef},
+};
+
+pub const READ_ONLY_ERROR: &str = "Subway is in read-only mode";
+
+enum Role {
+    // rejected while read-only mode is on
+    Write,
+    // reports read-only state in the health response
+    Health,
+}
+
+pub struct ReadOnlyMiddleware {
+    read_only: Arc<ReadOnly>,
+    role: Role,
+}
+
+impl ReadOnlyMiddleware {
+    pub fn new(read_only: Arc<ReadOnly>, method: &str) -> Option<Self> {
+        let role = if read_only.is_write_method(method) {
+            Role::Write
+        } else if method == "system_health" {
+            Role::Health
+        } else {
+            return None;
+        };
+        Some(Self { read_only, role })
+    }
+}
+
+#[async_trait]
+impl MiddlewareBuilder<RpcMethod, CallRequest, CallResult> for ReadOnlyMiddleware {
+    async fn build(
+        method: &RpcMethod,
+        extensions: &TypeRegistryRef,
+    ) -> Option<Box<dyn Middleware<CallRequest, CallResult>>> {
+        let read_only = extensions.read().await.get::<ReadOnly>()?;
+        Self::new(read_only, &method.method).map(|m| Box::new(m) as Box<dyn Middleware<CallRequest, CallResult>>)
+    }
+}
+
+#[async_trait]
+impl Middleware<CallRequest, CallResult> for ReadOnlyMiddleware {
+    async fn call(
+        &self,
+        request: CallRequest,
+        context: TypeRegistry,
+        next: NextFn<CallRequest, CallResult>,
+    ) -> CallResult {
+        async move {
+            match self.role {
+                Role::Write if self.read_only.is_enabled() => Err(errors::failed(READ_ONLY_ERROR)),
+                Role::Write => next(request, context).await,
+                Role::Health => {
+                    let mut result = next(request, context).await?;
+                    if let Some(health) = result.as_object_mut() {
+                        health.insert("readOnly".to_string(), self.read_only.is_enabled().into());
+                    }
+                    Ok(result)
+                }
+            }
+        }
+        .with_context(TRACER.context("read_only"))
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::extensions::read_only::ReadOnlyConfig;
+    use futures::FutureExt;
+    use serde_json::json;
+
+    fn read_only(enabled: bool) -> Arc<ReadOnly> {
+        Arc::new(ReadOnly::new(ReadOnlyConfig {
+            enabled,
+            methods: vec!["author_*".to_string()],
+            admin_toggle: false,
+        }))
+    }
+
+    #[tokio::test]
+    async fn rejects_write_and_serves_read() {
+        let read_only = read_only(true);
+        assert!(ReadOnlyMiddleware::new(read_only.clone(), "chain_getBlock").is_none());
+
+        let middleware = ReadOnlyMiddleware::new(read_only.clone(), "author_submitExtrinsic").unwrap();
+        let res = middleware
+            .call(
+                CallRequest::new("author_submitExtrinsic", vec![json!("0x01")]),
+                Default::default(),
+                Box::new(move |_, _| async move { panic!() }.boxed()),
+            )
+            .await;
+        assert_eq!(res, Err(errors::failed(READ_ONLY_ERROR)));
+
+        // toggled off at runtime
+        read_only.set_enabled(false);
+        let res = middleware
+            .call(
+                CallRequest::new("author_submitExtrinsic", vec![json!("0x01")]),
+                Default::default(),
+                Box::new(move |_, _| async move { Ok(json!("0x1234")) }.boxed()),
+            )
+            .await;
+        assert_eq!(res, Ok(json!("0x1234")));
+    }
+
+    #[tokio::test]
+    async fn reports_state_in_health() {
+        let middleware = ReadOnlyMiddleware::new(read_only(true), "system_health").unwrap();
+        let res = middleware
+            .call(
+                CallRequest::new("system_health", vec![]),
+                Default::default(),
+                Box::new(move |_, _| async move { Ok(json!({ "peers": 1 })) }.boxed()),
+            )
+            .await;
+        assert_eq!(res, Ok(json!({ "peers": 1, "readOnly": true })));
+    }
+}
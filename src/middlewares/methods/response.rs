@@ -2,17 +2,60 @@ use async_trait::async_trait;
 use jsonrpsee::core::JsonValue;
 
 use crate::{
+    extensions::api::HeadTracker,
     middlewares::{CallRequest, CallResult, Middleware, MiddlewareBuilder, NextFn, RpcMethod, TRACER},
     utils::{TypeRegistry, TypeRegistryRef},
 };
 
 pub struct ResponseMiddleware {
     resp: JsonValue,
+    // fills the head placeholders of the response
+    heads: Option<HeadTracker>,
 }
 
 impl ResponseMiddleware {
     pub fn new(resp: JsonValue) -> Self {
-        Self { resp }
+        Self { resp, heads: None }
+    }
+
+    /// Replaces `{{head_number}}`, `{{head_hash}}`, `{{finalized_number}}` and `{{finalized_hash}}`
+    /// in the strings of the response with the current heads, null until known.
+    pub fn with_heads(mut self, heads: HeadTracker) -> Self {
+        self.heads = Some(heads);
+        self
+    }
+}
+
+fn has_placeholder(value: &JsonValue) -> bool {
+    match value {
+        JsonValue::String(s) => s.contains("{{"),
+        JsonValue::Array(values) => values.iter().any(has_placeholder),
+        JsonValue::Object(map) => map.values().any(has_placeholder),
+        _ => false,
+    }
+}
+
+fn render(value: &JsonValue, vars: &[(&str, JsonValue)]) -> JsonValue {
+    match value {
+        JsonValue::String(s) => {
+            // a whole placeholder keeps the type of the value, e.g. a number
+            let whole = s.strip_prefix("{{").and_then(|s| s.strip_suffix("}}"));
+            if let Some((_, var)) = vars.iter().find(|(name, _)| whole == Some(*name)) {
+                return var.clone();
+            }
+            let mut rendered = s.clone();
+            for (name, var) in vars {
+                let var = match var {
+                    JsonValue::String(v) => v.clone(),
+                    v => v.to_string(),
+                };
+                rendered = rendered.replace(&format!("{{{{{name}}}}}"), &var);
+            }
+            rendered.into()
+        }
+        JsonValue::Array(values) => values.iter().map(|v| render(v, vars)).collect(),
+        JsonValue::Object(map) => map.iter().map(|(k, v)| (k.clone(), render(v, vars))).collect(),
+        _ => value.clone(),
     }
 }
 
@@ -20,12 +63,24 @@ impl ResponseMiddleware {
 impl MiddlewareBuilder<RpcMethod, CallRequest, CallResult> for ResponseMiddleware {
     async fn build(
         method: &RpcMethod,
-        _extensions: &TypeRegistryRef,
+        extensions: &TypeRegistryRef,
     ) -> Option<Box<dyn Middleware<CallRequest, CallResult>>> {
-        method
-            .response
-            .as_ref()
-            .map(|resp| Box::new(ResponseMiddleware::new(resp.clone())) as Box<dyn Middleware<CallRequest, CallResult>>)
+        let resp = method.response.as_ref()?;
+        let middleware = ResponseMiddleware::new(resp.clone());
+        if !has_placeholder(resp) {
+            return Some(Box::new(middleware));
+        }
+
+        match HeadTracker::from_registry(&*extensions.read().await) {
+            Some(heads) => Some(Box::new(middleware.with_heads(heads))),
+            None => {
+                tracing::warn!(
+                    "{} response has placeholders but no substrate_api or eth_api to fill them",
+                    method.method
+                );
+                Some(Box::new(middleware))
+            }
+        }
     }
 }
 
@@ -38,6 +93,33 @@ impl Middleware<CallRequest, CallResult> for ResponseMiddleware {
         _next: NextFn<CallRequest, CallResult>,
     ) -> CallResult {
         let _span = TRACER.context("response");
-        Ok(self.resp.clone())
+        let Some(ref heads) = self.heads else {
+            return Ok(self.resp.clone());
+        };
+
+        let (head_hash, head_number) = heads.current_head().unzip();
+        let (finalized_hash, finalized_number) = heads.current_finalized_head().unzip();
+        let vars = [
+            ("head_hash", head_hash.unwrap_or_default()),
+            ("head_number", head_number.into()),
+            ("finalized_hash", finalized_hash.unwrap_or_default()),
+            ("finalized_number", finalized_number.into()),
+        ];
+        Ok(render(&self.resp, &vars))
     }
 }
+
+#[test]
+fn render_works() {
+    use serde_json::json;
+
+    let vars = [("head_number", json!(16)), ("head_hash", json!("0xabcd"))];
+    assert_eq!(
+        render(
+            &json!({ "number": "{{head_number}}", "info": ["at {{head_hash}} #{{head_number}}", 1] }),
+            &vars
+        ),
+        json!({ "number": 16, "info": ["at 0xabcd #16", 1] })
+    );
+    assert_eq!(render(&json!("{{unknown}}"), &vars), json!("{{unknown}}"));
+}
@@ -0,0 +1,125 @@
+use async_trait::async_trait;
+use jsonrpsee::core::JsonValue;
+use opentelemetry::trace::FutureExt;
+
+use crate::{
+    middlewares::{CallRequest, CallResult, Middleware, MiddlewareBuilder, NextFn, RpcMethod, TRACER},
+    utils::{errors, TypeRegistry, TypeRegistryRef},
+};
+
+/// Truncates array results over `max_bytes`. The size of every response is already bounded by the
+/// `max_response_size` of the upstream client, which rejects larger ones without buffering them.
+pub struct ResponseSizeMiddleware {
+    max_bytes: usize,
+}
+
+impl ResponseSizeMiddleware {
+    pub fn new(max_bytes: usize) -> Self {
+        Self { max_bytes }
+    }
+}
+
+fn encoded_len(value: &JsonValue) -> usize {
+    serde_json::to_vec(value).map(|v| v.len()).unwrap_or(usize::MAX)
+}
+
+/// Keeps the leading items of the array that fit in `max_bytes` once encoded.
+fn truncate_array(items: &mut Vec<JsonValue>, max_bytes: usize) {
+    // brackets
+    let mut size = 2;
+    let mut keep = 0;
+    for (index, item) in items.iter().enumerate() {
+        // comma before every item but the first
+        let item_size = encoded_len(item) + usize::from(index > 0);
+        if size + item_size > max_bytes {
+            break;
+        }
+        size += item_size;
+        keep += 1;
+    }
+    items.truncate(keep);
+}
+
+#[async_trait]
+impl MiddlewareBuilder<RpcMethod, CallRequest, CallResult> for ResponseSizeMiddleware {
+    async fn build(
+        method: &RpcMethod,
+        _extensions: &TypeRegistryRef,
+    ) -> Option<Box<dyn Middleware<CallRequest, CallResult>>> {
+        method
+            .max_response_bytes
+            .map(|max_bytes| Box::new(Self::new(max_bytes)) as Box<dyn Middleware<CallRequest, CallResult>>)
+    }
+}
+
+#[async_trait]
+impl Middleware<CallRequest, CallResult> for ResponseSizeMiddleware {
+    async fn call(
+        &self,
+        request: CallRequest,
+        context: TypeRegistry,
+        next: NextFn<CallRequest, CallResult>,
+    ) -> CallResult {
+        async move {
+            let method = request.method.clone();
+            let mut result = next(request, context).await?;
+
+            let size = encoded_len(&result);
+            if size <= self.max_bytes {
+                return Ok(result);
+            }
+
+            match result.as_array_mut() {
+                Some(items) => {
+                    let total = items.len();
+                    truncate_array(items, self.max_bytes);
+                    tracing::warn!(
+                        "Response of {method} is {size} bytes, truncated to {} of {total} items",
+                        items.len()
+                    );
+                    Ok(result)
+                }
+                None => Err(errors::failed(format!(
+                    "Response is {size} bytes, over the limit of {} bytes",
+                    self.max_bytes
+                ))),
+            }
+        }
+        .with_context(TRACER.context("response_size"))
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::FutureExt;
+    use serde_json::json;
+
+    async fn call(middleware: &ResponseSizeMiddleware, response: JsonValue) -> CallResult {
+        middleware
+            .call(
+                CallRequest::new("state_getPairs", vec![json!("0x")]),
+                Default::default(),
+                Box::new(move |_, _| async move { Ok(response) }.boxed()),
+            )
+            .await
+    }
+
+    #[tokio::test]
+    async fn truncates_arrays() {
+        let middleware = ResponseSizeMiddleware::new(20);
+        assert_eq!(call(&middleware, json!("0x1234")).await, Ok(json!("0x1234")));
+        // `["0x1234","0x5678"]` is 19 bytes
+        assert_eq!(
+            call(&middleware, json!(["0x1234", "0x5678", "0x9abc"])).await,
+            Ok(json!(["0x1234", "0x5678"]))
+        );
+        // other values can not be truncated
+        assert_eq!(
+            call(&middleware, json!({ "data": "0x123456789abcdef0" })).await,
+            Err(errors::failed("Response is 29 bytes, over the limit of 20 bytes"))
+        );
+    }
+}
@@ -0,0 +1,177 @@
+use std::{future::Future, time::Duration};
+
+use async_trait::async_trait;
+use jsonrpsee::types::ErrorObjectOwned;
+use opentelemetry::trace::FutureExt;
+use rand::Rng;
+
+use crate::{
+    config::RetryParams,
+    middlewares::{
+        methods::{fallback_response::is_upstream_unavailable, upstream::CIRCUIT_OPEN_ERROR},
+        CallRequest, CallResult, Middleware, MiddlewareBuilder, NextFn, RpcMethod, TRACER,
+    },
+    utils::{TypeRegistry, TypeRegistryRef},
+};
+
+/// Retries of a call, put in the context by the retry middleware and run by the upstream middleware,
+/// which then sends every attempt once instead of leaving retries to the client.
+#[derive(Debug, Clone)]
+pub struct Retry(RetryParams);
+
+impl Retry {
+    /// Calls `send` again with backoff while upstream can not be reached, up to `max_attempts` calls.
+    /// Each call goes through the circuit breaker, calls failed by an open circuit are not retried.
+    pub async fn run<F, Fut>(&self, method: &str, mut send: F) -> CallResult
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = CallResult>,
+    {
+        let mut result = send().await;
+        let mut attempt = 1;
+        while attempt < self.0.max_attempts && result.as_ref().is_err_and(is_retryable) {
+            tokio::time::sleep(retry_backoff(&self.0, attempt)).await;
+            tracing::debug!("Retrying {method} ({attempt}/{})", self.0.max_attempts - 1);
+            result = send().await;
+            attempt += 1;
+        }
+        result
+    }
+}
+
+fn is_retryable(error: &ErrorObjectOwned) -> bool {
+    is_upstream_unavailable(error) && !error.data().is_some_and(|d| d.get().contains(CIRCUIT_OPEN_ERROR))
+}
+
+/// Delay before the given retry, starting at 1.
+fn retry_backoff(retry: &RetryParams, attempt: u32) -> Duration {
+    let backoff = retry.backoff_base_ms.saturating_mul(1u64 << (attempt - 1).min(16));
+    let jitter = rand::thread_rng().gen_range(0..=retry.jitter_ms);
+    Duration::from_millis(backoff.saturating_add(jitter))
+}
+
+/// Calls upstream again when it is disconnected or times out, for methods with `retry`.
+pub struct RetryMiddleware {
+    retry: Retry,
+}
+
+impl RetryMiddleware {
+    pub fn new(retry: RetryParams) -> Self {
+        Self { retry: Retry(retry) }
+    }
+}
+
+#[async_trait]
+impl MiddlewareBuilder<RpcMethod, CallRequest, CallResult> for RetryMiddleware {
+    async fn build(
+        method: &RpcMethod,
+        _extensions: &TypeRegistryRef,
+    ) -> Option<Box<dyn Middleware<CallRequest, CallResult>>> {
+        method
+            .retry
+            .clone()
+            .map(|retry| Box::new(RetryMiddleware::new(retry)) as Box<dyn Middleware<CallRequest, CallResult>>)
+    }
+}
+
+#[async_trait]
+impl Middleware<CallRequest, CallResult> for RetryMiddleware {
+    async fn call(
+        &self,
+        request: CallRequest,
+        mut context: TypeRegistry,
+        next: NextFn<CallRequest, CallResult>,
+    ) -> CallResult {
+        async move {
+            context.insert(self.retry.clone());
+            next(request, context).await
+        }
+        .with_context(TRACER.context("retry"))
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+    use crate::utils::errors;
+
+    fn retry(max_attempts: u32) -> Retry {
+        Retry(RetryParams {
+            max_attempts,
+            backoff_base_ms: 1,
+            jitter_ms: 0,
+        })
+    }
+
+    #[test]
+    fn retry_backoff_doubles_with_jitter() {
+        let retry = RetryParams {
+            max_attempts: 4,
+            backoff_base_ms: 100,
+            jitter_ms: 10,
+        };
+        for (attempt, base) in [(1, 100), (2, 200), (3, 400)] {
+            let backoff = retry_backoff(&retry, attempt).as_millis();
+            assert!((base..=base + 10).contains(&backoff), "{attempt}: {backoff}");
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_while_upstream_unavailable() {
+        let calls = &AtomicU32::new(0);
+        let res = retry(3)
+            .run("test", move || async move {
+                match calls.fetch_add(1, Ordering::SeqCst) {
+                    0 => Err(errors::upstream_unreachable("connection closed")),
+                    _ => Ok(serde_json::json!(1)),
+                }
+            })
+            .await;
+        assert_eq!(res, Ok(serde_json::json!(1)));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // gives up after max_attempts calls
+        let calls = &AtomicU32::new(0);
+        let res = retry(3)
+            .run("test", move || async move {
+                calls.fetch_add(1, Ordering::SeqCst);
+                Err(errors::upstream_unreachable("connection closed"))
+            })
+            .await;
+        assert!(res.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        // errors of upstream are not retried
+        for error in [
+            errors::invalid_params("bad"),
+            errors::internal_error("runtime panicked"),
+        ] {
+            let calls = &AtomicU32::new(0);
+            let res = retry(3)
+                .run("test", move || {
+                    let error = error.clone();
+                    async move {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        Err(error)
+                    }
+                })
+                .await;
+            assert!(res.is_err());
+            assert_eq!(calls.load(Ordering::SeqCst), 1);
+        }
+
+        // nor calls failed by an open circuit
+        let calls = &AtomicU32::new(0);
+        let res = retry(3)
+            .run("test", move || async move {
+                calls.fetch_add(1, Ordering::SeqCst);
+                Err(errors::failed(CIRCUIT_OPEN_ERROR))
+            })
+            .await;
+        assert!(res.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+}
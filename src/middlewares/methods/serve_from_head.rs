@@ -0,0 +1,218 @@
+use async_trait::async_trait;
+use jsonrpsee::core::JsonValue;
+use opentelemetry::trace::FutureExt;
+use std::sync::Arc;
+
+use crate::{
+    extensions::api::{SubstrateApi, ValueHandle},
+    middlewares::{CallRequest, CallResult, Middleware, MiddlewareBuilder, NextFn, RpcMethod, TRACER},
+    utils::{TypeRegistry, TypeRegistryRef},
+};
+
+pub enum HeadValue {
+    Header(ValueHandle<JsonValue>),
+    BlockHash(ValueHandle<(JsonValue, u64)>),
+}
+
+pub struct ServeFromHeadMiddleware {
+    value: HeadValue,
+}
+
+#[async_trait]
+impl MiddlewareBuilder<RpcMethod, CallRequest, CallResult> for ServeFromHeadMiddleware {
+    async fn build(
+        method: &RpcMethod,
+        extensions: &TypeRegistryRef,
+    ) -> Option<Box<dyn Middleware<CallRequest, CallResult>>> {
+        if !method.serve_from_head {
+            return None;
+        }
+
+        let Some(api) = extensions.read().await.get::<SubstrateApi>() else {
+            tracing::warn!(
+                "{} has serve_from_head but no substrate_api to follow heads",
+                method.method
+            );
+            return None;
+        };
+
+        Self::new(&method.method, api).map(|m| Box::new(m) as Box<dyn Middleware<CallRequest, CallResult>>)
+    }
+}
+
+impl ServeFromHeadMiddleware {
+    pub fn new(method: &str, api: Arc<SubstrateApi>) -> Option<Self> {
+        let value = match method {
+            "chain_getHeader" => HeadValue::Header(api.get_head_header()),
+            "chain_getBlockHash" => HeadValue::BlockHash(api.get_head()),
+            _ => return None,
+        };
+        Some(Self { value })
+    }
+}
+
+#[async_trait]
+impl Middleware<CallRequest, CallResult> for ServeFromHeadMiddleware {
+    async fn call(
+        &self,
+        request: CallRequest,
+        context: TypeRegistry,
+        next: NextFn<CallRequest, CallResult>,
+    ) -> CallResult {
+        // params pin a specific block
+        if request.params.iter().any(|p| !p.is_null()) {
+            return next(request, context).await;
+        }
+
+        async move {
+            match &self.value {
+                HeadValue::Header(header) => Ok(header.read().await),
+                HeadValue::BlockHash(head) => Ok(head.read().await.0),
+            }
+        }
+        .with_context(TRACER.context("serve_from_head"))
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::extensions::client::mock::{MockRequest, MockSubscription, TestServerBuilder};
+    use crate::extensions::client::Client;
+    use futures::FutureExt;
+    use jsonrpsee::server::ServerHandle;
+    use serde_json::json;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+
+    struct ExecutionContext {
+        api: Arc<SubstrateApi>,
+        _server: ServerHandle,
+        head_sub: MockSubscription,
+        _finalized_head_rx: mpsc::Receiver<MockSubscription>,
+        block_hash_rx: mpsc::Receiver<MockRequest>,
+    }
+
+    async fn create_api() -> ExecutionContext {
+        let mut builder = TestServerBuilder::new();
+
+        let mut head_rx =
+            builder.register_subscription("chain_subscribeNewHeads", "chain_newHead", "chain_unsubscribeNewHeads");
+
+        let _finalized_head_rx = builder.register_subscription(
+            "chain_subscribeFinalizedHeads",
+            "chain_finalizedHead",
+            "chain_unsubscribeFinalizedHeads",
+        );
+
+        let mut block_hash_rx = builder.register_method("chain_getBlockHash");
+
+        let (addr, _server) = builder.build().await;
+
+        let client = Client::with_endpoints([format!("ws://{addr}")]).unwrap();
+        let api = Arc::new(SubstrateApi::new(Arc::new(client), Duration::from_secs(100)));
+
+        let head_sub = head_rx.recv().await.unwrap();
+        head_sub
+            .send(json!({ "number": "0x4321", "parentHash": "0x01", "digest": { "logs": [] } }))
+            .await;
+        block_hash_rx.recv().await.unwrap().respond(json!("0xabcd"));
+
+        ExecutionContext {
+            api,
+            _server,
+            head_sub,
+            _finalized_head_rx,
+            block_hash_rx,
+        }
+    }
+
+    fn unreachable_next() -> NextFn<CallRequest, CallResult> {
+        Box::new(|_, _| async { panic!("should be served from head") }.boxed())
+    }
+
+    #[tokio::test]
+    async fn serves_latest_header_as_received() {
+        let mut context = create_api().await;
+        let middleware = ServeFromHeadMiddleware::new("chain_getHeader", context.api.clone()).unwrap();
+
+        let result = middleware
+            .call(
+                CallRequest::new("chain_getHeader", vec![]),
+                Default::default(),
+                unreachable_next(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            result,
+            json!({ "number": "0x4321", "parentHash": "0x01", "digest": { "logs": [] } })
+        );
+
+        context
+            .head_sub
+            .send(json!({ "number": "0x4322", "parentHash": "0xabcd", "extra": true }))
+            .await;
+        context.block_hash_rx.recv().await.unwrap().respond(json!("0xbcde"));
+        tokio::time::sleep(Duration::from_millis(1)).await;
+
+        let result = middleware
+            .call(
+                CallRequest::new("chain_getHeader", vec![JsonValue::Null]),
+                Default::default(),
+                unreachable_next(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            result,
+            json!({ "number": "0x4322", "parentHash": "0xabcd", "extra": true })
+        );
+    }
+
+    #[tokio::test]
+    async fn serves_head_block_hash() {
+        let context = create_api().await;
+        let middleware = ServeFromHeadMiddleware::new("chain_getBlockHash", context.api.clone()).unwrap();
+
+        let result = middleware
+            .call(
+                CallRequest::new("chain_getBlockHash", vec![]),
+                Default::default(),
+                unreachable_next(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(result, json!("0xabcd"));
+    }
+
+    #[tokio::test]
+    async fn pinned_block_goes_to_next() {
+        let context = create_api().await;
+        let middleware = ServeFromHeadMiddleware::new("chain_getBlockHash", context.api.clone()).unwrap();
+
+        let result = middleware
+            .call(
+                CallRequest::new("chain_getBlockHash", vec![json!(1)]),
+                Default::default(),
+                Box::new(|req: CallRequest, _| {
+                    async move {
+                        assert_eq!(req.params, vec![json!(1)]);
+                        Ok(json!("0x1111"))
+                    }
+                    .boxed()
+                }),
+            )
+            .await
+            .unwrap();
+        assert_eq!(result, json!("0x1111"));
+    }
+
+    #[tokio::test]
+    async fn only_head_methods_supported() {
+        let context = create_api().await;
+        assert!(ServeFromHeadMiddleware::new("chain_getBlock", context.api.clone()).is_none());
+    }
+}
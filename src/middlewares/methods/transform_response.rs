@@ -0,0 +1,141 @@
+use async_trait::async_trait;
+use jaq_interpret::{Ctx, Filter, FilterT, ParseCtx, RcIter, Val};
+use jsonrpsee::core::JsonValue;
+use opentelemetry::trace::FutureExt;
+
+use crate::{
+    middlewares::{CallRequest, CallResult, Middleware, MiddlewareBuilder, NextFn, RpcMethod, TRACER},
+    utils::{errors, TypeRegistry, TypeRegistryRef},
+};
+
+/// Compiles a jq filter, with the jq standard library available.
+pub fn compile(filter: &str) -> Result<Filter, String> {
+    let mut defs = ParseCtx::new(Vec::new());
+    defs.insert_natives(jaq_core::core());
+    defs.insert_defs(jaq_std::std());
+
+    let (parsed, errs) = jaq_parse::parse(filter, jaq_parse::main());
+    let parsed = match parsed {
+        Some(parsed) if errs.is_empty() => parsed,
+        _ => return Err(format!("Invalid filter `{filter}`: {errs:?}")),
+    };
+
+    let compiled = defs.compile(parsed);
+    if !defs.errs.is_empty() {
+        return Err(format!(
+            "Invalid filter `{filter}`: {} undefined filters or variables",
+            defs.errs.len()
+        ));
+    }
+
+    Ok(compiled)
+}
+
+/// Runs the filter and returns its first output, `null` if it has none.
+fn apply(filter: &Filter, value: JsonValue) -> Result<JsonValue, String> {
+    let inputs = RcIter::new(core::iter::empty());
+    let mut outputs = filter.run((Ctx::new([], &inputs), Val::from(value)));
+    match outputs.next() {
+        Some(Ok(output)) => Ok(output.into()),
+        Some(Err(e)) => Err(e.to_string()),
+        None => Ok(JsonValue::Null),
+    }
+}
+
+pub struct ResponseTransformMiddleware {
+    filters: Vec<Filter>,
+}
+
+impl ResponseTransformMiddleware {
+    pub fn new(filters: Vec<Filter>) -> Self {
+        Self { filters }
+    }
+}
+
+#[async_trait]
+impl MiddlewareBuilder<RpcMethod, CallRequest, CallResult> for ResponseTransformMiddleware {
+    async fn build(
+        method: &RpcMethod,
+        _extensions: &TypeRegistryRef,
+    ) -> Option<Box<dyn Middleware<CallRequest, CallResult>>> {
+        if method.transform_response.is_empty() {
+            return None;
+        }
+
+        let filters = method
+            .transform_response
+            .iter()
+            .map(|filter| compile(filter))
+            .collect::<Result<Vec<_>, _>>()
+            .expect("Invalid transform_response, checked by config validation");
+
+        Some(Box::new(ResponseTransformMiddleware::new(filters)))
+    }
+}
+
+#[async_trait]
+impl Middleware<CallRequest, CallResult> for ResponseTransformMiddleware {
+    async fn call(
+        &self,
+        request: CallRequest,
+        context: TypeRegistry,
+        next: NextFn<CallRequest, CallResult>,
+    ) -> CallResult {
+        async move {
+            let mut value = next(request, context).await?;
+            for filter in &self.filters {
+                value =
+                    apply(filter, value).map_err(|e| errors::failed(format!("Failed to transform response: {e}")))?;
+            }
+            Ok(value)
+        }
+        .with_context(TRACER.context("transform_response"))
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+    use serde_json::json;
+
+    async fn transform(filters: &[&str], response: JsonValue) -> CallResult {
+        let filters = filters.iter().map(|f| compile(f).unwrap()).collect();
+        ResponseTransformMiddleware::new(filters)
+            .call(
+                CallRequest::new("eth_getTransactionReceipt", vec![]),
+                Default::default(),
+                Box::new(move |_, _| async move { Ok(response) }.boxed()),
+            )
+            .await
+    }
+
+    #[tokio::test]
+    async fn applies_filters_in_order() {
+        let receipt = json!({ "status": "0x1", "logs": [{ "data": "0x01" }, { "data": "0x02" }] });
+
+        let result = transform(&["select(.status == \"0x1\")", ".logs[0]"], receipt.clone()).await;
+        assert_eq!(result, Ok(json!({ "data": "0x01" })));
+
+        let result = transform(&["[.logs[].data]", "length"], receipt.clone()).await;
+        assert_eq!(result, Ok(json!(2)));
+
+        // no output
+        let result = transform(&["select(.status == \"0x0\")", ".logs[0]"], receipt).await;
+        assert_eq!(result, Ok(JsonValue::Null));
+    }
+
+    #[tokio::test]
+    async fn reports_filter_errors() {
+        let result = transform(&[".status + 1"], json!({ "status": "0x1" })).await;
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_filters() {
+        assert!(compile(".logs[").is_err());
+        assert!(compile("not_a_filter(1)").is_err());
+        assert!(compile(".logs | first").is_ok());
+    }
+}
@@ -1,36 +1,358 @@
-use std::sync::Arc;
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
+};
 
 use async_trait::async_trait;
+use jsonrpsee::types::ErrorObjectOwned;
 use opentelemetry::trace::FutureExt;
+use prometheus::{IntGauge, IntGaugeVec};
+use tokio::sync::{Semaphore, SemaphorePermit};
 
 use crate::{
-    extensions::client::Client,
-    middlewares::{CallRequest, CallResult, Middleware, MiddlewareBuilder, NextFn, RpcMethod, TRACER},
-    utils::{TypeRegistry, TypeRegistryRef},
+    extensions::{
+        client::{CircuitBreakerConfig, Client, ForwardedHeaders, UpstreamQueueConfig},
+        metrics,
+        server::SubwayServerBuilder,
+    },
+    middlewares::{
+        methods::{fallback_response::is_upstream_unavailable, logging::UpstreamLatency, retry::Retry},
+        CallRequest, CallResult, Middleware, MiddlewareBuilder, NextFn, RpcMethod, TRACER,
+    },
+    utils::{errors, TypeRegistry, TypeRegistryRef},
 };
 
+/// Put in the context by middlewares that need to tell errors of upstream from errors raised by subway,
+/// e.g. timeouts. Set by the upstream middleware when upstream answered the call with an error.
+#[derive(Debug, Default)]
+pub struct UpstreamError(AtomicBool);
+
+impl UpstreamError {
+    pub fn set(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Number of upstream calls waiting for a slot, labeled by method group.
+pub fn queue_depth_gauge() -> &'static IntGaugeVec {
+    static GAUGE: OnceLock<IntGaugeVec> = OnceLock::new();
+    GAUGE.get_or_init(|| {
+        prometheus::register_int_gauge_vec!(
+            "subway_upstream_queue_depth",
+            "Upstream calls waiting for a concurrency slot",
+            &["group"]
+        )
+        .expect("Failed to register subway_upstream_queue_depth")
+    })
+}
+
+pub const QUEUE_FULL_ERROR: &str = "Service unavailable: upstream queue full";
+pub const QUEUE_TIMEOUT_ERROR: &str = "Service unavailable: timeout waiting in upstream queue";
+
+struct QueueGroup {
+    name: &'static str,
+    semaphore: Semaphore,
+    waiting: AtomicUsize,
+}
+
+impl QueueGroup {
+    fn new(name: &'static str, concurrency: usize) -> Self {
+        Self {
+            name,
+            semaphore: Semaphore::new(concurrency.max(1)),
+            waiting: AtomicUsize::new(0),
+        }
+    }
+}
+
+/// Limits concurrent upstream calls, heavy methods get their own slots so they can't starve light ones.
+/// Waiting calls can be bounded in number and in time. Shared by the upstream middlewares of all methods.
+pub struct UpstreamQueue {
+    light: QueueGroup,
+    heavy: QueueGroup,
+    heavy_methods: HashSet<String>,
+    max_queued: Option<usize>,
+    timeout: Option<Duration>,
+}
+
+impl UpstreamQueue {
+    pub fn new(config: &UpstreamQueueConfig) -> Self {
+        Self {
+            light: QueueGroup::new("light", config.light_concurrency),
+            heavy: QueueGroup::new("heavy", config.heavy_concurrency),
+            heavy_methods: config.heavy_methods.iter().cloned().collect(),
+            max_queued: config.max_queued,
+            timeout: config.queue_timeout_ms.map(Duration::from_millis),
+        }
+    }
+
+    /// Waits for a slot of the method group, counted in the queue depth gauge while waiting.
+    pub async fn acquire(&self, method: &str) -> Result<SemaphorePermit<'_>, ErrorObjectOwned> {
+        let group = if self.heavy_methods.contains(method) {
+            &self.heavy
+        } else {
+            &self.light
+        };
+
+        // a free slot is taken without queueing
+        if let Ok(permit) = group.semaphore.try_acquire() {
+            return Ok(permit);
+        }
+
+        // decremented on drop as the call may be aborted on timeout while waiting
+        struct Waiting<'a>(IntGauge, &'a AtomicUsize);
+        impl Drop for Waiting<'_> {
+            fn drop(&mut self) {
+                self.0.dec();
+                self.1.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+
+        let waiting = group.waiting.fetch_add(1, Ordering::Relaxed);
+        let depth = queue_depth_gauge().with_label_values(&[group.name]);
+        depth.inc();
+        let _waiting = Waiting(depth, &group.waiting);
+
+        if self.max_queued.is_some_and(|max| waiting >= max) {
+            return Err(errors::failed(QUEUE_FULL_ERROR));
+        }
+
+        let permit = match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, group.semaphore.acquire())
+                .await
+                .map_err(|_| errors::failed(QUEUE_TIMEOUT_ERROR))?,
+            None => group.semaphore.acquire().await,
+        };
+        permit.map_err(errors::internal_error)
+    }
+}
+
+pub const CIRCUIT_OPEN_ERROR: &str = "Service unavailable: upstream circuit open";
+
+enum CircuitState {
+    Closed,
+    // calls fail right away until then
+    Open(Instant),
+    // probe calls let through and the ones of them succeeded
+    HalfOpen { probing: usize, succeeded: usize },
+}
+
+struct CircuitInner {
+    state: CircuitState,
+    // whether each of the latest calls failed, while closed
+    outcomes: VecDeque<bool>,
+}
+
+/// Fails upstream calls right away once too many of the latest ones failed to reach upstream,
+/// so a dying upstream doesn't pile up calls waiting to time out.
+/// After a while probe calls are let through, the circuit closes once they succeed.
+/// Shared by the upstream middlewares of all methods.
+pub struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    inner: Mutex<CircuitInner>,
+}
+
+/// A call let through the circuit breaker, its outcome is recorded on drop.
+/// Calls dropped before completing, e.g. on timeout, count as failed.
+pub struct CircuitPermit<'a> {
+    breaker: &'a CircuitBreaker,
+    probe: bool,
+    failed: bool,
+}
+
+impl CircuitPermit<'_> {
+    pub fn set_failed(&mut self, failed: bool) {
+        self.failed = failed;
+    }
+}
+
+impl Drop for CircuitPermit<'_> {
+    fn drop(&mut self) {
+        self.breaker.record(self.probe, self.failed);
+    }
+}
+
+impl CircuitBreaker {
+    pub fn new(config: &CircuitBreakerConfig) -> Self {
+        Self {
+            config: CircuitBreakerConfig {
+                window: config.window.max(1),
+                half_open_probes: config.half_open_probes.max(1),
+                ..config.clone()
+            },
+            inner: Mutex::new(CircuitInner {
+                state: CircuitState::Closed,
+                outcomes: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Lets a call through unless the circuit is open.
+    pub fn acquire(&self) -> Result<CircuitPermit<'_>, ErrorObjectOwned> {
+        let mut guard = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let inner = &mut *guard;
+        let probe = match inner.state {
+            CircuitState::Closed => false,
+            CircuitState::Open(until) if Instant::now() < until => {
+                return Err(errors::failed(CIRCUIT_OPEN_ERROR));
+            }
+            CircuitState::Open(_) => {
+                tracing::info!("Upstream circuit half-open, sending probe calls");
+                inner.state = CircuitState::HalfOpen {
+                    probing: 1,
+                    succeeded: 0,
+                };
+                true
+            }
+            CircuitState::HalfOpen { ref mut probing, .. } => {
+                if *probing >= self.config.half_open_probes {
+                    return Err(errors::failed(CIRCUIT_OPEN_ERROR));
+                }
+                *probing += 1;
+                true
+            }
+        };
+        Ok(CircuitPermit {
+            breaker: self,
+            probe,
+            failed: true,
+        })
+    }
+
+    fn record(&self, probe: bool, failed: bool) {
+        let mut guard = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let inner = &mut *guard;
+        match inner.state {
+            CircuitState::Closed if !probe => {
+                inner.outcomes.push_back(failed);
+                if inner.outcomes.len() > self.config.window {
+                    inner.outcomes.pop_front();
+                }
+                if inner.outcomes.len() < self.config.window {
+                    return;
+                }
+                let failures = inner.outcomes.iter().filter(|failed| **failed).count();
+                if failures as f64 >= self.config.failure_rate * self.config.window as f64 {
+                    tracing::warn!(
+                        "Upstream circuit open, {failures} of the latest {} calls failed",
+                        self.config.window
+                    );
+                    self.open(inner);
+                }
+            }
+            CircuitState::HalfOpen { ref mut succeeded, .. } if probe => {
+                if failed {
+                    tracing::warn!("Upstream circuit open again, probe call failed");
+                    self.open(inner);
+                    return;
+                }
+                *succeeded += 1;
+                if *succeeded >= self.config.half_open_probes {
+                    tracing::info!("Upstream circuit closed");
+                    inner.state = CircuitState::Closed;
+                }
+            }
+            // calls let through before the state changed
+            _ => {}
+        }
+    }
+
+    fn open(&self, inner: &mut CircuitInner) {
+        inner.state = CircuitState::Open(Instant::now() + Duration::from_millis(self.config.open_duration_ms));
+        inner.outcomes.clear();
+    }
+}
+
 pub struct UpstreamMiddleware {
     client: Arc<Client>,
+    // health check methods use the client's reserved internal pool
+    internal: bool,
+    // set when upstream calls are queued
+    queue: Option<Arc<UpstreamQueue>>,
+    // set when upstream calls are guarded by a circuit breaker
+    circuit_breaker: Option<Arc<CircuitBreaker>>,
 }
 
 impl UpstreamMiddleware {
     pub fn new(client: Arc<Client>) -> Self {
-        Self { client }
+        Self {
+            client,
+            internal: false,
+            queue: None,
+            circuit_breaker: None,
+        }
+    }
+
+    pub fn internal(client: Arc<Client>) -> Self {
+        Self {
+            client,
+            internal: true,
+            queue: None,
+            circuit_breaker: None,
+        }
+    }
+
+    pub fn with_queue(mut self, queue: Arc<UpstreamQueue>) -> Self {
+        self.queue = Some(queue);
+        self
+    }
+
+    pub fn with_circuit_breaker(mut self, circuit_breaker: Arc<CircuitBreaker>) -> Self {
+        self.circuit_breaker = Some(circuit_breaker);
+        self
     }
 }
 
 #[async_trait]
 impl MiddlewareBuilder<RpcMethod, CallRequest, CallResult> for UpstreamMiddleware {
     async fn build(
-        _method: &RpcMethod,
+        method: &RpcMethod,
         extensions: &TypeRegistryRef,
     ) -> Option<Box<dyn Middleware<CallRequest, CallResult>>> {
-        let client = extensions
-            .read()
-            .await
-            .get::<Client>()
-            .expect("Client extension not found");
-        Some(Box::new(UpstreamMiddleware::new(client)))
+        let ext = extensions.read().await;
+        let client = ext.get::<Client>().expect("Client extension not found");
+
+        let is_health_method = ext
+            .get::<SubwayServerBuilder>()
+            .map(|server| server.config.http_methods.iter().any(|m| m.method == method.method))
+            .unwrap_or(false);
+
+        // health checks are not queued behind client traffic, nor limited or failed by the circuit breaker
+        if is_health_method {
+            return Some(Box::new(UpstreamMiddleware::internal(client)));
+        }
+
+        let queue_config = client.queue().cloned();
+        let circuit_breaker_config = client.circuit_breaker().cloned();
+        drop(ext);
+
+        let mut middleware = UpstreamMiddleware::new(client);
+
+        // the queue and the circuit breaker are shared by the middlewares of all methods
+        if let Some(config) = queue_config {
+            let mut extensions = extensions.write().await;
+            if !extensions.has::<UpstreamQueue>() {
+                extensions.insert(UpstreamQueue::new(&config));
+            }
+            middleware = middleware.with_queue(extensions.get::<UpstreamQueue>().expect("inserted above"));
+        }
+        if let Some(config) = circuit_breaker_config {
+            let mut extensions = extensions.write().await;
+            if !extensions.has::<CircuitBreaker>() {
+                extensions.insert(CircuitBreaker::new(&config));
+            }
+            middleware = middleware.with_circuit_breaker(extensions.get::<CircuitBreaker>().expect("inserted above"));
+        }
+
+        Some(Box::new(middleware))
     }
 }
 
@@ -39,12 +361,266 @@ impl Middleware<CallRequest, CallResult> for UpstreamMiddleware {
     async fn call(
         &self,
         request: CallRequest,
-        _context: TypeRegistry,
+        context: TypeRegistry,
         _next: NextFn<CallRequest, CallResult>,
     ) -> CallResult {
-        self.client
-            .request(&request.method, request.params)
-            .with_context(TRACER.context("upstream"))
-            .await
+        let client = match context.get::<ForwardedHeaders>() {
+            Some(headers) => self.client.headers_client(&headers).map_err(errors::internal_error)?,
+            None => self.client.clone(),
+        };
+
+        let retry = context.get::<Retry>();
+        let send = {
+            let (client, request, internal, once) = (&client, &request, self.internal, retry.is_some());
+            // every attempt goes through the circuit breaker and waits for its own slot in the queue
+            move || async move {
+                let mut circuit_permit = match self.circuit_breaker {
+                    Some(ref breaker) => Some(breaker.acquire()?),
+                    None => None,
+                };
+
+                let _permit = match self.queue {
+                    Some(ref queue) => match queue.acquire(&request.method).await {
+                        Ok(permit) => Some(permit),
+                        Err(e) => {
+                            // rejected before reaching upstream, not a failure of upstream
+                            if let Some(ref mut circuit_permit) = circuit_permit {
+                                circuit_permit.set_failed(false);
+                            }
+                            return Err(e);
+                        }
+                    },
+                    None => None,
+                };
+
+                let result = if internal {
+                    client
+                        .request_internal(&request.method, request.params.clone())
+                        .with_context(TRACER.context("upstream"))
+                        .await
+                } else if once {
+                    // retried by the retry middleware rather than by the client
+                    client
+                        .request_once(&request.method, request.params.clone())
+                        .with_context(TRACER.context("upstream"))
+                        .await
+                } else {
+                    client
+                        .request(&request.method, request.params.clone())
+                        .with_context(TRACER.context("upstream"))
+                        .await
+                };
+
+                if let Some(ref mut permit) = circuit_permit {
+                    permit.set_failed(result.as_ref().is_err_and(is_upstream_unavailable));
+                }
+                result
+            }
+        };
+
+        let start = Instant::now();
+        let result = match retry {
+            Some(ref retry) => retry.run(&request.method, send).await,
+            None => send().await,
+        };
+
+        if let Some(latency) = context.get::<UpstreamLatency>() {
+            latency.record(start.elapsed());
+        }
+
+        if let Err(ref e) = result {
+            // the client reports its own failures as unreachable, e.g. lost connections or timeouts,
+            // and calls rejected by the circuit breaker or the queue never reached upstream
+            if !is_upstream_unavailable(e) {
+                if let Some(upstream_error) = context.get::<UpstreamError>() {
+                    upstream_error.set();
+                }
+            }
+            metrics::upstream_errors()
+                .with_label_values(&[&request.method, &e.code().to_string()])
+                .inc();
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn circuit_breaker(open_duration_ms: u64) -> CircuitBreaker {
+        CircuitBreaker::new(&CircuitBreakerConfig {
+            failure_rate: 0.5,
+            window: 4,
+            open_duration_ms,
+            half_open_probes: 1,
+        })
+    }
+
+    fn call(breaker: &CircuitBreaker, failed: bool) -> Result<(), ErrorObjectOwned> {
+        breaker.acquire()?.set_failed(failed);
+        Ok(())
+    }
+
+    #[test]
+    fn circuit_opens_on_failure_rate() {
+        let breaker = circuit_breaker(60_000);
+        for failed in [true, false, true] {
+            call(&breaker, failed).unwrap();
+        }
+        // 2 of 4 failed
+        call(&breaker, false).unwrap();
+        assert_eq!(call(&breaker, false), Err(errors::failed(CIRCUIT_OPEN_ERROR)));
+    }
+
+    #[test]
+    fn circuit_stays_closed_below_failure_rate() {
+        let breaker = circuit_breaker(60_000);
+        for failed in [true, false, false, false, false, true, false, false] {
+            call(&breaker, failed).unwrap();
+        }
+    }
+
+    #[test]
+    fn circuit_half_opens_with_probe() {
+        let breaker = circuit_breaker(10);
+        for _ in 0..4 {
+            call(&breaker, true).unwrap();
+        }
+        assert!(breaker.acquire().is_err());
+        std::thread::sleep(Duration::from_millis(20));
+
+        // a single probe at a time
+        let probe = breaker.acquire().unwrap();
+        assert!(breaker.acquire().is_err());
+        // dropped without an outcome, e.g. timed out
+        drop(probe);
+        assert!(breaker.acquire().is_err());
+        std::thread::sleep(Duration::from_millis(20));
+
+        call(&breaker, false).unwrap();
+        call(&breaker, false).unwrap();
+    }
+
+    #[tokio::test]
+    async fn queue_depth_counts_waiting_calls() {
+        let queue = Arc::new(UpstreamQueue::new(&UpstreamQueueConfig {
+            light_concurrency: 1,
+            heavy_concurrency: 1,
+            heavy_methods: vec!["state_getKeysPaged".to_string()],
+            max_queued: None,
+            queue_timeout_ms: None,
+        }));
+        let heavy = queue_depth_gauge().with_label_values(&["heavy"]);
+
+        let permit = queue.acquire("state_getKeysPaged").await.unwrap();
+
+        // light calls have their own slots
+        drop(queue.acquire("chain_getBlock").await.unwrap());
+
+        let waiting = tokio::spawn({
+            let queue = queue.clone();
+            async move {
+                let _permit = queue.acquire("state_getKeysPaged").await.unwrap();
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(heavy.get(), 1);
+
+        drop(permit);
+        waiting.await.unwrap();
+        assert_eq!(heavy.get(), 0);
+
+        // aborted calls leave the queue
+        let permit = queue.acquire("state_getKeysPaged").await.unwrap();
+        let aborted = tokio::spawn({
+            let queue = queue.clone();
+            async move {
+                let _permit = queue.acquire("state_getKeysPaged").await.unwrap();
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(heavy.get(), 1);
+        aborted.abort();
+        let _ = aborted.await;
+        assert_eq!(heavy.get(), 0);
+        drop(permit);
+    }
+
+    #[tokio::test]
+    async fn queue_rejects_when_full_or_timed_out() {
+        let queue = Arc::new(UpstreamQueue::new(&UpstreamQueueConfig {
+            light_concurrency: 1,
+            heavy_concurrency: 1,
+            heavy_methods: vec![],
+            max_queued: Some(1),
+            queue_timeout_ms: Some(50),
+        }));
+
+        let permit = queue.acquire("chain_getBlock").await.unwrap();
+
+        let waiting = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.acquire("chain_getBlock").await.map(drop) }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        // the only queue slot is taken
+        assert_eq!(
+            queue.acquire("chain_getBlock").await.map(drop),
+            Err(errors::failed(QUEUE_FULL_ERROR))
+        );
+        assert_eq!(waiting.await.unwrap(), Err(errors::failed(QUEUE_TIMEOUT_ERROR)));
+
+        drop(permit);
+        assert!(queue.acquire("chain_getBlock").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn retried_attempts_go_through_circuit_breaker() {
+        use crate::{
+            config::RetryParams, extensions::client::mock::dummy_server, middlewares::methods::retry::RetryMiddleware,
+        };
+        use futures::FutureExt;
+
+        // upstream never answers
+        let (addr, _handle, _rx, _) = dummy_server().await;
+        let client = Client::new([format!("ws://{addr}")], Some(Duration::from_millis(50)), None, None).unwrap();
+        let breaker = Arc::new(CircuitBreaker::new(&CircuitBreakerConfig {
+            failure_rate: 0.5,
+            window: 2,
+            open_duration_ms: 60_000,
+            half_open_probes: 1,
+        }));
+        let upstream = Arc::new(UpstreamMiddleware::new(Arc::new(client)).with_circuit_breaker(breaker.clone()));
+        let retry = RetryMiddleware::new(RetryParams {
+            max_attempts: 3,
+            backoff_base_ms: 1,
+            jitter_ms: 0,
+        });
+
+        let res = retry
+            .call(
+                CallRequest::new("mock_rpc", vec![]),
+                Default::default(),
+                Box::new(move |req, context| {
+                    async move {
+                        upstream
+                            .call(
+                                req,
+                                context,
+                                Box::new(|_, _| async { panic!("should not be called") }.boxed()),
+                            )
+                            .await
+                    }
+                    .boxed()
+                }),
+            )
+            .await;
+
+        // the first two attempts failed and opened the circuit, the third one is not let through
+        assert_eq!(res, Err(errors::failed(CIRCUIT_OPEN_ERROR)));
+        assert!(breaker.acquire().is_err());
     }
 }
@@ -0,0 +1,180 @@
+use async_trait::async_trait;
+use jsonrpsee::core::JsonValue;
+use opentelemetry::trace::FutureExt;
+use regex::Regex;
+
+use crate::{
+    middlewares::{CallRequest, CallResult, Middleware, MiddlewareBuilder, NextFn, RpcMethod, TRACER},
+    utils::{errors, TypeRegistry, TypeRegistryRef},
+};
+
+/// Rejects calls to denied methods and calls with params breaking the method rules,
+/// before they reach the cache or upstream.
+pub struct ValidateMiddleware {
+    deny: Option<String>,
+    max_params: Option<usize>,
+    // pattern of each param by position
+    patterns: Vec<(usize, String, Regex)>,
+}
+
+impl ValidateMiddleware {
+    pub fn new(method: &RpcMethod) -> Option<Self> {
+        let patterns = method
+            .params
+            .iter()
+            .enumerate()
+            .filter_map(|(index, param)| {
+                let pattern = param.pattern.as_ref()?;
+                // patterns are checked when the config is read
+                let regex = Regex::new(&format!("^(?:{pattern})$")).expect("Invalid param pattern");
+                Some((index, param.name.clone(), regex))
+            })
+            .collect::<Vec<_>>();
+
+        if method.deny.is_none() && method.max_params.is_none() && patterns.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            deny: method.deny.clone(),
+            max_params: method.max_params,
+            patterns,
+        })
+    }
+
+    fn validate(&self, params: &[JsonValue]) -> Result<(), String> {
+        if let Some(max_params) = self.max_params {
+            if params.len() > max_params {
+                return Err(format!("Expected at most {max_params} params, got {}", params.len()));
+            }
+        }
+
+        for (index, name, regex) in &self.patterns {
+            let Some(param) = params.get(*index) else {
+                continue;
+            };
+            let matched = match param {
+                JsonValue::String(s) => regex.is_match(s),
+                // omitted optional param
+                JsonValue::Null => true,
+                other => regex.is_match(&other.to_string()),
+            };
+            if !matched {
+                return Err(format!("Invalid param {name}"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MiddlewareBuilder<RpcMethod, CallRequest, CallResult> for ValidateMiddleware {
+    async fn build(
+        method: &RpcMethod,
+        _extensions: &TypeRegistryRef,
+    ) -> Option<Box<dyn Middleware<CallRequest, CallResult>>> {
+        Self::new(method).map(|m| Box::new(m) as Box<dyn Middleware<CallRequest, CallResult>>)
+    }
+}
+
+#[async_trait]
+impl Middleware<CallRequest, CallResult> for ValidateMiddleware {
+    async fn call(
+        &self,
+        request: CallRequest,
+        context: TypeRegistry,
+        next: NextFn<CallRequest, CallResult>,
+    ) -> CallResult {
+        async move {
+            if let Some(ref message) = self.deny {
+                return Err(errors::failed(message));
+            }
+            self.validate(&request.params).map_err(errors::invalid_params)?;
+            next(request, context).await
+        }
+        .with_context(TRACER.context("validate"))
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config::MethodParam;
+    use futures::FutureExt;
+    use serde_json::json;
+
+    fn method(params: Vec<MethodParam>, max_params: Option<usize>, deny: Option<String>) -> RpcMethod {
+        RpcMethod {
+            method: "state_getStorage".to_string(),
+            params,
+            deny,
+            max_params,
+            ..Default::default()
+        }
+    }
+
+    fn param(name: &str, pattern: Option<&str>) -> MethodParam {
+        MethodParam {
+            name: name.to_string(),
+            ty: String::new(),
+            optional: true,
+            inject: false,
+            pattern: pattern.map(ToString::to_string),
+            resolve_number: false,
+            default: None,
+            coerce: None,
+        }
+    }
+
+    async fn call(middleware: &ValidateMiddleware, params: Vec<JsonValue>) -> CallResult {
+        middleware
+            .call(
+                CallRequest::new("state_getStorage", params),
+                Default::default(),
+                Box::new(move |_, _| async move { Ok(json!("0x00")) }.boxed()),
+            )
+            .await
+    }
+
+    #[test]
+    fn no_rules_no_middleware() {
+        assert!(ValidateMiddleware::new(&method(vec![param("key", None)], None, None)).is_none());
+    }
+
+    #[tokio::test]
+    async fn validates_params() {
+        let middleware = ValidateMiddleware::new(&method(
+            vec![param("key", Some("0x[0-9a-f]+")), param("at", Some("0x[0-9a-f]{64}"))],
+            Some(2),
+            None,
+        ))
+        .unwrap();
+
+        assert_eq!(call(&middleware, vec![json!("0x1234")]).await, Ok(json!("0x00")));
+        assert_eq!(
+            call(&middleware, vec![json!("0x1234"), JsonValue::Null]).await,
+            Ok(json!("0x00"))
+        );
+        assert_eq!(
+            call(&middleware, vec![json!("1234")]).await,
+            Err(errors::invalid_params("Invalid param key"))
+        );
+        assert_eq!(
+            call(&middleware, vec![json!("0x1234"), json!("0x12")]).await,
+            Err(errors::invalid_params("Invalid param at"))
+        );
+        assert_eq!(
+            call(&middleware, vec![json!("0x1234"), JsonValue::Null, json!(1)]).await,
+            Err(errors::invalid_params("Expected at most 2 params, got 3"))
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_denied_method() {
+        let middleware = ValidateMiddleware::new(&method(vec![], None, Some("Method disabled".to_string()))).unwrap();
+        assert_eq!(call(&middleware, vec![]).await, Err(errors::failed("Method disabled")));
+    }
+}
@@ -20,11 +20,47 @@ pub mod factory;
 pub mod methods;
 pub mod subscriptions;
 
+/// Per-request feature flags passed by the client in the `X-Subway-Features` header.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FeatureFlags {
+    /// `no-cache`: bypass the cache
+    pub no_cache: bool,
+    /// `finalized`: inject the finalized block instead of the latest one
+    pub finalized: bool,
+}
+
+impl FeatureFlags {
+    pub const HEADER: &'static str = "x-subway-features";
+
+    /// Parses a comma separated list of flags. Unknown flags and flags missing from `allowed` are ignored
+    /// unless `strict` is set.
+    pub fn parse(value: &str, allowed: &[String], strict: bool) -> Result<Self, String> {
+        let mut flags = Self::default();
+        for flag in value.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+            if !allowed.iter().any(|a| a == flag) {
+                if strict {
+                    return Err(format!("Feature flag not allowed: {flag}"));
+                }
+                tracing::debug!("Ignore feature flag not allowed: {flag}");
+                continue;
+            }
+            match flag {
+                "no-cache" => flags.no_cache = true,
+                "finalized" => flags.finalized = true,
+                unknown if strict => return Err(format!("Unknown feature flag: {unknown}")),
+                unknown => tracing::debug!("Ignore unknown feature flag: {unknown}"),
+            }
+        }
+        Ok(flags)
+    }
+}
+
 #[derive(Debug)]
 /// Represents a RPC request made to a middleware function.
 pub struct CallRequest {
     pub method: String,
     pub params: Vec<JsonValue>,
+    pub features: FeatureFlags,
 }
 
 impl CallRequest {
@@ -32,8 +68,14 @@ impl CallRequest {
         Self {
             method: method.to_string(),
             params,
+            features: Default::default(),
         }
     }
+
+    pub fn with_features(mut self, features: FeatureFlags) -> Self {
+        self.features = features;
+        self
+    }
 }
 
 /// Alias for the result of a method request.
@@ -66,6 +108,11 @@ pub type SubscriptionResult = Result<(), StringError>;
 pub trait Middleware<Request, Result>: Send + Sync {
     /// The method that will be called to handle the request.
     async fn call(&self, request: Request, context: TypeRegistry, next: NextFn<Request, Result>) -> Result;
+
+    /// Performs async setup before the middleware handles any request. Called once at startup.
+    async fn init(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
 }
 
 /// A type alias for the next function to be called in the middleware chain.
@@ -109,6 +156,24 @@ impl<Request: Debug + Send + 'static, Result: Send + 'static> Middlewares<Reques
         Self { middlewares, fallback }
     }
 
+    /// Number of middlewares in the chain, the fallback is not counted.
+    pub fn len(&self) -> usize {
+        self.middlewares.len()
+    }
+
+    /// Whether every call goes straight to the fallback.
+    pub fn is_empty(&self) -> bool {
+        self.middlewares.is_empty()
+    }
+
+    /// Initializes the middlewares in order, stops at the first failure.
+    pub async fn init(&self) -> anyhow::Result<()> {
+        for middleware in &self.middlewares {
+            middleware.init().await?;
+        }
+        Ok(())
+    }
+
     /// Calls the middleware chain with the given request and result sender.
     ///
     /// # Arguments
@@ -122,6 +187,18 @@ impl<Request: Debug + Send + 'static, Result: Send + 'static> Middlewares<Reques
         request: Request,
         result_tx: tokio::sync::oneshot::Sender<Result>,
         timeout: tokio::time::Duration,
+    ) {
+        self.call_with_context(request, TypeRegistry::new(), result_tx, timeout)
+            .await
+    }
+
+    /// Same as `call` but starts the middleware chain with the given context.
+    pub async fn call_with_context(
+        &self,
+        request: Request,
+        context: TypeRegistry,
+        result_tx: tokio::sync::oneshot::Sender<Result>,
+        timeout: tokio::time::Duration,
     ) {
         let iter = self.middlewares.iter().rev();
         let fallback = self.fallback.clone();
@@ -135,11 +212,16 @@ impl<Request: Debug + Send + 'static, Result: Send + 'static> Middlewares<Reques
                 Box::new(move |request, context| async move { middleware.call(request, context, next2).await }.boxed());
         }
 
-        let req = format!("{:?}", request);
+        let req = truncated_debug(&request);
+        let request_attribute = opentelemetry::KeyValue::new("request", req.clone());
 
+        // spans of the middlewares below are children of this one
         let mut task_handle = tokio::spawn(
             async move {
-                let result = next(request, TypeRegistry::new()).await;
+                opentelemetry::trace::get_active_span(|span| {
+                    span.set_attribute(request_attribute);
+                });
+                let result = next(request, context).await;
                 _ = result_tx.send(result);
 
                 opentelemetry::trace::get_active_span(|span| {
@@ -163,3 +245,145 @@ impl<Request: Debug + Send + 'static, Result: Send + 'static> Middlewares<Reques
         }
     }
 }
+
+/// Longest `Debug` output of a request recorded on spans and in logs. Params can be
+/// arbitrarily large (e.g. a whole extrinsic) so only their beginning is kept.
+const MAX_REQUEST_DEBUG_LEN: usize = 256;
+
+struct TruncatedWriter(String);
+
+impl std::fmt::Write for TruncatedWriter {
+    fn write_str(&mut self, s: &str) -> std::fmt::Result {
+        let remaining = MAX_REQUEST_DEBUG_LEN - self.0.len();
+        if s.len() <= remaining {
+            self.0.push_str(s);
+            return Ok(());
+        }
+        let mut end = remaining;
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.0.push_str(&s[..end]);
+        // stop formatting, the rest is dropped anyway
+        Err(std::fmt::Error)
+    }
+}
+
+fn truncated_debug(value: &impl Debug) -> String {
+    let mut writer = TruncatedWriter(String::new());
+    if std::fmt::write(&mut writer, format_args!("{value:?}")).is_err() {
+        writer.0.push_str("...");
+    }
+    writer.0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    #[test]
+    fn parse_feature_flags() {
+        let all = ["no-cache".to_string(), "finalized".to_string(), "foo".to_string()];
+        assert_eq!(FeatureFlags::parse("", &all, true), Ok(FeatureFlags::default()));
+        assert_eq!(
+            FeatureFlags::parse("no-cache, finalized", &all, true),
+            Ok(FeatureFlags {
+                no_cache: true,
+                finalized: true
+            })
+        );
+        assert_eq!(
+            FeatureFlags::parse("finalized,foo", &all, false),
+            Ok(FeatureFlags {
+                no_cache: false,
+                finalized: true
+            })
+        );
+        assert_eq!(
+            FeatureFlags::parse("finalized,foo", &all, true),
+            Err("Unknown feature flag: foo".to_string())
+        );
+
+        // flags not allowed are ignored, or rejected in strict mode
+        let allowed = ["finalized".to_string()];
+        assert_eq!(
+            FeatureFlags::parse("no-cache,finalized", &allowed, false),
+            Ok(FeatureFlags {
+                no_cache: false,
+                finalized: true
+            })
+        );
+        assert_eq!(
+            FeatureFlags::parse("no-cache", &allowed, true),
+            Err("Feature flag not allowed: no-cache".to_string())
+        );
+    }
+
+    #[test]
+    fn truncated_debug_works() {
+        let short = CallRequest::new("state_getStorage", vec![]);
+        assert_eq!(truncated_debug(&short), format!("{short:?}"));
+
+        let long = CallRequest::new("author_submitExtrinsic", vec![JsonValue::from("ü".repeat(1000))]);
+        let truncated = truncated_debug(&long);
+        assert!(truncated.starts_with("CallRequest { method: \"author_submitExtrinsic\""));
+        assert!(truncated.ends_with("..."));
+        assert!(truncated.len() <= MAX_REQUEST_DEBUG_LEN + 3);
+    }
+
+    struct InitMiddleware {
+        id: u32,
+        fail: bool,
+        inited: Arc<Mutex<Vec<u32>>>,
+    }
+
+    #[async_trait]
+    impl Middleware<CallRequest, CallResult> for InitMiddleware {
+        async fn call(
+            &self,
+            request: CallRequest,
+            context: TypeRegistry,
+            next: NextFn<CallRequest, CallResult>,
+        ) -> CallResult {
+            next(request, context).await
+        }
+
+        async fn init(&self) -> anyhow::Result<()> {
+            self.inited.lock().unwrap().push(self.id);
+            if self.fail {
+                anyhow::bail!("middleware {} init failed", self.id);
+            }
+            Ok(())
+        }
+    }
+
+    fn middlewares(fail_at: Option<u32>, inited: Arc<Mutex<Vec<u32>>>) -> Middlewares<CallRequest, CallResult> {
+        let list = (1..=3)
+            .map(|id| {
+                Arc::new(InitMiddleware {
+                    id,
+                    fail: fail_at == Some(id),
+                    inited: inited.clone(),
+                }) as Arc<dyn Middleware<CallRequest, CallResult>>
+            })
+            .collect();
+        Middlewares::new(list, Arc::new(|_, _| async { Ok(JsonValue::Null) }.boxed()))
+    }
+
+    #[tokio::test]
+    async fn init_runs_in_order() {
+        let inited = Arc::new(Mutex::new(vec![]));
+        middlewares(None, inited.clone()).init().await.unwrap();
+        assert_eq!(*inited.lock().unwrap(), vec![1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn init_stops_at_first_failure() {
+        let inited = Arc::new(Mutex::new(vec![]));
+        let err = middlewares(Some(2), inited.clone()).init().await.unwrap_err();
+        assert_eq!(err.to_string(), "middleware 2 init failed");
+        assert_eq!(*inited.lock().unwrap(), vec![1, 2]);
+    }
+}
@@ -0,0 +1,199 @@
+use std::sync::OnceLock;
+
+use async_trait::async_trait;
+use prometheus::IntCounterVec;
+
+use crate::{
+    config::SubscriptionBackpressureParams,
+    middlewares::{
+        subscriptions::merge_subscription::CoalescingBuffer, Middleware, MiddlewareBuilder, NextFn, RpcSubscription,
+        SubscriptionRequest, SubscriptionResult, TRACER,
+    },
+    utils::{TypeRegistry, TypeRegistryRef},
+};
+
+/// Notifications dropped because the subscriber could not keep up, labeled by subscription name.
+pub fn dropped_counter() -> &'static IntCounterVec {
+    static COUNTER: OnceLock<IntCounterVec> = OnceLock::new();
+    COUNTER.get_or_init(|| {
+        prometheus::register_int_counter_vec!(
+            "subway_subscription_dropped_notifications_total",
+            "Notifications dropped because the subscriber could not keep up",
+            &["subscription"]
+        )
+        .expect("Failed to register subway_subscription_dropped_notifications_total")
+    })
+}
+
+/// Subscribers disconnected for dropping too many notifications, labeled by subscription name.
+pub fn disconnect_counter() -> &'static IntCounterVec {
+    static COUNTER: OnceLock<IntCounterVec> = OnceLock::new();
+    COUNTER.get_or_init(|| {
+        prometheus::register_int_counter_vec!(
+            "subway_subscription_slow_consumer_disconnects_total",
+            "Subscribers disconnected for dropping too many notifications",
+            &["subscription"]
+        )
+        .expect("Failed to register subway_subscription_slow_consumer_disconnects_total")
+    })
+}
+
+/// Asks the middleware forwarding notifications to queue them while the subscriber is busy,
+/// instead of waiting for it and letting notifications pile up upstream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubscriptionBackpressure {
+    pub name: String,
+    pub buffer_size: usize,
+    pub disconnect_after: Option<u64>,
+}
+
+impl SubscriptionBackpressure {
+    pub fn outbox<T>(self) -> Outbox<T> {
+        Outbox {
+            config: self,
+            buffer: Default::default(),
+            dropped: 0,
+        }
+    }
+}
+
+/// Notifications waiting for a subscriber, at most `buffer_size` of them.
+pub struct Outbox<T> {
+    config: SubscriptionBackpressure,
+    buffer: CoalescingBuffer<T>,
+    dropped: u64,
+}
+
+impl<T> Outbox<T> {
+    /// Queues a notification, dropping the oldest one when full.
+    /// Returns false once the subscriber dropped more than `disconnect_after` notifications.
+    pub fn push(&mut self, key: Option<String>, value: T) -> bool {
+        self.buffer.push(key, value);
+        let config = &self.config;
+        if self.buffer.len() <= config.buffer_size {
+            return true;
+        }
+
+        self.buffer.pop_front();
+        self.dropped += 1;
+        dropped_counter().with_label_values(&[&config.name]).inc();
+
+        if config.disconnect_after.is_some_and(|max| self.dropped > max) {
+            tracing::debug!("Disconnecting slow subscriber of {}", config.name);
+            disconnect_counter().with_label_values(&[&config.name]).inc();
+            return false;
+        }
+        true
+    }
+
+    pub fn front(&self) -> Option<&T> {
+        self.buffer.front()
+    }
+
+    pub fn pop_front(&mut self) -> Option<T> {
+        self.buffer.pop_front()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+}
+
+pub struct SubscriptionBackpressureMiddleware {
+    backpressure: SubscriptionBackpressure,
+}
+
+#[async_trait]
+impl MiddlewareBuilder<RpcSubscription, SubscriptionRequest, SubscriptionResult>
+    for SubscriptionBackpressureMiddleware
+{
+    async fn build(
+        method: &RpcSubscription,
+        _extensions: &TypeRegistryRef,
+    ) -> Option<Box<dyn Middleware<SubscriptionRequest, SubscriptionResult>>> {
+        let SubscriptionBackpressureParams {
+            buffer_size,
+            disconnect_after,
+        } = method.backpressure.clone()?;
+
+        Some(Box::new(SubscriptionBackpressureMiddleware {
+            backpressure: SubscriptionBackpressure {
+                name: method.name.clone(),
+                buffer_size: buffer_size.max(1),
+                disconnect_after,
+            },
+        }))
+    }
+}
+
+#[async_trait]
+impl Middleware<SubscriptionRequest, SubscriptionResult> for SubscriptionBackpressureMiddleware {
+    async fn call(
+        &self,
+        request: SubscriptionRequest,
+        mut context: TypeRegistry,
+        next: NextFn<SubscriptionRequest, SubscriptionResult>,
+    ) -> SubscriptionResult {
+        let _span = TRACER.context("subscription_backpressure");
+        context.insert(self.backpressure.clone());
+        next(request, context).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outbox(name: &str, disconnect_after: Option<u64>) -> Outbox<u32> {
+        SubscriptionBackpressure {
+            name: name.to_string(),
+            buffer_size: 2,
+            disconnect_after,
+        }
+        .outbox()
+    }
+
+    #[test]
+    fn drops_oldest_when_full() {
+        let mut outbox = outbox("drops_oldest", None);
+        assert!(outbox.push(None, 1));
+        assert!(outbox.push(None, 2));
+        assert!(outbox.push(None, 3));
+        assert_eq!(outbox.pop_front(), Some(2));
+        assert_eq!(outbox.pop_front(), Some(3));
+        assert!(outbox.is_empty());
+        assert_eq!(dropped_counter().with_label_values(&["drops_oldest"]).get(), 1);
+    }
+
+    #[test]
+    fn coalesces_before_dropping() {
+        let mut outbox = outbox("coalesces", Some(0));
+        assert!(outbox.push(Some("head".to_string()), 1));
+        assert!(outbox.push(Some("head".to_string()), 2));
+        assert!(outbox.push(Some("head".to_string()), 3));
+        assert_eq!(outbox.pop_front(), Some(3));
+        assert!(outbox.is_empty());
+    }
+
+    #[test]
+    fn disconnects_after_too_many_drops() {
+        let mut outbox = outbox("disconnects", Some(1));
+        assert!(outbox.push(None, 1));
+        assert!(outbox.push(None, 2));
+        assert!(outbox.push(None, 3));
+        assert!(!outbox.push(None, 4));
+    }
+
+    #[test]
+    fn coalesces_in_place() {
+        let mut outbox = outbox("coalesces_in_place", None);
+        assert!(outbox.push(Some("a".to_string()), 1));
+        assert!(outbox.push(Some("b".to_string()), 2));
+        assert!(outbox.push(Some("a".to_string()), 3));
+        assert_eq!(outbox.pop_front(), Some(3));
+        assert!(outbox.push(Some("a".to_string()), 4));
+        assert_eq!(outbox.pop_front(), Some(2));
+        assert_eq!(outbox.pop_front(), Some(4));
+        assert!(outbox.is_empty());
+    }
+}
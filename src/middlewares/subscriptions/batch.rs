@@ -0,0 +1,141 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use jsonrpsee::core::JsonValue;
+use tokio::time::Instant;
+
+use crate::{
+    config::SubscriptionBatchParams,
+    middlewares::{
+        Middleware, MiddlewareBuilder, NextFn, RpcSubscription, SubscriptionRequest, SubscriptionResult, TRACER,
+    },
+    utils::{TypeRegistry, TypeRegistryRef},
+};
+
+/// Asks the middleware forwarding notifications to deliver them in batches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubscriptionBatch {
+    pub size: usize,
+    pub timeout: Duration,
+}
+
+impl SubscriptionBatch {
+    pub fn batcher(self) -> Batcher {
+        Batcher {
+            config: self,
+            items: Vec::with_capacity(self.size),
+            deadline: None,
+        }
+    }
+}
+
+/// Buffers notifications until the batch is full or its timeout elapsed.
+pub struct Batcher {
+    config: SubscriptionBatch,
+    items: Vec<JsonValue>,
+    deadline: Option<Instant>,
+}
+
+impl Batcher {
+    /// Adds a notification, returns the batch if it is full.
+    pub fn push(&mut self, item: JsonValue) -> Option<JsonValue> {
+        if self.items.is_empty() {
+            self.deadline = Some(Instant::now() + self.config.timeout);
+        }
+        self.items.push(item);
+        (self.items.len() >= self.config.size).then(|| self.take())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Waits for the timeout of the current batch and returns it. Never resolves while empty.
+    pub async fn expired(&mut self) -> JsonValue {
+        match self.deadline {
+            Some(deadline) => tokio::time::sleep_until(deadline).await,
+            None => futures::future::pending().await,
+        }
+        self.take()
+    }
+
+    fn take(&mut self) -> JsonValue {
+        self.deadline = None;
+        JsonValue::Array(std::mem::replace(&mut self.items, Vec::with_capacity(self.config.size)))
+    }
+}
+
+pub struct SubscriptionBatchMiddleware {
+    batch: SubscriptionBatch,
+}
+
+#[async_trait]
+impl MiddlewareBuilder<RpcSubscription, SubscriptionRequest, SubscriptionResult> for SubscriptionBatchMiddleware {
+    async fn build(
+        method: &RpcSubscription,
+        _extensions: &TypeRegistryRef,
+    ) -> Option<Box<dyn Middleware<SubscriptionRequest, SubscriptionResult>>> {
+        let SubscriptionBatchParams {
+            batch_size,
+            batch_timeout_ms,
+        } = method.batch.clone()?;
+
+        Some(Box::new(SubscriptionBatchMiddleware {
+            batch: SubscriptionBatch {
+                size: batch_size.max(1),
+                timeout: Duration::from_millis(batch_timeout_ms),
+            },
+        }))
+    }
+}
+
+#[async_trait]
+impl Middleware<SubscriptionRequest, SubscriptionResult> for SubscriptionBatchMiddleware {
+    async fn call(
+        &self,
+        request: SubscriptionRequest,
+        mut context: TypeRegistry,
+        next: NextFn<SubscriptionRequest, SubscriptionResult>,
+    ) -> SubscriptionResult {
+        let _span = TRACER.context("subscription_batch");
+        context.insert(self.batch);
+        next(request, context).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn batcher_flushes_when_full() {
+        let mut batcher = SubscriptionBatch {
+            size: 2,
+            timeout: Duration::from_secs(100),
+        }
+        .batcher();
+
+        assert_eq!(batcher.push(json!(1)), None);
+        assert_eq!(batcher.push(json!(2)), Some(json!([1, 2])));
+        assert!(batcher.is_empty());
+    }
+
+    #[tokio::test]
+    async fn batcher_flushes_after_timeout() {
+        let mut batcher = SubscriptionBatch {
+            size: 10,
+            timeout: Duration::from_millis(10),
+        }
+        .batcher();
+
+        assert_eq!(batcher.push(json!(1)), None);
+        assert_eq!(batcher.push(json!(2)), None);
+
+        let batch = tokio::time::timeout(Duration::from_millis(100), batcher.expired())
+            .await
+            .unwrap();
+        assert_eq!(batch, json!([1, 2]));
+        assert!(batcher.is_empty());
+    }
+}
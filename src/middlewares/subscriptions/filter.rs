@@ -0,0 +1,195 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use jsonrpsee::core::JsonValue;
+use tokio::time::Instant;
+
+use crate::{
+    config::SubscriptionFilterParams,
+    middlewares::{
+        Middleware, MiddlewareBuilder, NextFn, RpcSubscription, SubscriptionRequest, SubscriptionResult, TRACER,
+    },
+    utils::{errors, TypeRegistry, TypeRegistryRef},
+};
+
+/// Asks the middleware forwarding notifications to drop the ones the client does not want.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SubscriptionFilter {
+    pub dedup: bool,
+    pub min_interval: Option<Duration>,
+    /// `(json pointer, expected value)` pairs a notification must all match.
+    pub predicate: Vec<(String, JsonValue)>,
+}
+
+impl SubscriptionFilter {
+    pub fn state(self) -> FilterState {
+        FilterState {
+            config: self,
+            last: None,
+            last_sent_at: None,
+        }
+    }
+}
+
+/// Remembers what was sent last, one per subscription.
+pub struct FilterState {
+    config: SubscriptionFilter,
+    last: Option<JsonValue>,
+    last_sent_at: Option<Instant>,
+}
+
+impl FilterState {
+    /// Returns whether the notification should be sent, recording it if so.
+    pub fn accept(&mut self, item: &JsonValue) -> bool {
+        let matches = self
+            .config
+            .predicate
+            .iter()
+            .all(|(pointer, expected)| item.pointer(pointer) == Some(expected));
+        if !matches {
+            return false;
+        }
+        if self.config.dedup && self.last.as_ref() == Some(item) {
+            return false;
+        }
+        let now = Instant::now();
+        if let (Some(min_interval), Some(last_sent_at)) = (self.config.min_interval, self.last_sent_at) {
+            if now < last_sent_at + min_interval {
+                return false;
+            }
+        }
+
+        if self.config.dedup {
+            self.last = Some(item.clone());
+        }
+        self.last_sent_at = Some(now);
+        true
+    }
+}
+
+/// Parses a client predicate, an object of json pointers to the values they must equal.
+fn parse_predicate(value: JsonValue) -> Result<Vec<(String, JsonValue)>, String> {
+    match value {
+        JsonValue::Null => Ok(vec![]),
+        JsonValue::Object(map) => map
+            .into_iter()
+            .map(|(pointer, expected)| {
+                if pointer.is_empty() || pointer.starts_with('/') {
+                    Ok((pointer, expected))
+                } else {
+                    Err(format!("Invalid json pointer in filter: {pointer}"))
+                }
+            })
+            .collect(),
+        _ => Err("Filter must be an object of json pointers to values".to_string()),
+    }
+}
+
+pub struct SubscriptionFilterMiddleware {
+    filter: SubscriptionFilter,
+    predicate_param: Option<usize>,
+}
+
+#[async_trait]
+impl MiddlewareBuilder<RpcSubscription, SubscriptionRequest, SubscriptionResult> for SubscriptionFilterMiddleware {
+    async fn build(
+        method: &RpcSubscription,
+        _extensions: &TypeRegistryRef,
+    ) -> Option<Box<dyn Middleware<SubscriptionRequest, SubscriptionResult>>> {
+        let SubscriptionFilterParams {
+            dedup,
+            min_interval_ms,
+            predicate_param,
+        } = method.filter.clone()?;
+
+        Some(Box::new(SubscriptionFilterMiddleware {
+            filter: SubscriptionFilter {
+                dedup,
+                min_interval: min_interval_ms.map(Duration::from_millis),
+                predicate: vec![],
+            },
+            predicate_param,
+        }))
+    }
+}
+
+#[async_trait]
+impl Middleware<SubscriptionRequest, SubscriptionResult> for SubscriptionFilterMiddleware {
+    async fn call(
+        &self,
+        mut request: SubscriptionRequest,
+        mut context: TypeRegistry,
+        next: NextFn<SubscriptionRequest, SubscriptionResult>,
+    ) -> SubscriptionResult {
+        let _span = TRACER.context("subscription_filter");
+        let mut filter = self.filter.clone();
+
+        // the predicate is ours, upstream never sees it
+        if let Some(index) = self.predicate_param.filter(|i| *i < request.params.len()) {
+            match parse_predicate(request.params.remove(index)) {
+                Ok(predicate) => filter.predicate = predicate,
+                Err(e) => {
+                    request.pending_sink.reject(errors::invalid_params(e)).await;
+                    return Ok(());
+                }
+            }
+        }
+
+        if filter != SubscriptionFilter::default() {
+            context.insert(filter);
+        }
+        next(request, context).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn dedups_notifications() {
+        let mut state = SubscriptionFilter {
+            dedup: true,
+            ..Default::default()
+        }
+        .state();
+
+        assert!(state.accept(&json!(1)));
+        assert!(!state.accept(&json!(1)));
+        assert!(state.accept(&json!(2)));
+        assert!(state.accept(&json!(1)));
+    }
+
+    #[tokio::test]
+    async fn throttles_notifications() {
+        let mut state = SubscriptionFilter {
+            min_interval: Some(Duration::from_millis(50)),
+            ..Default::default()
+        }
+        .state();
+
+        assert!(state.accept(&json!(1)));
+        assert!(!state.accept(&json!(2)));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(state.accept(&json!(3)));
+    }
+
+    #[test]
+    fn filters_by_predicate() {
+        let predicate = parse_predicate(json!({ "/event/section": "balances" })).unwrap();
+        let mut state = SubscriptionFilter {
+            predicate,
+            ..Default::default()
+        }
+        .state();
+
+        assert!(state.accept(&json!({ "event": { "section": "balances", "method": "Transfer" } })));
+        assert!(!state.accept(&json!({ "event": { "section": "system" } })));
+        assert!(!state.accept(&json!(1)));
+
+        assert_eq!(parse_predicate(json!(null)), Ok(vec![]));
+        assert!(parse_predicate(json!({ "event": 1 })).is_err());
+        assert!(parse_predicate(json!([1])).is_err());
+    }
+}
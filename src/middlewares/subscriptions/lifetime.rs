@@ -0,0 +1,12 @@
+use std::time::Duration;
+
+use jsonrpsee::core::JsonValue;
+
+/// Maximum time a subscription stays open, after which it is closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubscriptionLifetime(pub Duration);
+
+/// Notification sent to a subscription right before it is closed for exceeding its lifetime.
+pub fn expired_notification() -> JsonValue {
+    serde_json::json!({ "type": "subscription_expired", "reason": "max_lifetime" })
+}
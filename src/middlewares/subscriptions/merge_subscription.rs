@@ -1,20 +1,31 @@
 use std::{
-    collections::{BTreeSet, HashMap},
+    collections::{BTreeSet, HashMap, VecDeque},
     sync::Arc,
     time::Duration,
 };
 
 use async_trait::async_trait;
 use blake2::Blake2b512;
-use jsonrpsee::{core::JsonValue, SubscriptionMessage};
+use jsonrpsee::{core::JsonValue, SubscriptionMessage, SubscriptionSink};
 use opentelemetry::trace::FutureExt;
 use serde::{Deserialize, Serialize};
 use tokio::sync::{broadcast, RwLock};
 
 use crate::{
     config::MergeStrategy,
-    extensions::{client::Client, merge_subscription::MergeSubscription},
+    extensions::{
+        client::{Client, ForwardedHeaders},
+        merge_subscription::MergeSubscription,
+        rebalance::{Rebalance, RECONNECT_HINT},
+        server::OpenSubscription,
+        subscription_stats::SubscriptionRate,
+    },
     middlewares::{
+        subscriptions::{
+            backpressure::SubscriptionBackpressure,
+            close_with_error,
+            lifetime::{expired_notification, SubscriptionLifetime},
+        },
         Middleware, MiddlewareBuilder, NextFn, RpcSubscription, SubscriptionRequest, SubscriptionResult, TRACER,
     },
     utils::{errors, CacheKey, TypeRegistry, TypeRegistryRef},
@@ -59,12 +70,90 @@ fn handle_value_change(
     }
 }
 
-type UpstreamSubscription = broadcast::Sender<SubscriptionMessage>;
+/// Per-subscriber queue that keeps only the latest message per key, in the place of the first one queued.
+/// Messages without a key are always queued.
+pub struct CoalescingBuffer<T> {
+    queue: VecDeque<(Option<String>, T)>,
+    // sequence number of the front message
+    head: u64,
+    // sequence number of the queued message of each key
+    index: HashMap<String, u64>,
+}
+
+impl<T> Default for CoalescingBuffer<T> {
+    fn default() -> Self {
+        Self {
+            queue: VecDeque::new(),
+            head: 0,
+            index: HashMap::new(),
+        }
+    }
+}
+
+impl<T> CoalescingBuffer<T> {
+    pub fn push(&mut self, key: Option<String>, value: T) {
+        if let Some(ref key) = key {
+            if let Some(seq) = self.index.get(key) {
+                self.queue[(seq - self.head) as usize].1 = value;
+                return;
+            }
+            self.index.insert(key.clone(), self.head + self.queue.len() as u64);
+        }
+        self.queue.push_back((key, value));
+    }
+
+    pub fn front(&self) -> Option<&T> {
+        self.queue.front().map(|(_, value)| value)
+    }
+
+    pub fn pop_front(&mut self) -> Option<T> {
+        let (key, value) = self.queue.pop_front()?;
+        if let Some(key) = key {
+            self.index.remove(&key);
+        }
+        self.head += 1;
+        Some(value)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+}
+
+/// Value at the JSON `pointer` of a notification, under which it is coalesced.
+/// None if the pointer goes through an array of several items, e.g. storage changes of several keys,
+/// as coalescing by one of them would lose the others.
+pub fn coalesce_key(pointer: &Option<String>, value: &JsonValue) -> Option<String> {
+    let pointer = pointer.as_ref()?;
+    if !pointer.is_empty() && !pointer.starts_with('/') {
+        return None;
+    }
+    let mut target = value;
+    for token in pointer.split('/').skip(1) {
+        let token = token.replace("~1", "/").replace("~0", "~");
+        target = match target {
+            JsonValue::Array(items) if items.len() == 1 => items.get(token.parse::<usize>().ok()?)?,
+            JsonValue::Object(fields) => fields.get(&token)?,
+            _ => return None,
+        };
+    }
+    Some(target.to_string())
+}
+
+// messages are tagged with their coalesce key, if configured
+type UpstreamSubscription = broadcast::Sender<(Option<String>, SubscriptionMessage)>;
 
 pub struct MergeSubscriptionMiddleware {
     client: Arc<Client>,
     merge_strategy: MergeStrategy,
+    coalesce_key: Option<String>,
     keep_alive_seconds: u64,
+    // set when subscribers can be asked to reconnect
+    rebalance: Option<Arc<Rebalance>>,
     upstream_subs: Arc<RwLock<HashMap<CacheKey<Blake2b512>, UpstreamSubscription>>>,
     current_values: Arc<RwLock<HashMap<CacheKey<Blake2b512>, JsonValue>>>,
 }
@@ -74,20 +163,34 @@ impl MergeSubscriptionMiddleware {
         Self {
             client,
             merge_strategy,
+            coalesce_key: None,
             keep_alive_seconds: keep_alive_seconds.unwrap_or(60), // 60s
+            rebalance: None,
             upstream_subs: Arc::new(RwLock::new(HashMap::new())),
             current_values: Arc::new(RwLock::new(HashMap::new())),
         }
     }
 
+    /// Coalesce queued messages of slow subscribers by the value at the given JSON pointer.
+    pub fn with_coalesce_key(mut self, coalesce_key: Option<String>) -> Self {
+        self.coalesce_key = coalesce_key;
+        self
+    }
+
+    pub fn with_rebalance(mut self, rebalance: Option<Arc<Rebalance>>) -> Self {
+        self.rebalance = rebalance;
+        self
+    }
+
     async fn get_upstream_subscription(
         &self,
+        client: Arc<Client>,
         key: CacheKey<Blake2b512>,
         subscribe: String,
         params: Vec<JsonValue>,
         unsubscribe: String,
     ) -> Result<
-        Box<dyn FnOnce() -> broadcast::Receiver<SubscriptionMessage> + Sync + Send + 'static>,
+        Box<dyn FnOnce() -> broadcast::Receiver<(Option<String>, SubscriptionMessage)> + Sync + Send + 'static>,
         jsonrpsee::core::Error,
     > {
         if let Some(tx) = self.upstream_subs.read().await.get(&key).cloned() {
@@ -97,17 +200,17 @@ impl MergeSubscriptionMiddleware {
 
         tracing::trace!("Create new upstream subscription for {}", &subscribe);
 
-        let mut subscription = self.client.subscribe(&subscribe, params.clone(), &unsubscribe).await?;
+        let mut subscription = client.subscribe(&subscribe, params.clone(), &unsubscribe).await?;
 
         let (tx, _) = broadcast::channel(1024);
 
         self.upstream_subs.write().await.insert(key.clone(), tx.clone());
 
         let merge_strategy = self.merge_strategy;
-        let client = self.client.clone();
         let upstream_subs = self.upstream_subs.clone();
         let current_values = self.current_values.clone();
         let keep_alive_seconds = self.keep_alive_seconds;
+        let coalesce_pointer = self.coalesce_key.clone();
 
         let subscribe = Box::new(move || {
             let rx = tx.subscribe();
@@ -127,12 +230,14 @@ impl MergeSubscriptionMiddleware {
                             interval.reset();
 
                             if let Some(Ok(value)) = resp {
+                                client.record_activity();
                                 // update current value
                                 let current_value = current_values.read().await.get(&key).cloned();
                                 current_values.write().await.insert(key.clone(), handle_value_change(merge_strategy, current_value, value.clone()));
 
                                 if let Ok(message) = SubscriptionMessage::from_json(&value) {
-                                    if let Err(err) = tx.send(message.clone()) {
+                                    let key = coalesce_key(&coalesce_pointer, &value);
+                                    if let Err(err) = tx.send((key, message)) {
                                         tracing::error!("Failed to send message: {}", err);
                                         break;
                                     }
@@ -187,11 +292,19 @@ impl MiddlewareBuilder<RpcSubscription, SubscriptionRequest, SubscriptionResult>
             .get::<MergeSubscription>()
             .expect("MergeSubscription extension not found");
 
-        Some(Box::new(MergeSubscriptionMiddleware::new(
-            client,
-            merge_strategy,
-            merge_subscription.config.keep_alive_seconds,
-        )))
+        Some(Box::new(
+            MergeSubscriptionMiddleware::new(client, merge_strategy, merge_subscription.config.keep_alive_seconds)
+                .with_coalesce_key(method.coalesce_key.clone())
+                .with_rebalance(ext.get::<Rebalance>()),
+        ))
+    }
+}
+
+async fn send_expired(sink: &SubscriptionSink) {
+    if let Ok(msg) = SubscriptionMessage::from_json(&expired_notification()) {
+        if let Err(e) = sink.send(msg).await {
+            tracing::trace!("subscription sink closed {e:?}");
+        }
     }
 }
 
@@ -200,11 +313,22 @@ impl Middleware<SubscriptionRequest, SubscriptionResult> for MergeSubscriptionMi
     async fn call(
         &self,
         request: SubscriptionRequest,
-        _context: TypeRegistry,
+        context: TypeRegistry,
         _next: NextFn<SubscriptionRequest, SubscriptionResult>,
     ) -> SubscriptionResult {
         async move {
             let key = CacheKey::new(&request.subscribe, &request.params);
+            // subscribers only share an upstream subscription made with their own forwarded headers
+            let (client, key) = match context.get::<ForwardedHeaders>() {
+                Some(headers) if !headers.is_empty() => match self.client.headers_client(&headers) {
+                    Ok(client) => (client, key.scoped(&headers.cache_scope())),
+                    Err(e) => {
+                        request.pending_sink.reject(errors::internal_error(e)).await;
+                        return Ok(());
+                    }
+                },
+                _ => (self.client.clone(), key),
+            };
 
             let SubscriptionRequest {
                 subscribe,
@@ -214,7 +338,7 @@ impl Middleware<SubscriptionRequest, SubscriptionResult> for MergeSubscriptionMi
             } = request;
 
             let subscribe = match self
-                .get_upstream_subscription(key.clone(), subscribe, params.to_owned(), unsubscribe)
+                .get_upstream_subscription(client, key.clone(), subscribe, params.to_owned(), unsubscribe)
                 .await
             {
                 Ok(subscribe) => subscribe,
@@ -234,9 +358,19 @@ impl Middleware<SubscriptionRequest, SubscriptionResult> for MergeSubscriptionMi
             };
 
             let current_values = self.current_values.clone();
+            let expires_at = context
+                .get::<SubscriptionLifetime>()
+                .map(|l| tokio::time::Instant::now() + l.0);
+            let rate = context.get::<SubscriptionRate>();
+            let backpressure = context.get::<SubscriptionBackpressure>();
+            let open_subscription = context.get::<OpenSubscription>();
+            // each subscriber is asked to reconnect on its own, the upstream subscription ends with the last one
+            let mut rebalance = self.rebalance.as_ref().map(|r| r.register());
 
             // send any current value and broadcast new values
             tokio::spawn(async move {
+                // counted as open on the connection until the subscription ends
+                let _open_subscription = open_subscription;
                 // read lock before subscribing to make sure we don't miss any value
                 let read_lock = current_values.read().await;
                 let mut stream = subscribe();
@@ -254,13 +388,64 @@ impl Middleware<SubscriptionRequest, SubscriptionResult> for MergeSubscriptionMi
                 }
                 drop(read_lock);
 
+                let Some(backpressure) = backpressure else {
+                    loop {
+                        tokio::select! {
+                            resp = stream.recv() => {
+                                match resp {
+                                    Ok((_, new_value)) => {
+                                        if let Some(rate) = rate.as_ref() {
+                                            rate.record();
+                                        }
+                                        if let Err(e) = sink.send(new_value).await {
+                                            tracing::trace!("subscription sink closed {e:?}");
+                                            break;
+                                        }
+                                    }
+                                    Err(e) => {
+                                        // remote upstream subscription failed, drop subscription
+                                        tracing::trace!("subscription stream error {e}");
+                                        break;
+                                    }
+                                }
+                            }
+                            _ = async { tokio::time::sleep_until(expires_at.expect("checked by precondition; qed")).await }, if expires_at.is_some() => {
+                                tracing::trace!("subscription reached its max lifetime");
+                                send_expired(&sink).await;
+                                break;
+                            }
+                            hint = async { (&mut rebalance.as_mut().expect("checked by precondition; qed").hint).await }, if rebalance.is_some() => {
+                                if hint.is_err() {
+                                    // rebalancing went away, stop listening for hints
+                                    rebalance = None;
+                                    continue;
+                                }
+                                tracing::trace!("asking subscription to reconnect");
+                                close_with_error(&sink, RECONNECT_HINT).await;
+                                break;
+                            }
+                            _ = sink.closed() => {
+                                tracing::trace!("subscription sink closed");
+                                break;
+                            }
+                        }
+                    }
+                    return;
+                };
+
+                // queue messages while the sink is busy and only keep the latest per key
+                let mut buffer = (*backpressure).clone().outbox();
                 loop {
+                    let next_message = buffer.front().cloned();
                     tokio::select! {
                         resp = stream.recv() => {
                             match resp {
-                                Ok(new_value) => {
-                                    if let Err(e) = sink.send(new_value).await {
-                                        tracing::trace!("subscription sink closed {e:?}");
+                                Ok((key, new_value)) => {
+                                    if let Some(rate) = rate.as_ref() {
+                                        rate.record();
+                                    }
+                                    if !buffer.push(key, new_value) {
+                                        // too slow, disconnect
                                         break;
                                     }
                                 }
@@ -271,6 +456,28 @@ impl Middleware<SubscriptionRequest, SubscriptionResult> for MergeSubscriptionMi
                                 }
                             }
                         }
+                        res = async { sink.send(next_message.expect("checked by precondition; qed")).await }, if next_message.is_some() => {
+                            if let Err(e) = res {
+                                tracing::trace!("subscription sink closed {e:?}");
+                                break;
+                            }
+                            buffer.pop_front();
+                        }
+                        _ = async { tokio::time::sleep_until(expires_at.expect("checked by precondition; qed")).await }, if expires_at.is_some() => {
+                            tracing::trace!("subscription reached its max lifetime");
+                            send_expired(&sink).await;
+                            break;
+                        }
+                        hint = async { (&mut rebalance.as_mut().expect("checked by precondition; qed").hint).await }, if rebalance.is_some() => {
+                            if hint.is_err() {
+                                // rebalancing went away, stop listening for hints
+                                rebalance = None;
+                                continue;
+                            }
+                            tracing::trace!("asking subscription to reconnect");
+                            close_with_error(&sink, RECONNECT_HINT).await;
+                            break;
+                        }
                         _ = sink.closed() => {
                             tracing::trace!("subscription sink closed");
                             break;
@@ -352,3 +559,67 @@ fn merge_storage_changes_works() {
         })
     );
 }
+
+#[test]
+fn coalescing_buffer_keeps_latest_per_key() {
+    let mut buffer = CoalescingBuffer::default();
+
+    buffer.push(Some("0x01".to_string()), 1);
+    buffer.push(Some("0x01".to_string()), 2);
+    buffer.push(None, 3);
+    buffer.push(Some("0x02".to_string()), 4);
+    buffer.push(None, 5);
+    buffer.push(Some("0x01".to_string()), 6);
+
+    let mut delivered = vec![];
+    while let Some(value) = buffer.pop_front() {
+        delivered.push(value);
+    }
+    // the latest value of a key takes the place of the first one queued
+    assert_eq!(delivered, vec![6, 3, 4, 5]);
+    assert!(buffer.is_empty());
+
+    // a key sent again after its message was delivered is queued again
+    buffer.push(Some("0x01".to_string()), 7);
+    buffer.push(Some("0x02".to_string()), 8);
+    assert_eq!(buffer.pop_front(), Some(7));
+    buffer.push(Some("0x01".to_string()), 9);
+    buffer.push(Some("0x02".to_string()), 10);
+    assert_eq!(buffer.pop_front(), Some(10));
+    assert_eq!(buffer.pop_front(), Some(9));
+    assert!(buffer.is_empty());
+}
+
+#[test]
+fn coalesce_key_skips_notifications_of_several_items() {
+    use serde_json::json;
+
+    let pointer = Some("/changes/0/0".to_string());
+    let single = json!({ "block": "0x01", "changes": [["0xaa", "0x01"]] });
+    assert_eq!(coalesce_key(&pointer, &single), Some("\"0xaa\"".to_string()));
+
+    // coalescing by the first key would lose the change of the second one
+    let several = json!({ "block": "0x01", "changes": [["0xaa", "0x01"], ["0xbb", "0x02"]] });
+    assert_eq!(coalesce_key(&pointer, &several), None);
+
+    let head = json!({ "number": "0x10", "parentHash": "0x00" });
+    assert_eq!(
+        coalesce_key(&Some("/number".to_string()), &head),
+        Some("\"0x10\"".to_string())
+    );
+    assert_eq!(coalesce_key(&Some("/missing".to_string()), &head), None);
+    assert_eq!(coalesce_key(&None, &head), None);
+}
+
+#[test]
+fn coalesce_key_extraction() {
+    use serde_json::json;
+
+    let value = json!({ "block": "0x01", "changes": [["0xaa", "0x01"]] });
+    assert_eq!(
+        coalesce_key(&Some("/changes/0/0".to_string()), &value),
+        Some("\"0xaa\"".to_string())
+    );
+    assert_eq!(coalesce_key(&Some("/missing".to_string()), &value), None);
+    assert_eq!(coalesce_key(&None, &value), None);
+}
@@ -1,2 +1,29 @@
+use jsonrpsee::{SubscriptionMessage, SubscriptionSink};
+
+pub mod backpressure;
+pub mod batch;
+pub mod filter;
+pub mod lifetime;
 pub mod merge_subscription;
+pub mod read_only;
+pub mod replay;
+pub mod stats;
 pub mod upstream;
+
+/// Ends a subscription with an error, sent like jsonrpsee does for a subscription failing on the server.
+pub async fn close_with_error(sink: &SubscriptionSink, error: impl ToString) {
+    let notification = serde_json::json!({
+        "jsonrpc": "2.0",
+        "method": sink.method_name(),
+        "params": {
+            "subscription": sink.subscription_id(),
+            "error": error.to_string(),
+        },
+    });
+    if let Err(e) = sink
+        .send(SubscriptionMessage::from_complete_message(notification.to_string()))
+        .await
+    {
+        tracing::debug!("Subscription sink closed: {}", e);
+    }
+}
@@ -0,0 +1,48 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use crate::{
+    extensions::read_only::ReadOnly,
+    middlewares::{
+        methods::read_only::READ_ONLY_ERROR, Middleware, MiddlewareBuilder, NextFn, RpcSubscription,
+        SubscriptionRequest, SubscriptionResult, TRACER,
+    },
+    utils::{errors, TypeRegistry, TypeRegistryRef},
+};
+
+pub struct ReadOnlyMiddleware {
+    read_only: Arc<ReadOnly>,
+}
+
+#[async_trait]
+impl MiddlewareBuilder<RpcSubscription, SubscriptionRequest, SubscriptionResult> for ReadOnlyMiddleware {
+    async fn build(
+        method: &RpcSubscription,
+        extensions: &TypeRegistryRef,
+    ) -> Option<Box<dyn Middleware<SubscriptionRequest, SubscriptionResult>>> {
+        let read_only = extensions.read().await.get::<ReadOnly>()?;
+        if !read_only.is_write_method(&method.subscribe) {
+            return None;
+        }
+        Some(Box::new(ReadOnlyMiddleware { read_only }))
+    }
+}
+
+#[async_trait]
+impl Middleware<SubscriptionRequest, SubscriptionResult> for ReadOnlyMiddleware {
+    async fn call(
+        &self,
+        request: SubscriptionRequest,
+        context: TypeRegistry,
+        next: NextFn<SubscriptionRequest, SubscriptionResult>,
+    ) -> SubscriptionResult {
+        if !self.read_only.is_enabled() {
+            return next(request, context).await;
+        }
+
+        let _span = TRACER.context("read_only");
+        request.pending_sink.reject(errors::failed(READ_ONLY_ERROR)).await;
+        Ok(())
+    }
+}
@@ -0,0 +1,142 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use blake2::Blake2b512;
+use jsonrpsee::core::JsonValue;
+
+use crate::{
+    middlewares::{
+        Middleware, MiddlewareBuilder, NextFn, RpcSubscription, SubscriptionRequest, SubscriptionResult, TRACER,
+    },
+    utils::{CacheKey, TypeRegistry, TypeRegistryRef},
+};
+
+#[derive(Default)]
+struct Entry {
+    value: Option<JsonValue>,
+    subscribers: usize,
+}
+
+/// Last notification of each subscription key, kept only while the key has subscribers so it is never stale.
+#[derive(Default)]
+pub struct LastValues {
+    entries: Mutex<HashMap<CacheKey<Blake2b512>, Entry>>,
+}
+
+/// Asks the middleware forwarding notifications to replay the last notification of `key` and record new ones.
+#[derive(Clone)]
+pub struct SubscriptionReplay {
+    key: CacheKey<Blake2b512>,
+    values: Arc<LastValues>,
+}
+
+impl SubscriptionReplay {
+    pub fn register(&self) -> ReplayHandle {
+        let mut entries = self.values.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.entry(self.key.clone()).or_default().subscribers += 1;
+        ReplayHandle {
+            key: self.key.clone(),
+            values: self.values.clone(),
+        }
+    }
+}
+
+/// Counts as a subscriber of its key until dropped.
+pub struct ReplayHandle {
+    key: CacheKey<Blake2b512>,
+    values: Arc<LastValues>,
+}
+
+impl ReplayHandle {
+    pub fn last(&self) -> Option<JsonValue> {
+        let entries = self.values.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.get(&self.key).and_then(|entry| entry.value.clone())
+    }
+
+    pub fn record(&self, value: &JsonValue) {
+        let mut entries = self.values.entries.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(entry) = entries.get_mut(&self.key) {
+            entry.value = Some(value.clone());
+        }
+    }
+}
+
+impl Drop for ReplayHandle {
+    fn drop(&mut self) {
+        let mut entries = self.values.entries.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(entry) = entries.get_mut(&self.key) {
+            entry.subscribers = entry.subscribers.saturating_sub(1);
+            if entry.subscribers == 0 {
+                entries.remove(&self.key);
+            }
+        }
+    }
+}
+
+pub struct SubscriptionReplayMiddleware {
+    values: Arc<LastValues>,
+}
+
+#[async_trait]
+impl MiddlewareBuilder<RpcSubscription, SubscriptionRequest, SubscriptionResult> for SubscriptionReplayMiddleware {
+    async fn build(
+        method: &RpcSubscription,
+        _extensions: &TypeRegistryRef,
+    ) -> Option<Box<dyn Middleware<SubscriptionRequest, SubscriptionResult>>> {
+        if !method.replay_last {
+            return None;
+        }
+        Some(Box::new(SubscriptionReplayMiddleware {
+            values: Default::default(),
+        }))
+    }
+}
+
+#[async_trait]
+impl Middleware<SubscriptionRequest, SubscriptionResult> for SubscriptionReplayMiddleware {
+    async fn call(
+        &self,
+        request: SubscriptionRequest,
+        mut context: TypeRegistry,
+        next: NextFn<SubscriptionRequest, SubscriptionResult>,
+    ) -> SubscriptionResult {
+        let _span = TRACER.context("subscription_replay");
+        context.insert(SubscriptionReplay {
+            key: CacheKey::new(&request.subscribe, &request.params),
+            values: self.values.clone(),
+        });
+        next(request, context).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn keeps_last_value_while_subscribed() {
+        let replay = SubscriptionReplay {
+            key: CacheKey::new(&"chain_subscribeNewHeads".to_string(), &[]),
+            values: Default::default(),
+        };
+
+        let first = replay.register();
+        assert_eq!(first.last(), None);
+        first.record(&json!(1));
+        first.record(&json!(2));
+
+        let second = replay.register();
+        assert_eq!(second.last(), Some(json!(2)));
+
+        drop(first);
+        assert_eq!(second.last(), Some(json!(2)));
+
+        // no subscriber left, the value may be outdated by now
+        drop(second);
+        assert_eq!(replay.register().last(), None);
+    }
+}
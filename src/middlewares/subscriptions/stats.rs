@@ -0,0 +1,43 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use crate::{
+    extensions::subscription_stats::{SubscriptionRate, SubscriptionStats},
+    middlewares::{
+        Middleware, MiddlewareBuilder, NextFn, RpcSubscription, SubscriptionRequest, SubscriptionResult, TRACER,
+    },
+    utils::{TypeRegistry, TypeRegistryRef},
+};
+
+/// Passes the rate of the subscription down, the middleware forwarding notifications records each of them.
+pub struct SubscriptionStatsMiddleware {
+    rate: Arc<SubscriptionRate>,
+}
+
+#[async_trait]
+impl MiddlewareBuilder<RpcSubscription, SubscriptionRequest, SubscriptionResult> for SubscriptionStatsMiddleware {
+    async fn build(
+        method: &RpcSubscription,
+        extensions: &TypeRegistryRef,
+    ) -> Option<Box<dyn Middleware<SubscriptionRequest, SubscriptionResult>>> {
+        let stats = extensions.read().await.get::<SubscriptionStats>()?;
+        Some(Box::new(SubscriptionStatsMiddleware {
+            rate: stats.rate(&method.name),
+        }))
+    }
+}
+
+#[async_trait]
+impl Middleware<SubscriptionRequest, SubscriptionResult> for SubscriptionStatsMiddleware {
+    async fn call(
+        &self,
+        request: SubscriptionRequest,
+        mut context: TypeRegistry,
+        next: NextFn<SubscriptionRequest, SubscriptionResult>,
+    ) -> SubscriptionResult {
+        let _span = TRACER.context("subscription_stats");
+        context.insert_raw(self.rate.clone());
+        next(request, context).await
+    }
+}
@@ -1,31 +1,265 @@
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use async_trait::async_trait;
-use jsonrpsee::SubscriptionMessage;
+use jsonrpsee::{
+    core::{client::Subscription, JsonValue},
+    SubscriptionMessage, SubscriptionSink,
+};
 use opentelemetry::trace::FutureExt;
+use tokio::sync::watch;
 
 use crate::{
-    extensions::client::Client,
+    extensions::{
+        client::{Client, ForwardedHeaders, SubscribeError},
+        metrics,
+        rebalance::{Rebalance, RECONNECT_HINT},
+        server::OpenSubscription,
+        subscription_stats::SubscriptionRate,
+    },
     middlewares::{
+        subscriptions::{
+            backpressure::SubscriptionBackpressure,
+            batch::SubscriptionBatch,
+            close_with_error,
+            filter::SubscriptionFilter,
+            lifetime::{expired_notification, SubscriptionLifetime},
+            merge_subscription::coalesce_key,
+            replay::SubscriptionReplay,
+        },
         Middleware, MiddlewareBuilder, NextFn, RpcSubscription, SubscriptionRequest, SubscriptionResult, TRACER,
     },
     utils::{errors, TypeRegistry, TypeRegistryRef},
 };
 
+/// Number of upstream subscriptions hosted by each endpoint, shared by all subscription methods.
+#[derive(Debug, Default)]
+pub struct EndpointSubscriptions {
+    counts: Mutex<HashMap<String, usize>>,
+}
+
+/// Holds one subscription of an endpoint, released on drop.
+pub struct SubscriptionSlot {
+    endpoint: String,
+    subscriptions: Arc<EndpointSubscriptions>,
+}
+
+impl Drop for SubscriptionSlot {
+    fn drop(&mut self) {
+        let mut counts = self.subscriptions.counts.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(count) = counts.get_mut(&self.endpoint) {
+            *count = count.saturating_sub(1);
+        }
+    }
+}
+
+impl EndpointSubscriptions {
+    pub fn count(&self, endpoint: &str) -> usize {
+        let counts = self.counts.lock().unwrap_or_else(|e| e.into_inner());
+        counts.get(endpoint).copied().unwrap_or_default()
+    }
+
+    fn try_acquire(self: &Arc<Self>, endpoint: &str, limit: Option<usize>) -> Option<SubscriptionSlot> {
+        let mut counts = self.counts.lock().unwrap_or_else(|e| e.into_inner());
+        let count = counts.entry(endpoint.to_string()).or_default();
+        if limit.is_some_and(|limit| *count >= limit) {
+            return None;
+        }
+        *count += 1;
+        Some(SubscriptionSlot {
+            endpoint: endpoint.to_string(),
+            subscriptions: self.clone(),
+        })
+    }
+
+    /// Picks the current endpoint if it has capacity, otherwise the first other endpoint that does.
+    pub fn acquire(self: &Arc<Self>, client: &Arc<Client>) -> Option<(Arc<Client>, SubscriptionSlot)> {
+        let current = client.current_endpoint();
+        let endpoints = client.endpoints();
+        let others = endpoints.iter().filter(|e| **e != current);
+
+        for endpoint in std::iter::once(&current).chain(others) {
+            let Some(slot) = self.try_acquire(endpoint, client.subscription_limit(endpoint)) else {
+                continue;
+            };
+            match client.endpoint_client(endpoint) {
+                Ok(endpoint_client) => return Some((endpoint_client, slot)),
+                Err(e) => tracing::warn!("Failed to create client for {endpoint}: {e}"),
+            }
+        }
+
+        None
+    }
+}
+
+/// Sends a notification downstream, returns false once the sink is closed.
+async fn send_json(sink: &SubscriptionSink, value: &JsonValue) -> bool {
+    let msg = match SubscriptionMessage::from_json(value) {
+        Ok(msg) => msg,
+        Err(e) => {
+            tracing::error!("Failed to serialize subscription response: {}", e);
+            return true;
+        }
+    };
+    match sink.send(msg).await {
+        Ok(()) => true,
+        Err(e) => {
+            // downstream went away, nothing to worry about
+            tracing::debug!("Subscription sink closed: {}", e);
+            false
+        }
+    }
+}
+
+/// Subscribes upstream, giving up after the subscribe timeout of the client if it has one.
+async fn subscribe_upstream(
+    client: &Client,
+    subscribe: &str,
+    params: Vec<JsonValue>,
+    unsubscribe: &str,
+) -> Result<Subscription<JsonValue>, SubscribeError> {
+    match client.subscribe_timeout() {
+        Some(timeout) => {
+            client
+                .subscribe_with_timeout(subscribe, params, unsubscribe, timeout)
+                .await
+        }
+        None => client
+            .subscribe(subscribe, params, unsubscribe)
+            .await
+            .map_err(SubscribeError::from),
+    }
+}
+
+// attempts to subscribe again after the upstream subscription failed
+const RESUBSCRIBE_ATTEMPTS: u32 = 5;
+
+// time the client gets to notice a lost connection after a subscription stream ended
+const CONNECTION_LOST_GRACE: Duration = Duration::from_millis(500);
+
+/// Delay before the given attempt to subscribe again, starting at 0.
+fn resubscribe_backoff(attempt: u32) -> Duration {
+    Duration::from_millis(100u64.saturating_mul(1 << attempt.min(7)))
+}
+
+/// Whether a subscription stream which ended without error was cut by its connection going away,
+/// which the client may only notice shortly after the stream ended.
+async fn connection_lost(connection: &mut watch::Receiver<u64>) -> bool {
+    if connection.has_changed().unwrap_or(false) {
+        return true;
+    }
+    matches!(
+        tokio::time::timeout(CONNECTION_LOST_GRACE, connection.changed()).await,
+        Ok(Ok(()))
+    )
+}
+
+/// Subscription re-established upstream, with the endpoint slot and connection it was made on.
+struct Resubscribed {
+    client: Arc<Client>,
+    slot: Option<SubscriptionSlot>,
+    connection: watch::Receiver<u64>,
+    subscription: Subscription<JsonValue>,
+}
+
+/// Subscribes again after the upstream subscription failed, backing off exponentially between attempts.
+/// The client rotates endpoint if needed.
+async fn resubscribe(
+    upstream: &Arc<Client>,
+    subscriptions: Option<&Arc<EndpointSubscriptions>>,
+    subscribe: &str,
+    params: &[JsonValue],
+    unsubscribe: &str,
+) -> Result<Resubscribed, String> {
+    let mut attempt = 0;
+    loop {
+        let acquired = match subscriptions {
+            Some(subscriptions) => subscriptions
+                .acquire(upstream)
+                .map(|(client, slot)| (client, Some(slot)))
+                .ok_or_else(|| "no upstream endpoint has subscription capacity".to_string()),
+            None => Ok((upstream.clone(), None)),
+        };
+        let result = match acquired {
+            Ok((client, slot)) => {
+                let connection = client.connection_changes();
+                match subscribe_upstream(&client, subscribe, params.to_vec(), unsubscribe).await {
+                    Ok(subscription) => Ok(Resubscribed {
+                        client,
+                        slot,
+                        connection,
+                        subscription,
+                    }),
+                    Err(err) => Err(err.to_string()),
+                }
+            }
+            Err(err) => Err(err),
+        };
+
+        match result {
+            Err(err) if attempt + 1 < RESUBSCRIBE_ATTEMPTS => {
+                tracing::warn!("Failed to resubscribe {}: {}, retrying", subscribe, err);
+                tokio::time::sleep(resubscribe_backoff(attempt)).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
 pub struct UpstreamMiddleware {
     client: Arc<Client>,
+    // set when endpoints have subscription limits
+    subscriptions: Option<Arc<EndpointSubscriptions>>,
+    // set when subscriptions can be asked to reconnect
+    rebalance: Option<Arc<Rebalance>>,
+    // JSON pointer coalescing notifications queued by backpressure
+    coalesce_key: Option<String>,
+    // whether failed upstream subscriptions are established again
+    resubscribe: bool,
 }
 
 impl UpstreamMiddleware {
     pub fn new(client: Arc<Client>) -> Self {
-        Self { client }
+        Self {
+            client,
+            subscriptions: None,
+            rebalance: None,
+            coalesce_key: None,
+            resubscribe: true,
+        }
+    }
+
+    /// Whether to subscribe again when the upstream subscription fails or its connection is lost.
+    pub fn with_resubscribe(mut self, resubscribe: bool) -> Self {
+        self.resubscribe = resubscribe;
+        self
+    }
+
+    /// Coalesce notifications queued for slow subscribers by the value at the given JSON pointer.
+    pub fn with_coalesce_key(mut self, coalesce_key: Option<String>) -> Self {
+        self.coalesce_key = coalesce_key;
+        self
+    }
+
+    pub fn with_rebalance(mut self, rebalance: Arc<Rebalance>) -> Self {
+        self.rebalance = Some(rebalance);
+        self
+    }
+
+    pub fn with_endpoint_subscriptions(mut self, subscriptions: Arc<EndpointSubscriptions>) -> Self {
+        self.subscriptions = Some(subscriptions);
+        self
     }
 }
 
 #[async_trait]
 impl MiddlewareBuilder<RpcSubscription, SubscriptionRequest, SubscriptionResult> for UpstreamMiddleware {
     async fn build(
-        _method: &RpcSubscription,
+        method: &RpcSubscription,
         extensions: &TypeRegistryRef,
     ) -> Option<Box<dyn Middleware<SubscriptionRequest, SubscriptionResult>>> {
         let client = extensions
@@ -33,7 +267,28 @@ impl MiddlewareBuilder<RpcSubscription, SubscriptionRequest, SubscriptionResult>
             .await
             .get::<Client>()
             .expect("Client extension not found");
-        Some(Box::new(UpstreamMiddleware::new(client)))
+
+        let mut middleware = UpstreamMiddleware::new(client.clone())
+            .with_coalesce_key(method.coalesce_key.clone())
+            .with_resubscribe(method.resubscribes());
+        if let Some(rebalance) = extensions.read().await.get::<Rebalance>() {
+            middleware = middleware.with_rebalance(rebalance);
+        }
+
+        if !client.has_subscription_limits() {
+            return Some(Box::new(middleware));
+        }
+
+        // accounting is shared by the middlewares of all subscriptions
+        let subscriptions = {
+            let mut extensions = extensions.write().await;
+            if !extensions.has::<EndpointSubscriptions>() {
+                extensions.insert(EndpointSubscriptions::default());
+            }
+            extensions.get::<EndpointSubscriptions>().expect("inserted above")
+        };
+
+        Some(Box::new(middleware.with_endpoint_subscriptions(subscriptions)))
     }
 }
 
@@ -42,7 +297,7 @@ impl Middleware<SubscriptionRequest, SubscriptionResult> for UpstreamMiddleware
     async fn call(
         &self,
         request: SubscriptionRequest,
-        _context: TypeRegistry,
+        context: TypeRegistry,
         _next: NextFn<SubscriptionRequest, SubscriptionResult>,
     ) -> SubscriptionResult {
         async move {
@@ -53,7 +308,33 @@ impl Middleware<SubscriptionRequest, SubscriptionResult> for UpstreamMiddleware
                 pending_sink,
             } = request;
 
-            let result = self.client.subscribe(&subscribe, params, &unsubscribe).await;
+            let upstream = match context.get::<ForwardedHeaders>() {
+                Some(headers) => match self.client.headers_client(&headers) {
+                    Ok(client) => client,
+                    Err(e) => {
+                        pending_sink.reject(errors::internal_error(e)).await;
+                        return Ok(());
+                    }
+                },
+                None => self.client.clone(),
+            };
+
+            let (client, slot) = match self.subscriptions {
+                Some(ref subscriptions) => match subscriptions.acquire(&upstream) {
+                    Some((client, slot)) => (client, Some(slot)),
+                    None => {
+                        pending_sink
+                            .reject(errors::failed("No upstream endpoint has subscription capacity"))
+                            .await;
+                        return Ok(());
+                    }
+                },
+                None => (upstream.clone(), None),
+            };
+
+            // taken first, so a connection lost while subscribing is not missed
+            let connection = client.connection_changes();
+            let result = subscribe_upstream(&client, &subscribe, params.clone(), &unsubscribe).await;
 
             let (mut subscription, sink) = match result {
                 // subscription was successful, accept the sink
@@ -70,40 +351,213 @@ impl Middleware<SubscriptionRequest, SubscriptionResult> for UpstreamMiddleware
                 },
                 // subscription failed, reject the sink
                 Err(e) => {
-                    pending_sink.reject(errors::map_error(e)).await;
+                    pending_sink.reject(e.into_error_object()).await;
                     return Ok(());
                 }
             };
 
+            // sink failures are expected when clients disconnect, upstream ones are not
+            let sink_closed = metrics::subscription_errors().with_label_values(&[&subscribe, "sink"]);
+            let upstream_failed = metrics::subscription_errors().with_label_values(&[&subscribe, "upstream"]);
+            let mut rebalance = self.rebalance.as_ref().map(|r| r.register());
+            let mut batcher = context.get::<SubscriptionBatch>().map(|b| b.batcher());
+            let mut filter = context.get::<SubscriptionFilter>().map(|f| (*f).clone().state());
+            let replay = context.get::<SubscriptionReplay>().map(|r| r.register());
+            let rate = context.get::<SubscriptionRate>();
+            let expires_at = context
+                .get::<SubscriptionLifetime>()
+                .map(|l| tokio::time::Instant::now() + l.0);
+            let open_subscription = context.get::<OpenSubscription>();
+            let coalesce_pointer = self.coalesce_key.clone();
+            let subscriptions = self.subscriptions.clone();
+            let resubscribe_enabled = self.resubscribe;
+            // with backpressure, notifications are queued while the sink is busy instead of awaited
+            let mut outbox = context
+                .get::<SubscriptionBackpressure>()
+                .map(|b| (*b).clone().outbox::<JsonValue>());
+
             tokio::spawn(async move {
+                // keep the endpoint slot until the subscription ends
+                let mut client = client;
+                let mut _slot = slot;
+                let mut connection = connection;
+                // counted as open on the connection until the subscription ends
+                let _open_subscription = open_subscription;
+
+                let mut replayed = replay.as_ref().and_then(|r| r.last());
+                if let Some(last) = replayed.as_ref() {
+                    if filter.as_mut().map_or(true, |f| f.accept(last)) {
+                        // batched subscriptions only ever receive arrays
+                        let last = if batcher.is_some() {
+                            JsonValue::Array(vec![last.clone()])
+                        } else {
+                            last.clone()
+                        };
+                        if !send_json(&sink, &last).await {
+                            sink_closed.inc();
+                            if let Err(err) = subscription.unsubscribe().await {
+                                tracing::error!("Failed to unsubscribe: {}", err);
+                            }
+                            return;
+                        }
+                    }
+                }
+
                 loop {
+                    let next_message = outbox.as_ref().and_then(|o| o.front().cloned());
                     tokio::select! {
+                        hint = async { (&mut rebalance.as_mut().expect("checked by precondition; qed").hint).await }, if rebalance.is_some() => {
+                            if hint.is_err() {
+                                // rebalancing went away, stop listening for hints
+                                rebalance = None;
+                                continue;
+                            }
+                            tracing::debug!("Asking subscription {} to reconnect", subscribe);
+                            close_with_error(&sink, RECONNECT_HINT).await;
+                            if let Err(err) = subscription.unsubscribe().await {
+                                tracing::error!("Failed to unsubscribe: {}", err);
+                            }
+                            break
+                        },
+                        _ = async { tokio::time::sleep_until(expires_at.expect("checked by precondition; qed")).await }, if expires_at.is_some() => {
+                            tracing::debug!("Subscription {} reached its max lifetime", subscribe);
+                            send_json(&sink, &expired_notification()).await;
+                            if let Err(err) = subscription.unsubscribe().await {
+                                tracing::error!("Failed to unsubscribe: {}", err);
+                            }
+                            break
+                        },
+                        sent = async { send_json(&sink, next_message.as_ref().expect("checked by precondition; qed")).await }, if next_message.is_some() => {
+                            if !sent {
+                                sink_closed.inc();
+                                if let Err(err) = subscription.unsubscribe().await {
+                                    tracing::error!("Failed to unsubscribe: {}", err);
+                                }
+                                break;
+                            }
+                            outbox.as_mut().expect("checked by precondition; qed").pop_front();
+                        },
+                        batch = async { batcher.as_mut().expect("checked by precondition; qed").expired().await }, if batcher.as_ref().is_some_and(|b| !b.is_empty()) => {
+                            if let Some(outbox) = outbox.as_mut() {
+                                if !outbox.push(None, batch) {
+                                    // subscriber is too slow, disconnect it
+                                    if let Err(err) = subscription.unsubscribe().await {
+                                        tracing::error!("Failed to unsubscribe: {}", err);
+                                    }
+                                    break;
+                                }
+                                continue;
+                            }
+                            if !send_json(&sink, &batch).await {
+                                sink_closed.inc();
+                                if let Err(err) = subscription.unsubscribe().await {
+                                    tracing::error!("Failed to unsubscribe: {}", err);
+                                }
+                                break;
+                            }
+                        },
                         msg = subscription.next() => {
                             match msg {
-                                Some(resp) => {
-                                    let resp = match resp {
-                                        Ok(resp) => resp,
-                                        Err(e) => {
-                                            tracing::error!("Subscription error: {}", e);
-                                            continue;
-                                        }
+                                Some(Ok(resp)) => {
+                                    client.record_activity();
+                                    if let Some(rate) = rate.as_ref() {
+                                        rate.record();
+                                    }
+                                    if let Some(replay) = replay.as_ref() {
+                                        replay.record(&resp);
+                                    }
+                                    // upstream usually starts with the value just replayed
+                                    if replayed.take().is_some_and(|last| last == resp) {
+                                        continue;
+                                    }
+                                    if filter.as_mut().is_some_and(|f| !f.accept(&resp)) {
+                                        continue;
+                                    }
+                                    let resp = match batcher.as_mut() {
+                                        Some(batcher) => match batcher.push(resp) {
+                                            Some(batch) => batch,
+                                            None => continue,
+                                        },
+                                        None => resp,
                                     };
-                                    let resp = match SubscriptionMessage::from_json(&resp) {
-                                        Ok(resp) => resp,
-                                        Err(e) => {
-                                            tracing::error!("Failed to serialize subscription response: {}", e);
-                                            continue;
+                                    if let Some(outbox) = outbox.as_mut() {
+                                        if !outbox.push(coalesce_key(&coalesce_pointer, &resp), resp) {
+                                            // subscriber is too slow, disconnect it
+                                            if let Err(err) = subscription.unsubscribe().await {
+                                                tracing::error!("Failed to unsubscribe: {}", err);
+                                            }
+                                            break;
+                                        }
+                                        continue;
+                                    }
+                                    if !send_json(&sink, &resp).await {
+                                        sink_closed.inc();
+                                        if let Err(err) = subscription.unsubscribe().await {
+                                            tracing::error!("Failed to unsubscribe: {}", err);
                                         }
+                                        break;
+                                    }
+                                }
+                                Some(Err(_)) | None => {
+                                    // a stream ended by upstream is passed on, unless its connection went away
+                                    if msg.is_none() && !connection_lost(&mut connection).await {
+                                        tracing::debug!("Upstream ended subscription {}", subscribe);
+                                        close_with_error(&sink, "upstream subscription closed").await;
+                                        break;
+                                    }
+                                    let reason = match msg {
+                                        Some(Err(e)) => format!("upstream subscription error: {e}"),
+                                        _ => "upstream connection lost".to_string(),
                                     };
-                                    if let Err(e) = sink.send(resp).await {
-                                        tracing::error!("Failed to send subscription response: {}", e);
+                                    tracing::warn!(
+                                        "{} endpoint: {} subscription: {}",
+                                        reason,
+                                        client.current_endpoint(),
+                                        subscribe
+                                    );
+                                    upstream_failed.inc();
+                                    TRACER.span_error(&errors::failed(&reason));
+
+                                    if !resubscribe_enabled {
+                                        close_with_error(&sink, reason).await;
                                         break;
                                     }
+
+                                    // the slot belongs to the old endpoint, take a new one where the client is now
+                                    _slot = None;
+                                    let resubscribed = resubscribe(
+                                        &upstream,
+                                        subscriptions.as_ref(),
+                                        &subscribe,
+                                        &params,
+                                        &unsubscribe,
+                                    )
+                                    .await;
+                                    match resubscribed {
+                                        Ok(resubscribed) => {
+                                            // after an error the old stream may still be alive upstream
+                                            let old_subscription =
+                                                std::mem::replace(&mut subscription, resubscribed.subscription);
+                                            if let Err(err) = old_subscription.unsubscribe().await {
+                                                tracing::debug!("Failed to unsubscribe: {}", err);
+                                            }
+                                            client = resubscribed.client;
+                                            _slot = resubscribed.slot;
+                                            connection = resubscribed.connection;
+                                        }
+                                        Err(err) => {
+                                            tracing::error!("Failed to resubscribe {}: {}", subscribe, err);
+                                            let reason = format!("{reason}, failed to resubscribe: {err}");
+                                            close_with_error(&sink, reason).await;
+                                            break;
+                                        }
+                                    }
                                 }
-                                None => break,
                             }
                         }
                         _ = sink.closed() => {
+                            tracing::debug!("Subscription sink closed");
+                            sink_closed.inc();
                             if let Err(err) = subscription.unsubscribe().await {
                                 tracing::error!("Failed to unsubscribe: {}", err);
                             }
@@ -119,3 +573,47 @@ impl Middleware<SubscriptionRequest, SubscriptionResult> for UpstreamMiddleware
         .await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resubscribe_backoff_doubles() {
+        let backoffs = (0..4)
+            .map(|attempt| resubscribe_backoff(attempt).as_millis())
+            .collect::<Vec<_>>();
+        assert_eq!(backoffs, vec![100, 200, 400, 800]);
+        assert_eq!(resubscribe_backoff(20), resubscribe_backoff(7));
+    }
+
+    #[tokio::test]
+    async fn connection_lost_only_when_connection_changes() {
+        let (tx, mut rx) = watch::channel(0u64);
+        // stream ended by upstream, the connection is still there
+        assert!(!connection_lost(&mut rx).await);
+
+        tx.send_modify(|c| *c += 1);
+        assert!(connection_lost(&mut rx).await);
+
+        // the client noticing the lost connection after the stream ended
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            tx.send_modify(|c| *c += 1);
+        });
+        assert!(connection_lost(&mut rx).await);
+    }
+
+    #[test]
+    fn submit_and_watch_does_not_resubscribe() {
+        let subscription = |subscribe: &str, resubscribe| RpcSubscription {
+            subscribe: subscribe.to_string(),
+            resubscribe,
+            ..Default::default()
+        };
+        assert!(subscription("chain_subscribeNewHeads", None).resubscribes());
+        assert!(!subscription("author_submitAndWatchExtrinsic", None).resubscribes());
+        assert!(!subscription("chain_subscribeNewHeads", Some(false)).resubscribes());
+        assert!(subscription("author_submitAndWatchExtrinsic", Some(true)).resubscribes());
+    }
+}
@@ -1,220 +1,789 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashSet},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::Duration,
+};
 
 use futures::FutureExt;
 use jsonrpsee::{
-    core::JsonValue,
+    core::{params::ArrayParams, JsonValue},
     server::{RpcModule, ServerHandle},
     types::ErrorObjectOwned,
 };
 use opentelemetry::trace::FutureExt as _;
 use serde_json::json;
+use tokio::time::Instant;
 
 use crate::{
-    config::Config,
+    config::{Config, MethodGroup, MiddlewaresConfig, RpcDefinitions, RpcMethod, RpcSubscription},
     extensions::{
+        access_log::{AccessLog, CACHE_STATUS},
+        admin::Admin,
+        auth::JWT_CLAIMS,
+        cache::Cache,
+        client::Client,
+        event_bus::{EventBus, EventReceiver, SubwayEvent},
+        metrics,
         rate_limit::{MethodWeights, RateLimitBuilder},
-        server::SubwayServerBuilder,
+        server::{
+            OpenSubscription, ServedMethods, ServerConfig, SubwayServerBuilder, UnsupportedMethodPolicy,
+            CONNECTION_ACTIVITY, FEATURE_FLAGS, FORWARDED_HEADERS,
+        },
+        ExtensionsConfig,
     },
-    middlewares::{factory, CallRequest, Middlewares, SubscriptionRequest},
-    utils::{errors, telemetry, TypeRegistryRef},
+    middlewares::{
+        factory::{self, CustomMiddlewares},
+        methods::bulkhead::MethodGroupRuntimes,
+        subscriptions::lifetime::SubscriptionLifetime,
+        CallRequest, CallResult, MiddlewareBuilder, Middlewares, SubscriptionRequest, SubscriptionResult,
+    },
+    utils::{errors, telemetry, TypeRegistry, TypeRegistryRef},
 };
 
 // TODO: https://github.com/paritytech/jsonrpsee/issues/985
+// names are leaked once and reused, so reloading the config does not leak them again
 fn string_to_static_str(s: String) -> &'static str {
-    Box::leak(s.into_boxed_str())
+    static NAMES: OnceLock<Mutex<HashSet<&'static str>>> = OnceLock::new();
+    let mut names = NAMES
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+    if let Some(name) = names.get(s.as_str()) {
+        return name;
+    }
+    let name: &'static str = Box::leak(s.into_boxed_str());
+    names.insert(name);
+    name
 }
 
-pub struct SubwayServerHandle {
-    pub handle: ServerHandle,
-    pub addr: SocketAddr,
-    pub extensions: TypeRegistryRef,
+/// Creates the middleware chain of a method from the configured middleware names, in order.
+/// Returns the chain and the names of the middlewares that apply to the method.
+pub async fn build_method_middlewares(
+    method: &RpcMethod,
+    middleware_names: &[String],
+    registry: &TypeRegistryRef,
+) -> (Middlewares<CallRequest, CallResult>, Vec<String>) {
+    let mut middlewares: Vec<Arc<_>> = vec![];
+    let mut names = vec![];
+
+    for middleware_name in middleware_names {
+        if let Some(middleware) = factory::create_method_middleware(middleware_name, method, registry).await {
+            middlewares.push(middleware.into());
+            names.push(middleware_name.clone());
+        }
+    }
+
+    let middlewares = Middlewares::new(
+        middlewares,
+        Arc::new(|_, _| async { Err(errors::failed("Bad configuration")) }.boxed()),
+    );
+    (middlewares, names)
 }
 
-pub async fn build(config: Config) -> anyhow::Result<SubwayServerHandle> {
-    // create extensions registry from config
-    let extensions_registry = config
-        .extensions
-        .create_registry()
-        .await
-        .expect("Failed to create extensions registry");
+/// Ensures the middleware chains only name built-in or registered middlewares.
+fn check_middlewares(config: &Config, custom: &CustomMiddlewares) -> anyhow::Result<()> {
+    let method_chains = config
+        .rpcs
+        .methods
+        .iter()
+        .filter_map(|method| method.middlewares.as_ref());
+    for name in config.middlewares.methods.iter().chain(method_chains.flatten()) {
+        if !custom.has_method_middleware(name) {
+            anyhow::bail!("Unknown method middleware: {name}");
+        }
+    }
+    for name in &config.middlewares.subscriptions {
+        if !custom.has_subscription_middleware(name) {
+            anyhow::bail!("Unknown subscription middleware: {name}");
+        }
+    }
+    Ok(())
+}
+
+/// Checks configured methods against the methods exposed by upstream and applies the given policy.
+async fn reconcile_methods(
+    methods: Vec<RpcMethod>,
+    client: &Client,
+    policy: UnsupportedMethodPolicy,
+) -> anyhow::Result<Vec<RpcMethod>> {
+    let upstream_methods = match client.request_internal("rpc_methods", vec![]).await {
+        // without a list of methods their support is unknown, not missing
+        Ok(res) => res["methods"].as_array().map(|methods| {
+            methods
+                .iter()
+                .filter_map(|m| m.as_str().map(ToOwned::to_owned))
+                .collect::<Vec<_>>()
+        }),
+        Err(err) => {
+            if policy == UnsupportedMethodPolicy::Fail {
+                anyhow::bail!("Unable to fetch upstream rpc_methods: {err}");
+            }
+            tracing::warn!("Unable to fetch upstream rpc_methods, skip method check: {err}");
+            return Ok(methods);
+        }
+    };
+    let Some(upstream_methods) = upstream_methods else {
+        if policy == UnsupportedMethodPolicy::Fail {
+            anyhow::bail!("Upstream rpc_methods returned no list of methods");
+        }
+        tracing::warn!("Upstream rpc_methods returned no list of methods, skip method check");
+        return Ok(methods);
+    };
 
-    // get the server extension
-    let server_builder = extensions_registry
+    let mut supported = Vec::with_capacity(methods.len());
+    for method in methods {
+        // remapped methods are called upstream by their new name
+        let upstream_name = method.remap.as_ref().map_or(&method.method, |remap| &remap.method);
+        // methods with static response are served by subway itself
+        if method.response.is_some() || upstream_methods.contains(upstream_name) {
+            supported.push(method);
+            continue;
+        }
+
+        match policy {
+            UnsupportedMethodPolicy::Fail => {
+                anyhow::bail!("Method {} is not supported by upstream", method.method);
+            }
+            UnsupportedMethodPolicy::Skip => {
+                tracing::warn!("Method {} is not supported by upstream, skipped", method.method);
+            }
+            UnsupportedMethodPolicy::Register => {
+                tracing::warn!("Method {} is not supported by upstream", method.method);
+                supported.push(method);
+            }
+        }
+    }
+
+    Ok(supported)
+}
+
+/// Configured methods, checked against upstream if `unsupported_methods` is set.
+async fn supported_methods(
+    methods: Vec<RpcMethod>,
+    registry: &TypeRegistryRef,
+    server_config: &ServerConfig,
+) -> anyhow::Result<Vec<RpcMethod>> {
+    let Some(policy) = server_config.unsupported_methods else {
+        return Ok(methods);
+    };
+    let client = registry
         .read()
         .await
-        .get::<SubwayServerBuilder>()
-        .expect("Server extension not found");
+        .get::<Client>()
+        .expect("Client extension not found");
+    reconcile_methods(methods, &client, policy).await
+}
 
-    let rate_limit_builder = extensions_registry.read().await.get::<RateLimitBuilder>();
+/// Summary of the effective operational limits of the extensions and per-method overrides.
+async fn effective_limits(registry: &TypeRegistryRef, methods: &[RpcMethod]) -> JsonValue {
+    let registry = registry.read().await;
 
-    let rpc_method_weights = MethodWeights::from_config(&config.rpcs.methods);
+    // only list methods overriding the defaults
+    let method_limits = methods
+        .iter()
+        .filter_map(|method| {
+            let mut limits = serde_json::Map::new();
+            if method.rate_limit_weight != 1 {
+                limits.insert("rate_limit_weight".into(), method.rate_limit_weight.into());
+            }
+            if let Some(timeout) = method.upstream_timeout_ms {
+                limits.insert("upstream_timeout_ms".into(), timeout.into());
+            }
+            if let Some(max_params) = method.max_params {
+                limits.insert("max_params".into(), max_params.into());
+            }
+            if let Some(max_response_bytes) = method.max_response_bytes {
+                limits.insert("max_response_bytes".into(), max_response_bytes.into());
+            }
+            if let Some(retry) = &method.retry {
+                limits.insert(
+                    "retry".into(),
+                    json!({
+                        "max_attempts": retry.max_attempts,
+                        "backoff_base_ms": retry.backoff_base_ms,
+                        "jitter_ms": retry.jitter_ms,
+                    }),
+                );
+            }
+            if let Some(cache) = &method.cache {
+                limits.insert(
+                    "cache".into(),
+                    json!({ "size": cache.size, "ttl_seconds": cache.ttl_seconds }),
+                );
+            }
+            (!limits.is_empty()).then(|| (method.method.clone(), JsonValue::Object(limits)))
+        })
+        .collect::<serde_json::Map<_, _>>();
 
-    let request_timeout_seconds = server_builder.config.request_timeout_seconds;
+    json!({
+        "server": registry.get::<SubwayServerBuilder>().map(|s| s.limits()),
+        "rate_limit": registry.get::<RateLimitBuilder>().map(|r| r.limits()),
+        "cache": registry.get::<Cache>().map(|c| c.limits()),
+        "upstream": registry.get::<Client>().map(|c| c.limits()),
+        "methods": method_limits,
+    })
+}
 
-    let registry = extensions_registry.clone();
-    let (addr, handle) = server_builder
-        .build(rate_limit_builder, rpc_method_weights, move || async move {
-            let mut module = RpcModule::new(());
+/// Registers the configured methods, subscriptions and aliases with their middlewares, and the built-in methods.
+async fn build_rpc_module(
+    rpcs: RpcDefinitions,
+    middlewares: MiddlewaresConfig,
+    registry: &TypeRegistryRef,
+    server_config: ServerConfig,
+) -> anyhow::Result<RpcModule<()>> {
+    let request_timeout_seconds = server_config.request_timeout_seconds;
+    let echo_method = server_config.echo_method;
+    let max_subscription_lifetime_secs = server_config.max_subscription_lifetime_secs;
 
-            let tracer = telemetry::Tracer::new("server");
+    let mut module = RpcModule::new(());
 
-            // register methods from config
-            for method in config.rpcs.methods {
-                let mut method_middlewares: Vec<Arc<_>> = vec![];
+    let tracer = telemetry::Tracer::new("server");
 
-                for middleware_name in &config.middlewares.methods {
-                    if let Some(middleware) =
-                        factory::create_method_middleware(middleware_name, &method, &registry).await
-                    {
-                        method_middlewares.push(middleware.into());
-                    }
+    let aliases = rpcs.resolve_aliases().map_err(anyhow::Error::msg)?;
+    let mut limits = effective_limits(registry, &rpcs.methods).await;
+    // middleware chain -> methods using it
+    let mut middleware_chains = BTreeMap::<String, Vec<String>>::new();
+
+    // register methods from config
+    for method in rpcs.methods {
+        let middleware_names = method.middlewares.as_deref().unwrap_or(&middlewares.methods);
+        let (method_middlewares, names) = build_method_middlewares(&method, middleware_names, registry).await;
+        if method_middlewares.is_empty() {
+            tracing::warn!("{} has no middlewares, calls to it will fail", method.method);
+        }
+        middleware_chains
+            .entry(names.join(","))
+            .or_default()
+            .push(method.method.clone());
+
+        method_middlewares
+            .init()
+            .await
+            .map_err(|err| anyhow::anyhow!("Failed to initialize middlewares for {}: {err}", method.method))?;
+
+        let method_name = string_to_static_str(method.method.clone());
+        let timeout = method
+            .upstream_timeout_ms
+            .map(tokio::time::Duration::from_millis)
+            .unwrap_or_else(|| tokio::time::Duration::from_secs(request_timeout_seconds))
+            // the cache lookup has its own budget on top of the upstream call
+            + method
+                .cache
+                .as_ref()
+                .and_then(|c| c.lookup_timeout_ms)
+                .map(tokio::time::Duration::from_millis)
+                .unwrap_or_default();
+
+        module.register_async_method(method_name, move |params, _| {
+            let method_middlewares = method_middlewares.clone();
+            async move {
+                let parsed = params.parse::<JsonValue>()?;
+                let params = if parsed == JsonValue::Null {
+                    vec![]
+                } else {
+                    parsed.as_array().ok_or_else(|| errors::invalid_params(""))?.to_owned()
+                };
+
+                let (result_tx, result_rx) = tokio::sync::oneshot::channel();
+
+                // pass the cache status down the middlewares if the request is access logged
+                let mut context = TypeRegistry::new();
+                if let Ok(cache_status) = CACHE_STATUS.try_with(|s| s.clone()) {
+                    context.insert_raw(cache_status);
+                }
+                if let Ok(headers) = FORWARDED_HEADERS.try_with(|h| h.clone()) {
+                    context.insert(headers);
+                }
+                if let Ok(claims) = JWT_CLAIMS.try_with(|c| c.clone()) {
+                    context.insert(claims);
                 }
 
-                let method_middlewares = Middlewares::new(
-                    method_middlewares,
-                    Arc::new(|_, _| async { Err(errors::failed("Bad configuration")) }.boxed()),
-                );
+                let started = Instant::now();
+                let features = FEATURE_FLAGS.try_with(|f| *f).unwrap_or_default();
+                let request = CallRequest::new(method_name, params).with_features(features);
+
+                method_middlewares
+                    .call_with_context(request, context, result_tx, timeout)
+                    .await;
 
-                let method_name = string_to_static_str(method.method.clone());
-
-                module.register_async_method(method_name, move |params, _| {
-                    let method_middlewares = method_middlewares.clone();
-                    async move {
-                        let parsed = params.parse::<JsonValue>()?;
-                        let params = if parsed == JsonValue::Null {
-                            vec![]
-                        } else {
-                            parsed.as_array().ok_or_else(|| errors::invalid_params(""))?.to_owned()
-                        };
-
-                        let (result_tx, result_rx) = tokio::sync::oneshot::channel();
-                        let timeout = tokio::time::Duration::from_secs(request_timeout_seconds);
-
-                        method_middlewares
-                            .call(CallRequest::new(method_name, params), result_tx, timeout)
-                            .await;
-
-                        let result = result_rx
-                            .await
-                            .map_err(|_| errors::map_error(jsonrpsee::core::Error::RequestTimeout))?;
-
-                        match result.as_ref() {
-                            Ok(_) => tracer.span_ok(),
-                            Err(err) => {
-                                tracer.span_error(err);
-                            }
-                        };
-
-                        result
+                let result = result_rx
+                    .await
+                    .map_err(|_| errors::internal_error(jsonrpsee::core::Error::RequestTimeout))?;
+
+                let status = if result.is_ok() { "ok" } else { "error" };
+                metrics::rpc_calls().with_label_values(&[method_name, status]).inc();
+                metrics::rpc_call_duration()
+                    .with_label_values(&[method_name])
+                    .observe(started.elapsed().as_secs_f64());
+
+                match result.as_ref() {
+                    Ok(_) => tracer.span_ok(),
+                    Err(err) => {
+                        tracer.span_error(err);
                     }
-                    .with_context(tracer.context(method_name))
-                })?;
+                };
+
+                result
             }
+            .with_context(tracer.context(method_name))
+        })?;
+    }
 
-            // register subscriptions from config
-            for subscription in config.rpcs.subscriptions {
-                let subscribe_name = string_to_static_str(subscription.subscribe.clone());
-                let unsubscribe_name = string_to_static_str(subscription.unsubscribe.clone());
-                let name = string_to_static_str(subscription.name.clone());
+    // register subscriptions from config
+    for subscription in rpcs.subscriptions {
+        let subscribe_name = string_to_static_str(subscription.subscribe.clone());
+        let unsubscribe_name = string_to_static_str(subscription.unsubscribe.clone());
+        let name = string_to_static_str(subscription.name.clone());
+        let lifetime = subscription
+            .max_lifetime_secs
+            .or(max_subscription_lifetime_secs)
+            .map(|secs| SubscriptionLifetime(tokio::time::Duration::from_secs(secs)));
 
-                let mut subscription_middlewares: Vec<Arc<_>> = vec![];
+        let mut subscription_middlewares: Vec<Arc<_>> = vec![];
 
-                for middleware_name in &config.middlewares.subscriptions {
-                    if let Some(middleware) =
-                        factory::create_subscription_middleware(middleware_name, &subscription, &registry).await
-                    {
-                        subscription_middlewares.push(middleware.into());
-                    }
+        for middleware_name in &middlewares.subscriptions {
+            if let Some(middleware) =
+                factory::create_subscription_middleware(middleware_name, &subscription, registry).await
+            {
+                subscription_middlewares.push(middleware.into());
+            }
+        }
+
+        let subscription_middlewares = Middlewares::new(
+            subscription_middlewares,
+            Arc::new(|_, _| async { Err("Bad configuration".into()) }.boxed()),
+        );
+        subscription_middlewares
+            .init()
+            .await
+            .map_err(|err| anyhow::anyhow!("Failed to initialize middlewares for {}: {err}", subscription.subscribe))?;
+
+        module.register_subscription(
+            subscribe_name,
+            name,
+            unsubscribe_name,
+            move |params, pending_sink, _| {
+                let subscription_middlewares = subscription_middlewares.clone();
+                let mut context = TypeRegistry::new();
+                if let Ok(headers) = FORWARDED_HEADERS.try_with(|h| h.clone()) {
+                    context.insert(headers);
+                }
+                if let Ok(claims) = JWT_CLAIMS.try_with(|c| c.clone()) {
+                    context.insert(claims);
                 }
+                if let Some(lifetime) = lifetime {
+                    context.insert(lifetime);
+                }
+                // released once the subscription ends, however it ends
+                if let Ok(activity) = CONNECTION_ACTIVITY.try_with(|a| a.clone()) {
+                    context.insert(OpenSubscription::new(activity));
+                }
+                async move {
+                    let parsed = params.parse::<JsonValue>()?;
+                    let params = if parsed == JsonValue::Null {
+                        vec![]
+                    } else {
+                        parsed.as_array().ok_or_else(|| errors::invalid_params(""))?.to_owned()
+                    };
 
-                let subscription_middlewares = Middlewares::new(
-                    subscription_middlewares,
-                    Arc::new(|_, _| async { Err("Bad configuration".into()) }.boxed()),
-                );
+                    let (result_tx, result_rx) = tokio::sync::oneshot::channel();
+                    let timeout = tokio::time::Duration::from_secs(request_timeout_seconds);
 
-                module.register_subscription(
-                    subscribe_name,
-                    name,
-                    unsubscribe_name,
-                    move |params, pending_sink, _| {
-                        let subscription_middlewares = subscription_middlewares.clone();
-                        async move {
-                            let parsed = params.parse::<JsonValue>()?;
-                            let params = if parsed == JsonValue::Null {
-                                vec![]
-                            } else {
-                                parsed.as_array().ok_or_else(|| errors::invalid_params(""))?.to_owned()
-                            };
-
-                            let (result_tx, result_rx) = tokio::sync::oneshot::channel();
-                            let timeout = tokio::time::Duration::from_secs(request_timeout_seconds);
-
-                            subscription_middlewares
-                                .call(
-                                    SubscriptionRequest {
-                                        subscribe: subscribe_name.into(),
-                                        params,
-                                        unsubscribe: unsubscribe_name.into(),
-                                        pending_sink,
-                                    },
-                                    result_tx,
-                                    timeout,
-                                )
-                                .await;
-
-                            let result = result_rx
-                                .await
-                                .map_err(|_| errors::map_error(jsonrpsee::core::Error::RequestTimeout))?;
-
-                            match result.as_ref() {
-                                Ok(_) => {
-                                    tracer.span_ok();
-                                }
-                                Err(err) => {
-                                    tracer.span_error(&errors::failed(format!("{:?}", err)));
-                                }
-                            };
-
-                            result
+                    subscription_middlewares
+                        .call_with_context(
+                            SubscriptionRequest {
+                                subscribe: subscribe_name.into(),
+                                params,
+                                unsubscribe: unsubscribe_name.into(),
+                                pending_sink,
+                            },
+                            context,
+                            result_tx,
+                            timeout,
+                        )
+                        .await;
+
+                    let result = result_rx
+                        .await
+                        .map_err(|_| errors::internal_error(jsonrpsee::core::Error::RequestTimeout))?;
+
+                    match result.as_ref() {
+                        Ok(_) => {
+                            tracer.span_ok();
                         }
-                        .with_context(tracer.context(name))
-                    },
-                )?;
-            }
+                        Err(err) => {
+                            tracer.span_error(&errors::failed(format!("{:?}", err)));
+                        }
+                    };
 
-            // register aliases from config
-            for (alias_old, alias_new) in config.rpcs.aliases {
-                let alias_old = string_to_static_str(alias_old);
-                let alias_new = string_to_static_str(alias_new);
-                module.register_alias(alias_new, alias_old)?;
-            }
+                    result
+                }
+                .with_context(tracer.context(name))
+            },
+        )?;
+    }
 
-            let mut rpc_methods = module.method_names().map(|x| x.to_owned()).collect::<Vec<_>>();
+    // register aliases from config, chains are resolved to the terminal method
+    for (target, alias) in aliases {
+        let target = string_to_static_str(target);
+        let alias = string_to_static_str(alias);
+        module.register_alias(alias, target)?;
+    }
 
-            rpc_methods.sort();
+    limits["middlewares"] = json!(middleware_chains);
+    tracing::info!("Effective limits: {limits}");
 
-            module.register_method("rpc_methods", move |_, _| {
-                Ok::<JsonValue, ErrorObjectOwned>(json!({
-                    "version": 1,
-                    "methods": rpc_methods
-                }))
-            })?;
+    if let Some(admin) = registry.read().await.get::<Admin>() {
+        admin.set_limits(limits);
+    }
 
-            Ok(module)
-        })
-        .await?;
+    if let Some(echo_method) = echo_method {
+        module.register_method(string_to_static_str(echo_method), |params, _| {
+            let params = params.parse::<JsonValue>().unwrap_or(JsonValue::Null);
+            let server_time = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or_default();
+            Ok::<JsonValue, ErrorObjectOwned>(json!({ "params": params, "server_time": server_time }))
+        })?;
+    }
+
+    let mut rpc_methods = module.method_names().map(|x| x.to_owned()).collect::<Vec<_>>();
+
+    rpc_methods.sort();
+
+    module.register_method("rpc_methods", move |_, _| {
+        Ok::<JsonValue, ErrorObjectOwned>(json!({
+            "version": 1,
+            "methods": rpc_methods
+        }))
+    })?;
+
+    Ok(module)
+}
+
+/// Calls the `cache.warm_up` methods through their middlewares before serving, so their responses are
+/// cached when the first clients connect. Failed calls are logged and left to the clients.
+async fn warm_up_caches(module: &RpcModule<()>, registry: &TypeRegistryRef) {
+    let Some(cache) = registry.read().await.get::<Cache>() else {
+        return;
+    };
 
-    Ok(SubwayServerHandle {
-        addr,
-        handle,
-        extensions: extensions_registry,
+    let calls = cache.config.warm_up.iter().map(|call| async move {
+        let mut params = ArrayParams::new();
+        for param in &call.params {
+            params.insert(param).expect("JSON values are serializable");
+        }
+        match module.call::<_, JsonValue>(&call.method, params).await {
+            Ok(_) => tracing::debug!("Warmed up cache of {}", call.method),
+            Err(err) => tracing::warn!("Failed to warm up cache of {}: {err}", call.method),
+        }
+    });
+    futures::future::join_all(calls).await;
+}
+
+pub struct SubwayServerHandle {
+    pub handle: ServerHandle,
+    pub addr: SocketAddr,
+    pub extensions: TypeRegistryRef,
+    shutdown_timeout: Duration,
+    drain: Arc<Drain>,
+    served_methods: ServedMethods,
+    // the runtimes of the groups are created once, see `reload`
+    method_groups: Vec<MethodGroup>,
+}
+
+#[derive(Default)]
+struct Drain {
+    started: OnceLock<Instant>,
+    done: AtomicBool,
+}
+
+impl SubwayServerHandle {
+    /// Stops accepting connections and waits for in-flight requests to complete, up to
+    /// `graceful_shutdown_timeout_secs`. Returns false if requests were still running when time ran out.
+    pub async fn shutdown(&self) -> bool {
+        let _ = self.drain.started.set(Instant::now());
+        let _ = self.handle.stop();
+        let drained = tokio::time::timeout(self.shutdown_timeout, self.handle.stopped())
+            .await
+            .is_ok();
+        self.drain.done.store(true, Ordering::Relaxed);
+        drained
+    }
+
+    /// Time left to drain in-flight requests, None until `shutdown` is called and zero once it completed.
+    pub fn drain_progress(&self) -> Option<Duration> {
+        let started = self.drain.started.get()?;
+        if self.drain.done.load(Ordering::Relaxed) {
+            return Some(Duration::ZERO);
+        }
+        Some(self.shutdown_timeout.saturating_sub(started.elapsed()))
+    }
+
+    /// Rebuilds the methods, subscriptions and aliases with their middlewares from the given config,
+    /// e.g. with new cache sizes or rate limit weights, and serves them to new requests and connections.
+    /// Open WebSocket connections keep the previous methods. Extensions and method groups are not
+    /// reloaded, a config changing the rate limits or the method groups is rejected as they require a restart.
+    pub async fn reload(&self, mut config: Config) -> anyhow::Result<()> {
+        let custom_middlewares = self.extensions.read().await.get::<CustomMiddlewares>();
+        check_middlewares(&config, &custom_middlewares.unwrap_or_default())?;
+
+        let rate_limit = self.extensions.read().await.get::<RateLimitBuilder>();
+        if rate_limit.as_ref().map(|r| r.config()) != config.extensions.rate_limit.as_ref() {
+            anyhow::bail!("Changing rate_limit requires a restart");
+        }
+        if config.rpcs.method_groups != self.method_groups {
+            anyhow::bail!("Changing method_groups requires a restart");
+        }
+
+        let server_config = self
+            .extensions
+            .read()
+            .await
+            .get::<SubwayServerBuilder>()
+            .expect("Server extension not found")
+            .config
+            .clone();
+
+        config.rpcs.methods = supported_methods(config.rpcs.methods, &self.extensions, &server_config).await?;
+        let rpc_method_weights = MethodWeights::from_config(&config.rpcs.methods);
+        let module = build_rpc_module(config.rpcs, config.middlewares, &self.extensions, server_config).await?;
+        self.served_methods.replace(module, rpc_method_weights);
+
+        if let Some(event_bus) = self.extensions.read().await.get::<EventBus>() {
+            event_bus.publish(SubwayEvent::ConfigReloaded);
+        }
+        Ok(())
+    }
+
+    /// Subscribes to server lifecycle events. Returns None if the event bus extension is not configured.
+    /// Events published while the server was built are only received with `SubwayBuilder::events`.
+    pub async fn events(&self) -> Option<EventReceiver> {
+        self.extensions
+            .read()
+            .await
+            .get::<EventBus>()
+            .map(|event_bus| event_bus.subscribe())
+    }
+}
+
+/// What `dry_run` found out about a config.
+#[derive(Debug)]
+pub struct DryRun {
+    /// Methods and subscriptions that would be served
+    pub methods: Vec<String>,
+    /// Hash of block 0 served by upstream, None without a client
+    pub genesis_hash: Option<JsonValue>,
+}
+
+/// Creates the extensions and the methods of `config` and checks upstream can be reached, without serving.
+/// The metrics extension is skipped as it would bind its port.
+pub async fn dry_run(mut config: Config) -> anyhow::Result<DryRun> {
+    check_middlewares(&config, &Default::default())?;
+
+    config.extensions.metrics = None;
+    let registry = config.extensions.create_registry().await?;
+    let server_config = registry
+        .read()
+        .await
+        .get::<SubwayServerBuilder>()
+        .expect("Server extension not found")
+        .config
+        .clone();
+
+    let genesis_hash = match registry.read().await.get::<Client>() {
+        Some(client) => Some(client.genesis_hash().await?),
+        None => None,
+    };
+
+    config.rpcs.methods = supported_methods(config.rpcs.methods, &registry, &server_config).await?;
+    let module = build_rpc_module(config.rpcs, config.middlewares, &registry, server_config).await?;
+
+    Ok(DryRun {
+        methods: module.method_names().map(ToOwned::to_owned).collect(),
+        genesis_hash,
     })
 }
 
+pub async fn build(config: Config) -> anyhow::Result<SubwayServerHandle> {
+    SubwayBuilder::new(config).build().await
+}
+
+/// Like `build`, with middlewares of the embedding crate usable by name in the config.
+pub async fn build_with_middlewares(
+    config: Config,
+    custom_middlewares: CustomMiddlewares,
+) -> anyhow::Result<SubwayServerHandle> {
+    SubwayBuilder::new(config)
+        .with_custom_middlewares(custom_middlewares)
+        .build()
+        .await
+}
+
+/// Builds a server from a config, or programmatically starting from `SubwayBuilder::default()`,
+/// e.g. to embed subway in another service or in integration tests.
+#[derive(Default)]
+pub struct SubwayBuilder {
+    config: Config,
+    custom_middlewares: CustomMiddlewares,
+    client: Option<Arc<Client>>,
+    event_bus: Option<Arc<EventBus>>,
+}
+
+impl SubwayBuilder {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_extensions(mut self, extensions: ExtensionsConfig) -> Self {
+        self.config.extensions = extensions;
+        self
+    }
+
+    /// Replaces the middleware chains of all methods and subscriptions.
+    pub fn with_middlewares(mut self, middlewares: MiddlewaresConfig) -> Self {
+        self.config.middlewares = middlewares;
+        self
+    }
+
+    pub fn with_method(mut self, method: RpcMethod) -> Self {
+        self.config.rpcs.methods.push(method);
+        self
+    }
+
+    pub fn with_subscription(mut self, subscription: RpcSubscription) -> Self {
+        self.config.rpcs.subscriptions.push(subscription);
+        self
+    }
+
+    pub fn with_custom_middlewares(mut self, custom_middlewares: CustomMiddlewares) -> Self {
+        self.custom_middlewares = custom_middlewares;
+        self
+    }
+
+    /// Registers a method middleware built by `M` under `name`, see `CustomMiddlewares`.
+    pub fn with_method_middleware<M: MiddlewareBuilder<RpcMethod, CallRequest, CallResult>>(
+        mut self,
+        name: impl ToString,
+    ) -> Self {
+        self.custom_middlewares = self.custom_middlewares.with_method_middleware::<M>(name);
+        self
+    }
+
+    /// Registers a subscription middleware built by `M` under `name`, see `CustomMiddlewares`.
+    pub fn with_subscription_middleware<
+        M: MiddlewareBuilder<RpcSubscription, SubscriptionRequest, SubscriptionResult>,
+    >(
+        mut self,
+        name: impl ToString,
+    ) -> Self {
+        self.custom_middlewares = self.custom_middlewares.with_subscription_middleware::<M>(name);
+        self
+    }
+
+    /// Uses the given client instead of creating one from `extensions.client`,
+    /// for the middlewares and for the extensions depending on it.
+    pub fn with_client(mut self, client: Arc<Client>) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// Subscribes to lifecycle events, including the ones published while the server is built, e.g. an
+    /// endpoint failing to connect. Enables the event bus, with the capacity of `extensions.event_bus` if set.
+    pub fn events(&mut self) -> EventReceiver {
+        let capacity = self.config.extensions.event_bus.clone().unwrap_or_default().capacity;
+        self.event_bus
+            .get_or_insert_with(|| Arc::new(EventBus::new(capacity)))
+            .subscribe()
+    }
+
+    /// Creates the extensions and middlewares and starts serving.
+    pub async fn build(self) -> anyhow::Result<SubwayServerHandle> {
+        let Self {
+            mut config,
+            custom_middlewares,
+            client,
+            event_bus,
+        } = self;
+
+        check_middlewares(&config, &custom_middlewares)?;
+
+        // create extensions registry from config
+        let mut registry = TypeRegistry::new();
+        if let Some(event_bus) = event_bus {
+            if let Some(ref client) = client {
+                client.set_event_bus(event_bus.clone());
+            }
+            registry.insert_raw(event_bus);
+        }
+        if let Some(client) = client {
+            registry.insert_raw(client);
+        }
+        let extensions_registry = config
+            .extensions
+            .create_registry_with(registry)
+            .await
+            .expect("Failed to create extensions registry");
+        extensions_registry.write().await.insert(custom_middlewares);
+
+        // get the server extension
+        let server_builder = extensions_registry
+            .read()
+            .await
+            .get::<SubwayServerBuilder>()
+            .expect("Server extension not found");
+
+        let rate_limit_builder = extensions_registry.read().await.get::<RateLimitBuilder>();
+
+        let access_log = extensions_registry.read().await.get::<AccessLog>();
+
+        let shutdown_timeout = Duration::from_secs(server_builder.config.graceful_shutdown_timeout_secs);
+
+        config.rpcs.methods =
+            supported_methods(config.rpcs.methods, &extensions_registry, &server_builder.config).await?;
+
+        let rpc_method_weights = MethodWeights::from_config(&config.rpcs.methods);
+
+        let method_groups = config.rpcs.method_groups.clone();
+        let runtimes = MethodGroupRuntimes::new(&method_groups)?;
+        if !runtimes.is_empty() {
+            extensions_registry.write().await.insert(runtimes);
+        }
+
+        let registry = &extensions_registry;
+        let server_config = server_builder.config.clone();
+        let (addr, handle, served_methods) = server_builder
+            .build(rate_limit_builder, rpc_method_weights, access_log, move || async move {
+                let module = build_rpc_module(config.rpcs, config.middlewares, registry, server_config).await?;
+                warm_up_caches(&module, registry).await;
+                Ok(module)
+            })
+            .await?;
+
+        Ok(SubwayServerHandle {
+            addr,
+            handle,
+            extensions: extensions_registry,
+            shutdown_timeout,
+            drain: Default::default(),
+            served_methods,
+            method_groups,
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use jsonrpsee::{
-        core::client::ClientT,
+        core::{client::ClientT, params::BatchRequestBuilder},
         rpc_params,
         server::ServerBuilder,
         server::ServerHandle,
@@ -225,20 +794,32 @@ mod tests {
     use super::*;
     use crate::{
         config::{MiddlewaresConfig, RpcDefinitions, RpcMethod},
-        extensions::{client::ClientConfig, server::ServerConfig, ExtensionsConfig},
+        extensions::{
+            admin::AdminConfig,
+            client::{ClientConfig, UpstreamQueueConfig},
+            server::{HealthConfig, HttpMethodsConfig, ServerConfig},
+            ExtensionsConfig,
+        },
+        middlewares::{
+            methods::{testing::CrazyMiddleware, upstream::QUEUE_TIMEOUT_ERROR},
+            Middleware, MiddlewareBuilder, NextFn,
+        },
     };
 
     const TIMEOUT: &str = "call_timeout";
+    const METHOD_TIMEOUT: &str = "call_method_timeout";
     const CRAZY: &str = "go_crazy";
+    const GENESIS: &str = "0x00";
     const PHO: &str = "call_pho";
     const BAR: &str = "bar";
 
-    async fn subway_server(endpoint: String, port: u16, request_timeout_seconds: Option<u64>) -> SubwayServerHandle {
-        let config = Config {
+    fn subway_config(endpoint: String, port: u16, request_timeout_seconds: Option<u64>) -> Config {
+        Config {
             extensions: ExtensionsConfig {
                 client: Some(ClientConfig {
                     endpoints: vec![endpoint],
                     shuffle_endpoints: false,
+                    ..Default::default()
                 }),
                 server: Some(ServerConfig {
                     listen_address: "127.0.0.1".to_string(),
@@ -247,6 +828,7 @@ mod tests {
                     request_timeout_seconds: request_timeout_seconds.unwrap_or(10),
                     http_methods: Vec::new(),
                     cors: None,
+                    ..Default::default()
                 }),
                 ..Default::default()
             },
@@ -263,6 +845,7 @@ mod tests {
                         response: None,
                         delay_ms: None,
                         rate_limit_weight: 1,
+                        ..Default::default()
                     },
                     RpcMethod {
                         method: TIMEOUT.to_string(),
@@ -271,6 +854,7 @@ mod tests {
                         response: None,
                         delay_ms: None,
                         rate_limit_weight: 1,
+                        ..Default::default()
                     },
                     RpcMethod {
                         method: CRAZY.to_string(),
@@ -279,13 +863,35 @@ mod tests {
                         response: None,
                         delay_ms: None,
                         rate_limit_weight: 1,
+                        ..Default::default()
+                    },
+                    RpcMethod {
+                        method: METHOD_TIMEOUT.to_string(),
+                        upstream_timeout_ms: Some(500),
+                        ..Default::default()
                     },
                 ],
                 subscriptions: vec![],
                 aliases: vec![],
+                ..Default::default()
             },
-        };
-        build(config).await.unwrap()
+        }
+    }
+
+    /// The middlewares used by `subway_config` on top of the built-in ones.
+    fn test_middlewares() -> CustomMiddlewares {
+        CustomMiddlewares::default().with_method_middleware::<CrazyMiddleware>("crazy")
+    }
+
+    /// Like `super::build`, with the test middlewares registered.
+    async fn build(config: Config) -> anyhow::Result<SubwayServerHandle> {
+        build_with_middlewares(config, test_middlewares()).await
+    }
+
+    async fn subway_server(endpoint: String, port: u16, request_timeout_seconds: Option<u64>) -> SubwayServerHandle {
+        build(subway_config(endpoint, port, request_timeout_seconds))
+            .await
+            .unwrap()
     }
 
     async fn upstream_dummy_server(url: &str) -> (String, ServerHandle) {
@@ -301,6 +907,11 @@ mod tests {
         module
             .register_method(PHO, |_, _| Ok::<String, ErrorObjectOwned>(BAR.to_string()))
             .unwrap();
+        module
+            .register_method("chain_getBlockHash", |_, _| {
+                Ok::<String, ErrorObjectOwned>(GENESIS.to_string())
+            })
+            .unwrap();
         module
             .register_async_method(TIMEOUT, |_, _| async {
                 loop {
@@ -308,6 +919,13 @@ mod tests {
                 }
             })
             .unwrap();
+        module
+            .register_async_method(METHOD_TIMEOUT, |_, _| async {
+                loop {
+                    tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+                }
+            })
+            .unwrap();
         let addr = format!("ws://{}", server.local_addr().unwrap());
         let handle = server.start(module);
         (addr, handle)
@@ -323,6 +941,21 @@ mod tests {
             .unwrap()
     }
 
+    #[tokio::test]
+    async fn graceful_shutdown_reports_drain_progress() {
+        let (endpoint, upstream_dummy_server_handle) = upstream_dummy_server("127.0.0.1:0").await;
+        let subway_server = subway_server(endpoint, 0, None).await;
+        let url = format!("ws://{}", subway_server.addr);
+        let client = ws_client(&url).await;
+        assert_eq!(BAR, client.request::<String, _>(PHO, rpc_params!()).await.unwrap());
+
+        assert_eq!(subway_server.drain_progress(), None);
+        assert!(subway_server.shutdown().await);
+        assert_eq!(subway_server.drain_progress(), Some(Duration::ZERO));
+
+        upstream_dummy_server_handle.stop().unwrap();
+    }
+
     #[tokio::test]
     async fn null_param_works() {
         let (endpoint, upstream_dummy_server_handle) = upstream_dummy_server("127.0.0.1:9955").await;
@@ -364,4 +997,607 @@ mod tests {
         subway_server.handle.stop().unwrap();
         upstream_dummy_server_handle.stop().unwrap();
     }
+
+    #[tokio::test]
+    async fn method_timeout_overrides_global() {
+        let (endpoint, upstream_dummy_server_handle) = upstream_dummy_server("127.0.0.1:9957").await;
+        // server with 10 seconds global timeout
+        let subway_server = subway_server(endpoint, 9946, None).await;
+        let url = format!("ws://{}", subway_server.addr);
+        let client = ws_client(&url).await;
+
+        let now = std::time::Instant::now();
+        let err = client
+            .request::<String, _>(METHOD_TIMEOUT, rpc_params!())
+            .await
+            .unwrap_err();
+        // should timeout in 500 millis
+        assert_eq!(now.elapsed().as_secs(), 0);
+        assert!(err.to_string().contains("Request timeout"));
+
+        subway_server.handle.stop().unwrap();
+        upstream_dummy_server_handle.stop().unwrap();
+    }
+
+    #[tokio::test]
+    async fn method_middlewares_override_global() {
+        let (endpoint, upstream_dummy_server_handle) = upstream_dummy_server("127.0.0.1:0").await;
+        let mut config = subway_config(endpoint, 0, Some(1));
+        let custom = RpcMethod {
+            method: "custom_chain".to_string(),
+            response: Some(json!("custom")),
+            middlewares: Some(vec!["response".to_string()]),
+            ..Default::default()
+        };
+        config.rpcs.methods.push(custom);
+        let subway_server = build(config).await.unwrap();
+        let url = format!("ws://{}", subway_server.addr);
+        let client = ws_client(&url).await;
+
+        // answered without going through the crazy middleware of the global chain
+        let res: String = client.request("custom_chain", rpc_params!()).await.unwrap();
+        assert_eq!(res, "custom");
+        assert!(client.request::<String, _>(CRAZY, rpc_params!()).await.is_err());
+
+        subway_server.handle.stop().unwrap();
+        upstream_dummy_server_handle.stop().unwrap();
+    }
+
+    struct TagMiddleware;
+
+    #[async_trait::async_trait]
+    impl MiddlewareBuilder<RpcMethod, CallRequest, CallResult> for TagMiddleware {
+        async fn build(
+            _method: &RpcMethod,
+            _extensions: &TypeRegistryRef,
+        ) -> Option<Box<dyn Middleware<CallRequest, CallResult>>> {
+            Some(Box::new(TagMiddleware))
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Middleware<CallRequest, CallResult> for TagMiddleware {
+        async fn call(
+            &self,
+            request: CallRequest,
+            _context: TypeRegistry,
+            _next: NextFn<CallRequest, CallResult>,
+        ) -> CallResult {
+            Ok(json!(format!("tagged {}", request.method)))
+        }
+    }
+
+    #[tokio::test]
+    async fn custom_middlewares_work() {
+        let (endpoint, upstream_dummy_server_handle) = upstream_dummy_server("127.0.0.1:0").await;
+        let mut config = subway_config(endpoint, 0, None);
+        let custom = RpcMethod {
+            method: "custom_chain".to_string(),
+            middlewares: Some(vec!["tag".to_string()]),
+            ..Default::default()
+        };
+        config.rpcs.methods.push(custom);
+
+        let err = check_middlewares(&config, &test_middlewares()).unwrap_err();
+        assert_eq!(err.to_string(), "Unknown method middleware: tag");
+
+        let custom_middlewares = test_middlewares().with_method_middleware::<TagMiddleware>("tag");
+        let subway_server = build_with_middlewares(config, custom_middlewares).await.unwrap();
+        let url = format!("ws://{}", subway_server.addr);
+        let client = ws_client(&url).await;
+
+        let res: String = client.request("custom_chain", rpc_params!()).await.unwrap();
+        assert_eq!(res, "tagged custom_chain");
+
+        subway_server.handle.stop().unwrap();
+        upstream_dummy_server_handle.stop().unwrap();
+    }
+
+    #[tokio::test]
+    async fn builder_uses_given_client() {
+        let (endpoint, upstream_dummy_server_handle) = upstream_dummy_server("127.0.0.1:0").await;
+        let client = Client::with_endpoints([endpoint]).unwrap();
+        // never connected to
+        let config = subway_config("ws://127.0.0.1:1".to_string(), 0, None);
+        let custom = RpcMethod {
+            method: "custom_chain".to_string(),
+            response: Some(json!("custom")),
+            middlewares: Some(vec!["response".to_string()]),
+            ..Default::default()
+        };
+        let subway_server = SubwayBuilder::new(config)
+            .with_custom_middlewares(test_middlewares())
+            .with_client(Arc::new(client))
+            .with_method(custom)
+            .build()
+            .await
+            .unwrap();
+        let url = format!("ws://{}", subway_server.addr);
+        let client = ws_client(&url).await;
+
+        assert_eq!(BAR, client.request::<String, _>(PHO, rpc_params!()).await.unwrap());
+        let res: String = client.request("custom_chain", rpc_params!()).await.unwrap();
+        assert_eq!(res, "custom");
+
+        subway_server.handle.stop().unwrap();
+        upstream_dummy_server_handle.stop().unwrap();
+    }
+
+    #[tokio::test]
+    async fn warms_up_caches() {
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let server = ServerBuilder::default().build("127.0.0.1:0").await.unwrap();
+        let mut module = RpcModule::new(calls.clone());
+        module
+            .register_method(PHO, |_, calls| {
+                calls.fetch_add(1, Ordering::SeqCst);
+                Ok::<String, ErrorObjectOwned>(BAR.to_string())
+            })
+            .unwrap();
+        let endpoint = format!("ws://{}", server.local_addr().unwrap());
+        let upstream_handle = server.start(module);
+
+        let mut config = subway_config(endpoint, 0, None);
+        config.extensions.cache = Some(crate::extensions::cache::CacheConfig {
+            default_ttl_seconds: None,
+            default_size: 10,
+            disk_spillover_path: None,
+            disk_spillover_max_entries: 100_000,
+            negative_caching: Default::default(),
+            negative_cache_size: 10,
+            redis: None,
+            warm_up: serde_json::from_value(json!([{ "method": PHO }, { "method": "unknown_method" }])).unwrap(),
+        });
+        config.middlewares.methods = vec!["cache".to_string(), "upstream".to_string()];
+        config.rpcs.methods[0].cache = serde_json::from_value(json!({ "size": 10 })).unwrap();
+
+        let subway_server = build(config).await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        let client = ws_client(&format!("ws://{}", subway_server.addr)).await;
+        assert_eq!(BAR, client.request::<String, _>(PHO, rpc_params!()).await.unwrap());
+        // answered from the cache
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        subway_server.handle.stop().unwrap();
+        upstream_handle.stop().unwrap();
+    }
+
+    #[tokio::test]
+    async fn builder_receives_startup_events() {
+        let (endpoint, upstream_dummy_server_handle) = upstream_dummy_server("127.0.0.1:0").await;
+        let mut config = subway_config(endpoint.clone(), 0, None);
+        // nothing listens on the first endpoint
+        config.extensions.client.as_mut().unwrap().endpoints = vec!["ws://127.0.0.1:1".to_string(), endpoint];
+
+        let mut builder = SubwayBuilder::new(config).with_custom_middlewares(test_middlewares());
+        let mut events = builder.events();
+        let subway_server = builder.build().await.unwrap();
+
+        let event = tokio::time::timeout(Duration::from_secs(5), events.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            event.event,
+            SubwayEvent::EndpointUnhealthy { ref endpoint, .. } if endpoint == "ws://127.0.0.1:1"
+        ));
+        // the handle subscribes to the same bus
+        assert!(subway_server.events().await.is_some());
+
+        subway_server.handle.stop().unwrap();
+        upstream_dummy_server_handle.stop().unwrap();
+    }
+
+    #[tokio::test]
+    async fn dry_run_works() {
+        let (endpoint, upstream_dummy_server_handle) = upstream_dummy_server("127.0.0.1:0").await;
+        let mut config = subway_config(endpoint, 0, None);
+        config.middlewares.methods = vec!["upstream".to_string()];
+        let report = dry_run(config).await.unwrap();
+        assert_eq!(report.genesis_hash, Some(json!(GENESIS)));
+        assert!(report.methods.iter().any(|method| method == PHO));
+
+        let mut config = subway_config("ws://127.0.0.1:1".to_string(), 0, None);
+        config.middlewares.methods = vec!["unknown".to_string(), "upstream".to_string()];
+        assert!(dry_run(config).await.is_err());
+
+        upstream_dummy_server_handle.stop().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn serves_on_unix_socket() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (endpoint, upstream_dummy_server_handle) = upstream_dummy_server("127.0.0.1:0").await;
+        let path = std::env::temp_dir().join(format!("subway-server-{}.sock", std::process::id()));
+        let mut config = subway_config(endpoint, 0, None);
+        config.extensions.server.as_mut().unwrap().unix_socket_path = Some(path.to_str().unwrap().to_string());
+        let subway_server = build(config).await.unwrap();
+
+        let body = json!({ "jsonrpc": "2.0", "id": 1, "method": PHO }).to_string();
+        let request = format!(
+            "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        );
+        let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.contains(&format!("\"result\":\"{BAR}\"")));
+
+        subway_server.handle.stop().unwrap();
+        upstream_dummy_server_handle.stop().unwrap();
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn oversized_batch_rejected() {
+        let (endpoint, upstream_dummy_server_handle) = upstream_dummy_server("127.0.0.1:9958").await;
+        let mut config = subway_config(endpoint, 9947, None);
+        config.extensions.server.as_mut().unwrap().max_batch_size = Some(2);
+        let subway_server = build(config).await.unwrap();
+        let url = format!("ws://{}", subway_server.addr);
+        let client = ws_client(&url).await;
+
+        let mut batch = BatchRequestBuilder::new();
+        batch.insert(PHO, rpc_params!()).unwrap();
+        batch.insert(PHO, rpc_params!()).unwrap();
+        let res = client.batch_request::<String>(batch).await.unwrap();
+        assert_eq!(res.num_successful_calls(), 2);
+
+        // over-limit batch is rejected as a whole before any call executes
+        let call = json!({ "jsonrpc": "2.0", "id": 1, "method": PHO });
+        let req = hyper::Request::post(format!("http://{}", subway_server.addr))
+            .header("content-type", "application/json")
+            .body(hyper::Body::from(json!([call, call, call]).to_string()))
+            .unwrap();
+        let res = hyper::Client::new().request(req).await.unwrap();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body = serde_json::from_slice::<JsonValue>(&body).unwrap();
+        assert!(body["error"]["message"]
+            .as_str()
+            .unwrap()
+            .contains("batch request was too large"));
+
+        subway_server.handle.stop().unwrap();
+        upstream_dummy_server_handle.stop().unwrap();
+    }
+
+    #[tokio::test]
+    async fn concurrent_calls_are_limited_per_connection() {
+        let (endpoint, upstream_dummy_server_handle) = upstream_dummy_server("127.0.0.1:0").await;
+        let mut config = subway_config(endpoint, 0, None);
+        config
+            .extensions
+            .server
+            .as_mut()
+            .unwrap()
+            .max_concurrent_calls_per_connection = Some(1);
+        let subway_server = build(config).await.unwrap();
+        let url = format!("ws://{}", subway_server.addr);
+        let client = Arc::new(ws_client(&url).await);
+
+        // holds the only slot of the connection until it times out after 500 millis
+        let start = std::time::Instant::now();
+        let blocking = tokio::spawn({
+            let client = client.clone();
+            async move { client.request::<String, _>(METHOD_TIMEOUT, rpc_params!()).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // other connections have their own slot
+        let other = ws_client(&url).await;
+        let now = std::time::Instant::now();
+        assert_eq!(BAR, other.request::<String, _>(PHO, rpc_params!()).await.unwrap());
+        assert!(now.elapsed() < Duration::from_millis(300));
+
+        // calls of the same connection wait, batched or not
+        assert_eq!(BAR, client.request::<String, _>(PHO, rpc_params!()).await.unwrap());
+        assert!(start.elapsed() >= Duration::from_millis(500));
+        assert!(blocking.await.unwrap().is_err());
+
+        subway_server.handle.stop().unwrap();
+        upstream_dummy_server_handle.stop().unwrap();
+    }
+
+    #[tokio::test]
+    async fn admin_limits_works() {
+        let (endpoint, upstream_dummy_server_handle) = upstream_dummy_server("127.0.0.1:9959").await;
+        let endpoint_host = endpoint.clone();
+        let endpoint = format!("{endpoint}/secret-key");
+        let mut config = subway_config(endpoint, 9948, None);
+        config.extensions.admin = Some(AdminConfig {
+            listen_address: "127.0.0.1".to_string(),
+            port: 0,
+        });
+        let subway_server = build(config).await.unwrap();
+        let admin_addr = subway_server.extensions.read().await.get::<Admin>().unwrap().addr();
+        let client = ws_client(&format!("ws://{admin_addr}")).await;
+
+        let limits: JsonValue = client.request("admin_limits", rpc_params!()).await.unwrap();
+        assert_eq!(limits["upstream"]["endpoints"], json!([format!("{endpoint_host}/***")]));
+        assert_eq!(limits["server"]["max_connections"], 1024);
+        assert_eq!(limits["server"]["request_timeout_seconds"], 10);
+        assert_eq!(limits["upstream"]["max_concurrent_requests"], JsonValue::Null);
+        assert_eq!(
+            limits["methods"],
+            json!({ METHOD_TIMEOUT: { "upstream_timeout_ms": 500 } })
+        );
+        assert_eq!(limits["middlewares"]["crazy,upstream"].as_array().unwrap().len(), 4);
+        assert!(limits["server"].get("idle_timeout_secs").is_some());
+        assert_eq!(limits["upstream"]["queue"], JsonValue::Null);
+
+        // still served under its previous name
+        let aliased: JsonValue = client.request("subway_limits", rpc_params!()).await.unwrap();
+        assert_eq!(aliased, limits);
+
+        subway_server.handle.stop().unwrap();
+        upstream_dummy_server_handle.stop().unwrap();
+    }
+
+    #[tokio::test]
+    async fn bind_retry_waits_for_port() {
+        let (endpoint, upstream_dummy_server_handle) = upstream_dummy_server("127.0.0.1:9960").await;
+
+        // port still held by a "previous process"
+        let listener = std::net::TcpListener::bind("127.0.0.1:9949").unwrap();
+        tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+            drop(listener);
+        });
+
+        let mut config = subway_config(endpoint, 9949, None);
+        let server_config = config.extensions.server.as_mut().unwrap();
+        server_config.bind_retry_attempts = 5;
+        server_config.bind_retry_delay_ms = 100;
+        let subway_server = build(config).await.unwrap();
+        assert_eq!(subway_server.addr.port(), 9949);
+
+        subway_server.handle.stop().unwrap();
+        upstream_dummy_server_handle.stop().unwrap();
+    }
+
+    #[tokio::test]
+    async fn bind_fails_fast_on_unavailable_address() {
+        let (endpoint, upstream_dummy_server_handle) = upstream_dummy_server("127.0.0.1:0").await;
+
+        // not an address of this host, retrying can not help
+        let mut config = subway_config(endpoint, 0, None);
+        let server_config = config.extensions.server.as_mut().unwrap();
+        server_config.listen_address = "192.0.2.1".to_string();
+        server_config.bind_retry_attempts = 5;
+        server_config.bind_retry_delay_ms = 1000;
+        let start = std::time::Instant::now();
+        assert!(build(config).await.is_err());
+        assert!(start.elapsed() < std::time::Duration::from_millis(1000));
+
+        upstream_dummy_server_handle.stop().unwrap();
+    }
+
+    #[tokio::test]
+    async fn unknown_feature_flag_rejected_in_strict_mode() {
+        let (endpoint, upstream_dummy_server_handle) = upstream_dummy_server("127.0.0.1:9961").await;
+        let mut config = subway_config(endpoint, 9950, None);
+        let server_config = config.extensions.server.as_mut().unwrap();
+        server_config.strict_feature_flags = true;
+        server_config.allowed_feature_flags.push("no-cache".to_string());
+        let subway_server = build(config).await.unwrap();
+
+        let call = |features: &'static str| {
+            let req = hyper::Request::post(format!("http://{}", subway_server.addr))
+                .header("content-type", "application/json")
+                .header("x-subway-features", features)
+                .body(hyper::Body::from(
+                    json!({ "jsonrpc": "2.0", "id": 1, "method": PHO }).to_string(),
+                ))
+                .unwrap();
+            async move {
+                let res = hyper::Client::new().request(req).await.unwrap();
+                let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+                serde_json::from_slice::<JsonValue>(&body).unwrap()
+            }
+        };
+
+        let res = call("no-cache").await;
+        assert_eq!(res["result"], json!(BAR));
+
+        let res = call("no-cache,warp-speed").await;
+        assert_eq!(res["error"]["data"], json!("Unknown feature flag: warp-speed"));
+
+        subway_server.handle.stop().unwrap();
+        upstream_dummy_server_handle.stop().unwrap();
+    }
+
+    #[tokio::test]
+    async fn echo_method_works() {
+        let (endpoint, upstream_dummy_server_handle) = upstream_dummy_server("127.0.0.1:9962").await;
+        let mut config = subway_config(endpoint, 9951, None);
+        config.extensions.server.as_mut().unwrap().echo_method = Some("debug_echo".to_string());
+        let subway_server = build(config).await.unwrap();
+        let url = format!("ws://{}", subway_server.addr);
+        let client = ws_client(&url).await;
+
+        let res: JsonValue = client.request("debug_echo", rpc_params!(1, "two")).await.unwrap();
+        assert_eq!(res["params"], json!([1, "two"]));
+        assert!(res["server_time"].as_u64().unwrap() > 0);
+
+        subway_server.handle.stop().unwrap();
+        upstream_dummy_server_handle.stop().unwrap();
+    }
+
+    #[tokio::test]
+    async fn http_request_timeout_returns_504() {
+        let (endpoint, upstream_dummy_server_handle) = upstream_dummy_server("127.0.0.1:9963").await;
+        let mut config = subway_config(endpoint, 9952, None);
+        config.extensions.server.as_mut().unwrap().http_request_timeout_ms = Some(100);
+        let subway_server = build(config).await.unwrap();
+
+        let call = json!({ "jsonrpc": "2.0", "id": 1, "method": TIMEOUT });
+        let req = hyper::Request::post(format!("http://{}", subway_server.addr))
+            .header("content-type", "application/json")
+            .body(hyper::Body::from(call.to_string()))
+            .unwrap();
+        let res = hyper::Client::new().request(req).await.unwrap();
+        assert_eq!(res.status(), hyper::StatusCode::GATEWAY_TIMEOUT);
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body = serde_json::from_slice::<JsonValue>(&body).unwrap();
+        assert_eq!(body["error"]["data"], "Request timeout");
+
+        // websocket calls are not affected by the http timeout
+        let url = format!("ws://{}", subway_server.addr);
+        let client = ws_client(&url).await;
+        let res: String = client.request(PHO, rpc_params!()).await.unwrap();
+        assert_eq!(res, BAR);
+
+        subway_server.handle.stop().unwrap();
+        upstream_dummy_server_handle.stop().unwrap();
+    }
+
+    #[tokio::test]
+    async fn websocket_can_be_disabled() {
+        let (endpoint, upstream_dummy_server_handle) = upstream_dummy_server("127.0.0.1:9964").await;
+        let mut config = subway_config(endpoint, 9953, None);
+        config.extensions.server.as_mut().unwrap().enable_ws = false;
+        let subway_server = build(config).await.unwrap();
+
+        let call = json!({ "jsonrpc": "2.0", "id": 1, "method": PHO });
+        let req = hyper::Request::post(format!("http://{}", subway_server.addr))
+            .header("content-type", "application/json")
+            .body(hyper::Body::from(call.to_string()))
+            .unwrap();
+        let res = hyper::Client::new().request(req).await.unwrap();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body = serde_json::from_slice::<JsonValue>(&body).unwrap();
+        assert_eq!(body["result"], BAR);
+
+        let url = format!("ws://{}", subway_server.addr);
+        assert!(WsClientBuilder::default().build(&url).await.is_err());
+
+        subway_server.handle.stop().unwrap();
+        upstream_dummy_server_handle.stop().unwrap();
+    }
+
+    #[tokio::test]
+    async fn health_probes_work() {
+        let (endpoint, upstream_dummy_server_handle) = upstream_dummy_server("127.0.0.1:9966").await;
+        let mut config = subway_config(endpoint, 9967, None);
+        config.extensions.server.as_mut().unwrap().health = Some(HealthConfig {
+            liveness_path: "/health".to_string(),
+            readiness_path: "/ready".to_string(),
+            readiness_timeout_ms: 1000,
+        });
+        let subway_server = build(config).await.unwrap();
+
+        let get = |path: &str| {
+            let uri = format!("http://{}{path}", subway_server.addr);
+            async move {
+                let res = hyper::Client::new().get(uri.parse().unwrap()).await.unwrap();
+                let status = res.status();
+                let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+                (status, serde_json::from_slice::<JsonValue>(&body).unwrap())
+            }
+        };
+
+        let (status, body) = get("/health").await;
+        assert_eq!(status, hyper::StatusCode::OK);
+        assert_eq!(body, json!({ "status": "ok" }));
+
+        // the dummy upstream has no system_health
+        let (status, body) = get("/ready").await;
+        assert_eq!(status, hyper::StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["ready"], false);
+
+        subway_server.handle.stop().unwrap();
+        upstream_dummy_server_handle.stop().unwrap();
+    }
+
+    #[tokio::test]
+    async fn http_methods_bypass_upstream_queue() {
+        let (endpoint, upstream_dummy_server_handle) = upstream_dummy_server("127.0.0.1:0").await;
+        let mut config = subway_config(endpoint, 0, None);
+        config.extensions.client.as_mut().unwrap().queue = Some(UpstreamQueueConfig {
+            light_concurrency: 1,
+            heavy_concurrency: 1,
+            heavy_methods: vec![],
+            max_queued: None,
+            queue_timeout_ms: Some(100),
+        });
+        config.extensions.server.as_mut().unwrap().http_methods = vec![HttpMethodsConfig {
+            path: "/pho".to_string(),
+            method: PHO.to_string(),
+        }];
+        let subway_server = build(config).await.unwrap();
+        let url = format!("ws://{}", subway_server.addr);
+        let client = ws_client(&url).await;
+
+        // the only queue slot is held by a call upstream never answers
+        let blocking = tokio::spawn({
+            let client = ws_client(&url).await;
+            async move { client.request::<String, _>(TIMEOUT, rpc_params!()).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let err = client
+            .request::<String, _>(METHOD_TIMEOUT, rpc_params!())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains(QUEUE_TIMEOUT_ERROR));
+
+        // served through the reserved internal slots, over JSON-RPC and GET
+        assert_eq!(BAR, client.request::<String, _>(PHO, rpc_params!()).await.unwrap());
+        let uri = format!("http://{}/pho", subway_server.addr);
+        let res = hyper::Client::new().get(uri.parse().unwrap()).await.unwrap();
+        assert_eq!(res.status(), hyper::StatusCode::OK);
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(serde_json::from_slice::<JsonValue>(&body).unwrap()["result"], BAR);
+
+        blocking.abort();
+        subway_server.handle.stop().unwrap();
+        upstream_dummy_server_handle.stop().unwrap();
+    }
+
+    #[tokio::test]
+    async fn reload_serves_new_methods_to_new_connections() {
+        let (endpoint, upstream_dummy_server_handle) = upstream_dummy_server("127.0.0.1:9965").await;
+        let subway_server = build(subway_config(endpoint.clone(), 9954, None)).await.unwrap();
+        let url = format!("ws://{}", subway_server.addr);
+        let old_client = ws_client(&url).await;
+
+        let mut config = subway_config(endpoint.clone(), 9954, None);
+        config.rpcs.methods.retain(|m| m.method == PHO);
+        config.rpcs.aliases = vec![(PHO.to_string(), "call_pho_alias".to_string())];
+        subway_server.reload(config).await.unwrap();
+
+        let new_client = ws_client(&url).await;
+        let res: String = new_client.request("call_pho_alias", rpc_params!()).await.unwrap();
+        assert_eq!(res, BAR);
+        assert!(new_client.request::<String, _>(CRAZY, rpc_params!()).await.is_err());
+
+        // open connections keep the methods they started with
+        let res: String = old_client.request(PHO, rpc_params!()).await.unwrap();
+        assert_eq!(res, BAR);
+        assert!(old_client
+            .request::<String, _>("call_pho_alias", rpc_params!())
+            .await
+            .is_err());
+
+        // rate limiters and method group runtimes are only created on start
+        let mut config = subway_config(endpoint.clone(), 9954, None);
+        config.extensions.rate_limit = Some(Default::default());
+        assert!(subway_server.reload(config).await.is_err());
+        let mut config = subway_config(endpoint, 9954, None);
+        config.rpcs.method_groups = vec![MethodGroup {
+            name: "heavy".to_string(),
+            methods: vec![PHO.to_string()],
+            runtime_threads: None,
+            max_concurrent_calls: None,
+        }];
+        assert!(subway_server.reload(config).await.is_err());
+
+        subway_server.handle.stop().unwrap();
+        upstream_dummy_server_handle.stop().unwrap();
+    }
 }
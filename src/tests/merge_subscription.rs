@@ -49,6 +49,7 @@ async fn merge_subscription_works() {
             client: Some(ClientConfig {
                 endpoints: vec![format!("ws://{addr}")],
                 shuffle_endpoints: false,
+                ..Default::default()
             }),
             server: Some(ServerConfig {
                 listen_address: "0.0.0.0".to_string(),
@@ -57,6 +58,7 @@ async fn merge_subscription_works() {
                 request_timeout_seconds: 120,
                 http_methods: Vec::new(),
                 cors: None,
+                ..Default::default()
             }),
             merge_subscription: Some(MergeSubscriptionConfig {
                 keep_alive_seconds: Some(1),
@@ -75,21 +77,25 @@ async fn merge_subscription_works() {
                     unsubscribe: unsubscribe_head.to_string(),
                     name: update_head.to_string(),
                     merge_strategy: None,
+                    ..Default::default()
                 },
                 RpcSubscription {
                     subscribe: subscribe_finalized.to_string(),
                     unsubscribe: unsubscribe_finalized.to_string(),
                     name: update_finalized.to_string(),
                     merge_strategy: None,
+                    ..Default::default()
                 },
                 RpcSubscription {
                     subscribe: subscribe_mock.to_string(),
                     unsubscribe: unsubscribe_mock.to_string(),
                     name: update_mock.to_string(),
                     merge_strategy: Some(MergeStrategy::MergeStorageChanges),
+                    ..Default::default()
                 },
             ],
             aliases: vec![],
+            ..Default::default()
         },
     };
 
@@ -0,0 +1,72 @@
+use serde_json::json;
+
+use crate::{
+    config::{CacheParams, RpcMethod},
+    extensions::{
+        cache::CacheConfig,
+        client::{mock::TestServerBuilder, ClientConfig},
+        ExtensionsConfig,
+    },
+    server::build_method_middlewares,
+};
+
+fn rpc_method(name: &str, cache_size: Option<usize>) -> RpcMethod {
+    RpcMethod {
+        method: name.to_string(),
+        cache: cache_size.map(|size| CacheParams {
+            size: Some(size),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn chain_depth_matches_config() {
+    let (addr, _upstream_handle) = TestServerBuilder::new().build().await;
+
+    let registry = ExtensionsConfig {
+        client: Some(ClientConfig {
+            endpoints: vec![format!("ws://{addr}")],
+            shuffle_endpoints: false,
+            ..Default::default()
+        }),
+        cache: Some(CacheConfig {
+            default_ttl_seconds: None,
+            default_size: 100,
+            disk_spillover_path: None,
+            disk_spillover_max_entries: 100_000,
+            negative_caching: Default::default(),
+            negative_cache_size: 100,
+            redis: None,
+            warm_up: Vec::new(),
+        }),
+        ..Default::default()
+    }
+    .create_registry()
+    .await
+    .unwrap();
+
+    let names = ["response", "cache", "upstream"].map(String::from);
+
+    let method = rpc_method("cached", Some(10));
+    let (chain, applied) = build_method_middlewares(&method, &names, &registry).await;
+    assert_eq!(chain.len(), 2);
+    assert_eq!(applied, ["cache", "upstream"]);
+
+    let method = RpcMethod {
+        response: Some(json!("0x01")),
+        ..rpc_method("mocked", Some(10))
+    };
+    let (chain, _) = build_method_middlewares(&method, &names, &registry).await;
+    assert_eq!(chain.len(), 3);
+
+    // cache disabled with size 0
+    let method = rpc_method("uncached", Some(0));
+    let (chain, applied) = build_method_middlewares(&method, &names, &registry).await;
+    assert_eq!(chain.len(), 1);
+    assert_eq!(applied, ["upstream"]);
+
+    let (chain, _) = build_method_middlewares(&method, &[], &registry).await;
+    assert!(chain.is_empty());
+}
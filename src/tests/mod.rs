@@ -1,2 +1,4 @@
 mod merge_subscription;
+mod middleware_chain;
 mod upstream;
+mod upstream_methods;
@@ -1,11 +1,16 @@
+use std::time::Duration;
+
 use crate::{
     config::{Config, MergeStrategy, MiddlewaresConfig, RpcDefinitions, RpcSubscription},
     extensions::{
+        admin::{Admin, AdminConfig},
         client::{mock::TestServerBuilder, Client, ClientConfig},
         merge_subscription::MergeSubscriptionConfig,
+        rebalance::RebalanceConfig,
         server::ServerConfig,
         ExtensionsConfig,
     },
+    middlewares::subscriptions::lifetime::expired_notification,
     server,
 };
 
@@ -31,6 +36,7 @@ async fn upstream_error_propagate() {
             client: Some(ClientConfig {
                 endpoints: vec![format!("ws://{addr}")],
                 shuffle_endpoints: false,
+                ..Default::default()
             }),
             server: Some(ServerConfig {
                 listen_address: "0.0.0.0".to_string(),
@@ -39,6 +45,7 @@ async fn upstream_error_propagate() {
                 request_timeout_seconds: 120,
                 http_methods: Vec::new(),
                 cors: None,
+                ..Default::default()
             }),
             merge_subscription: Some(MergeSubscriptionConfig {
                 keep_alive_seconds: Some(1),
@@ -57,15 +64,18 @@ async fn upstream_error_propagate() {
                     unsubscribe: unsubscribe_mock.to_string(),
                     name: update_mock.to_string(),
                     merge_strategy: None,
+                    ..Default::default()
                 },
                 RpcSubscription {
                     subscribe: subscribe_merge_mock.to_string(),
                     unsubscribe: unsubscribe_merge_mock.to_string(),
                     name: update_merge_mock.to_string(),
                     merge_strategy: Some(MergeStrategy::Replace),
+                    ..Default::default()
                 },
             ],
             aliases: vec![],
+            ..Default::default()
         },
     };
 
@@ -94,3 +104,241 @@ async fn upstream_error_propagate() {
     // stop server
     subway_server.handle.stop().unwrap();
 }
+
+#[tokio::test]
+async fn subscriptions_overflow_to_endpoint_with_capacity() {
+    let subscribe_mock = "mock_sub";
+    let unsubscribe_mock = "mock_unsub";
+    let update_mock = "mock";
+
+    let mut builder1 = TestServerBuilder::new();
+    let mut sub_rx1 = builder1.register_subscription(subscribe_mock, update_mock, unsubscribe_mock);
+    let (addr1, _upstream_handle1) = builder1.build().await;
+
+    let mut builder2 = TestServerBuilder::new();
+    let mut sub_rx2 = builder2.register_subscription(subscribe_mock, update_mock, unsubscribe_mock);
+    let (addr2, _upstream_handle2) = builder2.build().await;
+
+    let endpoint1 = format!("ws://{addr1}");
+    let endpoint2 = format!("ws://{addr2}");
+
+    let config = Config {
+        extensions: ExtensionsConfig {
+            client: Some(ClientConfig {
+                endpoints: vec![endpoint1.clone(), endpoint2],
+                shuffle_endpoints: false,
+                max_subscriptions: [(endpoint1, 1)].into(),
+                ..Default::default()
+            }),
+            server: Some(ServerConfig {
+                listen_address: "0.0.0.0".to_string(),
+                port: 0,
+                max_connections: 10,
+                ..Default::default()
+            }),
+            ..Default::default()
+        },
+        middlewares: MiddlewaresConfig {
+            methods: vec![],
+            subscriptions: vec!["upstream".to_string()],
+        },
+        rpcs: RpcDefinitions {
+            subscriptions: vec![RpcSubscription {
+                subscribe: subscribe_mock.to_string(),
+                unsubscribe: unsubscribe_mock.to_string(),
+                name: update_mock.to_string(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        },
+    };
+
+    let subway_server = server::build(config).await.unwrap();
+    let addr = subway_server.addr;
+
+    let client = Client::with_endpoints([format!("ws://{addr}")]).unwrap();
+
+    // first subscription fills the cap of the first endpoint
+    let _first = client
+        .subscribe(subscribe_mock, vec![], unsubscribe_mock)
+        .await
+        .unwrap();
+    sub_rx1.recv().await.unwrap();
+
+    // following subscriptions overflow to the second endpoint
+    let _second = client
+        .subscribe(subscribe_mock, vec![], unsubscribe_mock)
+        .await
+        .unwrap();
+    let _third = client
+        .subscribe(subscribe_mock, vec![], unsubscribe_mock)
+        .await
+        .unwrap();
+    sub_rx2.recv().await.unwrap();
+    sub_rx2.recv().await.unwrap();
+    assert!(sub_rx1.try_recv().is_err());
+
+    // stop server
+    subway_server.handle.stop().unwrap();
+}
+
+#[tokio::test]
+async fn rebalance_closes_subscriptions_with_reconnect_hint() {
+    let subscribe_mock = "mock_sub";
+    let unsubscribe_mock = "mock_unsub";
+    let update_mock = "mock";
+
+    let subscribe_merge_mock = "mock_merge_sub";
+    let unsubscribe_merge_mock = "mock_merge_unsub";
+    let update_merge_mock = "mock_merge";
+
+    let mut builder = TestServerBuilder::new();
+    let mut sub_rx = builder.register_subscription(subscribe_mock, update_mock, unsubscribe_mock);
+    let mut merge_sub_rx =
+        builder.register_subscription(subscribe_merge_mock, update_merge_mock, unsubscribe_merge_mock);
+    let (addr, _upstream_handle) = builder.build().await;
+
+    let config = Config {
+        extensions: ExtensionsConfig {
+            client: Some(ClientConfig {
+                endpoints: vec![format!("ws://{addr}")],
+                shuffle_endpoints: false,
+                ..Default::default()
+            }),
+            server: Some(ServerConfig {
+                listen_address: "0.0.0.0".to_string(),
+                port: 0,
+                max_connections: 10,
+                ..Default::default()
+            }),
+            merge_subscription: Some(MergeSubscriptionConfig {
+                keep_alive_seconds: Some(1),
+            }),
+            rebalance: Some(RebalanceConfig {
+                fraction: 1.0,
+                window_seconds: 0,
+                admin_method: true,
+            }),
+            admin: Some(AdminConfig {
+                listen_address: "127.0.0.1".to_string(),
+                port: 0,
+            }),
+            ..Default::default()
+        },
+        middlewares: MiddlewaresConfig {
+            methods: vec![],
+            subscriptions: vec!["merge_subscription".to_string(), "upstream".to_string()],
+        },
+        rpcs: RpcDefinitions {
+            subscriptions: vec![
+                RpcSubscription {
+                    subscribe: subscribe_mock.to_string(),
+                    unsubscribe: unsubscribe_mock.to_string(),
+                    name: update_mock.to_string(),
+                    ..Default::default()
+                },
+                RpcSubscription {
+                    subscribe: subscribe_merge_mock.to_string(),
+                    unsubscribe: unsubscribe_merge_mock.to_string(),
+                    name: update_merge_mock.to_string(),
+                    merge_strategy: Some(MergeStrategy::Replace),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        },
+    };
+
+    let subway_server = server::build(config).await.unwrap();
+    let addr = subway_server.addr;
+
+    let client = Client::with_endpoints([format!("ws://{addr}")]).unwrap();
+    let mut sub = client
+        .subscribe(subscribe_mock, vec![], unsubscribe_mock)
+        .await
+        .unwrap();
+    let _upstream_sub = sub_rx.recv().await.unwrap();
+    let mut merge_sub = client
+        .subscribe(subscribe_merge_mock, vec![], unsubscribe_merge_mock)
+        .await
+        .unwrap();
+    let _upstream_merge_sub = merge_sub_rx.recv().await.unwrap();
+
+    let admin_addr = subway_server.extensions.read().await.get::<Admin>().unwrap().addr();
+    let admin_client = Client::with_endpoints([format!("ws://{admin_addr}")]).unwrap();
+    let res = admin_client.request("admin_rebalance", vec![]).await.unwrap();
+    assert_eq!(res["targeted"], 2);
+
+    // closed with an error instead of a notification which is not a value of the subscription
+    for sub in [&mut sub, &mut merge_sub] {
+        let next = tokio::time::timeout(Duration::from_secs(5), sub.next()).await.unwrap();
+        assert!(next.is_none());
+    }
+
+    // stop server
+    subway_server.handle.stop().unwrap();
+}
+
+#[tokio::test]
+async fn subscription_closed_after_max_lifetime() {
+    let subscribe_mock = "mock_sub";
+    let unsubscribe_mock = "mock_unsub";
+    let update_mock = "mock";
+
+    let mut builder = TestServerBuilder::new();
+    let mut sub_rx = builder.register_subscription(subscribe_mock, update_mock, unsubscribe_mock);
+    let (addr, _upstream_handle) = builder.build().await;
+
+    let config = Config {
+        extensions: ExtensionsConfig {
+            client: Some(ClientConfig {
+                endpoints: vec![format!("ws://{addr}")],
+                shuffle_endpoints: false,
+                ..Default::default()
+            }),
+            server: Some(ServerConfig {
+                listen_address: "0.0.0.0".to_string(),
+                port: 0,
+                max_connections: 10,
+                max_subscription_lifetime_secs: Some(100),
+                ..Default::default()
+            }),
+            ..Default::default()
+        },
+        middlewares: MiddlewaresConfig {
+            methods: vec![],
+            subscriptions: vec!["upstream".to_string()],
+        },
+        rpcs: RpcDefinitions {
+            subscriptions: vec![RpcSubscription {
+                subscribe: subscribe_mock.to_string(),
+                unsubscribe: unsubscribe_mock.to_string(),
+                name: update_mock.to_string(),
+                max_lifetime_secs: Some(1),
+                ..Default::default()
+            }],
+            ..Default::default()
+        },
+    };
+
+    let subway_server = server::build(config).await.unwrap();
+    let addr = subway_server.addr;
+
+    let client = Client::with_endpoints([format!("ws://{addr}")]).unwrap();
+    let mut sub = client
+        .subscribe(subscribe_mock, vec![], unsubscribe_mock)
+        .await
+        .unwrap();
+    let _upstream_sub = sub_rx.recv().await.unwrap();
+
+    // subscription override takes precedence over the server wide lifetime
+    let msg = tokio::time::timeout(Duration::from_secs(5), sub.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!(msg, expired_notification());
+
+    // stop server
+    subway_server.handle.stop().unwrap();
+}
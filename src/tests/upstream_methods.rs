@@ -0,0 +1,141 @@
+use serde_json::{json, Value as JsonValue};
+
+use crate::{
+    config::{Config, MethodRemap, MiddlewaresConfig, RpcDefinitions, RpcMethod},
+    extensions::{
+        client::{mock::TestServerBuilder, Client, ClientConfig},
+        server::{ServerConfig, UnsupportedMethodPolicy},
+        ExtensionsConfig,
+    },
+    server,
+};
+
+const SUPPORTED: &str = "mock_supported";
+const MISSING: &str = "mock_missing";
+const REMAPPED: &str = "mock_remapped";
+
+fn rpc_method(name: &str) -> RpcMethod {
+    RpcMethod {
+        method: name.to_string(),
+        ..Default::default()
+    }
+}
+
+async fn config_with_policy(policy: UnsupportedMethodPolicy) -> Config {
+    config_with_rpc_methods(
+        policy,
+        Some(json!({ "version": 1, "methods": [SUPPORTED, "rpc_methods"] })),
+    )
+    .await
+}
+
+/// Upstream answering `rpc_methods` with the given response, or not serving it at all.
+async fn config_with_rpc_methods(policy: UnsupportedMethodPolicy, rpc_methods: Option<JsonValue>) -> Config {
+    let mut builder = TestServerBuilder::new();
+    let rpc_methods_rx = rpc_methods.as_ref().map(|_| builder.register_method("rpc_methods"));
+    let (addr, upstream_handle) = builder.build().await;
+
+    tokio::spawn(async move {
+        // keep upstream alive while serving rpc_methods
+        let _upstream_handle = upstream_handle;
+        let (Some(mut rpc_methods_rx), Some(response)) = (rpc_methods_rx, rpc_methods) else {
+            futures::future::pending::<()>().await;
+            return;
+        };
+        while let Some(req) = rpc_methods_rx.recv().await {
+            req.respond(response.clone());
+        }
+    });
+
+    Config {
+        extensions: ExtensionsConfig {
+            client: Some(ClientConfig {
+                endpoints: vec![format!("ws://{addr}")],
+                shuffle_endpoints: false,
+                ..Default::default()
+            }),
+            server: Some(ServerConfig {
+                listen_address: "0.0.0.0".to_string(),
+                port: 0,
+                max_connections: 10,
+                unsupported_methods: Some(policy),
+                ..Default::default()
+            }),
+            ..Default::default()
+        },
+        middlewares: MiddlewaresConfig {
+            methods: vec!["upstream".to_string()],
+            subscriptions: vec![],
+        },
+        rpcs: RpcDefinitions {
+            methods: vec![rpc_method(SUPPORTED), rpc_method(MISSING)],
+            ..Default::default()
+        },
+    }
+}
+
+async fn registered_methods(config: Config) -> Vec<String> {
+    let subway_server = server::build(config).await.unwrap();
+    let client = Client::with_endpoints([format!("ws://{}", subway_server.addr)]).unwrap();
+    let res = client.request("rpc_methods", vec![]).await.unwrap();
+    subway_server.handle.stop().unwrap();
+    serde_json::from_value(res["methods"].clone()).unwrap()
+}
+
+#[tokio::test]
+async fn unsupported_method_fail() {
+    let config = config_with_policy(UnsupportedMethodPolicy::Fail).await;
+    let err = server::build(config).await.err().unwrap();
+    assert!(err.to_string().contains(MISSING));
+}
+
+#[tokio::test]
+async fn unsupported_method_skip() {
+    let config = config_with_policy(UnsupportedMethodPolicy::Skip).await;
+    let methods = registered_methods(config).await;
+    assert!(methods.contains(&SUPPORTED.to_string()));
+    assert!(!methods.contains(&MISSING.to_string()));
+}
+
+#[tokio::test]
+async fn unsupported_method_register() {
+    let config = config_with_policy(UnsupportedMethodPolicy::Register).await;
+    let methods = registered_methods(config).await;
+    assert!(methods.contains(&SUPPORTED.to_string()));
+    assert!(methods.contains(&MISSING.to_string()));
+}
+
+#[tokio::test]
+async fn remapped_method_checked_by_upstream_name() {
+    let mut config = config_with_policy(UnsupportedMethodPolicy::Skip).await;
+    config.rpcs.methods.push(RpcMethod {
+        method: REMAPPED.to_string(),
+        remap: Some(MethodRemap {
+            method: SUPPORTED.to_string(),
+            params: None,
+        }),
+        ..Default::default()
+    });
+    let methods = registered_methods(config).await;
+    assert!(methods.contains(&REMAPPED.to_string()));
+}
+
+#[tokio::test]
+async fn unavailable_rpc_methods_fail() {
+    let config = config_with_rpc_methods(UnsupportedMethodPolicy::Fail, None).await;
+    let err = server::build(config).await.err().unwrap();
+    assert!(err.to_string().contains("rpc_methods"));
+
+    // other policies keep all methods
+    let config = config_with_rpc_methods(UnsupportedMethodPolicy::Skip, None).await;
+    let methods = registered_methods(config).await;
+    assert!(methods.contains(&MISSING.to_string()));
+}
+
+#[tokio::test]
+async fn malformed_rpc_methods_is_unknown() {
+    let config = config_with_rpc_methods(UnsupportedMethodPolicy::Skip, Some(json!({ "version": 1 }))).await;
+    let methods = registered_methods(config).await;
+    assert!(methods.contains(&SUPPORTED.to_string()));
+    assert!(methods.contains(&MISSING.to_string()));
+}
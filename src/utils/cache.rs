@@ -1,11 +1,19 @@
 use crate::middlewares::CallResult;
+use async_trait::async_trait;
 use blake2::{digest::Output, Digest};
 use futures::future::BoxFuture;
 use jsonrpsee::core::JsonValue;
 use jsonrpsee::types::ErrorObjectOwned;
+use moka::{notification::RemovalCause, Expiry};
+use std::collections::{BTreeMap, HashMap};
 use std::num::NonZeroUsize;
-use std::time::Duration;
-use tokio::sync::watch;
+use std::path::PathBuf;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, oneshot, watch};
 
 #[derive(Debug)]
 pub struct CacheKey<D: Digest>(pub Output<D>);
@@ -26,6 +34,27 @@ impl<D: Digest> CacheKey<D> {
 
         Self(hasher.finalize())
     }
+
+    /// Key of the same call made with other credentials, e.g. other forwarded headers.
+    pub fn scoped(&self, scope: &[u8]) -> Self {
+        let mut hasher = D::new();
+        hasher.update(self.0.as_slice());
+        hasher.update(scope);
+
+        Self(hasher.finalize())
+    }
+
+    /// Same as `new` but also keyed by the runtime spec version.
+    pub fn with_spec_version(method: &String, params: &[JsonValue], spec_version: u32) -> Self {
+        let mut hasher = D::new();
+        hasher.update(method.as_bytes());
+        for p in params {
+            hasher.update(p.to_string().as_bytes());
+        }
+        hasher.update(spec_version.to_le_bytes());
+
+        Self(hasher.finalize())
+    }
 }
 
 impl<D: Digest> PartialEq for CacheKey<D> {
@@ -48,29 +77,373 @@ pub enum CacheValue {
     Value(JsonValue),
 }
 
+/// Memory entry with the time its value was fetched, which it keeps when it moves between tiers.
+#[derive(Clone, Debug)]
+struct Entry {
+    value: CacheValue,
+    inserted: SystemTime,
+}
+
+impl Entry {
+    fn new(value: CacheValue) -> Self {
+        Self {
+            value,
+            inserted: SystemTime::now(),
+        }
+    }
+}
+
+/// Expires entries `ttl` after they were fetched, also when they come back from the disk tier.
+struct EntryExpiry(Duration);
+
+impl EntryExpiry {
+    fn remaining(&self, entry: &Entry) -> Option<Duration> {
+        let age = SystemTime::now().duration_since(entry.inserted).unwrap_or_default();
+        Some(self.0.saturating_sub(age))
+    }
+}
+
+impl<D: Digest> Expiry<CacheKey<D>, Entry> for EntryExpiry {
+    fn expire_after_create(&self, _key: &CacheKey<D>, entry: &Entry, _created_at: Instant) -> Option<Duration> {
+        self.remaining(entry)
+    }
+
+    fn expire_after_update(
+        &self,
+        _key: &CacheKey<D>,
+        entry: &Entry,
+        _updated_at: Instant,
+        _duration_until_expiry: Option<Duration>,
+    ) -> Option<Duration> {
+        self.remaining(entry)
+    }
+}
+
+/// Secondary tier holding entries evicted from memory for lack of capacity, one file per key.
+/// Files are written, read and removed in order by a dedicated thread, so the eviction listener never blocks.
+/// Files left by a previous process in the same directory are indexed on start, so they are served or removed too.
+struct DiskTier {
+    ops: mpsc::Sender<DiskOp>,
+}
+
+// spilled entries waiting for the disk thread, further ones are dropped
+const MAX_PENDING_DISK_OPS: usize = 1024;
+
+enum DiskOp {
+    Write(Vec<u8>, JsonValue, SystemTime),
+    Take(Vec<u8>, oneshot::Sender<Option<(JsonValue, SystemTime)>>),
+    Remove(Vec<u8>),
+    Clear(oneshot::Sender<()>),
+    Flush(oneshot::Sender<()>),
+}
+
+impl DiskTier {
+    fn spawn(dir: PathBuf, ttl: Option<Duration>, max_entries: usize) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        let (ops, mut rx) = mpsc::channel(MAX_PENDING_DISK_OPS);
+        let mut files = DiskFiles {
+            dir,
+            ttl,
+            max_entries,
+            written: Default::default(),
+            order: Default::default(),
+            next_seq: 0,
+        };
+        std::thread::Builder::new()
+            .name("cache-disk".to_string())
+            .spawn(move || {
+                files.index_existing();
+                while let Some(op) = rx.blocking_recv() {
+                    files.apply(op);
+                }
+            })?;
+        Ok(Self { ops })
+    }
+
+    // called from the eviction listener which is sync
+    fn write(&self, key: &[u8], value: &JsonValue, inserted: SystemTime) {
+        if let Err(e) = self.ops.try_send(DiskOp::Write(key.to_vec(), value.clone(), inserted)) {
+            match e {
+                mpsc::error::TrySendError::Full(_) => {
+                    tracing::warn!("Cache: disk tier is busy, dropping evicted entry")
+                }
+                mpsc::error::TrySendError::Closed(_) => tracing::warn!("Cache: disk tier is gone"),
+            }
+        }
+    }
+
+    /// Reads and removes the entry with the time it was fetched, it goes back to the memory tier.
+    async fn take(&self, key: &[u8]) -> Option<(JsonValue, SystemTime)> {
+        let (tx, rx) = oneshot::channel();
+        self.ops.send(DiskOp::Take(key.to_vec(), tx)).await.ok()?;
+        rx.await.ok()?
+    }
+
+    async fn remove(&self, key: &[u8]) {
+        let _ = self.ops.send(DiskOp::Remove(key.to_vec())).await;
+    }
+
+    async fn clear(&self) {
+        let (tx, rx) = oneshot::channel();
+        if self.ops.send(DiskOp::Clear(tx)).await.is_ok() {
+            let _ = rx.await;
+        }
+    }
+
+    /// Waits for all queued operations to be done.
+    async fn flush(&self) {
+        let (tx, rx) = oneshot::channel();
+        if self.ops.send(DiskOp::Flush(tx)).await.is_ok() {
+            let _ = rx.await;
+        }
+    }
+}
+
+/// Files of a disk tier, owned by its thread.
+struct DiskFiles {
+    dir: PathBuf,
+    ttl: Option<Duration>,
+    max_entries: usize,
+    // keys of the files of this tier with their write sequence number, other files in `dir` are never touched
+    written: HashMap<Vec<u8>, u64>,
+    // oldest first, removed when `max_entries` is exceeded
+    order: BTreeMap<u64, Vec<u8>>,
+    next_seq: u64,
+}
+
+impl DiskFiles {
+    fn path(&self, key: &[u8]) -> PathBuf {
+        let name: String = key.iter().map(|b| format!("{b:02x}")).collect();
+        self.dir.join(name)
+    }
+
+    /// Indexes the files written by a previous process, oldest first, and removes those beyond `max_entries`.
+    fn index_existing(&mut self) {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                tracing::warn!("Cache: failed to read disk tier: {e}");
+                return;
+            }
+        };
+        let mut existing: Vec<(SystemTime, Vec<u8>)> = entries
+            .filter_map(|entry| {
+                let entry = entry.ok()?;
+                let key = parse_key(entry.file_name().to_str()?)?;
+                let modified = entry.metadata().and_then(|m| m.modified()).ok()?;
+                Some((modified, key))
+            })
+            .collect();
+        existing.sort();
+        for (_, key) in existing {
+            self.track(key);
+        }
+        self.trim();
+    }
+
+    fn apply(&mut self, op: DiskOp) {
+        match op {
+            DiskOp::Write(key, value, inserted) => self.write(key, &value, inserted),
+            DiskOp::Take(key, tx) => {
+                let _ = tx.send(self.take(&key));
+            }
+            DiskOp::Remove(key) => self.remove(&key),
+            DiskOp::Clear(tx) => {
+                let keys: Vec<_> = self.written.keys().cloned().collect();
+                for key in keys {
+                    self.remove(&key);
+                }
+                let _ = tx.send(());
+            }
+            DiskOp::Flush(tx) => {
+                let _ = tx.send(());
+            }
+        }
+    }
+
+    fn write(&mut self, key: Vec<u8>, value: &JsonValue, inserted: SystemTime) {
+        let inserted_ms = inserted.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        let content = serde_json::json!({ "inserted_ms": inserted_ms, "value": value });
+        if let Err(e) = std::fs::write(self.path(&key), content.to_string()) {
+            tracing::warn!("Cache: failed to spill entry to disk: {e}");
+            return;
+        }
+        self.track(key);
+        self.trim();
+    }
+
+    fn track(&mut self, key: Vec<u8>) {
+        if let Some(seq) = self.written.insert(key.clone(), self.next_seq) {
+            self.order.remove(&seq);
+        }
+        self.order.insert(self.next_seq, key);
+        self.next_seq += 1;
+    }
+
+    fn trim(&mut self) {
+        while self.written.len() > self.max_entries {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            self.remove(&oldest);
+        }
+    }
+
+    fn take(&mut self, key: &[u8]) -> Option<(JsonValue, SystemTime)> {
+        if !self.written.contains_key(key) {
+            return None;
+        }
+        let content = std::fs::read(self.path(key)).ok();
+        self.remove(key);
+        let mut content: JsonValue = serde_json::from_slice(&content?).ok()?;
+        let inserted = UNIX_EPOCH + Duration::from_millis(content["inserted_ms"].as_u64()?);
+        // expires from the time the value was fetched, not the time it was spilled
+        if self
+            .ttl
+            .is_some_and(|ttl| SystemTime::now().duration_since(inserted).unwrap_or_default() > ttl)
+        {
+            return None;
+        }
+        Some((content["value"].take(), inserted))
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        let Some(seq) = self.written.remove(key) else {
+            return;
+        };
+        self.order.remove(&seq);
+        let _ = std::fs::remove_file(self.path(key));
+    }
+}
+
+/// Key of a disk tier file name, None for files which are not written by a disk tier.
+fn parse_key(name: &str) -> Option<Vec<u8>> {
+    if name.is_empty() || name.len() % 2 != 0 {
+        return None;
+    }
+    (0..name.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(name.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Key-value store shared by several subway instances, e.g. Redis behind a load balancer.
+/// Checked on a miss before the value is fetched, and written with every fetched value.
+/// Failures are logged by the backend and count as a miss.
+#[async_trait]
+pub trait SharedCacheBackend: Send + Sync {
+    async fn get(&self, key: &[u8]) -> Option<JsonValue>;
+    async fn set(&self, key: &[u8], value: &JsonValue, ttl: Option<Duration>);
+    async fn remove(&self, key: &[u8]);
+    /// Removes all entries of this cache, not of other caches sharing the store.
+    async fn clear(&self);
+}
+
 #[derive(Clone)]
 pub struct Cache<D: Digest> {
-    cache: moka::future::Cache<CacheKey<D>, CacheValue>,
+    cache: moka::future::Cache<CacheKey<D>, Entry>,
+    // number of entries evicted for lack of capacity, invalidated with `remove` or cleared
+    evictions: Arc<AtomicU64>,
+    disk: Option<Arc<DiskTier>>,
+    shared: Option<Arc<dyn SharedCacheBackend>>,
+    // scopes the keys of the shared backend, see `start_generation`
+    generation: Arc<AtomicU64>,
+    ttl: Option<Duration>,
 }
 
 impl<D: Digest + 'static> Cache<D> {
     pub fn new(size: NonZeroUsize, ttl: Option<Duration>) -> Self {
+        Self::build(size, ttl, None)
+    }
+
+    /// Creates a cache which writes entries evicted for lack of capacity to `dir`
+    /// and checks it on a miss before the value is fetched again.
+    /// Disk entries follow the same ttl as memory entries, and the oldest are removed
+    /// once there are more than `max_disk_entries`.
+    pub fn with_disk_spillover(
+        size: NonZeroUsize,
+        ttl: Option<Duration>,
+        dir: impl Into<PathBuf>,
+        max_disk_entries: usize,
+    ) -> std::io::Result<Self> {
+        let disk = DiskTier::spawn(dir.into(), ttl, max_disk_entries)?;
+        Ok(Self::build(size, ttl, Some(Arc::new(disk))))
+    }
+
+    fn build(size: NonZeroUsize, ttl: Option<Duration>, disk: Option<Arc<DiskTier>>) -> Self {
         let size = size.get();
-        let mut builder = moka::future::Cache::<CacheKey<D>, CacheValue>::builder()
+        let mut builder = moka::future::Cache::<CacheKey<D>, Entry>::builder()
             .max_capacity(size as u64)
             .initial_capacity(size);
 
         if let Some(duration) = ttl {
-            builder = builder.time_to_live(duration);
+            builder = builder.expire_after(EntryExpiry(duration));
         }
 
+        let evictions = Arc::<AtomicU64>::default();
+        builder = builder.eviction_listener({
+            let evictions = evictions.clone();
+            let disk = disk.clone();
+            move |key, entry: Entry, cause| {
+                if !matches!(cause, RemovalCause::Size) {
+                    return;
+                }
+                evictions.fetch_add(1, Ordering::Relaxed);
+                if let (Some(disk), CacheValue::Value(value)) = (&disk, entry.value) {
+                    disk.write(key.0.as_slice(), &value, entry.inserted);
+                }
+            }
+        });
+
         let cache = builder.build();
 
-        Self { cache }
+        Self {
+            cache,
+            evictions,
+            disk,
+            shared: None,
+            generation: Default::default(),
+            ttl,
+        }
+    }
+
+    /// Also reads and writes entries in `backend`, with the same ttl as memory entries.
+    pub fn with_shared_backend(mut self, backend: Arc<dyn SharedCacheBackend>) -> Self {
+        self.shared = Some(backend);
+        self
+    }
+
+    /// Key of the entry in the shared backend, scoped by the generation once one was started.
+    fn shared_key(&self, key: &CacheKey<D>) -> Vec<u8> {
+        match self.generation.load(Ordering::Relaxed) {
+            0 => key.0.to_vec(),
+            generation => key.scoped(&generation.to_le_bytes()).0.to_vec(),
+        }
+    }
+
+    /// Moves the entry from the disk tier back to memory, or copies it from the shared backend, if any.
+    async fn promote(&self, key: &CacheKey<D>) -> Option<JsonValue> {
+        let value = match self.disk {
+            Some(ref disk) => disk.take(key.0.as_slice()).await,
+            None => None,
+        };
+        let (value, inserted) = match value {
+            Some(value) => value,
+            None => (
+                self.shared.as_ref()?.get(&self.shared_key(key)).await?,
+                SystemTime::now(),
+            ),
+        };
+        let entry = Entry {
+            value: CacheValue::Value(value.clone()),
+            inserted,
+        };
+        self.cache.insert(key.clone(), entry).await;
+        Some(value)
     }
 
     pub async fn get(&self, key: &CacheKey<D>) -> Option<JsonValue> {
-        match self.cache.get(key).await {
+        match self.cache.get(key).await.map(|entry| entry.value) {
             Some(CacheValue::Value(value)) => Some(value),
             Some(CacheValue::Pending(mut rx)) => {
                 let value = rx.borrow();
@@ -87,69 +460,165 @@ impl<D: Digest + 'static> Cache<D> {
                     None
                 }
             }
-            None => None,
+            None => self.promote(key).await,
         }
     }
 
     pub async fn insert(&self, key: CacheKey<D>, value: JsonValue) {
-        self.cache.insert(key, CacheValue::Value(value)).await;
+        if let Some(shared) = &self.shared {
+            shared.set(&self.shared_key(&key), &value, self.ttl).await;
+        }
+        self.cache.insert(key, Entry::new(CacheValue::Value(value))).await;
     }
 
     pub async fn get_or_insert_with<F>(&self, key: CacheKey<D>, f: F) -> CallResult
     where
         F: FnOnce() -> BoxFuture<'static, CallResult>,
     {
-        let fetch = || async {
-            let (tx, rx) = watch::channel(None);
-            self.cache.insert(key.clone(), CacheValue::Pending(rx)).await;
-            let value = f().await;
-            let _ = tx.send(Some(value.clone()));
-            match &value {
-                Ok(value) => {
-                    self.cache.insert(key.clone(), CacheValue::Value(value.clone())).await;
-                }
-                Err(_) => {
-                    self.cache.remove(&key).await;
-                }
-            };
-            value
-        };
+        match self.lookup(&key).await {
+            Some(value) => value,
+            None => self.insert_with(key, f).await,
+        }
+    }
 
-        match self.cache.get(&key).await {
-            Some(CacheValue::Value(value)) => Ok(value),
+    /// Reads the value from memory or the disk tier, waiting for a pending fetch of the same key.
+    /// Returns `None` on a miss or if the pending fetch got canceled.
+    pub async fn lookup(&self, key: &CacheKey<D>) -> Option<CallResult> {
+        self.lookup_with_timeout(key, None).await
+    }
+
+    /// Same as `lookup` but a read of the disk tier or shared backend slower than `promote_timeout` is a miss.
+    /// Waiting for a pending fetch of the same key is not bounded, the value is already on its way.
+    pub async fn lookup_with_timeout(
+        &self,
+        key: &CacheKey<D>,
+        promote_timeout: Option<Duration>,
+    ) -> Option<CallResult> {
+        match self.cache.get(key).await.map(|entry| entry.value) {
+            Some(CacheValue::Value(value)) => Some(Ok(value)),
             Some(CacheValue::Pending(mut rx)) => {
                 {
                     // limit the scope of value
                     let value = rx.borrow();
                     if value.is_some() {
-                        return value.clone().unwrap();
+                        return value.clone();
                     }
                 }
 
                 let _ = rx.changed().await;
 
-                {
-                    // limit the scope of value
-                    let value = rx.borrow();
-                    if let Some(value) = &*value {
-                        return value.clone();
+                // `None` only happens when initial fetch request got canceled for some reason
+                // in that case the caller needs to fetch again
+                let value = rx.borrow();
+                value.clone()
+            }
+            None => match promote_timeout {
+                Some(timeout) => match tokio::time::timeout(timeout, self.promote(key)).await {
+                    Ok(value) => value.map(Ok),
+                    Err(_) => {
+                        tracing::debug!("Cache: lookup timed out, fetching instead");
+                        None
                     }
-                }
+                },
+                None => self.promote(key).await.map(Ok),
+            },
+        }
+    }
 
-                // this only happens when initial fetch request got canceled for some reason
-                // in that case we need to fetch again
-                fetch().await
+    /// Fetches the value with `f` and caches it, concurrent lookups of the key wait for the result.
+    pub async fn insert_with<F>(&self, key: CacheKey<D>, f: F) -> CallResult
+    where
+        F: FnOnce() -> BoxFuture<'static, CallResult>,
+    {
+        let (tx, rx) = watch::channel(None);
+        self.cache
+            .insert(key.clone(), Entry::new(CacheValue::Pending(rx)))
+            .await;
+        let value = f().await;
+        let _ = tx.send(Some(value.clone()));
+        match &value {
+            Ok(value) => {
+                if let Some(shared) = &self.shared {
+                    shared.set(&self.shared_key(&key), value, self.ttl).await;
+                }
+                self.cache
+                    .insert(key.clone(), Entry::new(CacheValue::Value(value.clone())))
+                    .await;
             }
-            None => fetch().await,
+            Err(_) => {
+                self.cache.remove(&key).await;
+            }
+        };
+        value
+    }
+
+    /// Invalidates the entry and returns its value if it was resolved.
+    pub async fn remove(&self, key: &CacheKey<D>) -> Option<JsonValue> {
+        let removed = self.take(key).await?;
+        self.evictions.fetch_add(1, Ordering::Relaxed);
+        match removed {
+            CacheValue::Value(value) => Some(value),
+            CacheValue::Pending(_) => None,
+        }
+    }
+
+    /// Drops the entry without counting it as an eviction, e.g. a value which should not have been cached.
+    pub async fn discard(&self, key: &CacheKey<D>) {
+        self.take(key).await;
+    }
+
+    async fn take(&self, key: &CacheKey<D>) -> Option<CacheValue> {
+        if let Some(disk) = &self.disk {
+            disk.remove(key.0.as_slice()).await;
+        }
+        if let Some(shared) = &self.shared {
+            shared.remove(&self.shared_key(key)).await;
+        }
+        self.cache.remove(key).await.map(|entry| entry.value)
+    }
+
+    /// Removes all entries.
+    pub async fn clear(&self) {
+        self.clear_local().await;
+        if let Some(shared) = &self.shared {
+            shared.clear().await;
+        }
+    }
+
+    /// Removes the entries in memory and on disk, and keys the shared backend by `generation` from now on,
+    /// so entries of other generations are no longer found without removing them one by one.
+    /// Instances starting the same generation share entries again, entries of older ones expire with the ttl.
+    pub async fn start_generation(&self, generation: u64) {
+        // 0 means no generation
+        self.generation.store(generation.max(1), Ordering::Relaxed);
+        self.clear_local().await;
+    }
+
+    async fn clear_local(&self) {
+        self.cache.run_pending_tasks().await;
+        self.evictions.fetch_add(self.cache.entry_count(), Ordering::Relaxed);
+        self.cache.invalidate_all();
+        self.cache.run_pending_tasks().await;
+        if let Some(disk) = &self.disk {
+            disk.clear().await;
         }
     }
 
-    pub async fn remove(&self, key: &CacheKey<D>) {
-        self.cache.remove(key).await;
+    /// Approximate number of entries in memory.
+    pub fn entry_count(&self) -> u64 {
+        self.cache.entry_count()
+    }
+
+    /// Number of entries evicted for lack of capacity, invalidated with `remove` or cleared.
+    pub fn evictions(&self) -> u64 {
+        self.evictions.load(Ordering::Relaxed)
     }
 
     pub async fn sync(&self) {
         self.cache.run_pending_tasks().await;
+        if let Some(disk) = &self.disk {
+            disk.flush().await;
+        }
     }
 }
 
@@ -172,9 +641,219 @@ mod tests {
 
         assert_eq!(cache.get(&key).await, Some(json!("value")));
 
-        cache.remove(&key).await;
+        assert_eq!(cache.remove(&key).await, Some(json!("value")));
 
         assert_eq!(cache.get(&key).await, None);
+        assert_eq!(cache.remove(&key).await, None);
+        assert_eq!(cache.evictions(), 1);
+
+        cache.insert(key.clone(), json!("value")).await;
+        cache.discard(&key).await;
+        assert_eq!(cache.get(&key).await, None);
+        assert_eq!(cache.evictions(), 1);
+    }
+
+    #[tokio::test]
+    async fn counts_capacity_evictions() {
+        let cache = Cache::<blake2::Blake2b512>::new(NonZeroUsize::new(1).unwrap(), None);
+
+        for i in 0..3 {
+            let key = CacheKey::<blake2::Blake2b512>::new(&format!("key{i}"), &[]);
+            cache.insert(key, json!(i)).await;
+            cache.sync().await;
+        }
+
+        assert_eq!(cache.entry_count(), 1);
+        assert_eq!(cache.evictions(), 2);
+    }
+
+    #[tokio::test]
+    async fn clear_works() {
+        let cache = Cache::<blake2::Blake2b512>::new(NonZeroUsize::new(10).unwrap(), None);
+
+        let key1 = CacheKey::<blake2::Blake2b512>::new(&"key1".to_string(), &[]);
+        let key2 = CacheKey::<blake2::Blake2b512>::new(&"key2".to_string(), &[]);
+
+        cache.insert(key1.clone(), json!(1)).await;
+        cache.insert(key2.clone(), json!(2)).await;
+
+        cache.clear().await;
+
+        assert_eq!(cache.get(&key1).await, None);
+        assert_eq!(cache.get(&key2).await, None);
+        assert_eq!(cache.evictions(), 2);
+    }
+
+    #[tokio::test]
+    async fn disk_spillover_works() {
+        let dir = std::env::temp_dir().join(format!("subway-spillover-{}", std::process::id()));
+        let cache =
+            Cache::<blake2::Blake2b512>::with_disk_spillover(NonZeroUsize::new(1).unwrap(), None, &dir, 10).unwrap();
+
+        let key1 = CacheKey::<blake2::Blake2b512>::new(&"key1".to_string(), &[]);
+        let key2 = CacheKey::<blake2::Blake2b512>::new(&"key2".to_string(), &[]);
+
+        cache.insert(key1.clone(), json!(1)).await;
+        cache.insert(key2.clone(), json!(2)).await;
+        cache.sync().await;
+
+        // one of them is evicted to disk, both are still served
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        assert_eq!(
+            cache
+                .get_or_insert_with(key1.clone(), || async { panic!() }.boxed())
+                .await,
+            Ok(json!(1))
+        );
+        cache.sync().await;
+        assert_eq!(cache.get(&key2).await, Some(json!(2)));
+
+        cache.clear().await;
+        assert_eq!(cache.get(&key1).await, None);
+        assert_eq!(cache.get(&key2).await, None);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn disk_spillover_is_bounded_and_keeps_other_files() {
+        let dir = std::env::temp_dir().join(format!("subway-spillover-bounded-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("other"), "keep").unwrap();
+        let cache =
+            Cache::<blake2::Blake2b512>::with_disk_spillover(NonZeroUsize::new(1).unwrap(), None, &dir, 2).unwrap();
+
+        let keys: Vec<_> = (0..5)
+            .map(|i| CacheKey::<blake2::Blake2b512>::new(&format!("key{i}"), &[]))
+            .collect();
+        for (i, key) in keys.iter().enumerate() {
+            cache.insert(key.clone(), json!(i)).await;
+            cache.sync().await;
+        }
+
+        // 4 evicted from memory, only the 2 latest kept on disk next to the foreign file
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 3);
+        assert_eq!(cache.get(&keys[0]).await, None);
+        assert_eq!(cache.get(&keys[3]).await, Some(json!(3)));
+
+        cache.clear().await;
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        assert_eq!(std::fs::read_to_string(dir.join("other")).unwrap(), "keep");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn disk_spillover_indexes_files_of_previous_process() {
+        let dir = std::env::temp_dir().join(format!("subway-spillover-restart-{}", std::process::id()));
+        let ttl = Some(Duration::from_secs(60));
+        let key1 = CacheKey::<blake2::Blake2b512>::new(&"key1".to_string(), &[]);
+        let key2 = CacheKey::<blake2::Blake2b512>::new(&"key2".to_string(), &[]);
+
+        let cache =
+            Cache::<blake2::Blake2b512>::with_disk_spillover(NonZeroUsize::new(1).unwrap(), ttl, &dir, 10).unwrap();
+        cache.insert(key1.clone(), json!(1)).await;
+        cache.insert(key2.clone(), json!(2)).await;
+        cache.sync().await;
+        drop(cache);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        // spilled by the previous cache, served by the new one
+        let cache =
+            Cache::<blake2::Blake2b512>::with_disk_spillover(NonZeroUsize::new(1).unwrap(), ttl, &dir, 10).unwrap();
+        cache.sync().await;
+        let spilled = if cache.get(&key1).await.is_some() { key1 } else { key2 };
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+
+        // expires from the time it was fetched, not the time it was spilled
+        let name: String = spilled.0.iter().map(|b| format!("{b:02x}")).collect();
+        let inserted_ms = (SystemTime::now() - Duration::from_secs(120))
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        std::fs::write(
+            dir.join(name),
+            json!({ "inserted_ms": inserted_ms, "value": 1 }).to_string(),
+        )
+        .unwrap();
+        let cache =
+            Cache::<blake2::Blake2b512>::with_disk_spillover(NonZeroUsize::new(1).unwrap(), ttl, &dir, 10).unwrap();
+        cache.sync().await;
+        assert_eq!(cache.get(&spilled).await, None);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+
+        // files beyond the limit are removed on start
+        for i in 0..3u8 {
+            std::fs::write(dir.join(format!("{i:02x}")), "{}").unwrap();
+        }
+        let cache =
+            Cache::<blake2::Blake2b512>::with_disk_spillover(NonZeroUsize::new(1).unwrap(), ttl, &dir, 2).unwrap();
+        cache.sync().await;
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[derive(Default)]
+    struct MemoryBackend(std::sync::Mutex<std::collections::HashMap<Vec<u8>, JsonValue>>);
+
+    #[async_trait]
+    impl SharedCacheBackend for MemoryBackend {
+        async fn get(&self, key: &[u8]) -> Option<JsonValue> {
+            self.0.lock().unwrap().get(key).cloned()
+        }
+
+        async fn set(&self, key: &[u8], value: &JsonValue, _ttl: Option<Duration>) {
+            self.0.lock().unwrap().insert(key.to_vec(), value.clone());
+        }
+
+        async fn remove(&self, key: &[u8]) {
+            self.0.lock().unwrap().remove(key);
+        }
+
+        async fn clear(&self) {
+            self.0.lock().unwrap().clear();
+        }
+    }
+
+    #[tokio::test]
+    async fn shared_backend_works() {
+        let backend = Arc::new(MemoryBackend::default());
+        let new_cache = || {
+            Cache::<blake2::Blake2b512>::new(NonZeroUsize::new(10).unwrap(), None).with_shared_backend(backend.clone())
+        };
+        let (cache1, cache2) = (new_cache(), new_cache());
+
+        let key = CacheKey::<blake2::Blake2b512>::new(&"key".to_string(), &[]);
+
+        // fetched by one instance, served by the other
+        assert_eq!(
+            cache1
+                .get_or_insert_with(key.clone(), || async { Ok(json!(1)) }.boxed())
+                .await,
+            Ok(json!(1))
+        );
+        assert_eq!(
+            cache2
+                .get_or_insert_with(key.clone(), || async { panic!() }.boxed())
+                .await,
+            Ok(json!(1))
+        );
+
+        // a new generation does not see the entries of the previous one, without removing them
+        cache1.start_generation(1).await;
+        cache2.start_generation(1).await;
+        assert_eq!(
+            cache2
+                .get_or_insert_with(key.clone(), || async { Ok(json!(2)) }.boxed())
+                .await,
+            Ok(json!(2))
+        );
+        assert_eq!(backend.0.lock().unwrap().len(), 2);
+        assert_eq!(cache1.get(&key).await, Some(json!(2)));
+
+        cache1.clear().await;
+        assert!(backend.0.lock().unwrap().is_empty());
     }
 
     #[tokio::test]
@@ -25,15 +25,60 @@ pub mod errors {
         )
     }
 
+    /// Call over the rate limit, `retry_after` is how long until it would be allowed.
+    pub fn rate_limited(retry_after: std::time::Duration) -> ErrorObjectOwned {
+        ErrorObjectOwned::owned(
+            CALL_EXECUTION_FAILED_CODE,
+            "rate limit exceeded",
+            Some(serde_json::json!({ "retry_after_ms": retry_after.as_millis() as u64 })),
+        )
+    }
+
+    /// Server error code of calls rejected for lack of permission.
+    pub const UNAUTHORIZED_CODE: i32 = -32001;
+
+    pub fn unauthorized<T: ToString>(msg: T) -> ErrorObjectOwned {
+        ErrorObjectOwned::owned(UNAUTHORIZED_CODE, "Unauthorized", Some(msg.to_string()))
+    }
+
+    /// Server error code of calls over the daily or monthly quota of their API key.
+    pub const QUOTA_EXCEEDED_CODE: i32 = -32017;
+
+    /// Call over a quota, `reset_at` is when the quota resets (RFC 3339).
+    pub fn quota_exceeded(period: impl ToString, limit: u64, reset_at: impl ToString) -> ErrorObjectOwned {
+        ErrorObjectOwned::owned(
+            QUOTA_EXCEEDED_CODE,
+            "quota exceeded",
+            Some(serde_json::json!({
+                "period": period.to_string(),
+                "limit": limit,
+                "reset_at": reset_at.to_string(),
+            })),
+        )
+    }
+
     pub fn internal_error<T: ToString>(msg: T) -> ErrorObjectOwned {
         ErrorObjectOwned::owned(INTERNAL_ERROR_CODE, INTERNAL_ERROR_MSG, Some(msg.to_string()))
     }
 
+    /// Message of internal errors raised by the client when upstream could not be reached,
+    /// e.g. lost connections or timeouts, as opposed to internal errors returned by upstream.
+    pub const UPSTREAM_UNREACHABLE_MSG: &str = "Upstream unreachable";
+
+    pub fn upstream_unreachable<T: ToString>(msg: T) -> ErrorObjectOwned {
+        ErrorObjectOwned::owned(INTERNAL_ERROR_CODE, UPSTREAM_UNREACHABLE_MSG, Some(msg.to_string()))
+    }
+
+    pub fn is_upstream_unreachable(err: &ErrorObjectOwned) -> bool {
+        err.code() == INTERNAL_ERROR_CODE && err.message() == UPSTREAM_UNREACHABLE_MSG
+    }
+
+    /// Errors of upstream are passed on, failures of the client to get an answer are `upstream_unreachable`.
     pub fn map_error(err: jsonrpsee::core::Error) -> ErrorObjectOwned {
         use jsonrpsee::core::Error::*;
         match err {
             Call(e) => e,
-            x => internal_error(x),
+            x => upstream_unreachable(x),
         }
     }
 }